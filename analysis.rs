@@ -3,6 +3,82 @@ use std::num::*;
 use std::vec;
 use variable_type::*;
 use basic_block::*;
+use std::hashmap::HashMap;
+
+/**
+ * Why a function's bytecode was rejected before it was compiled or
+ * translated. The interpreter does not need these guarantees.
+ */
+#[deriving(Clone)]
+#[deriving(Eq)]
+#[deriving(ToStr)]
+pub enum VerifyError {
+    /// The integer-only opcode at the given offset has a float operand.
+    FloatOperand(u32),
+
+    /// The TryEnd at the given offset does not close a try region.
+    UnmatchedTryEnd(u32),
+
+    /// The paths entering the basic block at the given offset are in
+    /// different try regions.
+    InconsistentTryRegions(u32),
+
+    /// The Divide at the given offset has an operand whose type cannot be
+    /// inferred, so compiled code cannot choose integer or float division.
+    UnknownOperand(u32)
+}
+
+/**
+ * Checks the analyses a function must pass to be compiled or translated.
+ *
+ * # Arguments
+ *
+ * * function - The function.
+ */
+pub fn verify(function: &[Opcode]) -> Result<(), VerifyError> {
+    let basic_blocks = get_basic_blocks(function);
+    let local_types = match infer_local_types(basic_blocks, local_count(function)) {
+        Ok(local_types) => local_types,
+        Err(error) => return Err(error)
+    };
+    match unknown_division(basic_blocks, local_types) {
+        Some(offset) => return Err(UnknownOperand(offset)),
+        None => { }
+    }
+    match handler_stacks(basic_blocks) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(error)
+    }
+}
+
+/**
+ * Finds a Divide with an operand of unknown type. The interpreter picks
+ * integer or float division from the values it is given, but compiled
+ * code has to pick from the types. This can only be checked once local
+ * types are fully inferred, since a local may be loaded before the store
+ * that gives it a type is seen.
+ *
+ * # Arguments
+ *
+ * * basic_blocks - The basic blocks that comprise the function.
+ * * local_types  - The inferred types of the function's locals.
+ *
+ * Returns the offset of the first such Divide, if any.
+ */
+fn unknown_division(basic_blocks: &[@mut BasicBlock], local_types: &[VariableType]) -> Option<u32> {
+    for basic_block in basic_blocks.iter() {
+        let types = stack_types(&**basic_block, local_types);
+        for (index, (opcode, stack)) in basic_block.opcodes.iter().zip(types.iter()).enumerate() {
+            match *opcode {
+                Divide if stack.rev_iter().take(2).any(|&t| t == Unknown) => {
+                    return Some(basic_block.start + index as u32);
+                }
+                _ => { }
+            }
+        }
+    }
+    None
+}
 
 /**
  * Returns the number of local variables in the function.
@@ -15,7 +91,7 @@ pub fn local_count(function: &[Opcode]) -> u32 {
     let mut count = 0u32;
     for opcode in function.iter() {
         match *opcode {
-            Loadi32(n) | Loadf32(n) => count = max(count, n),
+            Loadi32(n) | Loadf32(n) | Loadref(n) => count = max(count, n),
             Store(n) => count = max(count, n),
             _ => ()
         }
@@ -31,9 +107,10 @@ pub fn local_count(function: &[Opcode]) -> u32 {
  * * basic_blocks - The basic blocks that comprise the function.
  * * local_count  - The number of local variables in the function.
  *
- * Returns a list of VariableTypes representing the inferred types of the locals.
+ * Returns a list of VariableTypes representing the inferred types of the
+ * locals, or the first type error found.
  */
-pub fn infer_local_types(basic_blocks: &[@mut BasicBlock], local_count: u32) -> ~[VariableType] {
+pub fn infer_local_types(basic_blocks: &[@mut BasicBlock], local_count: u32) -> Result<~[VariableType], VerifyError> {
     // TODO: get local count in this function.
     let mut locals = vec::from_elem(local_count as uint, Unknown);
     let mut changed = true;
@@ -42,11 +119,14 @@ pub fn infer_local_types(basic_blocks: &[@mut BasicBlock], local_count: u32) ->
     while changed {
         changed = false;
         for basic_block in basic_blocks.iter() {
-            changed |= infer_local_types_for_basic_block(*basic_block, locals);
+            match infer_local_types_for_basic_block(*basic_block, locals) {
+                Ok(block_changed) => changed |= block_changed,
+                Err(error) => return Err(error)
+            }
         }
     }
 
-    return locals;
+    return Ok(locals);
 }
 
 /**
@@ -57,60 +137,373 @@ pub fn infer_local_types(basic_blocks: &[@mut BasicBlock], local_count: u32) ->
  * * basic_block - The basic blocks within which to infer local types.
  * * local_types - The current state of inferred local types.
  *
- * Returns a boolean indicating whether any additional type information was inferred.
+ * Returns a boolean indicating whether any additional type information was
+ * inferred, or the first type error found.
  */
-fn infer_local_types_for_basic_block(basic_block: &mut BasicBlock, local_types: &mut [VariableType]) -> bool {
+fn infer_local_types_for_basic_block(basic_block: &mut BasicBlock,
+                                     local_types: &mut [VariableType]) -> Result<bool, VerifyError> {
     let mut changed = false;
 
-    // The evaluation stack should be empty on entering a basic block.
-    let mut stack = ~[];
+    let mut stack = entry_stack_types(basic_block);
 
-    for opcode in basic_block.opcodes.iter() {
-        match *opcode {
-            Constf32(_) => {
-                stack.push(Float32); 
+    for (index, opcode) in basic_block.opcodes.iter().enumerate() {
+        let offset = basic_block.start + index as u32;
+        match infer_opcode_types(opcode, offset, &mut stack, local_types) {
+            Ok(opcode_changed) => changed |= opcode_changed,
+            Err(error) => return Err(error)
+        }
+    }
+    
+    Ok(changed)
+}
+
+/**
+ * Infers the types produced by a single opcode.
+ *
+ * # Arguments
+ *
+ * * opcode      - The opcode.
+ * * offset      - The offset of the opcode within its function.
+ * * stack       - The types on the evaluation stack, updated in place.
+ * * local_types - The current state of inferred local types.
+ *
+ * Returns a boolean indicating whether any additional type information was
+ * inferred, or the type error if the opcode does not accept its operands.
+ */
+fn infer_opcode_types(opcode: &Opcode, offset: u32, stack: &mut ~[VariableType],
+                      local_types: &mut [VariableType]) -> Result<bool, VerifyError> {
+    let mut changed = false;
+
+    match *opcode {
+        Constf32(_) => {
+            stack.push(Float32); 
+        }
+        Consti32(_) => {
+            stack.push(Int32);
+        }
+        ConstStr(_) => {
+            stack.push(String);
+        }
+        Concat => {
+            stack.pop();
+            stack.pop();
+            stack.push(String);
+        }
+        StrLen => {
+            stack.pop();
+            stack.push(Int32);
+        }
+        StrCmp => {
+            stack.pop();
+            stack.pop();
+            stack.push(Int32);
+        }
+        IntToStr | FloatToStr => {
+            stack.pop();
+            stack.push(String);
+        }
+        Add | Subtract | Multiply | Divide | Min | Max => { 
+            let t = stack.pop();
+            let t2 = stack.pop();
+            if t != t2 && t != Unknown && t2 != Unknown {
+                // TODO: may cause problems if one is Unknown
+                println(fmt!("Warning... conflicting types... taking as %?", t));
+            }
+            stack.push(t);
+        }
+        Eq | Neq | Leq | Geq | Lt | Gt => { 
+            stack.pop();
+            stack.pop();
+            stack.push(Int32);
+        }
+        And | Or | Xor | Rem | Shl | Shr | Ushr | Ltu | Gtu | Lequ | Gequ | AddOvf | SubOvf | MulOvf => {
+            let t = stack.pop();
+            let t2 = stack.pop();
+            if t == Float32 || t2 == Float32 {
+                return Err(FloatOperand(offset));
             }
-            Consti32(_) => {
-                stack.push(Int32);
+            stack.push(Int32);
+        }
+        Pow => {
+            stack.pop();
+            stack.pop();
+            stack.push(Float32);
+        }
+        Negate | Abs => { /* pop & push same type */ }
+        Sqrt | Floor | Ceil | Round | Trunc | Sin | Cos | Tan | Exp | Log => {
+            stack.pop();
+            stack.push(Float32);
+        }
+        Not => { 
+            if stack.pop() == Float32 {
+                return Err(FloatOperand(offset));
             }
-            Add | Subtract | Multiply | Divide => { 
-                let t = stack.pop();
-                let t2 = stack.pop();
-                if t != t2 && t != Unknown && t2 != Unknown {
-                    // TODO: may cause problems if one is Unknown
-                    println(fmt!("Warning... conflicting types... taking as %?", t));
+            stack.push(Int32);
+        }
+        Store(addr) => {
+            let t = stack.pop();
+            if t != Unknown {
+                if local_types[addr] != Unknown && local_types[addr] != t {
+                    println(fmt!("Warning... local(%?) has conflicting types", addr));
+                } else if local_types[addr] != t {
+                    local_types[addr] = t;
+                    changed = true;
                 }
-                stack.push(t);
             }
-            And | Or | Xor | Eq | Neq | Leq | Geq | Lt | Gt => { 
-                // TODO: warning if float
-                stack.pop();
-                stack.pop();
-                stack.push(Int32);
+        }
+        Loadi32(addr) | Loadf32(addr) | Loadref(addr) => {
+            stack.push(local_types[addr]);
+        }
+        NewArray(_) => {
+            stack.pop();
+            stack.push(Array);
+        }
+        ArrayLen => {
+            stack.pop();
+            stack.push(Int32);
+        }
+        ArrayLoad(element_type) => {
+            stack.pop();
+            stack.pop();
+            stack.push(element_type);
+        }
+        ArrayStore(_) => {
+            stack.pop();
+            stack.pop();
+            stack.pop();
+        }
+        Alloc(_) => {
+            stack.push(Object);
+        }
+        GetField(_, field_type) => {
+            stack.pop();
+            stack.push(field_type);
+        }
+        SetField(_, _) => {
+            stack.pop();
+            stack.pop();
+        }
+        Ret | Disp => {
+            stack.pop();
+        }
+        Throw => {
+            if stack.pop() == Float32 {
+                return Err(FloatOperand(offset));
             }
-            Negate => { /* pop & push same type */ }
-            Not => { 
-                stack.pop();
-                // TODO: warning if float
-                stack.push(Int32);
+        }
+        _ => { }
+    }
+
+    Ok(changed)
+}
+
+/**
+ * Returns the types on the evaluation stack on entering a basic block.
+ * The stack is empty, except in exception handlers, which start with
+ * the exception value.
+ *
+ * # Arguments
+ *
+ * * basic_block - The basic block.
+ */
+fn entry_stack_types(basic_block: &BasicBlock) -> ~[VariableType] {
+    if basic_block.handler { ~[Int32] } else { ~[] }
+}
+
+/**
+ * Computes the types on the evaluation stack before each opcode in a basic
+ * block. Type errors are not reported again; infer_local_types rejects them.
+ *
+ * # Arguments
+ *
+ * * basic_block - The basic block.
+ * * local_types - The inferred types of the function's locals.
+ *
+ * Returns one list of types per opcode, bottom of the stack first.
+ */
+pub fn stack_types(basic_block: &BasicBlock, local_types: &[VariableType]) -> ~[~[VariableType]] {
+    let mut local_types = local_types.to_owned();
+    let mut stack = entry_stack_types(basic_block);
+    let mut result = ~[];
+
+    for (index, opcode) in basic_block.opcodes.iter().enumerate() {
+        result.push(stack.clone());
+        let _ = infer_opcode_types(opcode, basic_block.start + index as u32, &mut stack, local_types);
+    }
+
+    result
+}
+
+/**
+ * Returns the locals that hold heap references. This is the precise
+ * root map the garbage collector uses for a compiled function's locals.
+ *
+ * # Arguments
+ *
+ * * local_types - The inferred types of the function's locals.
+ */
+pub fn reference_locals(local_types: &[VariableType]) -> ~[u32] {
+    let mut locals = ~[];
+    for (index, local_type) in local_types.iter().enumerate() {
+        if local_type.is_reference() {
+            locals.push(index as u32);
+        }
+    }
+    locals
+}
+
+/**
+ * Infers the type of the value a function returns.
+ *
+ * # Arguments
+ *
+ * * basic_blocks - The basic blocks that comprise the function.
+ * * local_types  - The inferred types of the function's locals.
+ *
+ * Returns the type of the first Ret whose operand type is known, or Unknown.
+ */
+pub fn infer_return_type(basic_blocks: &[@mut BasicBlock], local_types: &[VariableType]) -> VariableType {
+    for basic_block in basic_blocks.iter() {
+        let types = stack_types(&**basic_block, local_types);
+        for (opcode, stack) in basic_block.opcodes.iter().zip(types.iter()) {
+            match *opcode {
+                Ret => {
+                    match stack.last_opt() {
+                        Some(&t) if t != Unknown => return t,
+                        _ => { }
+                    }
+                }
+                _ => { }
             }
-            Store(addr) => {
-                let t = stack.pop();
-                if t != Unknown {
-                    if local_types[addr] != Unknown && local_types[addr] != t {
-                        println(fmt!("Warning... local(%?) has conflicting types", addr));
-                    } else if local_types[addr] != t {
-                        local_types[addr] = t;
-                        changed = true;
+        }
+    }
+    Unknown
+}
+
+/**
+ * Works out whether a function returns a value. The interpreter returns
+ * nothing when a call runs off the end of the function instead of reaching
+ * a Ret, which compiled code cannot tell apart from returning a value.
+ *
+ * # Arguments
+ *
+ * * basic_blocks - The basic blocks that comprise the function.
+ *
+ * Returns Some(true) if every reachable path that returns ends in a Ret,
+ * Some(false) if none does, and None if both kinds of path are reachable.
+ */
+pub fn returns_value(basic_blocks: &[@mut BasicBlock]) -> Option<bool> {
+    let mut visited: HashMap<u32, ()> = HashMap::new();
+    let mut worklist = ~[basic_blocks[0]];
+    let mut rets = false;
+    let mut falls_off = false;
+
+    while !worklist.is_empty() {
+        let basic_block = worklist.pop();
+        if !visited.insert(basic_block.start, ()) {
+            loop;
+        }
+
+        // Control leaves the block at its first Ret or Throw, if any.
+        let mut ends = false;
+        let mut successors = ~[];
+        for opcode in basic_block.opcodes.iter() {
+            match *opcode {
+                TryBegin(handler) => successors.push(handler),
+                Ret => { rets = true; ends = true; break; }
+                Throw => { ends = true; break; }
+                _ => { }
+            }
+        }
+        if !ends {
+            for next in basic_block.next_block.iter().chain(basic_block.conditional_block.iter()) {
+                successors.push(next.start);
+            }
+            if basic_block.next_block.is_none() {
+                falls_off = true;
+            }
+        }
+
+        for start in successors.iter() {
+            for block in basic_blocks.iter() {
+                if block.start == *start {
+                    worklist.push(*block);
+                }
+            }
+        }
+    }
+
+    match (rets, falls_off) {
+        (true, true) => None,
+        (rets, _) => Some(rets)
+    }
+}
+
+/**
+ * Computes the exception handlers installed on entering each basic block.
+ * Try regions must be entered and left the same way on every path, so
+ * that compiled code knows statically which handler is active.
+ *
+ * # Arguments
+ *
+ * * basic_blocks - The basic blocks that comprise the function.
+ *
+ * Returns the handler addresses for each block, innermost last, in the
+ * order of basic_blocks, or the first place the try regions do not nest.
+ * Unreachable blocks have no handlers.
+ */
+pub fn handler_stacks(basic_blocks: &[@mut BasicBlock]) -> Result<~[~[u32]], VerifyError> {
+    let mut stacks: HashMap<u32, ~[u32]> = HashMap::new();
+    let mut worklist = ~[basic_blocks[0]];
+    stacks.insert(basic_blocks[0].start, ~[]);
+
+    while !worklist.is_empty() {
+        let basic_block = worklist.pop();
+        let mut handlers = stacks.get(&basic_block.start).clone();
+
+        let mut successors = ~[];
+        for (index, opcode) in basic_block.opcodes.iter().enumerate() {
+            match *opcode {
+                TryBegin(handler) => {
+                    // The handler runs under the handlers that enclose its region.
+                    successors.push((handler, handlers.clone()));
+                    handlers.push(handler);
+                }
+                TryEnd => {
+                    if handlers.pop_opt().is_none() {
+                        return Err(UnmatchedTryEnd(basic_block.start + index as u32));
                     }
                 }
+                _ => { }
             }
-            Loadi32(addr) | Loadf32(addr) => {
-                stack.push(local_types[addr]);
+        }
+        for next in basic_block.next_block.iter().chain(basic_block.conditional_block.iter()) {
+            successors.push((next.start, handlers.clone()));
+        }
+
+        for (start, handlers) in successors.move_iter() {
+            match stacks.find(&start) {
+                Some(existing) => {
+                    if *existing != handlers {
+                        return Err(InconsistentTryRegions(start));
+                    }
+                    loop;
+                }
+                None => { }
+            }
+            stacks.insert(start, handlers);
+            for block in basic_blocks.iter() {
+                if block.start == start {
+                    worklist.push(*block);
+                }
             }
-            _ => { }
         }
     }
-    
-    changed
+
+    Ok(do basic_blocks.map |basic_block| {
+        match stacks.find(&basic_block.start) {
+            Some(handlers) => handlers.clone(),
+            None => ~[]
+        }
+    })
 }
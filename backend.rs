@@ -0,0 +1,226 @@
+use std::libc::*;
+
+/// libjit's builtin exception codes (JIT_RESULT_*), which every backend
+/// throws for the same errors.
+pub static JIT_RESULT_OVERFLOW: c_int = 0;
+pub static JIT_RESULT_ARITHMETIC: c_int = -1;
+pub static JIT_RESULT_DIVISION_BY_ZERO: c_int = -2;
+pub static JIT_RESULT_OUT_OF_MEMORY: c_int = -4;
+pub static JIT_RESULT_NULL_REFERENCE: c_int = -5;
+pub static JIT_RESULT_OUT_OF_BOUNDS: c_int = -8;
+
+/**
+ * An exception that escaped compiled code.
+ */
+pub enum JitException {
+    /// A builtin exception, with its JIT_RESULT_* code.
+    Builtin(c_int),
+    /// An object thrown by compiled code.
+    Thrown(*c_void)
+}
+
+/**
+ * A value in the function a Backend is building. Backends hand these out
+ * and map them to their own representation.
+ */
+#[deriving(Clone)]
+#[deriving(Eq)]
+pub struct ValueId(uint);
+
+/**
+ * A label in the function a Backend is building.
+ */
+#[deriving(Clone)]
+#[deriving(Eq)]
+pub struct LabelId(uint);
+
+/**
+ * The machine types compiled code works with. VM integers and heap
+ * handles are IntType, and VM floats are FloatType.
+ */
+#[deriving(Clone)]
+#[deriving(Eq)]
+#[deriving(ToStr)]
+pub enum ScalarType {
+    /// A signed 32-bit integer.
+    IntType,
+    /// An unsigned 32-bit integer.
+    UIntType,
+    /// A signed pointer-sized integer.
+    NIntType,
+    /// A 32-bit float.
+    FloatType,
+    /// A pointer.
+    PointerType,
+    /// No value, for helpers that return nothing.
+    VoidType
+}
+
+/**
+ * Operations on two values. Integer operations are signed unless both
+ * operands are UIntType. Comparisons produce an IntType 0 or 1.
+ */
+#[deriving(Clone)]
+#[deriving(Eq)]
+#[deriving(ToStr)]
+pub enum BinaryOp {
+    AddOp,
+    SubOp,
+    MulOp,
+    DivOp,
+    RemOp,
+    /// Addition that throws JIT_RESULT_OVERFLOW if it overflows.
+    AddOvfOp,
+    /// Subtraction that throws JIT_RESULT_OVERFLOW if it overflows.
+    SubOvfOp,
+    /// Multiplication that throws JIT_RESULT_OVERFLOW if it overflows.
+    MulOvfOp,
+    AndOp,
+    OrOp,
+    XorOp,
+    ShlOp,
+    /// A right shift, which is logical for unsigned values.
+    ShrOp,
+    /// An arithmetic right shift.
+    SshrOp,
+    MinOp,
+    MaxOp,
+    PowOp,
+    EqOp,
+    NeqOp,
+    LtOp,
+    GtOp,
+    LeqOp,
+    GeqOp
+}
+
+/**
+ * Operations on one value. The math functions take and return floats.
+ */
+#[deriving(Clone)]
+#[deriving(Eq)]
+#[deriving(ToStr)]
+pub enum UnaryOp {
+    NegOp,
+    NotOp,
+    AbsOp,
+    SqrtOp,
+    FloorOp,
+    CeilOp,
+    RoundOp,
+    TruncOp,
+    SinOp,
+    CosOp,
+    TanOp,
+    ExpOp,
+    LogOp
+}
+
+/**
+ * A code generator that jit.rs compiles functions into. The frontend works
+ * out what to emit from the opcodes; a Backend only has to provide the
+ * instructions below, in the style of libjit's: values are immutable
+ * temporaries, except for locals, which are written with store.
+ *
+ * A Backend builds one function at a time, between create_function and
+ * finalize.
+ */
+pub trait Backend {
+    /**
+     * Starts building a function.
+     *
+     * # Arguments
+     *
+     * * params      - The types of the function's parameters.
+     * * return_type - The type the function returns.
+     */
+    fn create_function(&mut self, params: &[ScalarType], return_type: ScalarType);
+
+    /// Finishes building the function, once every instruction is emitted.
+    fn finalize(&mut self);
+
+    /// Returns one of the function's parameters.
+    fn param(&mut self, index: uint) -> ValueId;
+
+    fn constant_int32(&mut self, constant: i32) -> ValueId;
+    fn constant_float32(&mut self, constant: f32) -> ValueId;
+    fn constant_pointer(&mut self, constant: *c_void) -> ValueId;
+
+    /// Creates a local, which unlike other values can be stored to.
+    fn create_local(&mut self, value_type: ScalarType) -> ValueId;
+
+    /// Stores a value in a local.
+    fn store(&mut self, local: ValueId, value: ValueId);
+
+    /// Returns a copy of a value, which later stores to a local do not change.
+    fn copy(&mut self, value: ValueId) -> ValueId;
+
+    fn binary(&mut self, op: BinaryOp, v1: ValueId, v2: ValueId) -> ValueId;
+    fn unary(&mut self, op: UnaryOp, value: ValueId) -> ValueId;
+
+    /// Converts a value to another type, wrapping integers and truncating floats.
+    fn convert(&mut self, value: ValueId, value_type: ScalarType) -> ValueId;
+
+    /// Loads a value of the given type from an address plus a byte offset.
+    fn load_relative(&mut self, address: ValueId, offset: int, value_type: ScalarType) -> ValueId;
+
+    /// Stores a value at an address plus a byte offset.
+    fn store_relative(&mut self, address: ValueId, offset: int, value: ValueId);
+
+    /// Returns an address plus a byte offset.
+    fn add_relative(&mut self, address: ValueId, offset: int) -> ValueId;
+
+    /// Loads the element at an index from an array of the given type.
+    fn load_elem(&mut self, address: ValueId, index: ValueId, value_type: ScalarType) -> ValueId;
+
+    /// Stores a value as the element at an index of an array of the value's type.
+    fn store_elem(&mut self, address: ValueId, index: ValueId, value: ValueId);
+
+    /// Throws JIT_RESULT_NULL_REFERENCE if a pointer is null.
+    fn check_null(&mut self, value: ValueId);
+
+    /**
+     * Calls a native function.
+     *
+     * # Arguments
+     *
+     * * name        - The name of the function, for dumps.
+     * * address     - The address of the function.
+     * * return_type - The type the function returns.
+     * * param_types - The types of the function's parameters.
+     * * args        - The values to pass.
+     *
+     * Returns the value the function returned, which is meaningless if it returns VoidType.
+     */
+    fn call_native(&mut self, name: &str, address: *c_void, return_type: ScalarType, param_types: &[ScalarType],
+                   args: &[ValueId]) -> ValueId;
+
+    fn new_label(&mut self) -> LabelId;
+
+    /// Places a label at the current position.
+    fn set_label(&mut self, label: LabelId);
+
+    fn branch(&mut self, label: LabelId);
+    fn branch_if(&mut self, condition: ValueId, label: LabelId);
+    fn branch_if_not(&mut self, condition: ValueId, label: LabelId);
+
+    fn ret(&mut self, value: ValueId);
+
+    /// Throws a pointer-sized value to the function's catcher or its caller.
+    fn throw(&mut self, value: ValueId);
+
+    /// Throws a builtin exception (JIT_RESULT_*).
+    fn throw_builtin(&mut self, exception_type: c_int);
+
+    /// Starts the function's catcher, which every exception thrown in the function goes to.
+    fn start_catcher(&mut self);
+
+    /**
+     * Returns the exception being caught, in the catcher. Builtin exceptions
+     * are encoded as (code << 1) | 1, and thrown values as they were thrown.
+     */
+    fn thrown_exception(&mut self) -> ValueId;
+
+    /// Throws the exception being caught on to the caller, from the catcher.
+    fn rethrow_unhandled(&mut self);
+}
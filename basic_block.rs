@@ -1,7 +1,7 @@
 use opcode::*;
 use std::trie::*;
 use std::ptr::*;
-use libjit::*;
+use backend::*;
 use std::to_bytes::*;
 use std::hash::*;
 
@@ -11,6 +11,10 @@ use std::hash::*;
  */
 #[Deriving(Hash)]
 struct BasicBlock {
+    /// The index of the first instruction of the block
+    /// within the function.
+    start: u32,
+
     /// Basic blocks that control can flow to this one from.
     prev_blocks: ~[@mut BasicBlock],
 
@@ -26,8 +30,13 @@ struct BasicBlock {
     /// The instructions within the basic block.
     opcodes: ~[Opcode],
 
-    /// The JIT Label that marks the start of this basic block.
-    label: ~Label
+    /// Whether the block is an exception handler, which is entered
+    /// with the exception value on the evaluation stack.
+    handler: bool,
+
+    /// The label that marks the start of this basic block, once
+    /// the Backend compiling it has created one.
+    label: Option<LabelId>
 }
 
 impl IterBytes for BasicBlock {
@@ -45,14 +54,20 @@ impl Eq for BasicBlock {
 impl BasicBlock {
     /**
      * Creates a new BasicBlock.
+     *
+     * # Arguments
+     *
+     * * start - The index of the first instruction of the block.
      */
-    pub fn new() -> @mut BasicBlock {
+    pub fn new(start: u32) -> @mut BasicBlock {
         @mut BasicBlock {
+            start: start,
             prev_blocks: ~[],
             conditional_block: None,
             next_block: None,
             opcodes: ~[],
-            label: Label::new()
+            handler: false,
+            label: None
         }
     }
 
@@ -67,7 +82,8 @@ impl BasicBlock {
      * Prints a basic block for diagnostic purposes.
      */
     pub fn print(&self) {
-        println(fmt!("BasicBlock: 0x%X", to_unsafe_ptr(self) as uint));
+        println(fmt!("BasicBlock: 0x%X (starts at %u%s)", to_unsafe_ptr(self) as uint, self.start as uint,
+                     if self.handler { ", handler" } else { "" }));
         
         println("prev_blocks:");
         for block in self.prev_blocks.iter() {
@@ -106,18 +122,26 @@ impl BasicBlock {
  */
 pub fn get_basic_blocks(function: &[Opcode]) -> ~[@mut BasicBlock] {
     let mut basic_blocks_map = TrieMap::new();
-    basic_blocks_map.insert(0u, BasicBlock::new());
+    basic_blocks_map.insert(0u, BasicBlock::new(0));
 
     for (index, opcode) in range(0, function.len()).zip(function.iter()) {
         match *opcode {
             Jmp(n) | Iftrue(n) => {
                 match basic_blocks_map.find(&(n as uint)) {
                     None => {
-                        basic_blocks_map.insert(n as uint, BasicBlock::new());
+                        basic_blocks_map.insert(n as uint, BasicBlock::new(n));
+                    }
+                    _ => { }
+                }
+                basic_blocks_map.insert(index + 1, BasicBlock::new((index + 1) as u32));
+            }
+            TryBegin(n) => {
+                match basic_blocks_map.find(&(n as uint)) {
+                    None => {
+                        basic_blocks_map.insert(n as uint, BasicBlock::new(n));
                     }
                     _ => { }
                 }
-                basic_blocks_map.insert(index + 1, BasicBlock::new());
             }
             _ => { }
         }
@@ -127,14 +151,31 @@ pub fn get_basic_blocks(function: &[Opcode]) -> ~[@mut BasicBlock] {
     let temp = basic_blocks_map.find(&0);
     let mut current_block: @mut BasicBlock = **temp.get_ref();
 
+    // Whether control can fall from the current block into the next.
+    let mut falls_through = true;
+
     // TODO: the types here are an absolute mess
     for (index, opcode) in range(0, function.len()).zip(function.iter()) {
+        if index != 0 {
+            match basic_blocks_map.find(&index) {
+                Some(b) => {
+                    if falls_through && current_block.next_block.is_none() {
+                        current_block.next_block = Some(*b);
+                        b.prev_blocks.push(current_block);
+                    }
+                    current_block = *b;
+                    falls_through = true;
+                }
+                None => { }
+            }
+        }
+
         match *opcode {
             Jmp(n) => {
                 let next_block = basic_blocks_map.find(&(n as uint));
                 next_block.get_ref().prev_blocks.push(current_block);
                 current_block.next_block = Some(**next_block.get_ref());
-                current_block = **next_block.get_ref();
+                falls_through = false;
             }
             Iftrue(n) => {
                 let conditional_block = basic_blocks_map.find(&(n as uint));
@@ -142,26 +183,19 @@ pub fn get_basic_blocks(function: &[Opcode]) -> ~[@mut BasicBlock] {
                 current_block.conditional_block = Some(**conditional_block.get_ref());
 
                 let next_block = basic_blocks_map.find(&(index + 1));
+                next_block.get_ref().prev_blocks.push(current_block);
                 current_block.next_block = Some(**next_block.get_ref());
-                current_block = **next_block.get_ref();
+                falls_through = false;
+            }
+            TryBegin(n) => {
+                basic_blocks_map.find(&(n as uint)).get_ref().handler = true;
+                current_block.push_opcode(*opcode);
+            }
+            Ret | Throw => {
+                current_block.push_opcode(*opcode);
+                falls_through = false;
             }
             _ => {
-                if (index != 0) {
-                    let temp = basic_blocks_map.find(&index);
-                    match temp {
-                        Some(b) => {
-                            match current_block.next_block {
-                                None => {
-                                    current_block.next_block = Some(*b);
-                                    b.prev_blocks.push(current_block);
-                                }
-                                _ => { }
-                            }
-                            current_block = *b; 
-                        }
-                        _ => { }
-                    }  
-                }
                 current_block.push_opcode(*opcode); 
             }
         }
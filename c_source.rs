@@ -0,0 +1,448 @@
+use opcode::*;
+use analysis::*;
+use basic_block::*;
+use variable_type::*;
+use vm_error::*;
+use std::cast::transmute;
+use std::i32;
+
+/// The statuses a translated function returns, as the prelude declares them.
+pub static C_OK: int = 0;
+pub static C_DIVISION_BY_ZERO: int = 1;
+pub static C_OVERFLOW: int = 2;
+
+/// The declarations every translated function needs.
+static PRELUDE: &'static str = "#include <math.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+
+/* The statuses a function returns. Errors store the offset of the
+   opcode that raised them in *error_offset. */
+enum { VM_OK, VM_DIVISION_BY_ZERO, VM_OVERFLOW };
+
+/* Returns the float with the given bits, for constants that have
+   no exact decimal form. */
+static inline float vm_float(uint32_t bits)
+{
+    float value;
+    memcpy(&value, &bits, sizeof value);
+    return value;
+}
+";
+
+/**
+ * Why a function cannot be translated to C.
+ */
+#[deriving(Clone)]
+#[deriving(ToStr)]
+pub enum CSourceError {
+    /// The function name is not a C identifier.
+    InvalidName(~str),
+
+    /// The opcode at the given offset needs the VM heap or exception
+    /// handling, which translated functions do not have.
+    UnsupportedOpcode(u32, Opcode),
+
+    /// The function does not verify.
+    InvalidBytecode(VerifyError)
+}
+
+/**
+ * Returns the VM error a translated function's status stands for, or
+ * None if it returned normally.
+ *
+ * # Arguments
+ *
+ * * status       - The status the function returned.
+ * * error_offset - The offset the function stored in *error_offset.
+ */
+pub fn status_error(status: int, error_offset: u32) -> Option<VMError> {
+    match status {
+        C_OK => None,
+        C_DIVISION_BY_ZERO => Some(DivisionByZero),
+        C_OVERFLOW => Some(Overflow(error_offset)),
+        _ => fail!(fmt!("Unexpected status %d", status))
+    }
+}
+
+/**
+ * Translates a function into a standalone C translation unit, with the
+ * VM's numeric semantics: integers wrap, integer division and checked
+ * arithmetic report errors as the interpreter does, and floats follow
+ * IEEE 754.
+ *
+ * The function is declared as
+ *
+ *     int name(T *result, uint32_t *error_offset)
+ *
+ * where T is int32_t or float, from the inferred return type. It returns
+ * VM_OK after storing the returned value, or an error status. Locals
+ * become C variables of their inferred types, basic blocks become labels,
+ * branches become gotos, and each value pushed on the evaluation stack
+ * becomes a temporary.
+ *
+ * Only numeric functions can be translated. Disp prints floats with
+ * printf's %.9g, which can differ from the interpreter's format.
+ *
+ * # Arguments
+ *
+ * * function - The function to translate.
+ * * name     - The name of the C function.
+ */
+pub fn translate(function: &[Opcode], name: &str) -> Result<~str, CSourceError> {
+    if !is_identifier(name) {
+        return Err(InvalidName(name.to_owned()));
+    }
+
+    match verify(function) {
+        Ok(()) => { }
+        Err(error) => return Err(InvalidBytecode(error))
+    }
+    let basic_blocks = get_basic_blocks(function);
+    let local_types = match infer_local_types(basic_blocks, local_count(function)) {
+        Ok(local_types) => local_types,
+        Err(error) => return Err(InvalidBytecode(error))
+    };
+    let return_type = infer_return_type(basic_blocks, local_types);
+
+    let mut translation = Translation { lines: ~[], temporaries: ~[], uses_wide: false };
+    for basic_block in basic_blocks.iter() {
+        match translation.translate_basic_block(*basic_block, local_types) {
+            Ok(()) => { }
+            Err(error) => return Err(error)
+        }
+    }
+
+    let mut source = PRELUDE.to_owned();
+    source.push_str(fmt!("\nint %s(%s *result, uint32_t *error_offset)\n{\n", name, c_type(return_type)));
+    for (index, &local_type) in local_types.iter().enumerate() {
+        source.push_str(fmt!("    %s l%u = 0;\n", c_type(local_type), index));
+    }
+    if translation.uses_wide {
+        source.push_str("    int64_t wide;\n");
+    }
+    for (index, &temporary_type) in translation.temporaries.iter().enumerate() {
+        source.push_str(fmt!("    %s s%u;\n", c_type(temporary_type), index));
+    }
+    for line in translation.lines.iter() {
+        source.push_str(fmt!("%s\n", *line));
+    }
+
+    // Falling off the end returns nothing, as it does in the interpreter.
+    source.push_str("    return VM_OK;\n}\n");
+    Ok(source)
+}
+
+/**
+ * A value on the evaluation stack: a C expression, which is either a
+ * temporary or a constant, and its type.
+ */
+struct Operand {
+    expression: ~str,
+    value_type: VariableType
+}
+
+impl Operand {
+    fn is_float(&self) -> bool {
+        self.value_type == Float32
+    }
+
+    /**
+     * Returns the expression converted to a float, as arithmetic with a
+     * float operand converts the other.
+     */
+    fn as_float(&self) -> ~str {
+        if self.is_float() { self.expression.clone() } else { fmt!("(float)%s", self.expression) }
+    }
+
+    /**
+     * Returns the expression converted to an integer, as integer-only
+     * opcodes truncate a float operand.
+     */
+    fn as_int(&self) -> ~str {
+        if self.is_float() { fmt!("(int32_t)%s", self.expression) } else { self.expression.clone() }
+    }
+}
+
+/**
+ * The body of a function being translated.
+ */
+struct Translation {
+    lines: ~[~str],
+
+    /// The type of each temporary, in the order they were created.
+    temporaries: ~[VariableType],
+
+    /// Whether checked arithmetic needs the 64-bit temporary.
+    uses_wide: bool
+}
+
+impl Translation {
+    fn line(&mut self, line: ~str) {
+        self.lines.push(fmt!("    %s", line));
+    }
+
+    /**
+     * Assigns an expression to a new temporary, and pushes the temporary.
+     */
+    fn assign(&mut self, stack: &mut ~[Operand], value_type: VariableType, expression: ~str) {
+        let temporary = fmt!("s%u", self.temporaries.len());
+        self.temporaries.push(value_type);
+        self.line(fmt!("%s = %s;", temporary, expression));
+        stack.push(Operand { expression: temporary, value_type: value_type });
+    }
+
+    /**
+     * Emits a return of an error status unless a condition holds.
+     */
+    fn check(&mut self, condition: ~str, offset: u32, status: &str) {
+        self.line(fmt!("if (!(%s)) { *error_offset = %u; return %s; }", condition, offset as uint, status));
+    }
+
+    fn translate_basic_block(&mut self, basic_block: @mut BasicBlock,
+                             local_types: &[VariableType]) -> Result<(), CSourceError> {
+        self.lines.push(fmt!("block_%u:", basic_block.start as uint));
+
+        let mut stack = ~[];
+        for (index, opcode) in basic_block.opcodes.iter().enumerate() {
+            let offset = basic_block.start + index as u32;
+            match self.translate_opcode(opcode, offset, &mut stack, local_types) {
+                Ok(()) => { }
+                Err(error) => return Err(error)
+            }
+        }
+
+        match basic_block.conditional_block {
+            Some(b) => {
+                let condition = stack.pop();
+                self.line(fmt!("if (%s != 0) goto block_%u;", condition.expression, b.start as uint));
+            }
+            None => { }
+        }
+        match basic_block.next_block {
+            Some(b) => self.line(fmt!("goto block_%u;", b.start as uint)),
+            None => { }
+        }
+        Ok(())
+    }
+
+    fn translate_opcode(&mut self, opcode: &Opcode, offset: u32, stack: &mut ~[Operand],
+                        local_types: &[VariableType]) -> Result<(), CSourceError> {
+        match *opcode {
+            Constf32(value) => stack.push(Operand { expression: float_literal(value), value_type: Float32 }),
+            Consti32(value) => stack.push(Operand { expression: int_literal(value), value_type: Int32 }),
+            Add => self.arithmetic(stack, "+"),
+            Subtract => self.arithmetic(stack, "-"),
+            Multiply => self.arithmetic(stack, "*"),
+            Divide => {
+                if stack.rev_iter().take(2).any(|operand| operand.is_float()) {
+                    self.arithmetic(stack, "/");
+                } else {
+                    self.checked_division(stack, offset, "/");
+                }
+            }
+            Rem => self.checked_division(stack, offset, "%"),
+            And => self.integer(stack, |v1, v2| fmt!("%s & %s", v1, v2)),
+            Or => self.integer(stack, |v1, v2| fmt!("%s | %s", v1, v2)),
+            Xor => self.integer(stack, |v1, v2| fmt!("%s ^ %s", v1, v2)),
+            Eq => self.comparison(stack, "=="),
+            Neq => self.comparison(stack, "!="),
+            Leq => self.comparison(stack, "<="),
+            Geq => self.comparison(stack, ">="),
+            Lt => self.comparison(stack, "<"),
+            Gt => self.comparison(stack, ">"),
+            Shl => self.integer(stack, |v1, v2| fmt!("(int32_t)((uint32_t)%s << (%s & 31))", v1, v2)),
+            Shr => self.integer(stack, |v1, v2| fmt!("%s >> (%s & 31)", v1, v2)),
+            Ushr => self.integer(stack, |v1, v2| fmt!("(int32_t)((uint32_t)%s >> (%s & 31))", v1, v2)),
+            Ltu => self.integer(stack, |v1, v2| fmt!("(uint32_t)%s < (uint32_t)%s", v1, v2)),
+            Gtu => self.integer(stack, |v1, v2| fmt!("(uint32_t)%s > (uint32_t)%s", v1, v2)),
+            Lequ => self.integer(stack, |v1, v2| fmt!("(uint32_t)%s <= (uint32_t)%s", v1, v2)),
+            Gequ => self.integer(stack, |v1, v2| fmt!("(uint32_t)%s >= (uint32_t)%s", v1, v2)),
+            AddOvf => self.checked_arithmetic(stack, offset, "+"),
+            SubOvf => self.checked_arithmetic(stack, offset, "-"),
+            MulOvf => self.checked_arithmetic(stack, offset, "*"),
+            Min => self.min_max(stack, "<="),
+            Max => self.min_max(stack, ">="),
+            Pow => {
+                let v2 = stack.pop();
+                let v1 = stack.pop();
+                self.assign(stack, Float32, fmt!("powf(%s, %s)", v1.as_float(), v2.as_float()));
+            }
+            Negate => {
+                let v = stack.pop();
+                if v.is_float() {
+                    self.assign(stack, Float32, fmt!("-%s", v.expression));
+                } else {
+                    self.assign(stack, Int32, fmt!("(int32_t)(0u - (uint32_t)%s)", v.expression));
+                }
+            }
+            Not => {
+                let v = stack.pop();
+                self.assign(stack, Int32, fmt!("~%s", v.expression));
+            }
+            Abs => {
+                let v = stack.pop();
+                if v.is_float() {
+                    self.assign(stack, Float32, fmt!("fabsf(%s)", v.expression));
+                } else {
+                    self.assign(stack, Int32, fmt!("%s < 0 ? (int32_t)(0u - (uint32_t)%s) : %s",
+                                                   v.expression, v.expression, v.expression));
+                }
+            }
+            Sqrt => self.math(stack, "sqrtf"),
+            Floor => self.math(stack, "floorf"),
+            Ceil => self.math(stack, "ceilf"),
+            Round => self.math(stack, "roundf"),
+            Trunc => self.math(stack, "truncf"),
+            Sin => self.math(stack, "sinf"),
+            Cos => self.math(stack, "cosf"),
+            Tan => self.math(stack, "tanf"),
+            Exp => self.math(stack, "expf"),
+            Log => self.math(stack, "logf"),
+            Ret => {
+                let v = stack.pop();
+                self.line(fmt!("*result = %s;", v.expression));
+                self.line(~"return VM_OK;");
+            }
+            Disp => {
+                let v = stack.pop();
+                let format = if v.is_float() { "%.9g" } else { "%d" };
+                self.line(fmt!("printf(\"%s\\n\", %s);", format, v.expression));
+            }
+            Store(addr) => {
+                let v = stack.pop();
+                self.line(fmt!("l%u = %s;", addr as uint, v.expression));
+            }
+            Loadf32(addr) | Loadi32(addr) => {
+                // A local whose type is unknown is read as the opcode says.
+                let value_type = match local_types[addr] {
+                    Float32 => Float32,
+                    Int32 => Int32,
+                    _ => match *opcode { Loadf32(_) => Float32, _ => Int32 }
+                };
+                self.assign(stack, value_type, fmt!("l%u", addr as uint));
+            }
+            Nop | Jmp(_) | Iftrue(_) => { }
+            _ => return Err(UnsupportedOpcode(offset, opcode.clone()))
+        }
+        Ok(())
+    }
+
+    /**
+     * Translates Add, Subtract, Multiply or a float Divide. Integer
+     * arithmetic is done unsigned, so that it wraps rather than being
+     * undefined.
+     */
+    fn arithmetic(&mut self, stack: &mut ~[Operand], op: &str) {
+        let v2 = stack.pop();
+        let v1 = stack.pop();
+        if v1.is_float() || v2.is_float() {
+            self.assign(stack, Float32, fmt!("%s %s %s", v1.as_float(), op, v2.as_float()));
+        } else {
+            self.assign(stack, Int32, fmt!("(int32_t)((uint32_t)%s %s (uint32_t)%s)", v1.expression, op,
+                                           v2.expression));
+        }
+    }
+
+    /**
+     * Translates an integer Divide or Rem, which fails for a zero divisor
+     * and for the smallest integer divided by -1.
+     */
+    fn checked_division(&mut self, stack: &mut ~[Operand], offset: u32, op: &str) {
+        let v2 = stack.pop().as_int();
+        let v1 = stack.pop().as_int();
+        self.check(fmt!("%s != 0", v2), offset, "VM_DIVISION_BY_ZERO");
+        self.check(fmt!("%s != INT32_MIN || %s != -1", v1, v2), offset, "VM_OVERFLOW");
+        self.assign(stack, Int32, fmt!("%s %s %s", v1, op, v2));
+    }
+
+    /**
+     * Translates AddOvf, SubOvf or MulOvf, computing in 64 bits, where
+     * the result of 32-bit operands cannot overflow.
+     */
+    fn checked_arithmetic(&mut self, stack: &mut ~[Operand], offset: u32, op: &str) {
+        let v2 = stack.pop();
+        let v1 = stack.pop();
+        self.uses_wide = true;
+        self.line(fmt!("wide = (int64_t)%s %s %s;", v1.expression, op, v2.expression));
+        self.check(~"wide >= INT32_MIN && wide <= INT32_MAX", offset, "VM_OVERFLOW");
+        self.assign(stack, Int32, ~"(int32_t)wide");
+    }
+
+    /**
+     * Translates an integer-only opcode, given the expression for its operands.
+     */
+    fn integer(&mut self, stack: &mut ~[Operand], expression: &fn(&str, &str) -> ~str) {
+        let v2 = stack.pop().as_int();
+        let v1 = stack.pop().as_int();
+        self.assign(stack, Int32, expression(v1, v2));
+    }
+
+    /**
+     * Translates a comparison. C converts an integer compared with a
+     * float to a float, as the interpreter does.
+     */
+    fn comparison(&mut self, stack: &mut ~[Operand], op: &str) {
+        let v2 = stack.pop();
+        let v1 = stack.pop();
+        self.assign(stack, Int32, fmt!("%s %s %s", v1.expression, op, v2.expression));
+    }
+
+    /**
+     * Translates Min or Max, whose float result is NaN if either operand is.
+     */
+    fn min_max(&mut self, stack: &mut ~[Operand], op: &str) {
+        let v2 = stack.pop();
+        let v1 = stack.pop();
+        if v1.is_float() || v2.is_float() {
+            let (f1, f2) = (v1.as_float(), v2.as_float());
+            self.assign(stack, Float32, fmt!("%s != %s || %s != %s ? %s + %s : (%s %s %s ? %s : %s)",
+                                             f1, f1, f2, f2, f1, f2, f1, op, f2, f1, f2));
+        } else {
+            self.assign(stack, Int32, fmt!("%s %s %s ? %s : %s", v1.expression, op, v2.expression,
+                                           v1.expression, v2.expression));
+        }
+    }
+
+    /**
+     * Translates a float math intrinsic.
+     */
+    fn math(&mut self, stack: &mut ~[Operand], function: &str) {
+        let v = stack.pop();
+        self.assign(stack, Float32, fmt!("%s(%s)", function, v.as_float()));
+    }
+}
+
+/**
+ * Returns the C type of values of a VM type. Locals of unknown type are
+ * never stored to, and are given int32_t.
+ */
+fn c_type(variable_type: VariableType) -> &'static str {
+    if variable_type == Float32 { "float" } else { "int32_t" }
+}
+
+fn int_literal(value: i32) -> ~str {
+    // -2147483648 is the negation of a literal too big for int.
+    if value == i32::min_value { ~"INT32_MIN" } else { value.to_str() }
+}
+
+/**
+ * Returns a C expression for a float constant: a decimal literal for
+ * whole numbers, and the exact bits otherwise.
+ */
+fn float_literal(value: f32) -> ~str {
+    let bits: u32 = unsafe { transmute(value) };
+    if !value.is_NaN() {
+        let whole = value as i32;
+        let whole_bits: u32 = unsafe { transmute(whole as f32) };
+        if whole_bits == bits {
+            return fmt!("%d.0f", whole as int);
+        }
+    }
+    fmt!("vm_float(0x%08Xu) /* %s */", bits as uint, value.to_str())
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && !name.char_at(0).is_digit() && name.iter().all(|c| c == '_' || c.is_alphanumeric())
+}
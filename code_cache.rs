@@ -0,0 +1,249 @@
+use opcode::*;
+use jit::*;
+use frontend::*;
+use libjit::*;
+use heap::*;
+use constant_pool::*;
+use analysis::*;
+use std::cast::transmute;
+use std::hash::Hash;
+use std::hashmap::HashMap;
+
+/**
+ * Where a compiled function is entered, which determines its signature.
+ */
+#[deriving(Clone)]
+#[deriving(Eq)]
+#[deriving(ToStr)]
+#[deriving(IterBytes)]
+pub enum EntryPoint {
+    /// The normal entry, which takes no arguments.
+    NormalEntry,
+
+    /// An on-stack replacement entry at a loop header,
+    /// which takes a pointer to the function's locals.
+    OsrEntry(u32)
+}
+
+/**
+ * Counters describing how well a CodeCache is doing.
+ */
+#[deriving(Clone)]
+pub struct CacheStats {
+    /// Lookups that found compiled code.
+    hits: uint,
+    /// Lookups that had to compile.
+    misses: uint,
+    /// Entries removed to make room for others.
+    evictions: uint,
+    /// Entries removed by invalidate.
+    invalidations: uint
+}
+
+/**
+ * A compiled function held by a CodeCache.
+ */
+struct CacheEntry<'self> {
+    function: ~CompiledFunction<'self>,
+    /// The number of opcodes compiled, which is what the cache's size limit counts.
+    size: uint,
+    /// The value of the cache's clock when the entry was last used.
+    last_used: uint
+}
+
+/**
+ * Reuses compiled code for identical opcode streams, within one long-lived
+ * Context. Entries are keyed by a hash of the opcode stream, the entry
+ * point and the compile options. The cache borrows the constant pool and
+ * heap its code is compiled against, since compiled code refers to both.
+ *
+ * libjit cannot free a single compiled function, so the machine code of
+ * evicted and invalidated entries is only reclaimed when the Context is dropped.
+ */
+pub struct CodeCache<'self> {
+    priv context: &'self Context,
+    priv constants: &'self ConstantPool,
+    priv heap: &'self mut Heap,
+    priv entries: HashMap<u64, CacheEntry<'self>>,
+
+    /// The most opcodes the cached functions may hold in total.
+    priv max_size: uint,
+    priv size: uint,
+
+    /// Counts lookups, to find the least recently used entry.
+    priv clock: uint,
+    priv stats: CacheStats
+}
+
+impl<'self> CodeCache<'self> {
+    /**
+     * Creates an empty CodeCache.
+     *
+     * # Arguments
+     *
+     * * context   - The JIT context within which to compile functions.
+     * * constants - The constant pool of the functions' module.
+     * * heap      - The heap on which the compiled code allocates objects.
+     * * max_size  - The most opcodes the cached functions may hold in total.
+     *               The least recently used entries are evicted beyond this.
+     */
+    pub fn new(context: &'self Context, constants: &'self ConstantPool, heap: &'self mut Heap,
+               max_size: uint) -> CodeCache<'self> {
+        CodeCache {
+            context: context,
+            constants: constants,
+            heap: heap,
+            entries: HashMap::new(),
+            max_size: max_size,
+            size: 0,
+            clock: 0,
+            stats: CacheStats { hits: 0, misses: 0, evictions: 0, invalidations: 0 }
+        }
+    }
+
+    /**
+     * Returns the compiled code for a function, compiling it if it is not
+     * in the cache.
+     *
+     * # Arguments
+     *
+     * * function - The function.
+     * * entry    - Where the compiled code is entered.
+     * * options  - How to compile the function.
+     *
+     * Returns the compiled code, or why the function was rejected.
+     */
+    pub fn get<'a>(&'a mut self, function: &[Opcode], entry: EntryPoint,
+                   options: &CompileOptions) -> Result<&'a CompiledFunction<'self>, VerifyError> {
+        let key = cache_key(function, entry, options);
+        self.clock += 1;
+
+        if self.entries.contains_key(&key) {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+
+            let heap = self.compiled_heap();
+            let compiled = match entry {
+                NormalEntry => compile(function, options, self.constants, self.context, heap),
+                OsrEntry(header) => compile_osr(function, header, options, self.constants, self.context, heap)
+            };
+            let compiled = match compiled {
+                Ok(compiled) => compiled,
+                Err(error) => return Err(error)
+            };
+            self.evict_to_fit(function.len());
+            self.size += function.len();
+            self.entries.insert(key, CacheEntry { function: compiled, size: function.len(), last_used: 0 });
+        }
+
+        let cached = self.entries.get_mut(&key);
+        cached.last_used = self.clock;
+        Ok(&*cached.function)
+    }
+
+    /**
+     * Removes the compiled code for a function, so that the next lookup
+     * compiles it again.
+     *
+     * # Arguments
+     *
+     * * function - The function.
+     * * entry    - Where the compiled code is entered.
+     * * options  - The options it was compiled with.
+     *
+     * Returns whether the function was in the cache.
+     */
+    pub fn invalidate(&mut self, function: &[Opcode], entry: EntryPoint, options: &CompileOptions) -> bool {
+        let key = cache_key(function, entry, options);
+        match self.entries.pop(&key) {
+            Some(removed) => {
+                self.size -= removed.size;
+                self.stats.invalidations += 1;
+                true
+            }
+            None => false
+        }
+    }
+
+    /**
+     * Removes every entry.
+     */
+    pub fn clear(&mut self) {
+        self.stats.invalidations += self.entries.len();
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    /**
+     * Returns the cache's hit, miss and eviction counts.
+     */
+    pub fn stats(&self) -> CacheStats {
+        self.stats.clone()
+    }
+
+    /**
+     * Returns the number of functions in the cache.
+     */
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+
+    /**
+     * Returns the number of opcodes held by the cached functions.
+     */
+    pub fn size(&self) -> uint {
+        self.size
+    }
+
+    /**
+     * Returns the heap for compiled code to keep a borrow of. The cache
+     * holds the only borrow of it for 'self, and cached code can only be
+     * called while the cache itself is borrowed, so the two never use it
+     * at the same time.
+     */
+    fn compiled_heap(&mut self) -> &'self mut Heap {
+        unsafe { transmute(&mut *self.heap) }
+    }
+
+    /**
+     * Evicts the least recently used entries until a function of the given
+     * size fits. A function larger than the whole cache is still cached,
+     * on its own.
+     */
+    fn evict_to_fit(&mut self, size: uint) {
+        while self.size + size > self.max_size && !self.entries.is_empty() {
+            let mut oldest = None;
+            let mut oldest_use = 0;
+            for (key, entry) in self.entries.iter() {
+                if oldest.is_none() || entry.last_used < oldest_use {
+                    oldest = Some(*key);
+                    oldest_use = entry.last_used;
+                }
+            }
+
+            match oldest {
+                Some(key) => {
+                    let removed = self.entries.pop(&key).unwrap();
+                    self.size -= removed.size;
+                    self.stats.evictions += 1;
+                }
+                None => break
+            }
+        }
+    }
+}
+
+/**
+ * Returns the key under which a function's compiled code is cached: a
+ * hash of the opcode stream and the signature and options it is compiled with.
+ *
+ * # Arguments
+ *
+ * * function - The function.
+ * * entry    - Where the compiled code is entered.
+ * * options  - How it is compiled.
+ */
+fn cache_key(function: &[Opcode], entry: EntryPoint, options: &CompileOptions) -> u64 {
+    (function, entry, options.clone()).hash()
+}
@@ -0,0 +1,490 @@
+extern mod extra;
+
+use opcode::*;
+use interpret::*;
+use libjit::*;
+use backend::*;
+use jit::*;
+use frontend::*;
+use heap::*;
+use constant_pool::*;
+use vm_error::*;
+use variable_type::*;
+use jit_helpers::*;
+use c_source::*;
+use analysis::*;
+use basic_block::*;
+use std::cast::transmute;
+use std::f32;
+use std::i32;
+use std::os;
+use std::io;
+use std::run;
+use std::str;
+
+mod variable_type;
+mod vm_error;
+mod heap;
+mod constant_pool;
+mod jit_helpers;
+mod tiered;
+mod profile;
+mod debugger;
+mod trace;
+mod code_cache;
+mod optimize;
+mod backend;
+mod x86_64;
+mod c_source;
+mod libjit;
+mod opcode;
+mod interpret;
+mod jit;
+mod frontend;
+mod basic_block;
+mod analysis;
+
+/**
+ * A function, and the result every execution engine must give for it.
+ */
+struct Case {
+    name: ~str,
+    function: ~[Opcode],
+    expected: Result<StackValue, VMError>,
+
+    /// Whether the function uses the heap, which the C translation rejects.
+    uses_heap: bool
+}
+
+/**
+ * Builds a case that applies a binary opcode to two constants. The
+ * operands pass through locals so that libjit cannot fold them.
+ * The opcode is at offset 6.
+ */
+fn binary(name: &str, v1: Opcode, v2: Opcode, opcode: Opcode, expected: Result<StackValue, VMError>) -> Case {
+    Case {
+        name: name.to_owned(),
+        function: ~[v1.clone(), Store(0), v2.clone(), Store(1), load(&v1, 0), load(&v2, 1), opcode, Ret],
+        expected: expected,
+        uses_heap: false
+    }
+}
+
+/**
+ * Builds a case that applies a unary opcode to a constant.
+ */
+fn unary(name: &str, v: Opcode, opcode: Opcode, expected: Result<StackValue, VMError>) -> Case {
+    Case {
+        name: name.to_owned(),
+        function: ~[v.clone(), Store(0), load(&v, 0), opcode, Ret],
+        expected: expected,
+        uses_heap: false
+    }
+}
+
+/**
+ * Builds a case that stores a value in element 1 of a new three-element
+ * array of one type, and loads element `index` back as another.
+ */
+fn array(name: &str, stored_type: VariableType, value: Opcode, loaded_type: VariableType, index: i32,
+         expected: Result<StackValue, VMError>) -> Case {
+    Case {
+        name: name.to_owned(),
+        function: ~[Consti32(3), NewArray(stored_type), Store(0),
+                    Loadref(0), Consti32(1), value, ArrayStore(stored_type),
+                    Loadref(0), Consti32(index), ArrayLoad(loaded_type), Ret],
+        expected: expected,
+        uses_heap: true
+    }
+}
+
+/**
+ * Builds a case that sets field 1 of a new two-field object to a value of
+ * one type, and gets field `index` back as another.
+ */
+fn field(name: &str, stored_type: VariableType, value: Opcode, loaded_type: VariableType, index: u32,
+         expected: Result<StackValue, VMError>) -> Case {
+    Case {
+        name: name.to_owned(),
+        function: ~[Alloc(2), Store(0),
+                    Loadref(0), value, SetField(1, stored_type),
+                    Loadref(0), GetField(index, loaded_type), Ret],
+        expected: expected,
+        uses_heap: true
+    }
+}
+
+/**
+ * Returns the load opcode for a local holding the given constant.
+ */
+fn load(constant: &Opcode, local: u32) -> Opcode {
+    match *constant {
+        Constf32(_) => Loadf32(local),
+        _ => Loadi32(local)
+    }
+}
+
+/**
+ * Returns the conformance table.
+ */
+fn cases() -> ~[Case] {
+    let nan = Constf32(f32::NaN);
+    let min = Consti32(i32::min_value);
+
+    ~[
+        // Integer arithmetic wraps around.
+        binary("int add", Consti32(2), Consti32(3), Add, Ok(Int(5))),
+        binary("int add wraps", Consti32(i32::max_value), Consti32(1), Add, Ok(Int(i32::min_value))),
+        binary("int subtract", Consti32(2), Consti32(3), Subtract, Ok(Int(-1))),
+        binary("int subtract wraps", min, Consti32(1), Subtract, Ok(Int(i32::max_value))),
+        binary("int multiply wraps", Consti32(65536), Consti32(65536), Multiply, Ok(Int(0))),
+        binary("int multiply min by -1", min, Consti32(-1), Multiply, Ok(Int(i32::min_value))),
+
+        // Integer division truncates towards zero.
+        binary("int divide", Consti32(7), Consti32(2), Divide, Ok(Int(3))),
+        binary("int divide negative", Consti32(-7), Consti32(2), Divide, Ok(Int(-3))),
+        binary("int divide by zero", Consti32(7), Consti32(0), Divide, Err(DivisionByZero)),
+        binary("int divide min by -1", min, Consti32(-1), Divide, Err(Overflow(6))),
+        binary("int rem", Consti32(7), Consti32(3), Rem, Ok(Int(1))),
+        binary("int rem negative", Consti32(-7), Consti32(3), Rem, Ok(Int(-1))),
+        binary("int rem by zero", Consti32(7), Consti32(0), Rem, Err(DivisionByZero)),
+        binary("int rem min by -1", min, Consti32(-1), Rem, Err(Overflow(6))),
+
+        // Float division follows IEEE 754.
+        binary("float divide", Constf32(7f32), Constf32(2f32), Divide, Ok(Float(3.5f32))),
+        binary("float divide by zero", Constf32(1f32), Constf32(0f32), Divide, Ok(Float(f32::infinity))),
+        binary("float zero by zero", Constf32(0f32), Constf32(0f32), Divide, Ok(Float(f32::NaN))),
+
+        // Checked arithmetic.
+        binary("add ovf", Consti32(2), Consti32(3), AddOvf, Ok(Int(5))),
+        binary("add ovf overflows", min, Consti32(-1), AddOvf, Err(Overflow(6))),
+        binary("sub ovf overflows", min, Consti32(1), SubOvf, Err(Overflow(6))),
+        binary("sub ovf", Consti32(2), Consti32(3), SubOvf, Ok(Int(-1))),
+        binary("mul ovf negative", Consti32(-65536), Consti32(32768), MulOvf, Ok(Int(i32::min_value))),
+        binary("mul ovf overflows", Consti32(65536), Consti32(65536), MulOvf, Err(Overflow(6))),
+        Case {
+            // An opcode needs its operands pushed first, so offset 2 is the
+            // lowest an overflow can happen at; main checks offset 0's code.
+            name: ~"add ovf overflows at the lowest offset",
+            function: ~[Consti32(i32::max_value), Consti32(1), AddOvf, Ret],
+            expected: Err(Overflow(2)),
+            uses_heap: false
+        },
+
+        // If either operand is a float, the other is converted to a float.
+        binary("int add float", Consti32(2), Constf32(0.5f32), Add, Ok(Float(2.5f32))),
+        binary("float subtract int", Constf32(0.5f32), Consti32(2), Subtract, Ok(Float(-1.5f32))),
+        binary("int multiply float", Consti32(3), Constf32(0.5f32), Multiply, Ok(Float(1.5f32))),
+        binary("int divide float", Consti32(1), Constf32(4f32), Divide, Ok(Float(0.25f32))),
+        binary("int lt float", Consti32(1), Constf32(1.5f32), Lt, Ok(Int(1))),
+        binary("float eq int", Constf32(2f32), Consti32(2), Eq, Ok(Int(1))),
+        binary("int max float", Consti32(2), Constf32(1.5f32), Max, Ok(Float(2f32))),
+
+        // Rem is an integer opcode, so float operands are truncated.
+        binary("float rem", Constf32(7.5f32), Constf32(2f32), Rem, Ok(Int(1))),
+        binary("float rem by a fraction", Constf32(7.5f32), Constf32(0.5f32), Rem, Err(DivisionByZero)),
+
+        // Comparisons are signed for integers and IEEE for floats.
+        binary("int lt negative", Consti32(-1), Consti32(1), Lt, Ok(Int(1))),
+        binary("int gt negative", Consti32(-1), Consti32(1), Gt, Ok(Int(0))),
+        binary("int ltu negative", Consti32(-1), Consti32(1), Ltu, Ok(Int(0))),
+        binary("int gtu negative", Consti32(-1), Consti32(1), Gtu, Ok(Int(1))),
+        binary("int lequ negative", Consti32(-1), Consti32(1), Lequ, Ok(Int(0))),
+        binary("int lequ equal", Consti32(-1), Consti32(-1), Lequ, Ok(Int(1))),
+        binary("int gequ negative", Consti32(-1), Consti32(1), Gequ, Ok(Int(1))),
+        binary("int gequ zero", Consti32(0), Consti32(1), Gequ, Ok(Int(0))),
+        binary("float lt fraction", Constf32(0.5f32), Constf32(0.7f32), Lt, Ok(Int(1))),
+        binary("float eq fraction", Constf32(0.5f32), Constf32(0.7f32), Eq, Ok(Int(0))),
+        binary("float gt fraction", Constf32(0.7f32), Constf32(0.5f32), Gt, Ok(Int(1))),
+        binary("float gt negative", Constf32(-2.5f32), Constf32(-0.5f32), Gt, Ok(Int(0))),
+        binary("float leq negative", Constf32(-2.5f32), Constf32(-2.5f32), Leq, Ok(Int(1))),
+        binary("nan eq nan", nan, nan, Eq, Ok(Int(0))),
+        binary("nan neq nan", nan, nan, Neq, Ok(Int(1))),
+        binary("nan lt one", nan, Constf32(1f32), Lt, Ok(Int(0))),
+        binary("one geq nan", Constf32(1f32), nan, Geq, Ok(Int(0))),
+        binary("nan gt one", nan, Constf32(1f32), Gt, Ok(Int(0))),
+
+        // Min and Max keep the operand type and propagate NaN.
+        binary("int min", Consti32(3), Consti32(-2), Min, Ok(Int(-2))),
+        binary("float max", Constf32(0.5f32), Constf32(0.7f32), Max, Ok(Float(0.7f32))),
+        binary("min nan", nan, Constf32(1f32), Min, Ok(Float(f32::NaN))),
+        binary("max nan", Constf32(1f32), nan, Max, Ok(Float(f32::NaN))),
+
+        // Bitwise opcodes and shifts.
+        binary("and", Consti32(12), Consti32(10), And, Ok(Int(8))),
+        binary("or", Consti32(12), Consti32(10), Or, Ok(Int(14))),
+        binary("xor", Consti32(12), Consti32(10), Xor, Ok(Int(6))),
+        binary("xor negative", Consti32(-1), Consti32(10), Xor, Ok(Int(-11))),
+        binary("shl count modulo 32", Consti32(1), Consti32(33), Shl, Ok(Int(2))),
+        binary("shr negative", Consti32(-8), Consti32(1), Shr, Ok(Int(-4))),
+        binary("ushr negative", Consti32(-1), Consti32(28), Ushr, Ok(Int(15))),
+
+        // Unary opcodes.
+        unary("int negate", Consti32(5), Negate, Ok(Int(-5))),
+        unary("int negate min", min, Negate, Ok(Int(i32::min_value))),
+        unary("float negate", Constf32(0.5f32), Negate, Ok(Float(-0.5f32))),
+        unary("int abs", Consti32(-3), Abs, Ok(Int(3))),
+        unary("float abs", Constf32(-2.5f32), Abs, Ok(Float(2.5f32))),
+        unary("not", Consti32(0), Not, Ok(Int(-1))),
+
+        // Math intrinsics compute in single precision, and take integers as floats.
+        unary("sqrt", Constf32(2.25f32), Sqrt, Ok(Float(1.5f32))),
+        unary("sqrt negative", Constf32(-1f32), Sqrt, Ok(Float(f32::NaN))),
+        unary("sqrt int", Consti32(16), Sqrt, Ok(Float(4f32))),
+        unary("floor negative", Constf32(-2.5f32), Floor, Ok(Float(-3f32))),
+        unary("ceil negative", Constf32(-2.5f32), Ceil, Ok(Float(-2f32))),
+        unary("round half away from zero", Constf32(-2.5f32), Round, Ok(Float(-3f32))),
+        unary("trunc negative", Constf32(-2.5f32), Trunc, Ok(Float(-2f32))),
+        unary("sin zero", Constf32(0f32), Sin, Ok(Float(0f32))),
+        unary("cos zero", Constf32(0f32), Cos, Ok(Float(1f32))),
+        unary("tan zero", Constf32(0f32), Tan, Ok(Float(0f32))),
+        unary("exp zero", Constf32(0f32), Exp, Ok(Float(1f32))),
+        unary("log one", Constf32(1f32), Log, Ok(Float(0f32))),
+        unary("log zero", Constf32(0f32), Log, Ok(Float(f32::neg_infinity))),
+        binary("pow", Constf32(2f32), Constf32(10f32), Pow, Ok(Float(1024f32))),
+        binary("pow int", Consti32(3), Consti32(2), Pow, Ok(Float(9f32))),
+
+        // Iftrue branches on NaN.
+        Case {
+            name: ~"iftrue nan",
+            function: ~[nan, Iftrue(4), Consti32(0), Ret, Consti32(1), Ret],
+            expected: Ok(Int(1)),
+            uses_heap: false
+        },
+
+        // Arrays check their bounds and element types.
+        array("int array store and load", Int32, Consti32(42), Int32, 1, Ok(Int(42))),
+        array("float array store and load", Float32, Constf32(2.5f32), Float32, 1, Ok(Float(2.5f32))),
+        array("array load unset element", Int32, Consti32(42), Int32, 0, Ok(Int(0))),
+        array("array load past the end", Int32, Consti32(42), Int32, 3, Err(IndexOutOfBounds)),
+        array("array load negative index", Int32, Consti32(42), Int32, -1, Err(IndexOutOfBounds)),
+        array("array load wrong element type", Int32, Consti32(42), Float32, 1, Err(ElementTypeMismatch)),
+        Case {
+            name: ~"array store wrong element type",
+            function: ~[Consti32(3), NewArray(Float32), Store(0),
+                        Loadref(0), Consti32(1), Consti32(42), ArrayStore(Int32), Consti32(0), Ret],
+            expected: Err(ElementTypeMismatch),
+            uses_heap: true
+        },
+        Case {
+            name: ~"array store past the end",
+            function: ~[Consti32(3), NewArray(Int32), Store(0),
+                        Loadref(0), Consti32(3), Consti32(42), ArrayStore(Int32), Consti32(0), Ret],
+            expected: Err(IndexOutOfBounds),
+            uses_heap: true
+        },
+
+        // Fields check their index and the type they were set with.
+        field("int field", Int32, Consti32(7), Int32, 1, Ok(Int(7))),
+        field("float field", Float32, Constf32(0.5f32), Float32, 1, Ok(Float(0.5f32))),
+        field("unset field", Int32, Consti32(7), Float32, 0, Ok(Float(0f32))),
+        field("field past the end", Int32, Consti32(7), Int32, 2, Err(IndexOutOfBounds)),
+        field("field wrong type", Int32, Consti32(7), Float32, 1, Err(ElementTypeMismatch)),
+        Case {
+            name: ~"field of an array",
+            function: ~[Consti32(2), NewArray(Int32), GetField(0, Int32), Ret],
+            expected: Err(ElementTypeMismatch),
+            uses_heap: true
+        }
+    ]
+}
+
+/**
+ * Returns functions that must be rejected before they are compiled,
+ * with the error each must be rejected with.
+ */
+fn rejected() -> ~[(~str, ~[Opcode], VerifyError)] {
+    ~[
+        (~"float operand to and", ~[Constf32(1f32), Consti32(1), And, Ret], FloatOperand(2)),
+        (~"float operand to not", ~[Constf32(1f32), Not, Ret], FloatOperand(1)),
+        (~"float thrown", ~[Constf32(1f32), Throw], FloatOperand(1)),
+        // Local 0 is never stored to, so the interpreter divides by Int(0)
+        // as a float, but its type cannot be inferred.
+        (~"divisor of unknown type", ~[Constf32(1f32), Loadf32(0), Divide, Ret], UnknownOperand(2)),
+        (~"try end without try begin", ~[TryEnd, Consti32(0), Ret], UnmatchedTryEnd(0)),
+        (~"try regions entered differently", ~[Consti32(1), Iftrue(3), TryBegin(6), Consti32(0), Ret,
+                                               Nop, Consti32(1), Ret], InconsistentTryRegions(3))
+    ]
+}
+
+/**
+ * Returns whether a result matches the expected one, treating
+ * NaN as equal to itself.
+ */
+fn matches(actual: &Result<Option<StackValue>, VMError>, expected: &Result<StackValue, VMError>) -> bool {
+    match (actual, expected) {
+        (&Ok(Some(Float(a))), &Ok(Float(e))) => (a.is_NaN() && e.is_NaN()) || a == e,
+        (&Ok(Some(ref a)), &Ok(ref e)) => a == e,
+        (&Err(ref a), &Err(ref e)) => a == e,
+        _ => false
+    }
+}
+
+/**
+ * Converts the bits a compiled case returns by the case's inferred return type.
+ */
+fn returned(case: &Case, retval: u32) -> Option<StackValue> {
+    if return_type(case.function) == Ok(Float32) {
+        Some(Float(unsafe { transmute(retval) }))
+    } else {
+        Some(Int(retval as i32))
+    }
+}
+
+/**
+ * Runs a case through the JIT.
+ */
+fn run_compiled(case: &Case, constants: &ConstantPool, context: &Context,
+                heap: &mut Heap) -> Result<Option<StackValue>, VMError> {
+    let function = match compile(case.function, &CompileOptions::default(), constants, context, heap) {
+        Ok(function) => function,
+        Err(error) => fail!(fmt!("Cannot compile %s: %s", case.name, error.to_str()))
+    };
+    match function.call_word([]) {
+        Ok(retval) => Ok(returned(case, retval)),
+        Err(Raised(exception)) => Err(to_vm_error(exception)),
+        Err(error) => fail!(fmt!("Cannot call %s: %?", case.name, error))
+    }
+}
+
+/**
+ * Runs a case through the x86-64 backend.
+ */
+fn run_native(case: &Case, constants: &ConstantPool, heap: &mut Heap) -> Result<Option<StackValue>, VMError> {
+    let function = match compile_native(case.function, &CompileOptions::default(), constants, heap) {
+        Ok(function) => function,
+        Err(error) => fail!(fmt!("Cannot compile %s: %s", case.name, error.to_str()))
+    };
+    match function.callable().call([]) {
+        Ok(retval) => Ok(returned(case, retval)),
+        Err(exception) => Err(to_vm_error(exception))
+    }
+}
+
+/**
+ * Runs a case through its C translation, compiled with the system cc. The
+ * program prints the status, the error offset and the bits of the result.
+ */
+fn run_c(case: &Case) -> Result<Option<StackValue>, VMError> {
+    let source = match translate(case.function, "vm_function") {
+        Ok(source) => source,
+        Err(error) => fail!(fmt!("Cannot translate %s: %s", case.name, error.to_str()))
+    };
+    let is_float = return_type(case.function) == Ok(Float32);
+    let program = source + fmt!("
+int main(void)
+{
+    %s result = 0;
+    uint32_t error_offset = 0, bits = 0;
+    int status = vm_function(&result, &error_offset);
+    memcpy(&bits, &result, sizeof bits);
+    printf(\"%%d %%u %%u\\n\", status, (unsigned)error_offset, (unsigned)bits);
+    return 0;
+}
+", if is_float { "float" } else { "int32_t" });
+
+    let source_path = os::tmpdir().push("conformance.c");
+    let program_path = os::tmpdir().push("conformance");
+    match io::file_writer(&source_path, [io::Create, io::Truncate]) {
+        Ok(writer) => writer.write_str(program),
+        Err(error) => fail!(error)
+    }
+    let cc = run::process_output("cc", [~"-o", program_path.to_str(), source_path.to_str(), ~"-lm"]);
+    if cc.status != 0 {
+        fail!(fmt!("cc failed for %s: %s", case.name, str::from_utf8(cc.error)));
+    }
+
+    let output = str::from_utf8(run::process_output(program_path.to_str(), []).output);
+    let fields: ~[uint] = output.word_iter().map(|field| from_str(field).unwrap()).collect();
+    match status_error(fields[0] as int, fields[1] as u32) {
+        Some(error) => Err(error),
+        None if is_float => Ok(Some(Float(unsafe { transmute(fields[2] as u32) }))),
+        None => Ok(Some(Int(fields[2] as i32)))
+    }
+}
+
+/**
+ * Checks that the interpreter, the JIT and the x86-64 backend agree with
+ * the VM's numeric semantics, opcode by opcode. To run:
+ *
+ * > rust run conformance.rs
+ *
+ * With --cc, each case is also translated to C and compiled with the
+ * system cc:
+ *
+ * > rust run conformance.rs --cc
+ */
+fn main() {
+    let constants = ConstantPool::new();
+    let mut heap = Heap::new();
+    let context = Context::new();
+    let mut failures = 0;
+    let through_c = os::args().contains(&~"--cc");
+
+    // The code thrown for an overflow at instruction 0 must not be one
+    // of libjit's result codes, and must come back as the same error.
+    let code = error_code(Overflow(0));
+    if code <= JIT_RESULT_OK || to_vm_error(Builtin(code)) != Overflow(0) {
+        failures += 1;
+        println(fmt!("FAIL overflow at offset 0: thrown as %d", code as int));
+    }
+
+    for &(ref name, ref function, ref expected) in rejected().iter() {
+        let options = CompileOptions::default();
+        match compile(*function, &options, constants, context, heap) {
+            Err(ref error) if error == expected => { }
+            Err(error) => {
+                failures += 1;
+                println(fmt!("FAIL %s (jit): expected %s, got %s", *name, expected.to_str(), error.to_str()));
+            }
+            Ok(_) => {
+                failures += 1;
+                println(fmt!("FAIL %s (jit): compiled", *name));
+            }
+        }
+        match compile_native(*function, &options, constants, heap) {
+            Err(ref error) if error == expected => { }
+            Err(error) => {
+                failures += 1;
+                println(fmt!("FAIL %s (x86-64): expected %s, got %s", *name, expected.to_str(), error.to_str()));
+            }
+            Ok(_) => {
+                failures += 1;
+                println(fmt!("FAIL %s (x86-64): compiled", *name));
+            }
+        }
+    }
+
+    for case in cases().iter() {
+        let interpreted = interpret(case.function, constants, heap);
+        let compiled = run_compiled(case, constants, context, heap);
+        let native = run_native(case, constants, heap);
+
+        if !matches(&interpreted, &case.expected) {
+            failures += 1;
+            println(fmt!("FAIL %s (interpreter): expected %?, got %?", case.name, case.expected, interpreted));
+        }
+        if !matches(&compiled, &case.expected) {
+            failures += 1;
+            println(fmt!("FAIL %s (jit): expected %?, got %?", case.name, case.expected, compiled));
+        }
+        if !matches(&native, &case.expected) {
+            failures += 1;
+            println(fmt!("FAIL %s (x86-64): expected %?, got %?", case.name, case.expected, native));
+        }
+        if through_c && !case.uses_heap {
+            let translated = run_c(case);
+            if !matches(&translated, &case.expected) {
+                failures += 1;
+                println(fmt!("FAIL %s (c): expected %?, got %?", case.name, case.expected, translated));
+            }
+        }
+    }
+
+    if failures > 0 {
+        println(fmt!("%u failures", failures as uint));
+        os::set_exit_status(1);
+    } else {
+        println("All cases passed");
+    }
+}
@@ -0,0 +1,54 @@
+/**
+ * The constants referred to by a module's functions. Every function
+ * in a module shares the module's pool.
+ */
+pub struct ConstantPool {
+    /// The string constants, indexed by the operand of ConstStr.
+    priv strings: ~[~str]
+}
+
+impl ConstantPool {
+    /**
+     * Creates an empty ConstantPool.
+     */
+    pub fn new() -> ~ConstantPool {
+        ~ConstantPool { strings: ~[] }
+    }
+
+    /**
+     * Adds a string constant, reusing an identical one if already present.
+     *
+     * # Arguments
+     *
+     * * value - The string.
+     *
+     * Returns the index to use with ConstStr.
+     */
+    pub fn add_string(&mut self, value: &str) -> u32 {
+        for (index, existing) in self.strings.iter().enumerate() {
+            if existing.as_slice() == value {
+                return index as u32;
+            }
+        }
+        self.strings.push(value.to_owned());
+        (self.strings.len() - 1) as u32
+    }
+
+    /**
+     * Returns a string constant.
+     *
+     * # Arguments
+     *
+     * * index - The index returned by add_string.
+     */
+    pub fn get_string<'a>(&'a self, index: u32) -> &'a str {
+        self.strings[index].as_slice()
+    }
+
+    /**
+     * Returns the number of string constants.
+     */
+    pub fn string_count(&self) -> uint {
+        self.strings.len()
+    }
+}
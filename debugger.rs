@@ -0,0 +1,428 @@
+use opcode::*;
+use interpret::*;
+use analysis::*;
+use basic_block::*;
+use variable_type::*;
+use heap::*;
+use constant_pool::*;
+use vm_error::*;
+use std::cast::transmute;
+use std::hashmap::HashMap;
+use std::io;
+use std::vec;
+
+/**
+ * The reason the debugger handed control back to the user.
+ */
+#[deriving(Clone)]
+pub enum Stop {
+    /// A single instruction ran; execution is at the given offset.
+    Stepped(u32),
+
+    /// Execution reached a breakpoint at the given offset.
+    AtBreakpoint(u32),
+
+    /// A watched local changed from the first value to the second.
+    WatchTriggered(u32, StackValue, StackValue),
+
+    /// The function returned.
+    Returned(Option<StackValue>),
+
+    /// The function raised an error.
+    Failed(VMError)
+}
+
+impl ToStr for Stop {
+    fn to_str(&self) -> ~str {
+        match *self {
+            Stepped(ip) => fmt!("stepped to %u", ip as uint),
+            AtBreakpoint(ip) => fmt!("breakpoint at %u", ip as uint),
+            WatchTriggered(local, ref old, ref new) => {
+                fmt!("local %u changed from %s to %s", local as uint, old.to_str(), new.to_str())
+            }
+            Returned(Some(ref value)) => fmt!("returned %s", value.to_str()),
+            Returned(None) => ~"returned nothing",
+            Failed(error) => fmt!("failed with %s", error.to_str())
+        }
+    }
+}
+
+/**
+ * Runs an interpreted function under the control of a user,
+ * one instruction at a time.
+ */
+pub struct Debugger {
+    /// The function being debugged.
+    priv function: ~[Opcode],
+
+    /// The VM runtime stack of the call being debugged.
+    priv stack: ~[StackValue],
+
+    /// The runtime environment of the call being debugged.
+    priv environment: Environment,
+
+    /// The inferred types of the function's locals.
+    priv local_types: ~[VariableType],
+
+    /// The inferred types on the evaluation stack before each instruction.
+    priv stack_types: ~[~[VariableType]],
+
+    /// The offsets at which to stop.
+    priv breakpoints: ~[u32],
+
+    /// The locals at which to stop when their values change.
+    priv watchpoints: ~[u32],
+
+    /// Names for instruction offsets.
+    priv labels: HashMap<~str, u32>
+}
+
+impl Debugger {
+    /**
+     * Creates a Debugger positioned at the start of a call to a function.
+     *
+     * # Arguments
+     *
+     * * function - The function to debug.
+     *
+     * Returns the debugger, or the type error that keeps the debugger
+     * from showing the function's types.
+     */
+    pub fn new(function: &[Opcode]) -> Result<Debugger, VerifyError> {
+        let mut stack = ~[];
+        let environment = Environment::new(function, &mut stack);
+
+        let basic_blocks = get_basic_blocks(function);
+        let local_types = match infer_local_types(basic_blocks, local_count(function)) {
+            Ok(local_types) => local_types,
+            Err(error) => return Err(error)
+        };
+
+        let mut types = vec::from_elem(function.len(), ~[]);
+        for basic_block in basic_blocks.iter() {
+            let block_types = stack_types(&**basic_block, local_types);
+            for (index, snapshot) in block_types.iter().enumerate() {
+                types[basic_block.start as uint + index] = snapshot.clone();
+            }
+        }
+
+        Ok(Debugger {
+            function: function.to_owned(),
+            stack: stack,
+            environment: environment,
+            local_types: local_types,
+            stack_types: types,
+            breakpoints: ~[],
+            watchpoints: ~[],
+            labels: HashMap::new()
+        })
+    }
+
+    /**
+     * Names an instruction offset, so that it can be used as a location.
+     *
+     * # Arguments
+     *
+     * * name   - The label.
+     * * offset - The instruction offset.
+     */
+    pub fn define_label(&mut self, name: &str, offset: u32) {
+        self.labels.insert(name.to_owned(), offset);
+    }
+
+    /**
+     * Resolves a location given as an instruction offset or a label.
+     *
+     * # Arguments
+     *
+     * * location - The offset or label.
+     */
+    pub fn resolve(&self, location: &str) -> Option<u32> {
+        match from_str::<u32>(location) {
+            Some(offset) => Some(offset),
+            None => match self.labels.find_equiv(&location) {
+                Some(&offset) => Some(offset),
+                None => None
+            }
+        }
+    }
+
+    /**
+     * Stops execution before the instruction at an offset runs.
+     */
+    pub fn set_breakpoint(&mut self, offset: u32) {
+        if !self.breakpoints.contains(&offset) {
+            self.breakpoints.push(offset);
+        }
+    }
+
+    /**
+     * Removes the breakpoint at an offset.
+     */
+    pub fn clear_breakpoint(&mut self, offset: u32) {
+        self.breakpoints.retain(|&breakpoint| breakpoint != offset);
+    }
+
+    /**
+     * Stops execution whenever a local changes value.
+     */
+    pub fn watch(&mut self, local: u32) {
+        if !self.watchpoints.contains(&local) {
+            self.watchpoints.push(local);
+        }
+    }
+
+    /**
+     * Removes the watchpoint on a local.
+     */
+    pub fn unwatch(&mut self, local: u32) {
+        self.watchpoints.retain(|&watchpoint| watchpoint != local);
+    }
+
+    /**
+     * Returns the offset of the next instruction to run.
+     */
+    pub fn ip(&self) -> u32 {
+        self.environment.ip
+    }
+
+    /**
+     * Returns the value of a local.
+     */
+    pub fn local(&self, local: u32) -> StackValue {
+        self.stack[self.environment.bp - local - 1]
+    }
+
+    /**
+     * Runs a single instruction.
+     *
+     * # Arguments
+     *
+     * * constants - The constant pool of the function's module.
+     * * heap      - The heap on which the function allocates objects.
+     */
+    pub fn step(&mut self, constants: &ConstantPool, heap: &mut Heap) -> Stop {
+        if self.environment.ip >= self.environment.end_ip {
+            return Returned(self.environment.return_value);
+        }
+
+        let watched = self.watchpoints.map(|&local| self.local(local));
+
+        match self.environment.consume_fuel() {
+            Ok(()) => { }
+            Err(error) => return Failed(error)
+        }
+        let opcode = self.function[self.environment.ip].clone();
+        match interpret_opcode(&opcode, &mut self.stack, &mut self.environment, constants, heap) {
+            Ok(ip) => {
+                if ip <= self.environment.ip {
+                    self.environment.back_edges += 1;
+                }
+                self.environment.ip = ip;
+            }
+            Err(error) => return Failed(error)
+        }
+
+        for (&local, old) in self.watchpoints.iter().zip(watched.iter()) {
+            let new = self.local(local);
+            if !same_bits(old, &new) {
+                return WatchTriggered(local, *old, new);
+            }
+        }
+
+        if self.environment.ip >= self.environment.end_ip {
+            Returned(self.environment.return_value)
+        } else {
+            Stepped(self.environment.ip)
+        }
+    }
+
+    /**
+     * Runs until a breakpoint or watchpoint is hit, or the function returns.
+     *
+     * # Arguments
+     *
+     * * constants - The constant pool of the function's module.
+     * * heap      - The heap on which the function allocates objects.
+     */
+    pub fn resume(&mut self, constants: &ConstantPool, heap: &mut Heap) -> Stop {
+        // The breakpoint being resumed from, if any, does not stop the
+        // first step, but does if the function comes back to it.
+        let mut first = true;
+        loop {
+            let ip = self.environment.ip;
+            if !first && self.breakpoints.contains(&ip) {
+                return AtBreakpoint(ip);
+            }
+            first = false;
+            match self.step(constants, heap) {
+                Stepped(_) => { }
+                stop => return stop
+            }
+        }
+    }
+
+    /**
+     * Formats the evaluation stack, top first, with the inferred type of each value.
+     */
+    pub fn format_stack(&self) -> ~str {
+        let base = self.environment.bp as uint;
+        let types = if self.environment.ip < self.environment.end_ip {
+            self.stack_types[self.environment.ip].clone()
+        } else {
+            ~[]
+        };
+
+        let mut result = ~"";
+        for index in range(base, self.stack.len()).invert() {
+            let depth = index - base;
+            let value_type = if depth < types.len() { types[depth] } else { Unknown };
+            result.push_str(fmt!("  [%u] %s: %s\n", depth, value_type.to_str(), self.stack[index].to_str()));
+        }
+        result
+    }
+
+    /**
+     * Formats the locals with their inferred types.
+     */
+    pub fn format_locals(&self) -> ~str {
+        let mut result = ~"";
+        for (index, local_type) in self.local_types.iter().enumerate() {
+            result.push_str(fmt!("  local %u %s: %s\n", index, local_type.to_str(),
+                                 self.local(index as u32).to_str()));
+        }
+        result
+    }
+
+    /**
+     * Formats the instructions around the next one to run.
+     */
+    pub fn format_location(&self) -> ~str {
+        let ip = self.environment.ip as uint;
+        let start = if ip > 2 { ip - 2 } else { 0 };
+        let end = if ip + 3 < self.function.len() { ip + 3 } else { self.function.len() };
+
+        let mut result = ~"";
+        for offset in range(start, end) {
+            let marker = if offset == ip { "=>" } else { "  " };
+            result.push_str(fmt!("%s %4u  %s\n", marker, offset, self.function[offset].to_str()));
+        }
+        result
+    }
+
+    /**
+     * Runs one console command.
+     *
+     * # Arguments
+     *
+     * * line      - The command.
+     * * constants - The constant pool of the function's module.
+     * * heap      - The heap on which the function allocates objects.
+     *
+     * Returns the command's output, and whether the console should exit.
+     */
+    pub fn execute_command(&mut self, line: &str, constants: &ConstantPool, heap: &mut Heap) -> (~str, bool) {
+        let words: ~[&str] = line.word_iter().collect();
+        if words.is_empty() {
+            return (~"", false);
+        }
+
+        let output = match (words[0], words.slice_from(1)) {
+            ("break", [location]) | ("b", [location]) => {
+                match self.resolve(location) {
+                    Some(offset) => {
+                        self.set_breakpoint(offset);
+                        fmt!("Breakpoint at %u", offset as uint)
+                    }
+                    None => fmt!("Unknown location %s", location)
+                }
+            }
+            ("delete", [location]) | ("d", [location]) => {
+                match self.resolve(location) {
+                    Some(offset) => {
+                        self.clear_breakpoint(offset);
+                        fmt!("Deleted breakpoint at %u", offset as uint)
+                    }
+                    None => fmt!("Unknown location %s", location)
+                }
+            }
+            ("label", [name, offset]) => {
+                match from_str::<u32>(offset) {
+                    Some(offset) => {
+                        self.define_label(name, offset);
+                        fmt!("%s is %u", name, offset as uint)
+                    }
+                    None => fmt!("Bad offset %s", offset)
+                }
+            }
+            ("watch", [local]) | ("unwatch", [local]) => {
+                match from_str::<u32>(local) {
+                    Some(local) if (local as uint) < self.local_types.len() => {
+                        if words[0] == "watch" {
+                            self.watch(local);
+                            fmt!("Watching local %u", local as uint)
+                        } else {
+                            self.unwatch(local);
+                            fmt!("Stopped watching local %u", local as uint)
+                        }
+                    }
+                    _ => fmt!("No local %s", local)
+                }
+            }
+            ("step", []) | ("s", []) => {
+                let stop = self.step(constants, heap);
+                fmt!("%s\n%s", stop.to_str(), self.format_location())
+            }
+            ("continue", []) | ("c", []) => {
+                let stop = self.resume(constants, heap);
+                fmt!("%s\n%s", stop.to_str(), self.format_location())
+            }
+            ("stack", []) => self.format_stack(),
+            ("locals", []) => self.format_locals(),
+            ("where", []) => self.format_location(),
+            ("quit", []) | ("q", []) => return (~"", true),
+            _ => ~"Commands: break <offset|label>, delete <offset|label>, label <name> <offset>, \
+                   watch <local>, unwatch <local>, step, continue, stack, locals, where, quit"
+        };
+        (output, false)
+    }
+
+    /**
+     * Runs an interactive console on standard input until the user quits.
+     *
+     * # Arguments
+     *
+     * * constants - The constant pool of the function's module.
+     * * heap      - The heap on which the function allocates objects.
+     */
+    pub fn run_console(&mut self, constants: &ConstantPool, heap: &mut Heap) {
+        let input = io::stdin();
+        print(self.format_location());
+
+        loop {
+            print("(debug) ");
+            if input.eof() {
+                break;
+            }
+            let line = input.read_line();
+            let (output, quit) = self.execute_command(line, constants, heap);
+            if quit {
+                break;
+            }
+            println(output);
+        }
+    }
+}
+
+/**
+ * Returns whether two values are the same kind of value with the same bits,
+ * so that a float local holding NaN does not look changed on every step.
+ */
+fn same_bits(a: &StackValue, b: &StackValue) -> bool {
+    match (*a, *b) {
+        (Int(a), Int(b)) => a == b,
+        (Float(a), Float(b)) => unsafe { transmute::<f32, u32>(a) == transmute::<f32, u32>(b) },
+        (Reference(a), Reference(b)) => a == b,
+        _ => false
+    }
+}
@@ -0,0 +1,140 @@
+use opcode::*;
+use interpret::*;
+use heap::*;
+use constant_pool::*;
+use debugger::*;
+use std::f32;
+use std::os;
+
+mod variable_type;
+mod vm_error;
+mod heap;
+mod constant_pool;
+mod backend;
+mod opcode;
+mod interpret;
+mod basic_block;
+mod analysis;
+mod debugger;
+
+/**
+ * Returns the factorial(10) function from factorial.rs, whose loop
+ * header is at offset 4.
+ */
+fn factorial() -> ~[Opcode] {
+    ~[
+        Constf32(10f32),
+        Store(0),
+        Constf32(1f32),
+        Store(1),
+        Loadf32(0),
+        Constf32(1f32),
+        Leq,
+        Iftrue(17),
+        Loadf32(0),
+        Loadf32(1),
+        Multiply,
+        Store(1),
+        Loadf32(0),
+        Constf32(1f32),
+        Subtract,
+        Store(0),
+        Jmp(4),
+        Loadf32(1),
+        Ret
+    ]
+}
+
+/**
+ * Creates a debugger for a function that the checks know to be valid.
+ */
+fn debug(function: &[Opcode]) -> Debugger {
+    match Debugger::new(function) {
+        Ok(debugger) => debugger,
+        Err(error) => fail!(error.to_str())
+    }
+}
+
+fn main() {
+    let constants = ConstantPool::new();
+    let mut heap = Heap::new();
+    let mut failures = 0;
+
+    // A breakpoint on the loop header is hit on the first iteration, and
+    // again on the next one after resuming from it.
+    {
+        let mut debugger = debug(factorial());
+        debugger.set_breakpoint(4);
+        match debugger.resume(constants, heap) {
+            AtBreakpoint(4) if debugger.local(0) == Float(10f32) => { }
+            stop => {
+                failures += 1;
+                println(fmt!("FAIL breakpoint: expected breakpoint at 4 with n = 10, got %s", stop.to_str()));
+            }
+        }
+        match debugger.resume(constants, heap) {
+            AtBreakpoint(4) if debugger.local(0) == Float(9f32) => { }
+            stop => {
+                failures += 1;
+                println(fmt!("FAIL breakpoint after resume: expected breakpoint at 4 with n = 9, got %s",
+                             stop.to_str()));
+            }
+        }
+    }
+
+    // Storing NaN over NaN leaves the local as it was, so a watchpoint
+    // fires when NaN is first stored and not again.
+    {
+        let mut debugger = debug(~[Constf32(f32::NaN), Store(0), Loadf32(0), Store(0), Consti32(1), Ret]);
+        debugger.watch(0);
+        match debugger.resume(constants, heap) {
+            WatchTriggered(0, Int(0), Float(value)) if value.is_NaN() => { }
+            stop => {
+                failures += 1;
+                println(fmt!("FAIL watchpoint: expected local 0 to change to NaN, got %s", stop.to_str()));
+            }
+        }
+        match debugger.resume(constants, heap) {
+            Returned(Some(Int(1))) => { }
+            stop => {
+                failures += 1;
+                println(fmt!("FAIL NaN watchpoint: expected to return 1, got %s", stop.to_str()));
+            }
+        }
+    }
+
+    // The break command sets a breakpoint on a label, and reports
+    // locations it does not know.
+    {
+        let mut debugger = debug(factorial());
+        debugger.define_label("loop", 4);
+        match debugger.execute_command("break loop", constants, heap) {
+            (ref output, false) if *output == ~"Breakpoint at 4" => { }
+            result => {
+                failures += 1;
+                println(fmt!("FAIL break <label>: got %?", result));
+            }
+        }
+        match debugger.execute_command("break nowhere", constants, heap) {
+            (ref output, false) if *output == ~"Unknown location nowhere" => { }
+            result => {
+                failures += 1;
+                println(fmt!("FAIL break <unknown label>: got %?", result));
+            }
+        }
+        match debugger.resume(constants, heap) {
+            AtBreakpoint(4) => { }
+            stop => {
+                failures += 1;
+                println(fmt!("FAIL break <label>: expected breakpoint at 4, got %s", stop.to_str()));
+            }
+        }
+    }
+
+    if failures > 0 {
+        println(fmt!("%u failures", failures as uint));
+        os::set_exit_status(1);
+    } else {
+        println("All checks passed");
+    }
+}
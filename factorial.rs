@@ -1,16 +1,44 @@
-use std::libc::*;
+extern mod extra;
+
 use opcode::*;
 use interpret::*;
 use libjit::*;
 use jit::*;
+use frontend::*;
+use heap::*;
+use constant_pool::*;
+use tiered::*;
+use profile::*;
+use debugger::*;
+use trace::*;
+use code_cache::*;
+use wasm::*;
+use wasm_import::*;
+use std::io;
+use std::os;
 
 mod variable_type;
+mod vm_error;
+mod heap;
+mod constant_pool;
+mod jit_helpers;
+mod tiered;
+mod profile;
+mod debugger;
+mod trace;
+mod code_cache;
+mod optimize;
+mod backend;
+mod x86_64;
 mod libjit;
 mod opcode;
 mod interpret;
 mod jit;
+mod frontend;
 mod basic_block;
 mod analysis;
+mod wasm;
+mod wasm_import;
 
 fn main() {
 
@@ -50,30 +78,146 @@ fn main() {
         Ret                 //
     ];
 
+    let mut heap = Heap::new();
+    let constants = ConstantPool::new();
+
+    if os::args().contains(&~"--debug") {
+        let mut debugger = match Debugger::new(factorial) {
+            Ok(debugger) => debugger,
+            Err(error) => fail!(error.to_str())
+        };
+        debugger.define_label("loop", 4);
+        debugger.define_label("end", 17);
+        debugger.run_console(constants, heap);
+        return;
+    }
+
+    if os::args().contains(&~"--wasm") {
+        match export(factorial, "factorial") {
+            Ok(module) => {
+                match io::file_writer(&Path("factorial.wasm"), [io::Create, io::Truncate]) {
+                    Ok(writer) => writer.write(module.binary),
+                    Err(error) => fail!(error)
+                }
+                print(module.text);
+
+                // Importing the module back gives an equivalent VM function.
+                match import(module.binary, "factorial", []) {
+                    Ok(imported) => println(fmt!("Reimported: %?", interpret(imported, constants, heap))),
+                    Err(error) => println(fmt!("Error: %s", error.to_str()))
+                }
+            }
+            Err(error) => println(fmt!("Error: %s", error.to_str()))
+        }
+        return;
+    }
+
+    if os::args().contains(&~"--trace") {
+        let _ = interpret_traced(factorial, constants, heap, io::stdout());
+        return;
+    }
+
     println("Interpreting factorial(10)...");
-    interpret(factorial);
+    match interpret(factorial, constants, heap) {
+        Ok(Some(value)) => println(fmt!("Returned: %s", value.to_str())),
+        Ok(None) => println("Returned nothing"),
+        Err(error) => println(fmt!("Error: %s", error.to_str()))
+    }
+
+    println("");
+    println("Profiling factorial(10)...");
+
+    let mut profile = Profile::new(factorial);
+    let _ = interpret_profiled(factorial, constants, heap, &mut profile);
+    print(profile.to_text());
+    println(profile.to_json());
 
     println("");
     println("Jitting factorial(10)...");
     
     let context = Context::new();
-    let function = compile(factorial, context);
+    let options = CompileOptions::default();
+    {
+        // The cache borrows the heap until it goes out of scope.
+        let mut cache = CodeCache::new(context, constants, heap, 1024);
+        {
+            let function = match cache.get(factorial, NormalEntry, &options) {
+                Ok(function) => function,
+                Err(error) => fail!(error.to_str())
+            };
 
-    function.dump("factorial");
-    println("");
+            function.dump("factorial");
+            println("");
+
+            println("Returned:");
+            match function.call::<f32>([]) {
+                Ok(retval) => println(fmt!("%?", retval)),
+                Err(error) => println(fmt!("%?", error))
+            }
+        }
+
+        println("");
+        println("Closure factorial(10)...");
 
-    let args: ~[*c_void] = ~[];
-    let mut retval: ~f32 = ~0f32;
-    function.apply(args, retval);
+        {
+            // The second lookup reuses the code compiled above.
+            let function = match cache.get(factorial, NormalEntry, &options) {
+                Ok(function) => function,
+                Err(error) => fail!(error.to_str())
+            };
+            match function.closure0::<f32>() {
+                Ok(f) => println(fmt!("%?", f.call())),
+                Err(error) => println(fmt!("%?", error))
+            }
+        }
+        println(fmt!("%?", cache.stats()));
+    }
 
-    println("Returned:");
-    println(fmt!("%?", *retval));
+    println("");
+    println("Lazy factorial(10)...");
+
+    {
+        // The lazy function borrows the heap until it goes out of scope.
+        let lazy = match compile_lazily(factorial, &options, constants, context, heap) {
+            Ok(lazy) => lazy,
+            Err(error) => fail!(error.to_str())
+        };
+        match lazy.callable().closure0::<f32>() {
+            Ok(f) => {
+                println(fmt!("Compiled before the first call: %b", lazy.is_compiled()));
+                println(fmt!("%?", f.call()));
+                println(fmt!("Compiled after the first call: %b", lazy.is_compiled()));
+            }
+            Err(error) => println(fmt!("%?", error))
+        }
+    }
 
     println("");
-    println("Closure factorial(10)...");
+    println("Native factorial(10)...");
 
-    let f: extern "C" fn() -> c_float = function.closure();
-    let ret = f();
+    let native = match compile_native(factorial, &options, constants, heap) {
+        Ok(native) => native,
+        Err(error) => fail!(error.to_str())
+    };
+    println(fmt!("%u bytes of x86-64 code", native.callable().size()));
+    match (native.callable().call([]), return_type(factorial)) {
+        (Ok(bits), Ok(returned)) => println(fmt!("%?", StackValue::from_word(bits, returned))),
+        (Err(error), _) => println(fmt!("%?", error)),
+        (_, Err(error)) => println(fmt!("%?", error))
+    }
+
+    println("");
+    println("Tiered factorial(10)...");
 
-    println(fmt!("%?", ret));
+    let mut runtime = TieredRuntime::new(context, ConstantPool::new());
+    let index = match runtime.add_function(factorial.clone()) {
+        Ok(index) => index,
+        Err(error) => fail!(error.to_str())
+    };
+    for _ in range(0, 12) {
+        let result = runtime.call(index);
+        let stats = runtime.stats(index);
+        println(fmt!("%? in tier %s", result, stats.tier.to_str()));
+    }
+    println(fmt!("%?", runtime.stats(index)));
 }
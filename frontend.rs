@@ -0,0 +1,1370 @@
+use opcode::*;
+use backend::*;
+use analysis::*;
+use basic_block::*;
+use variable_type::*;
+use heap::*;
+use constant_pool::*;
+use jit_helpers::*;
+use vm_error::*;
+use x86_64::{X86Backend, NativeFunction};
+use std::cast::transmute;
+use std::libc::*;
+use std::ptr;
+use std::i32;
+use optimize::*;
+
+/**
+ * How to compile a function, to trade compile time for code quality.
+ */
+#[deriving(Clone)]
+#[deriving(IterBytes)]
+pub struct CompileOptions {
+    /// How hard libjit optimizes the function, from 0 up to
+    /// Function::max_optimization_level(), or None for libjit's default.
+    optimization_level: Option<uint>,
+
+    /// Whether to run the bytecode passes in optimize.rs first. On-stack
+    /// replacement entries are compiled without them, since threading
+    /// jumps can leave the loop header without a basic block of its own.
+    bytecode_passes: bool,
+
+    /// Whether array and field accesses check that the index is in bounds.
+    /// Without the checks, a bad index reads or writes arbitrary memory.
+    bounds_checks: bool,
+
+    /// Whether AddOvf, SubOvf and MulOvf check for overflow. Without the
+    /// checks they wrap, like Add, Subtract and Multiply.
+    overflow_checks: bool,
+
+    /// Whether to keep libjit's IR after compiling, so that dump shows it
+    /// rather than the machine code.
+    keep_ir: bool
+}
+
+impl CompileOptions {
+    /**
+     * Returns libjit's default optimization level, with the bytecode
+     * passes and every check enabled.
+     */
+    pub fn default() -> CompileOptions {
+        CompileOptions {
+            optimization_level: None,
+            bytecode_passes: true,
+            bounds_checks: true,
+            overflow_checks: true,
+            keep_ir: false
+        }
+    }
+}
+
+/**
+ * Compiles a function's normal entry with any Backend. The backend is
+ * finalized once the function is emitted.
+ *
+ * This is unsafe because the compiled code keeps pointers to the constant
+ * pool and heap, but whatever the backend produces does not borrow them:
+ * the caller must not let the code run once either is moved or dropped.
+ *
+ * # Arguments
+ *
+ * * function - The function to compile.
+ * * options - How to compile the function. Only the bytecode passes and
+ *             checks apply; optimization_level and keep_ir are libjit's.
+ * * constants - The constant pool of the function's module. It must
+ *               outlive the compiled function.
+ * * heap - The heap on which the compiled code allocates objects. It must
+ *          outlive the compiled function.
+ * * backend - The code generator to emit the function into.
+ *
+ * Returns the error the function was rejected with, if it does not
+ * verify, in which case the backend is not finalized.
+ */
+pub unsafe fn compile_to(function: &[Opcode], options: &CompileOptions, constants: &ConstantPool, heap: &mut Heap,
+                         backend: &mut Backend) -> Result<(), VerifyError> {
+    emit_function(function, None, None, options, constants, heap, backend)
+}
+
+/**
+ * Compiles a function's normal entry to x86-64 machine code with
+ * X86Backend, which generates the code itself rather than through libjit.
+ *
+ * The NativeCode borrows the constant pool and heap, since the machine
+ * code refers to both.
+ *
+ * # Arguments
+ *
+ * * function - The function to compile.
+ * * options - How to compile the function. optimization_level and
+ *             keep_ir do not apply.
+ * * constants - The constant pool of the function's module.
+ * * heap - The heap on which the compiled code allocates objects.
+ *
+ * Returns the compiled function, or why the function was rejected.
+ */
+pub fn compile_native<'a>(function: &[Opcode], options: &CompileOptions, constants: &'a ConstantPool,
+                          heap: &'a mut Heap) -> Result<~NativeCode<'a>, VerifyError> {
+    let mut backend = X86Backend::new();
+    // The NativeCode returned carries the borrows of both.
+    match unsafe { compile_to(function, options, constants, heap, &mut backend as &mut Backend) } {
+        Ok(()) => Ok(~NativeCode { function: backend.compiled(), _constants: constants }),
+        Err(error) => Err(error)
+    }
+}
+
+/**
+ * A function compiled by compile_native. It borrows the constant pool and
+ * heap the code was compiled against, so neither can go away while it can
+ * still be called.
+ */
+pub struct NativeCode<'self> {
+    priv function: ~NativeFunction,
+    priv _constants: &'self ConstantPool
+}
+
+impl<'self> NativeCode<'self> {
+    /**
+     * Returns the function to call.
+     */
+    pub fn callable<'a>(&'a self) -> &'a NativeFunction {
+        &*self.function
+    }
+}
+
+/**
+ * Creates, builds and finalizes a function in a Backend.
+ *
+ * # Arguments
+ *
+ * * function  - The function to compile.
+ * * entry     - The index of the loop header for an on-stack replacement
+ *               entry, or None for the normal entry.
+ * * fuel      - The fuel counter to consume on backward branches, if any.
+ * * options   - How to compile the function.
+ * * constants - The constant pool of the function's module.
+ * * heap      - The heap on which the compiled code allocates objects.
+ * * backend   - The code generator.
+ *
+ * Returns the error the function was rejected with, if it does not
+ * verify, in which case the backend is not finalized.
+ */
+pub fn emit_function(function: &[Opcode], entry: Option<u32>, fuel: Option<*mut u32>, options: &CompileOptions,
+                     constants: &ConstantPool, heap: &mut Heap, backend: &mut Backend) -> Result<(), VerifyError> {
+    let (params, return_type) = match signature(function, entry) {
+        Ok(signature) => signature,
+        Err(error) => return Err(error)
+    };
+    backend.create_function(params, return_type);
+    match build_function(prepare(function, entry, options), entry, fuel, options, backend, constants, heap) {
+        Ok(()) => { }
+        Err(error) => return Err(error)
+    }
+    backend.finalize();
+    Ok(())
+}
+
+/**
+ * Returns the parameter and return types of a compiled function.
+ *
+ * # Arguments
+ *
+ * * function - The function to compile.
+ * * entry    - The index of the loop header for an on-stack replacement
+ *              entry, or None for the normal entry.
+ *
+ * Returns the types, or why the function was rejected.
+ */
+pub fn signature(function: &[Opcode], entry: Option<u32>) -> Result<(~[ScalarType], ScalarType), VerifyError> {
+    // TODO: at the moment, functions take no arguments.
+    // On-stack replacement entries take a pointer to their locals.
+    let params = if entry.is_some() { ~[PointerType] } else { ~[] };
+    match verify(function) {
+        Ok(()) => { }
+        Err(error) => return Err(error)
+    }
+    match return_type(function) {
+        Ok(return_type) => Ok((params, scalar_type(return_type))),
+        Err(error) => Err(error)
+    }
+}
+
+/**
+ * Returns the inferred return type of a function. Compiled code returns
+ * floats as FloatType and everything else, including references and
+ * values of unknown type, as IntType.
+ *
+ * # Arguments
+ *
+ * * function - The function.
+ *
+ * Returns the type, or the type error that rejects the function.
+ */
+pub fn return_type(function: &[Opcode]) -> Result<VariableType, VerifyError> {
+    let basic_blocks = get_basic_blocks(function);
+    match infer_local_types(basic_blocks, local_count(function)) {
+        Ok(local_types) => Ok(infer_return_type(basic_blocks, local_types)),
+        Err(error) => Err(error)
+    }
+}
+
+/**
+ * Returns the opcodes to compile, after the bytecode passes if they are enabled.
+ *
+ * # Arguments
+ *
+ * * function - The function to compile.
+ * * entry    - The index of the loop header for an on-stack replacement
+ *              entry, or None for the normal entry.
+ * * options  - How to compile the function.
+ */
+pub fn prepare(function: &[Opcode], entry: Option<u32>, options: &CompileOptions) -> ~[Opcode] {
+    if options.bytecode_passes && entry.is_none() {
+        optimize(function)
+    } else {
+        function.to_owned()
+    }
+}
+
+/**
+ * Emits the body of a function.
+ *
+ * # Arguments
+ *
+ * * function  - The function to compile.
+ * * entry     - The index of the loop header for an on-stack replacement
+ *               entry, or None for the normal entry.
+ * * fuel      - The fuel counter to consume on backward branches, if any.
+ * * options   - How to compile the function.
+ * * backend   - The code generator, with the function created.
+ * * constants - The constant pool of the function's module.
+ * * heap      - The heap on which the compiled code allocates objects.
+ *
+ * Returns the error the function was rejected with, if it does not verify.
+ */
+pub fn build_function(function: &[Opcode], entry: Option<u32>, fuel: Option<*mut u32>, options: &CompileOptions,
+                      backend: &mut Backend, constants: &ConstantPool, heap: &mut Heap) -> Result<(), VerifyError> {
+    // Convert stream of opcodes to basic block representation.
+    let basic_blocks = get_basic_blocks(function);
+    for basic_block in basic_blocks.iter() {
+        basic_block.label = Some(backend.new_label());
+    }
+
+    // Pre-create Values for each local variable.
+    let local_types = match infer_local_types(basic_blocks, local_count(function)) {
+        Ok(local_types) => local_types,
+        Err(error) => return Err(error)
+    };
+    let mut locals = reserve_locals(local_types, backend);
+
+    // Reserve somewhere for the collector to find this invocation's references.
+    let frame = reserve_root_frame(basic_blocks, local_types, heap, backend);
+
+    // Work out which exception handler is active in each block.
+    let handlers = match handler_stacks(basic_blocks) {
+        Ok(handlers) => handlers,
+        Err(error) => return Err(error)
+    };
+    let exceptions = reserve_exception_state(function, backend);
+    match exceptions {
+        Some(ref state) => {
+            let entry_start = match entry { Some(entry) => entry, None => 0 };
+            for (basic_block, block_handlers) in basic_blocks.iter().zip(handlers.iter()) {
+                if basic_block.start == entry_start {
+                    set_active_handler(backend, state, *block_handlers);
+                }
+            }
+        }
+        None => { }
+    }
+
+    match entry {
+        Some(entry) => enter_at(entry, basic_blocks, backend, locals, local_types),
+        None => { }
+    }
+
+    let exception_state = match exceptions {
+        Some(ref state) => Some(state),
+        None => None
+    };
+    for (basic_block, block_handlers) in basic_blocks.iter().zip(handlers.iter()) {
+        compile_basic_block(*basic_block, backend, &mut locals, local_types, &frame, fuel,
+                            block_handlers.clone(), exception_state, options, constants, heap);
+    }
+
+    // The root frame must be unregistered however the invocation ends.
+    if exception_state.is_some() || frame.size > 0 {
+        compile_catcher(backend, basic_blocks, handlers, exception_state, &frame, heap);
+    }
+    Ok(())
+}
+
+/**
+ * Emits an on-stack replacement entry: loads the locals passed in by the
+ * interpreter and jumps to the loop header.
+ *
+ * # Arguments
+ *
+ * * entry        - The index of the loop header instruction.
+ * * basic_blocks - The basic block representation of the function.
+ * * backend      - The code generator.
+ * * locals       - The list of the function's local variable Values.
+ * * local_types  - The inferred types of the function's locals.
+ */
+fn enter_at(entry: u32, basic_blocks: &[@mut BasicBlock], backend: &mut Backend,
+            locals: &[ValueId], local_types: &[VariableType]) {
+    let words = backend.param(0);
+    for (index, &local) in locals.iter().enumerate() {
+        let value = backend.load_relative(words, (index * 4) as int, scalar_type(local_types[index]));
+        backend.store(local, value);
+    }
+
+    for basic_block in basic_blocks.iter() {
+        if basic_block.start == entry {
+            backend.branch(basic_block.label.unwrap());
+            return;
+        }
+    }
+    fail!(fmt!("No basic block starts at %u", entry as uint));
+}
+
+/**
+ * JIT compiles a single basic block.
+ *
+ * # Arguments
+ *
+ * * basic_block - The basic block to compile.
+ * * backend     - The code generator.
+ * * locals      - The list of the function's local variable Values.
+ * * local_types - The inferred types of the function's locals.
+ * * frame       - The function's GC root frame.
+ * * fuel        - The fuel counter to consume on backward branches, if any.
+ * * handlers    - The exception handlers installed on entering the block.
+ * * exceptions  - The function's exception state, if it has handlers.
+ * * options     - How to compile the function.
+ * * constants   - The constant pool of the function's module.
+ * * heap        - The VM heap.
+ */
+fn compile_basic_block(basic_block: @mut BasicBlock,
+                       backend: &mut Backend,
+                       locals: &mut ~[ValueId],
+                       local_types: &[VariableType],
+                       frame: &RootFrame,
+                       fuel: Option<*mut u32>,
+                       handlers: ~[u32],
+                       exceptions: Option<&ExceptionState>,
+                       options: &CompileOptions,
+                       constants: &ConstantPool,
+                       heap: &mut Heap) {
+
+    // The evaluation stack must be empty on entering a basic block,
+    // except in a handler, which starts with the exception value.
+    let mut stack = ~[];
+    let mut handlers = handlers;
+    match exceptions {
+        Some(state) if basic_block.handler => stack.push(backend.copy(state.value)),
+        _ => { }
+    }
+
+    // Place this basic block's label.
+    backend.set_label(basic_block.label.unwrap());
+
+    let types = stack_types(&*basic_block, local_types);
+    for (index, (opcode, stack_types)) in basic_block.opcodes.iter().zip(types.iter()).enumerate() {
+        match *opcode {
+            TryBegin(handler) => {
+                handlers.push(handler);
+                set_active_handler(backend, exceptions.unwrap(), handlers);
+            }
+            TryEnd => {
+                handlers.pop();
+                set_active_handler(backend, exceptions.unwrap(), handlers);
+            }
+            _ => {
+                let offset = basic_block.start + index as u32;
+                compile_opcode(opcode, offset, backend, &mut stack, *stack_types, locals, frame, options,
+                               constants, heap);
+            }
+        }
+    }
+
+    // If the basic block ends in a conditional branch (Iftrue),
+    // emit a conditional branch instruction here.
+    match basic_block.conditional_block {
+        Some(b) => {
+            let value = stack.pop();
+            match fuel {
+                Some(fuel) if b.start <= basic_block.start => {
+                    let fall_through = backend.new_label();
+                    backend.branch_if_not(value, fall_through);
+                    consume_fuel(backend, fuel);
+                    backend.branch(b.label.unwrap());
+                    backend.set_label(fall_through);
+                }
+                _ => backend.branch_if(value, b.label.unwrap())
+            }
+        }
+    _   => { }
+    }
+
+    // If the basic block has a successor, emit a branch
+    // instruction. Backends (hopefully) optimise these out
+    // if they are unnecessary.
+    match basic_block.next_block {
+        Some(b) => {
+            match fuel {
+                Some(fuel) if b.start <= basic_block.start => consume_fuel(backend, fuel),
+                _ => { }
+            }
+            backend.branch(b.label.unwrap());
+        }
+    _   => { /* TODO must end in a Ret? */ }
+    }
+}
+
+/**
+ * The Values compiled code uses to dispatch exceptions to handlers.
+ */
+struct ExceptionState {
+    /// The address of the innermost installed handler, or -1.
+    active: ValueId,
+
+    /// The value of the exception being handled.
+    value: ValueId
+}
+
+/**
+ * Creates the exception state for a function, if it installs any handlers.
+ *
+ * # Arguments
+ *
+ * * function - The function being compiled.
+ * * backend  - The code generator.
+ */
+fn reserve_exception_state(function: &[Opcode], backend: &mut Backend) -> Option<ExceptionState> {
+    let has_handlers = do function.iter().any |opcode| {
+        match *opcode {
+            TryBegin(_) => true,
+            _ => false
+        }
+    };
+
+    if has_handlers {
+        Some(ExceptionState {
+            active: backend.create_local(IntType),
+            value: backend.create_local(IntType)
+        })
+    } else {
+        None
+    }
+}
+
+/**
+ * Emits a store recording the innermost installed handler.
+ *
+ * # Arguments
+ *
+ * * backend    - The code generator.
+ * * exceptions - The function's exception state.
+ * * handlers   - The installed handlers, innermost last.
+ */
+fn set_active_handler(backend: &mut Backend, exceptions: &ExceptionState, handlers: &[u32]) {
+    let active = match handlers.last_opt() {
+        Some(&handler) => handler as i32,
+        None => -1
+    };
+    let active = backend.constant_int32(active);
+    backend.store(exceptions.active, active);
+}
+
+/**
+ * Emits the function's catcher, which receives every exception thrown
+ * while it runs and branches to the active handler. Exceptions with no
+ * active handler, running out of fuel and negative thrown values
+ * propagate to the caller once the root frame is unregistered.
+ *
+ * # Arguments
+ *
+ * * backend      - The code generator.
+ * * basic_blocks - The basic block representation of the function.
+ * * handlers     - The handlers installed on entering each basic block.
+ * * exceptions   - The function's exception state, if it has handlers.
+ * * frame        - The function's GC root frame.
+ * * heap         - The VM heap.
+ */
+fn compile_catcher(backend: &mut Backend, basic_blocks: &[@mut BasicBlock], handlers: &[~[u32]],
+                   exceptions: Option<&ExceptionState>, frame: &RootFrame, heap: &mut Heap) {
+    backend.start_catcher();
+    let exception = backend.thrown_exception();
+
+    let exceptions = match exceptions {
+        Some(exceptions) => exceptions,
+        None => {
+            release_root_frame(backend, frame, heap);
+            backend.rethrow_unhandled();
+            return;
+        }
+    };
+
+    // Builtin exceptions are odd, and thrown values are shifted left by one.
+    let one = backend.constant_int32(1);
+    let thrown = backend.convert(exception, NIntType);
+    let low_bit = backend.binary(AndOp, thrown, one);
+    let is_builtin = backend.binary(EqOp, low_bit, one);
+    let shifted = backend.binary(SshrOp, thrown, one);
+    let value = backend.convert(shifted, IntType);
+    backend.store(exceptions.value, value);
+
+    // Handlers see the exception values interpret.rs gives them: the
+    // thrown value, or the error's code. Negative thrown values are
+    // reserved for errors, so they propagate like running out of fuel.
+    let catchable = backend.new_label();
+    let not_builtin = backend.new_label();
+    let propagate = backend.new_label();
+    backend.branch_if_not(is_builtin, not_builtin);
+    let out_of_fuel_code = backend.constant_int32(OUT_OF_FUEL as i32);
+    let out_of_fuel = backend.binary(EqOp, value, out_of_fuel_code);
+    backend.branch_if(out_of_fuel, propagate);
+    let overflow_base = backend.constant_int32(OVERFLOW_BASE as i32);
+    let is_overflow = backend.binary(GeqOp, value, overflow_base);
+    backend.branch_if_not(is_overflow, catchable);
+    let overflow_value = backend.constant_int32(Overflow(0).exception_value().unwrap());
+    backend.store(exceptions.value, overflow_value);
+    backend.branch(catchable);
+
+    backend.set_label(not_builtin);
+    let zero = backend.constant_int32(0);
+    let is_negative = backend.binary(LtOp, value, zero);
+    backend.branch_if(is_negative, propagate);
+
+    backend.set_label(catchable);
+    for (basic_block, block_handlers) in basic_blocks.iter().zip(handlers.iter()) {
+        if basic_block.handler {
+            let next = backend.new_label();
+            let start = backend.constant_int32(basic_block.start as i32);
+            let is_active = backend.binary(EqOp, exceptions.active, start);
+            backend.branch_if_not(is_active, next);
+
+            // The handler runs under the handlers that enclose its region.
+            set_active_handler(backend, exceptions, *block_handlers);
+            backend.branch(basic_block.label.unwrap());
+            backend.set_label(next);
+        }
+    }
+
+    backend.set_label(propagate);
+    release_root_frame(backend, frame, heap);
+    backend.rethrow_unhandled();
+}
+
+/**
+ * Returns whether either operand of a binary opcode is a float.
+ *
+ * # Arguments
+ *
+ * * stack_types - The VM types of the values on the stack.
+ */
+fn has_float_operand(stack_types: &[VariableType]) -> bool {
+    stack_types.rev_iter().take(2).any(|&t| t == Float32)
+}
+
+/**
+ * Emits an integer Divide or Rem that raises DivisionByZero for a zero
+ * divisor and Overflow for the smallest integer divided by -1, as the
+ * interpreter does, rather than the backend's own exceptions.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * offset  - The offset of the opcode within its function.
+ * * stack   - The VM stack.
+ * * op      - DivOp or RemOp.
+ */
+fn checked_division(backend: &mut Backend, offset: u32, stack: &mut ~[ValueId], op: BinaryOp) {
+    // Rem truncates float operands, as the interpreter does.
+    let divisor = backend.convert(stack.pop(), IntType);
+    let dividend = backend.convert(stack.pop(), IntType);
+
+    let zero = backend.constant_int32(0);
+    let nonzero = backend.binary(NeqOp, divisor, zero);
+    emit_check(backend, nonzero, JIT_RESULT_DIVISION_BY_ZERO);
+
+    let min = backend.constant_int32(i32::min_value);
+    let minus_one = backend.constant_int32(-1);
+    let is_min = backend.binary(EqOp, dividend, min);
+    let is_minus_one = backend.binary(EqOp, divisor, minus_one);
+    let overflows = backend.binary(AndOp, is_min, is_minus_one);
+    let in_range = backend.binary(EqOp, overflows, zero);
+    emit_check(backend, in_range, error_code(Overflow(offset)));
+
+    stack.push(backend.binary(op, dividend, divisor));
+}
+
+/**
+ * Emits an AddOvf, SubOvf or MulOvf that raises Overflow with the opcode's
+ * offset, which the backends' own overflow checks cannot carry. The
+ * operation is exact on pointer-sized integers, and overflows if its
+ * result does not fit in 32 bits.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * offset  - The offset of the opcode within its function.
+ * * stack   - The VM stack.
+ * * op      - AddOp, SubOp or MulOp.
+ */
+fn checked_arithmetic(backend: &mut Backend, offset: u32, stack: &mut ~[ValueId], op: BinaryOp) {
+    let v2 = backend.convert(stack.pop(), NIntType);
+    let v1 = backend.convert(stack.pop(), NIntType);
+    let exact = backend.binary(op, v1, v2);
+    let result = backend.convert(exact, IntType);
+    let extended = backend.convert(result, NIntType);
+    let in_range = backend.binary(EqOp, extended, exact);
+    emit_check(backend, in_range, error_code(Overflow(offset)));
+    stack.push(result);
+}
+
+/**
+ * Emits a shift with the count reduced modulo 32, as the interpreter does.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * value   - The value to shift.
+ * * count   - The shift count.
+ * * op      - The shift.
+ */
+fn shift(backend: &mut Backend, value: ValueId, count: ValueId, op: BinaryOp) -> ValueId {
+    let mask = backend.constant_int32(31);
+    let count = backend.binary(AndOp, count, mask);
+    backend.binary(op, value, count)
+}
+
+/**
+ * Emits a float Min or Max whose result is NaN if either operand is NaN,
+ * which backends do not guarantee. Integer operands are compared as they are.
+ *
+ * # Arguments
+ *
+ * * backend  - The code generator.
+ * * stack    - The VM stack.
+ * * is_float - Whether the operands are floats.
+ * * op       - MinOp or MaxOp.
+ */
+fn nan_propagating(backend: &mut Backend, stack: &mut ~[ValueId], is_float: bool, op: BinaryOp) {
+    let v2 = stack.pop();
+    let v1 = stack.pop();
+    let result = backend.binary(op, v1, v2);
+    if !is_float {
+        stack.push(result);
+        return;
+    }
+
+    let merged = backend.create_local(FloatType);
+    backend.store(merged, result);
+
+    // NaN is the only value that is not equal to itself.
+    let done = backend.new_label();
+    let v1_ordered = backend.binary(EqOp, v1, v1);
+    let v2_ordered = backend.binary(EqOp, v2, v2);
+    let ordered = backend.binary(AndOp, v1_ordered, v2_ordered);
+    backend.branch_if(ordered, done);
+    let nan = backend.binary(AddOp, v1, v2);
+    backend.store(merged, nan);
+    backend.set_label(done);
+
+    stack.push(merged);
+}
+
+/**
+ * Emits code that consumes a unit of fuel, throwing OutOfFuel if
+ * there is none left.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * fuel    - The fuel counter.
+ */
+fn consume_fuel(backend: &mut Backend, fuel: *mut u32) {
+    let counter = backend.constant_pointer(fuel as *c_void);
+    let remaining = backend.load_relative(counter, 0, IntType);
+    let zero = backend.constant_int32(0);
+    let has_fuel = backend.binary(NeqOp, remaining, zero);
+    emit_check(backend, has_fuel, OUT_OF_FUEL);
+    let one = backend.constant_int32(1);
+    let remaining = backend.binary(SubOp, remaining, one);
+    backend.store_relative(counter, 0, remaining);
+}
+
+/**
+ * JIT compiles a single opcode.
+ *
+ * # Arguments
+ *
+ * * opcode      - The Opcode to compile.
+ * * offset      - The offset of the opcode within its function.
+ * * backend     - The code generator.
+ * * stack       - The VM stack.
+ * * stack_types - The VM types of the values on the stack.
+ * * locals      - The list of the function's local variable Values.
+ * * frame       - The function's GC root frame.
+ * * options     - How to compile the function.
+ * * constants   - The constant pool of the function's module.
+ * * heap        - The VM heap.
+ */
+fn compile_opcode(opcode: &Opcode,
+                  offset: u32,
+                  backend: &mut Backend,
+                  stack: &mut ~[ValueId],
+                  stack_types: &[VariableType],
+                  locals: &mut ~[ValueId],
+                  frame: &RootFrame,
+                  options: &CompileOptions,
+                  constants: &ConstantPool,
+                  heap: &mut Heap) {
+
+    // Anything that allocates may collect, so the function's
+    // references must be where the collector can see them.
+    if allocates(opcode) {
+        spill_roots(backend, frame, *locals, *stack, stack_types);
+    }
+
+    match *opcode {
+        Constf32(operand) => {
+            stack.push(backend.constant_float32(operand));
+        }
+        Consti32(operand) => {
+            stack.push(backend.constant_int32(operand));
+        }
+        ConstStr(index) => {
+            let heap_ptr = heap_pointer(backend, heap);
+            let constants_ptr = backend.constant_pointer(ptr::to_unsafe_ptr(constants) as *c_void);
+            let index = backend.constant_int32(index as i32);
+            let handle = unsafe {
+                call_helper(backend, "const_str_helper", transmute(const_str_helper),
+                            [PointerType, PointerType, IntType], [heap_ptr, constants_ptr, index])
+            };
+            stack.push(handle);
+        }
+        Concat => {
+            let v2 = stack.pop();
+            let v1 = stack.pop();
+            let handle = unsafe { string_helper(backend, heap, "concat_helper", transmute(concat_helper), [v1, v2]) };
+            stack.push(handle);
+        }
+        StrLen => {
+            let value = stack.pop();
+            let length = unsafe { string_helper(backend, heap, "strlen_helper", transmute(strlen_helper), [value]) };
+            stack.push(length);
+        }
+        StrCmp => {
+            let v2 = stack.pop();
+            let v1 = stack.pop();
+            let order = unsafe { string_helper(backend, heap, "strcmp_helper", transmute(strcmp_helper), [v1, v2]) };
+            stack.push(order);
+        }
+        IntToStr => {
+            let value = backend.convert(stack.pop(), IntType);
+            let handle = unsafe {
+                string_helper(backend, heap, "int_to_str_helper", transmute(int_to_str_helper), [value])
+            };
+            stack.push(handle);
+        }
+        FloatToStr => {
+            let value = backend.convert(stack.pop(), FloatType);
+            let heap_ptr = heap_pointer(backend, heap);
+            let handle = unsafe {
+                call_helper(backend, "float_to_str_helper", transmute(float_to_str_helper),
+                            [PointerType, FloatType], [heap_ptr, value])
+            };
+            stack.push(handle);
+        }
+        Add => binary_opcode(backend, stack, AddOp),
+        Subtract => binary_opcode(backend, stack, SubOp),
+        Multiply => binary_opcode(backend, stack, MulOp),
+        Divide => {
+            if has_float_operand(stack_types) {
+                binary_opcode(backend, stack, DivOp);
+            } else {
+                checked_division(backend, offset, stack, DivOp);
+            }
+        }
+        And => binary_opcode(backend, stack, AndOp),
+        Or => binary_opcode(backend, stack, OrOp),
+        Xor => binary_opcode(backend, stack, XorOp),
+        Eq => binary_opcode(backend, stack, EqOp),
+        Neq => binary_opcode(backend, stack, NeqOp),
+        Leq => binary_opcode(backend, stack, LeqOp),
+        Geq => binary_opcode(backend, stack, GeqOp),
+        Lt => binary_opcode(backend, stack, LtOp),
+        Gt => binary_opcode(backend, stack, GtOp),
+        Rem => checked_division(backend, offset, stack, RemOp),
+        Shl => {
+            let count = stack.pop();
+            let value = stack.pop();
+            stack.push(shift(backend, value, count, ShlOp));
+        }
+        Shr => {
+            let count = stack.pop();
+            let value = stack.pop();
+            stack.push(shift(backend, value, count, SshrOp));
+        }
+        Ushr => {
+            // A right shift is logical when the shifted value is unsigned.
+            let count = stack.pop();
+            let value = backend.convert(stack.pop(), UIntType);
+            let shifted = shift(backend, value, count, ShrOp);
+            stack.push(backend.convert(shifted, IntType));
+        }
+        Ltu => unsigned_comparison(backend, stack, LtOp),
+        Gtu => unsigned_comparison(backend, stack, GtOp),
+        Lequ => unsigned_comparison(backend, stack, LeqOp),
+        Gequ => unsigned_comparison(backend, stack, GeqOp),
+        AddOvf if options.overflow_checks => checked_arithmetic(backend, offset, stack, AddOp),
+        SubOvf if options.overflow_checks => checked_arithmetic(backend, offset, stack, SubOp),
+        MulOvf if options.overflow_checks => checked_arithmetic(backend, offset, stack, MulOp),
+        AddOvf => binary_opcode(backend, stack, AddOp),
+        SubOvf => binary_opcode(backend, stack, SubOp),
+        MulOvf => binary_opcode(backend, stack, MulOp),
+        Min => nan_propagating(backend, stack, has_float_operand(stack_types), MinOp),
+        Max => nan_propagating(backend, stack, has_float_operand(stack_types), MaxOp),
+        Pow => {
+            let v2 = backend.convert(stack.pop(), FloatType);
+            let v1 = backend.convert(stack.pop(), FloatType);
+            stack.push(backend.binary(PowOp, v1, v2));
+        }
+        Negate => unary_opcode(backend, stack, NegOp),
+        Not => unary_opcode(backend, stack, NotOp),
+        Abs => unary_opcode(backend, stack, AbsOp),
+        Sqrt => math_opcode(backend, stack, SqrtOp),
+        Floor => math_opcode(backend, stack, FloorOp),
+        Ceil => math_opcode(backend, stack, CeilOp),
+        Round => math_opcode(backend, stack, RoundOp),
+        Trunc => math_opcode(backend, stack, TruncOp),
+        Sin => math_opcode(backend, stack, SinOp),
+        Cos => math_opcode(backend, stack, CosOp),
+        Tan => math_opcode(backend, stack, TanOp),
+        Exp => math_opcode(backend, stack, ExpOp),
+        Log => math_opcode(backend, stack, LogOp),
+        Ret => {
+            let v = stack.pop();
+            release_root_frame(backend, frame, heap);
+            backend.ret(v);
+        },
+        Throw => {
+            // Shift the value clear of the bit that marks builtin exceptions.
+            let value = backend.convert(stack.pop(), NIntType);
+            let one = backend.constant_int32(1);
+            let shifted = backend.binary(ShlOp, value, one);
+            backend.throw(shifted);
+        }
+        TryBegin(_) | TryEnd => {
+            fail!("Try regions are compiled by compile_basic_block");
+        }
+        Disp => {
+            let value = stack.pop();
+            let value_type = stack_types.last_opt().map_default(Unknown, |&t| t);
+            unsafe {
+                if value_type.is_reference() {
+                    let heap_ptr = heap_pointer(backend, heap);
+                    backend.call_native("disp_ref_helper", transmute(disp_ref_helper), VoidType,
+                                        [PointerType, IntType], [heap_ptr, value]);
+                } else if value_type == Float32 {
+                    let number = backend.convert(value, FloatType);
+                    backend.call_native("disp_number_helper", transmute(disp_number_helper), VoidType,
+                                        [FloatType], [number]);
+                } else {
+                    backend.call_native("disp_int_helper", transmute(disp_int_helper), VoidType,
+                                        [IntType], [value]);
+                }
+            }
+        }
+        Store(addr) => {
+            let v = stack.pop();
+            backend.store(locals[addr], v);
+        }
+        Loadf32(addr) | Loadi32(addr) | Loadref(addr) => {
+            stack.push(backend.copy(locals[addr]));
+        }
+        NewArray(element_type) => {
+            let length = stack.pop();
+            let handle = new_array(backend, heap, element_type, length);
+            stack.push(handle);
+        }
+        ArrayLen => {
+            let handle = stack.pop();
+            let words = array_words(backend, heap, handle);
+            stack.push(backend.load_relative(words, 0, IntType));
+        }
+        ArrayLoad(element_type) => {
+            let index = stack.pop();
+            let handle = stack.pop();
+            let (elements, _) = checked_elements(backend, heap, handle, element_type.to_code(), index,
+                                                 options.bounds_checks);
+            stack.push(backend.load_elem(elements, index, scalar_type(element_type)));
+        }
+        ArrayStore(element_type) => {
+            let value = stack.pop();
+            let index = stack.pop();
+            let handle = stack.pop();
+            let (elements, _) = checked_elements(backend, heap, handle, element_type.to_code(), index,
+                                                 options.bounds_checks);
+            let converted = backend.convert(value, scalar_type(element_type));
+            backend.store_elem(elements, index, converted);
+        }
+        Alloc(field_count) => {
+            let handle = new_object(backend, heap, field_count);
+            stack.push(handle);
+        }
+        GetField(index, field_type) => {
+            let handle = stack.pop();
+            let index = backend.constant_int32(index as i32);
+            let (elements, length) = checked_elements(backend, heap, handle, BOXED_OBJECT_KIND, index,
+                                                      options.bounds_checks);
+
+            // Fields that have never been set hold zero of any type.
+            let tag_index = backend.binary(AddOp, length, index);
+            let tag = backend.load_elem(elements, tag_index, IntType);
+            let unknown = backend.constant_int32(Unknown.to_code());
+            let expected = backend.constant_int32(field_type.to_code());
+            let is_unknown = backend.binary(EqOp, tag, unknown);
+            let is_expected = backend.binary(EqOp, tag, expected);
+            let tag_matches = backend.binary(OrOp, is_unknown, is_expected);
+            emit_check(backend, tag_matches, ELEMENT_TYPE_MISMATCH);
+
+            stack.push(backend.load_elem(elements, index, scalar_type(field_type)));
+        }
+        SetField(index, field_type) => {
+            let value = stack.pop();
+            let handle = stack.pop();
+            let index = backend.constant_int32(index as i32);
+            let (elements, length) = checked_elements(backend, heap, handle, BOXED_OBJECT_KIND, index,
+                                                      options.bounds_checks);
+            let converted = backend.convert(value, scalar_type(field_type));
+            backend.store_elem(elements, index, converted);
+
+            // Record the field's type in its tag, which follows the fields.
+            let tag_index = backend.binary(AddOp, length, index);
+            let tag = backend.constant_int32(field_type.to_code());
+            backend.store_elem(elements, tag_index, tag);
+        }
+        _ => { }
+    }
+}
+
+/**
+ * Pre-creates some locals for use as the local variables in a function.
+ *
+ * * local_types - The inferred types of the function's locals.
+ * * backend     - The code generator to create locals in.
+ *
+ * Returns the list of pre-created local variable Values.
+ */
+fn reserve_locals(local_types: &[VariableType], backend: &mut Backend) -> ~[ValueId] {
+    local_types.map(|&local_type| backend.create_local(scalar_type(local_type)))
+}
+
+/**
+ * The GC root frame of a compiled function. Each invocation registers its
+ * own frame with the heap on entry and unregisters it on the way out, and
+ * before each allocation spills its live references there, so the
+ * collector can find them.
+ */
+struct RootFrame {
+    /// The local holding the address of this invocation's frame.
+    address: ValueId,
+
+    /// The locals that hold references, from the inferred local types.
+    reference_locals: ~[u32],
+
+    /// The number of words in the frame. Functions that never hold a
+    /// reference across an allocation have no frame.
+    size: uint
+}
+
+/**
+ * Emits the registration of an invocation's GC root frame with the heap.
+ *
+ * # Arguments
+ *
+ * * basic_blocks - The basic block representation of the function.
+ * * local_types  - The inferred types of the function's locals.
+ * * heap         - The VM heap.
+ * * backend      - The code generator.
+ */
+fn reserve_root_frame(basic_blocks: &[@mut BasicBlock], local_types: &[VariableType], heap: &mut Heap,
+                      backend: &mut Backend) -> RootFrame {
+    let reference_locals = reference_locals(local_types);
+
+    // Leave room for the most references held on the evaluation
+    // stack at any one allocation.
+    let mut max_temporaries = 0u;
+    for basic_block in basic_blocks.iter() {
+        let types = stack_types(&**basic_block, local_types);
+        for (opcode, stack_types) in basic_block.opcodes.iter().zip(types.iter()) {
+            if allocates(opcode) {
+                let mut temporaries = 0u;
+                for t in stack_types.iter() {
+                    if t.is_reference() {
+                        temporaries += 1;
+                    }
+                }
+                max_temporaries = max_temporaries.max(&temporaries);
+            }
+        }
+    }
+
+    let size = reference_locals.len() + max_temporaries;
+    let address = backend.create_local(PointerType);
+    if size > 0 {
+        let heap_ptr = heap_pointer(backend, heap);
+        let size_value = backend.constant_int32(size as i32);
+        let registered = unsafe {
+            backend.call_native("register_root_frame_helper", transmute(register_root_frame_helper), PointerType,
+                                [PointerType, IntType], [heap_ptr, size_value])
+        };
+        backend.store(address, registered);
+    }
+    RootFrame {
+        address: address,
+        reference_locals: reference_locals,
+        size: size
+    }
+}
+
+/**
+ * Returns whether an opcode may allocate, and so trigger a collection.
+ *
+ * # Arguments
+ *
+ * * opcode - The opcode.
+ */
+fn allocates(opcode: &Opcode) -> bool {
+    match *opcode {
+        NewArray(_) | Alloc(_) | ConstStr(_) | Concat | IntToStr | FloatToStr => true,
+        _ => false
+    }
+}
+
+/**
+ * Emits stores of every live reference into the root frame, ahead of
+ * an allocation that may trigger a collection.
+ *
+ * # Arguments
+ *
+ * * backend     - The code generator.
+ * * frame       - The function's GC root frame.
+ * * locals      - The list of the function's local variable Values.
+ * * stack       - The VM stack.
+ * * stack_types - The VM types of the values on the stack.
+ */
+fn spill_roots(backend: &mut Backend, frame: &RootFrame, locals: &[ValueId], stack: &[ValueId], stack_types: &[VariableType]) {
+    let base = frame.address;
+    let mut slot = 0u;
+
+    for &local in frame.reference_locals.iter() {
+        backend.store_relative(base, (slot * 4) as int, locals[local]);
+        slot += 1;
+    }
+    for (&value, t) in stack.iter().zip(stack_types.iter()) {
+        if t.is_reference() {
+            backend.store_relative(base, (slot * 4) as int, value);
+            slot += 1;
+        }
+    }
+
+    // Clear whatever an earlier allocation left in the remaining slots.
+    let zero = backend.constant_int32(0);
+    while slot < frame.size {
+        backend.store_relative(base, (slot * 4) as int, zero);
+        slot += 1;
+    }
+}
+
+/**
+ * Emits the unregistration of an invocation's root frame, so that its
+ * references die with it.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * frame   - The function's GC root frame.
+ * * heap    - The VM heap.
+ */
+fn release_root_frame(backend: &mut Backend, frame: &RootFrame, heap: &mut Heap) {
+    if frame.size == 0 {
+        return;
+    }
+    let heap_ptr = heap_pointer(backend, heap);
+    unsafe {
+        backend.call_native("unregister_root_frame_helper", transmute(unregister_root_frame_helper), VoidType,
+                            [PointerType, PointerType], [heap_ptr, frame.address]);
+    }
+}
+
+/**
+ * Returns the machine type used to hold values of a VM type. Heap
+ * references are held as integer handles.
+ *
+ * # Arguments
+ *
+ * * variable_type - The VM type.
+ */
+fn scalar_type(variable_type: VariableType) -> ScalarType {
+    if variable_type == Float32 { FloatType } else { IntType }
+}
+
+/**
+ * Emits a runtime check that throws a builtin exception
+ * unless a condition holds.
+ *
+ * # Arguments
+ *
+ * * backend        - The code generator.
+ * * condition      - The Value that must be non-zero.
+ * * exception_type - The exception code to throw otherwise.
+ */
+fn emit_check(backend: &mut Backend, condition: ValueId, exception_type: c_int) {
+    let ok = backend.new_label();
+    backend.branch_if(condition, ok);
+    backend.throw_builtin(exception_type);
+    backend.set_label(ok);
+}
+
+/**
+ * Returns a Value holding the address of the heap, for passing to helpers.
+ */
+fn heap_pointer(backend: &mut Backend, heap: &mut Heap) -> ValueId {
+    backend.constant_pointer(ptr::to_mut_unsafe_ptr(heap) as *c_void)
+}
+
+/**
+ * Emits an array allocation.
+ *
+ * # Arguments
+ *
+ * * backend      - The code generator.
+ * * heap         - The VM heap.
+ * * element_type - The type of each element.
+ * * length       - The Value holding the number of elements.
+ *
+ * Returns the Value holding the handle of the new array.
+ */
+fn new_array(backend: &mut Backend, heap: &mut Heap, element_type: VariableType, length: ValueId) -> ValueId {
+    let heap_ptr = heap_pointer(backend, heap);
+    let code = backend.constant_int32(element_type.to_code());
+    unsafe {
+        call_helper(backend, "new_array_helper", transmute(new_array_helper),
+                    [PointerType, IntType, IntType], [heap_ptr, code, length])
+    }
+}
+
+/**
+ * Emits a boxed object allocation.
+ *
+ * # Arguments
+ *
+ * * backend     - The code generator.
+ * * heap        - The VM heap.
+ * * field_count - The number of fields.
+ *
+ * Returns the Value holding the handle of the new object.
+ */
+fn new_object(backend: &mut Backend, heap: &mut Heap, field_count: u32) -> ValueId {
+    let heap_ptr = heap_pointer(backend, heap);
+    let count = backend.constant_int32(field_count as i32);
+    unsafe {
+        call_helper(backend, "new_object_helper", transmute(new_object_helper),
+                    [PointerType, IntType], [heap_ptr, count])
+    }
+}
+
+/**
+ * Emits a call to a string helper that takes the heap and some integer
+ * arguments and returns an integer, throwing the helper's errors.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * heap    - The VM heap.
+ * * name    - The name of the helper, for dumps.
+ * * helper  - The address of the helper.
+ * * args    - The integer Values to pass after the heap.
+ */
+fn string_helper(backend: &mut Backend, heap: &mut Heap, name: &str, helper: *c_void, args: &[ValueId]) -> ValueId {
+    let heap_ptr = heap_pointer(backend, heap);
+    let mut param_types = ~[PointerType];
+    let mut all_args = ~[heap_ptr];
+    for &arg in args.iter() {
+        param_types.push(IntType);
+        all_args.push(arg);
+    }
+    call_helper(backend, name, helper, param_types, all_args)
+}
+
+/**
+ * Emits a call to a helper that can raise an error. The helper returns
+ * its integer result zero-extended to a pointer-sized integer, or the
+ * exception for an error, which is negative, and the call throws it.
+ *
+ * # Arguments
+ *
+ * * backend     - The code generator.
+ * * name        - The name of the helper, for dumps.
+ * * helper      - The address of the helper.
+ * * param_types - The types of the helper's parameters.
+ * * args        - The Values to pass.
+ *
+ * Returns the Value holding the helper's result.
+ */
+fn call_helper(backend: &mut Backend, name: &str, helper: *c_void, param_types: &[ScalarType],
+               args: &[ValueId]) -> ValueId {
+    let result = backend.call_native(name, helper, NIntType, param_types, args);
+    let zero = backend.constant_int32(0);
+    let failed = backend.binary(LtOp, result, zero);
+    let succeeded = backend.new_label();
+    backend.branch_if_not(failed, succeeded);
+    backend.throw(result);
+    backend.set_label(succeeded);
+    backend.convert(result, IntType)
+}
+
+/**
+ * Emits a lookup of the words of an array in the heap's handle table.
+ * Null and dead handles have a null entry, and handles beyond the end of
+ * the table have none at all; both throw NullReference.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * heap    - The VM heap.
+ * * handle  - The Value holding the array handle.
+ */
+fn array_words(backend: &mut Backend, heap: &Heap, handle: ValueId) -> ValueId {
+    let unsigned_handle = backend.convert(handle, UIntType);
+    let table_length = backend.constant_int32(heap.table_length() as i32);
+    let unsigned_length = backend.convert(table_length, UIntType);
+    let in_table = backend.binary(LtOp, unsigned_handle, unsigned_length);
+    emit_check(backend, in_table, JIT_RESULT_NULL_REFERENCE);
+
+    let table = backend.constant_pointer(heap.table_address() as *c_void);
+    let words = backend.load_elem(table, handle, PointerType);
+    backend.check_null(words);
+    words
+}
+
+/**
+ * Emits a check that a live handle refers to an object of the expected
+ * kind, which throws ElementTypeMismatch otherwise.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * heap    - The VM heap.
+ * * handle  - The Value holding the handle, already looked up with array_words.
+ * * kind    - The expected kind code: an array's element type code, or BOXED_OBJECT_KIND.
+ */
+fn check_kind(backend: &mut Backend, heap: &Heap, handle: ValueId, kind: i32) {
+    let kinds = backend.constant_pointer(heap.kind_table_address() as *c_void);
+    let actual = backend.load_elem(kinds, handle, IntType);
+    let expected = backend.constant_int32(kind);
+    let matches = backend.binary(EqOp, actual, expected);
+    emit_check(backend, matches, ELEMENT_TYPE_MISMATCH);
+}
+
+/**
+ * Emits the lookup of an array or boxed object, a check of its kind and
+ * an inline bounds check of an array index or field index, in the order
+ * the interpreter makes them.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * heap    - The VM heap.
+ * * handle  - The Value holding the handle.
+ * * kind    - The expected kind code, as for check_kind.
+ * * index   - The Value holding the index.
+ * * check   - Whether to emit the bounds check. The handle and kind are checked regardless.
+ *
+ * Returns the Values holding the address of the first element and the length.
+ */
+fn checked_elements(backend: &mut Backend, heap: &Heap, handle: ValueId, kind: i32, index: ValueId,
+                    check: bool) -> (ValueId, ValueId) {
+    let words = array_words(backend, heap, handle);
+    check_kind(backend, heap, handle, kind);
+    let length = backend.load_relative(words, 0, IntType);
+
+    // Comparing as unsigned rejects negative indices too.
+    if check {
+        let unsigned_index = backend.convert(index, UIntType);
+        let unsigned_length = backend.convert(length, UIntType);
+        let in_bounds = backend.binary(LtOp, unsigned_index, unsigned_length);
+        emit_check(backend, in_bounds, JIT_RESULT_OUT_OF_BOUNDS);
+    }
+
+    (backend.add_relative(words, 4), length)
+}
+
+/**
+ * Helper function for a binary opcode.
+ *
+ * Pops 2 Values from the stack and pushes the resulting Value.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * stack   - The VM stack.
+ * * op      - The operation to apply to the 2 Values.
+ */
+fn binary_opcode(backend: &mut Backend, stack: &mut ~[ValueId], op: BinaryOp) {
+    let v2 = stack.pop();
+    let v1 = stack.pop();
+    stack.push(backend.binary(op, v1, v2));
+}
+
+/**
+ * Helper function for a unary opcode.
+ *
+ * Pops a Value from the stack and pushes the resulting Value.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * stack   - The VM stack.
+ * * op      - The operation to apply to the Value.
+ */
+fn unary_opcode(backend: &mut Backend, stack: &mut ~[ValueId], op: UnaryOp) {
+    let value = stack.pop();
+    stack.push(backend.unary(op, value));
+}
+
+/**
+ * Helper function for a float-only math intrinsic. The operand is
+ * converted to a 32-bit float first, so that it computes in the same
+ * precision as the interpreter.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * stack   - The VM stack.
+ * * op      - The intrinsic.
+ */
+fn math_opcode(backend: &mut Backend, stack: &mut ~[ValueId], op: UnaryOp) {
+    let value = backend.convert(stack.pop(), FloatType);
+    stack.push(backend.unary(op, value));
+}
+
+/**
+ * Helper function for an unsigned comparison opcode, which reinterprets
+ * both integer operands as unsigned.
+ *
+ * # Arguments
+ *
+ * * backend - The code generator.
+ * * stack   - The VM stack.
+ * * op      - The comparison.
+ */
+fn unsigned_comparison(backend: &mut Backend, stack: &mut ~[ValueId], op: BinaryOp) {
+    let v2 = backend.convert(stack.pop(), UIntType);
+    let v1 = backend.convert(stack.pop(), UIntType);
+    stack.push(backend.binary(op, v1, v2));
+}
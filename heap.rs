@@ -0,0 +1,617 @@
+use variable_type::*;
+use vm_error::*;
+use std::ptr;
+use std::vec;
+
+/// A handle to an object on the VM heap. Handle 0 is the null reference.
+pub type HeapRef = u32;
+
+/**
+ * Limits on the size of a Heap.
+ */
+#[deriving(Clone)]
+pub struct HeapLimits {
+    /// The maximum number of objects that may be live at once.
+    max_objects: uint,
+
+    /// The maximum number of bytes of object data that may be live at once.
+    max_bytes: uint,
+
+    /// A collection is triggered once this many bytes have been
+    /// allocated since the previous collection.
+    collection_interval: uint
+}
+
+impl HeapLimits {
+    /**
+     * Returns the limits used by Heap::new.
+     */
+    pub fn default() -> HeapLimits {
+        HeapLimits {
+            max_objects: 65536,
+            max_bytes: 64 * 1024 * 1024,
+            collection_interval: 1024 * 1024
+        }
+    }
+}
+
+/**
+ * Garbage collection statistics.
+ */
+#[deriving(Clone)]
+pub struct HeapStats {
+    /// The number of collections run.
+    collections: uint,
+    objects_allocated: uint,
+    objects_freed: uint,
+    bytes_allocated: uint,
+    bytes_freed: uint,
+    live_objects: uint,
+    live_bytes: uint
+}
+
+/// The code of a boxed object in the kind table. Arrays are recorded
+/// with the code of their element type, which is always smaller.
+pub static BOXED_OBJECT_KIND: i32 = 16;
+
+/// The code of a string in the kind table.
+pub static STRING_KIND: i32 = 17;
+
+/**
+ * The kinds of object on the heap.
+ */
+enum ObjectKind {
+    /// An array whose elements all have the given type.
+    ArrayObject(VariableType),
+
+    /// A boxed object whose fields each record their own type.
+    BoxedObject,
+
+    /// An immutable string. Its only word is zero, so JIT code that
+    /// mistakes it for an array sees an empty one.
+    StringObject(~str)
+}
+
+/**
+ * An object allocated on the VM heap.
+ *
+ * The words of an object are laid out as [length, slots...] so that JIT
+ * code can read the length and slots directly. Boxed objects are followed
+ * by a further [tags...], one VariableType code per field.
+ */
+struct HeapObject {
+    kind: ObjectKind,
+    words: ~[u32]
+}
+
+impl ObjectKind {
+    /**
+     * Returns the code recorded for the kind in the kind table.
+     */
+    fn code(&self) -> i32 {
+        match *self {
+            ArrayObject(element_type) => element_type.to_code(),
+            BoxedObject => BOXED_OBJECT_KIND,
+            StringObject(_) => STRING_KIND
+        }
+    }
+}
+
+impl HeapObject {
+    fn length(&self) -> u32 {
+        self.words[0]
+    }
+
+    fn size(&self) -> uint {
+        match self.kind {
+            StringObject(ref value) => self.words.len() * 4 + value.len(),
+            _ => self.words.len() * 4
+        }
+    }
+
+    /**
+     * Calls a function with each non-null reference held by the object.
+     */
+    fn each_reference(&self, f: &fn(handle: HeapRef)) {
+        let length = self.length() as uint;
+        match self.kind {
+            ArrayObject(element_type) if element_type.is_reference() => {
+                for index in range(1, length + 1) {
+                    if self.words[index] != 0 {
+                        f(self.words[index]);
+                    }
+                }
+            }
+            ArrayObject(_) | StringObject(_) => { }
+            BoxedObject => {
+                for index in range(1, length + 1) {
+                    let tag = VariableType::from_code(self.words[length + index] as i32);
+                    if tag.is_reference() && self.words[index] != 0 {
+                        f(self.words[index]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/**
+ * The VM heap. Objects are addressed by handle, shared between the
+ * interpreter and JIT code, and reclaimed by a mark-and-sweep collector.
+ *
+ * The collector only runs when the owner of the heap asks it to, passing
+ * in its roots. Running compiled functions additionally register root frames,
+ * which they fill with their live references before each allocation.
+ */
+pub struct Heap {
+    /// The live objects, indexed by handle.
+    priv objects: ~[Option<~HeapObject>],
+
+    /// Pointers to the words of each live object, indexed by handle. This is
+    /// allocated once up front so that JIT code can embed its address.
+    priv table: ~[*mut u32],
+
+    /// The kind code of each live object, indexed by handle, so that JIT
+    /// code can check what a handle refers to. Allocated up front too.
+    priv kinds: ~[i32],
+
+    /// Handles that are not in use.
+    priv free_handles: ~[HeapRef],
+
+    /// Root frames registered by compiled functions.
+    priv root_frames: ~[~[u32]],
+
+    /// Bytes allocated since the previous collection.
+    priv bytes_since_collection: uint,
+
+    priv limits: HeapLimits,
+    priv stats: HeapStats
+}
+
+impl Heap {
+    /**
+     * Creates a new Heap with the default limits.
+     */
+    pub fn new() -> ~Heap {
+        Heap::with_limits(HeapLimits::default())
+    }
+
+    /**
+     * Creates a new Heap.
+     *
+     * # Arguments
+     *
+     * * limits - Limits on the size of the heap.
+     */
+    pub fn with_limits(limits: HeapLimits) -> ~Heap {
+        let max_objects = limits.max_objects;
+
+        // Handle 0 is never handed out; free handles are popped lowest first.
+        let free_handles = do vec::from_fn(max_objects - 1) |index| {
+            (max_objects - 1 - index) as HeapRef
+        };
+
+        ~Heap {
+            objects: vec::from_fn(max_objects, |_| None),
+            table: vec::from_elem(max_objects, ptr::mut_null()),
+            kinds: vec::from_elem(max_objects, 0i32),
+            free_handles: free_handles,
+            root_frames: ~[],
+            bytes_since_collection: 0,
+            limits: limits,
+            stats: HeapStats {
+                collections: 0,
+                objects_allocated: 0,
+                objects_freed: 0,
+                bytes_allocated: 0,
+                bytes_freed: 0,
+                live_objects: 0,
+                live_bytes: 0
+            }
+        }
+    }
+
+    /**
+     * Returns the garbage collection statistics.
+     */
+    pub fn stats(&self) -> HeapStats {
+        self.stats.clone()
+    }
+
+    /**
+     * Returns the number of bytes an array of the given length occupies.
+     */
+    pub fn array_size(length: i32) -> uint {
+        (length.max(&0) as uint + 1) * 4
+    }
+
+    /**
+     * Returns the number of bytes a string occupies.
+     */
+    pub fn string_size(value: &str) -> uint {
+        4 + value.len()
+    }
+
+    /**
+     * Returns the number of bytes a boxed object with the given number of fields occupies.
+     */
+    pub fn object_size(field_count: u32) -> uint {
+        (field_count as uint * 2 + 1) * 4
+    }
+
+    /**
+     * Returns whether allocating an object of the given size should be
+     * preceded by a collection.
+     */
+    pub fn wants_collection(&self, size: uint) -> bool {
+        self.free_handles.is_empty() ||
+            self.bytes_since_collection + size > self.limits.collection_interval ||
+            self.stats.live_bytes + size > self.limits.max_bytes
+    }
+
+    /**
+     * Registers a root frame for an invocation of a compiled function.
+     *
+     * # Arguments
+     *
+     * * size - The number of references the frame can hold.
+     *
+     * Returns the address of the frame's words, which live until the
+     * frame is unregistered.
+     */
+    pub fn register_root_frame(&mut self, size: uint) -> *mut u32 {
+        let mut frame = vec::from_elem(size.max(&1), 0u32);
+        let address = vec::raw::to_mut_ptr(frame);
+        self.root_frames.push(frame);
+        address
+    }
+
+    /**
+     * Unregisters a root frame once the invocation that registered it
+     * returns or unwinds. Frames are normally unregistered innermost first.
+     *
+     * # Arguments
+     *
+     * * address - The address register_root_frame returned for the frame.
+     */
+    pub fn unregister_root_frame(&mut self, address: *mut u32) {
+        let index = do self.root_frames.rposition |frame| {
+            vec::raw::to_ptr(*frame) == address as *u32
+        };
+        match index {
+            Some(index) => { self.root_frames.remove(index); }
+            None => fail!("Unregistering a root frame that was never registered")
+        }
+    }
+
+    /**
+     * Runs a collection, freeing every object not reachable from the given
+     * roots or from a registered root frame.
+     *
+     * # Arguments
+     *
+     * * roots - Handles held by the caller. Null handles are ignored.
+     */
+    pub fn collect(&mut self, roots: &[HeapRef]) {
+        let mut marked = vec::from_elem(self.objects.len(), false);
+        let mut pending: ~[HeapRef] = ~[];
+
+        for &root in roots.iter() {
+            pending.push(root);
+        }
+        for frame in self.root_frames.iter() {
+            for &root in frame.iter() {
+                pending.push(root);
+            }
+        }
+
+        // Mark.
+        while !pending.is_empty() {
+            let handle = pending.pop();
+            if handle == 0 || handle as uint >= self.objects.len() || marked[handle] {
+                loop;
+            }
+            match self.objects[handle] {
+                Some(ref object) => {
+                    marked[handle] = true;
+                    do object.each_reference |child| {
+                        pending.push(child);
+                    }
+                }
+                None => { }
+            }
+        }
+
+        // Sweep.
+        for handle in range(1, self.objects.len()) {
+            if marked[handle] {
+                loop;
+            }
+            match self.objects[handle].take() {
+                Some(object) => {
+                    self.table[handle] = ptr::mut_null();
+                    self.free_handles.push(handle as HeapRef);
+                    self.stats.objects_freed += 1;
+                    self.stats.bytes_freed += object.size();
+                    self.stats.live_objects -= 1;
+                    self.stats.live_bytes -= object.size();
+                }
+                None => { }
+            }
+        }
+
+        self.bytes_since_collection = 0;
+        self.stats.collections += 1;
+    }
+
+    /**
+     * Allocates a zero-filled array.
+     *
+     * # Arguments
+     *
+     * * element_type - The type of each element.
+     * * length       - The number of elements.
+     *
+     * Returns the handle of the new array.
+     */
+    pub fn new_array(&mut self, element_type: VariableType, length: i32) -> Result<HeapRef, VMError> {
+        if length < 0 {
+            return Err(IndexOutOfBounds);
+        }
+        match self.check_limits(Heap::array_size(length)) {
+            Err(error) => return Err(error),
+            _ => { }
+        }
+
+        let mut words = vec::from_elem(length as uint + 1, 0u32);
+        words[0] = length as u32;
+        self.insert(~HeapObject { kind: ArrayObject(element_type), words: words })
+    }
+
+    /**
+     * Allocates a boxed object with zero-filled fields of unknown type.
+     *
+     * # Arguments
+     *
+     * * field_count - The number of fields.
+     *
+     * Returns the handle of the new object.
+     */
+    pub fn new_object(&mut self, field_count: u32) -> Result<HeapRef, VMError> {
+        match self.check_limits(Heap::object_size(field_count)) {
+            Err(error) => return Err(error),
+            _ => { }
+        }
+
+        let mut words = vec::from_elem(field_count as uint * 2 + 1, 0u32);
+        words[0] = field_count;
+        self.insert(~HeapObject { kind: BoxedObject, words: words })
+    }
+
+    /**
+     * Allocates a string.
+     *
+     * # Arguments
+     *
+     * * value - The contents of the string.
+     *
+     * Returns the handle of the new string.
+     */
+    pub fn new_string(&mut self, value: ~str) -> Result<HeapRef, VMError> {
+        let words = ~[0u32];
+        self.insert(~HeapObject { kind: StringObject(value), words: words })
+    }
+
+    /**
+     * Returns a copy of the contents of a string.
+     */
+    pub fn get_string(&self, handle: HeapRef) -> Result<~str, VMError> {
+        do self.with_object(handle) |object| {
+            match object.kind {
+                StringObject(ref value) => Ok(value.clone()),
+                _ => Err(ElementTypeMismatch)
+            }
+        }
+    }
+
+    /**
+     * Returns the length of an array, or the field count of a boxed object.
+     */
+    pub fn array_length(&self, handle: HeapRef) -> Result<u32, VMError> {
+        do self.with_object(handle) |object| {
+            Ok(object.length())
+        }
+    }
+
+    /**
+     * Returns the element type of an array.
+     */
+    pub fn element_type(&self, handle: HeapRef) -> Result<VariableType, VMError> {
+        do self.with_object(handle) |object| {
+            match object.kind {
+                ArrayObject(element_type) => Ok(element_type),
+                _ => Err(ElementTypeMismatch)
+            }
+        }
+    }
+
+    /**
+     * Loads the raw bits of an array element.
+     *
+     * # Arguments
+     *
+     * * handle - The array.
+     * * index  - The index of the element, checked against the array bounds.
+     */
+    pub fn array_load(&self, handle: HeapRef, index: i32) -> Result<u32, VMError> {
+        do self.with_object(handle) |object| {
+            if index < 0 || index as u32 >= object.length() {
+                Err(IndexOutOfBounds)
+            } else {
+                Ok(object.words[index + 1])
+            }
+        }
+    }
+
+    /**
+     * Stores the raw bits of an array element.
+     *
+     * # Arguments
+     *
+     * * handle - The array.
+     * * index  - The index of the element, checked against the array bounds.
+     * * word   - The bits to store.
+     */
+    pub fn array_store(&mut self, handle: HeapRef, index: i32, word: u32) -> Result<(), VMError> {
+        do self.with_object_mut(handle) |object| {
+            if index < 0 || index as u32 >= object.length() {
+                Err(IndexOutOfBounds)
+            } else {
+                object.words[index + 1] = word;
+                Ok(())
+            }
+        }
+    }
+
+    /**
+     * Loads the raw bits of a field of a boxed object.
+     *
+     * # Arguments
+     *
+     * * handle     - The object.
+     * * index      - The index of the field.
+     * * field_type - The type the caller expects the field to hold.
+     */
+    pub fn get_field(&self, handle: HeapRef, index: u32, field_type: VariableType) -> Result<u32, VMError> {
+        do self.with_object(handle) |object| {
+            let length = object.length();
+            match object.kind {
+                BoxedObject if index < length => {
+                    // Fields that have never been set hold zero of any type.
+                    let tag = VariableType::from_code(object.words[length + index + 1] as i32);
+                    if tag != Unknown && tag != field_type {
+                        Err(ElementTypeMismatch)
+                    } else {
+                        Ok(object.words[index + 1])
+                    }
+                }
+                BoxedObject => Err(IndexOutOfBounds),
+                _ => Err(ElementTypeMismatch)
+            }
+        }
+    }
+
+    /**
+     * Stores the raw bits of a field of a boxed object.
+     *
+     * # Arguments
+     *
+     * * handle     - The object.
+     * * index      - The index of the field.
+     * * word       - The bits to store.
+     * * field_type - The type of the stored value.
+     */
+    pub fn set_field(&mut self, handle: HeapRef, index: u32, word: u32, field_type: VariableType) -> Result<(), VMError> {
+        do self.with_object_mut(handle) |object| {
+            let length = object.length();
+            match object.kind {
+                BoxedObject if index < length => {
+                    object.words[index + 1] = word;
+                    object.words[length + index + 1] = field_type.to_code() as u32;
+                    Ok(())
+                }
+                BoxedObject => Err(IndexOutOfBounds),
+                _ => Err(ElementTypeMismatch)
+            }
+        }
+    }
+
+    /**
+     * Returns the address of the handle table, for use by JIT code. Entry
+     * N points to the words of the object with handle N, or is null.
+     */
+    pub fn table_address(&self) -> *mut *mut u32 {
+        vec::raw::to_ptr(self.table) as *mut *mut u32
+    }
+
+    /**
+     * Returns the number of entries in the handle table and the kind
+     * table. Handles at or beyond it are never valid.
+     */
+    pub fn table_length(&self) -> uint {
+        self.table.len()
+    }
+
+    /**
+     * Returns the address of the kind table, for use by JIT code. Entry N
+     * holds the element type code of the array with handle N, or
+     * BOXED_OBJECT_KIND or STRING_KIND. Entries for dead handles are stale,
+     * so JIT code checks the handle table first.
+     */
+    pub fn kind_table_address(&self) -> *i32 {
+        vec::raw::to_ptr(self.kinds)
+    }
+
+    /**
+     * Returns OutOfMemory if an object of the given size would exceed the
+     * heap limits. Allocations check this before building the object, so
+     * that an absurd length fails cleanly rather than aborting the process.
+     */
+    fn check_limits(&self, size: uint) -> Result<(), VMError> {
+        if self.free_handles.is_empty() || size > self.limits.max_bytes - self.stats.live_bytes {
+            Err(OutOfMemory)
+        } else {
+            Ok(())
+        }
+    }
+
+    /**
+     * Gives a new object a handle, enforcing the heap limits.
+     */
+    fn insert(&mut self, object: ~HeapObject) -> Result<HeapRef, VMError> {
+        let size = object.size();
+        match self.check_limits(size) {
+            Err(error) => return Err(error),
+            _ => { }
+        }
+
+        let handle = self.free_handles.pop();
+        let mut object = object;
+        self.table[handle] = vec::raw::to_mut_ptr(object.words);
+        self.kinds[handle] = object.kind.code();
+        self.objects[handle] = Some(object);
+
+        self.bytes_since_collection += size;
+        self.stats.objects_allocated += 1;
+        self.stats.bytes_allocated += size;
+        self.stats.live_objects += 1;
+        self.stats.live_bytes += size;
+        Ok(handle)
+    }
+
+    /**
+     * Calls a function with the object for a handle.
+     */
+    fn with_object<T>(&self, handle: HeapRef, f: &fn(object: &HeapObject) -> Result<T, VMError>) -> Result<T, VMError> {
+        if handle as uint >= self.objects.len() {
+            return Err(NullReference);
+        }
+        match self.objects[handle] {
+            Some(ref object) => f(&**object),
+            None => Err(NullReference)
+        }
+    }
+
+    /**
+     * Calls a function with the object for a handle, allowing it to be modified.
+     */
+    fn with_object_mut<T>(&mut self, handle: HeapRef, f: &fn(object: &mut HeapObject) -> Result<T, VMError>) -> Result<T, VMError> {
+        if handle as uint >= self.objects.len() {
+            return Err(NullReference);
+        }
+        match self.objects[handle] {
+            Some(ref mut object) => f(&mut **object),
+            None => Err(NullReference)
+        }
+    }
+}
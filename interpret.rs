@@ -1,5 +1,140 @@
 use opcode::*;
 use analysis::*;
+use heap::*;
+use variable_type::*;
+use vm_error::*;
+use constant_pool::*;
+use std::cast::transmute;
+use std::num::{CheckedAdd, CheckedSub, CheckedMul};
+use std::i32;
+
+/**
+ * A value on the interpreter's runtime stack.
+ *
+ * Both execution engines follow the same numeric semantics:
+ *
+ * * Integers are 32-bit two's complement. Add, Subtract, Multiply, Negate
+ *   and Abs wrap around; AddOvf, SubOvf and MulOvf raise Overflow instead.
+ * * Integer Divide and Rem truncate towards zero, so the remainder has the
+ *   sign of the dividend. A zero divisor raises DivisionByZero, and the
+ *   smallest integer divided by -1 raises Overflow.
+ * * Shift counts are taken modulo 32.
+ * * Floats are IEEE 754 single precision. Division by zero yields an
+ *   infinity or NaN. Comparisons involving NaN are false, except Neq,
+ *   which is true, and Min and Max yield NaN if either operand is NaN.
+ * * If one operand of a binary opcode is a float, the other is converted
+ *   to a float first.
+ * * Comparisons yield the integer 1 or 0, and Iftrue branches on any
+ *   value other than zero, including NaN.
+ */
+#[deriving(Clone)]
+#[deriving(Eq)]
+pub enum StackValue {
+    /// A 32-bit integer.
+    Int(i32),
+    /// A 32-bit float.
+    Float(f32),
+    /// A handle to an object on the heap.
+    Reference(HeapRef)
+}
+
+impl StackValue {
+    /**
+     * Returns the value as an integer, truncating a float, and
+     * failing if it is a reference.
+     */
+    pub fn to_i32(&self) -> i32 {
+        match *self {
+            Int(value) => value,
+            Float(value) => value as i32,
+            Reference(_) => fail!("Expected a number but found a reference")
+        }
+    }
+
+    /**
+     * Returns the value as a float, converting an integer, and
+     * failing if it is a reference.
+     */
+    pub fn to_f32(&self) -> f32 {
+        match *self {
+            Int(value) => value as f32,
+            Float(value) => value,
+            Reference(_) => fail!("Expected a number but found a reference")
+        }
+    }
+
+    /**
+     * Returns whether the value is a float.
+     */
+    pub fn is_float(&self) -> bool {
+        match *self {
+            Float(_) => true,
+            _ => false
+        }
+    }
+
+    /**
+     * Returns whether the value makes an Iftrue jump: it is non-zero.
+     */
+    pub fn is_true(&self) -> bool {
+        match *self {
+            Int(value) => value != 0,
+            value => value.to_f32() != 0f32
+        }
+    }
+
+    /**
+     * Returns the value as a heap reference, failing if it is a number.
+     */
+    pub fn to_ref(&self) -> HeapRef {
+        match *self {
+            Reference(handle) => handle,
+            _ => fail!("Expected a reference but found a number")
+        }
+    }
+
+    /**
+     * Converts the value to the raw bits stored in an array element
+     * or passed to an on-stack replacement entry.
+     *
+     * # Arguments
+     *
+     * * element_type - The element type of the array.
+     */
+    pub fn to_word(&self, element_type: VariableType) -> u32 {
+        match element_type {
+            Float32 => unsafe { transmute(self.to_f32()) },
+            Int32 => self.to_i32() as u32,
+            _ => self.to_ref()
+        }
+    }
+
+    /**
+     * Converts the raw bits of an array element to a StackValue.
+     *
+     * # Arguments
+     *
+     * * word         - The raw bits.
+     * * element_type - The element type of the array.
+     */
+    pub fn from_word(word: u32, element_type: VariableType) -> StackValue {
+        match element_type {
+            Float32 => Float(unsafe { transmute(word) }),
+            Int32 => Int(word as i32),
+            _ => Reference(word)
+        }
+    }
+}
+
+impl ToStr for StackValue {
+    fn to_str(&self) -> ~str {
+        match *self {
+            Int(value) => value.to_str(),
+            Float(value) => fmt!("%?", value),
+            Reference(handle) => fmt!("ref %u", handle as uint)
+        }
+    }
+}
 
 /**
  * Represents the runtime environment of the VM.
@@ -10,7 +145,71 @@ pub struct Environment {
     /// Instruction pointer.
     ip: u32,
     /// Points just past the last instruction.
-    end_ip: u32
+    end_ip: u32,
+    /// The number of backward jumps taken so far.
+    back_edges: uint,
+    /// The value popped by Ret, once the function has returned.
+    return_value: Option<StackValue>,
+    /// The number of instructions left to run, or None for no limit.
+    fuel: Option<uint>,
+    /// The addresses of the installed exception handlers, innermost last.
+    handlers: ~[u32]
+}
+
+impl Environment {
+    /**
+     * Creates the environment for a call to a function, and
+     * allocates room on the stack for its locals.
+     *
+     * # Arguments
+     *
+     * * function - The function being called.
+     * * stack    - The VM runtime stack.
+     */
+    pub fn new(function: &[Opcode], stack: &mut ~[StackValue]) -> Environment {
+        let local_count = local_count(function);
+        stack.grow(local_count as uint, &Int(0));
+
+        Environment {
+            bp: stack.len() as u32,
+            ip: 0,
+            end_ip: function.len() as u32,
+            back_edges: 0,
+            return_value: None,
+            fuel: None,
+            handlers: ~[]
+        }
+    }
+
+    /**
+     * Adds to the number of instructions the function may run.
+     *
+     * # Arguments
+     *
+     * * fuel - The number of instructions to add.
+     */
+    pub fn refuel(&mut self, fuel: uint) {
+        self.fuel = match self.fuel {
+            Some(remaining) => Some(remaining + fuel),
+            None => Some(fuel)
+        };
+    }
+
+    /**
+     * Uses up the fuel for one instruction.
+     *
+     * Returns OutOfFuel if there is none left.
+     */
+    pub fn consume_fuel(&mut self) -> Result<(), VMError> {
+        match self.fuel {
+            Some(0) => Err(OutOfFuel),
+            Some(remaining) => {
+                self.fuel = Some(remaining - 1);
+                Ok(())
+            }
+            None => Ok(())
+        }
+    }
 }
 
 /**
@@ -18,91 +217,406 @@ pub struct Environment {
  *
  * # Arguments
  *
- * * function - The function to interpret.
+ * * function  - The function to interpret.
+ * * constants - The constant pool of the function's module.
+ * * heap      - The heap on which the function allocates objects.
+ *
+ * Returns the value the function returned, if any.
  */
-pub fn interpret(function: &[Opcode]) {
+pub fn interpret(function: &[Opcode], constants: &ConstantPool, heap: &mut Heap) -> Result<Option<StackValue>, VMError> {
     let stack = &mut ~[];
-    let environment = &mut Environment { bp: 0, ip: 0, end_ip: function.len() as u32 };
+    let environment = &mut Environment::new(function, stack);
+    run(function, stack, environment, constants, heap)
+}
+
+/**
+ * Interprets a function, running at most a given number of instructions.
+ *
+ * # Arguments
+ *
+ * * function  - The function to interpret.
+ * * constants - The constant pool of the function's module.
+ * * heap      - The heap on which the function allocates objects.
+ * * fuel      - The number of instructions the function may run.
+ *
+ * Returns the value the function returned, if any, or OutOfFuel.
+ */
+pub fn interpret_with_fuel(function: &[Opcode], constants: &ConstantPool, heap: &mut Heap,
+                           fuel: uint) -> Result<Option<StackValue>, VMError> {
+    let stack = &mut ~[];
+    let environment = &mut Environment::new(function, stack);
+    environment.refuel(fuel);
+    run(function, stack, environment, constants, heap)
+}
 
-    // Allocate room on the stack for locals.
-    let local_count = local_count(function);
-    environment.bp += local_count;
-    stack.grow(local_count as uint, &0f32);
+/**
+ * Interprets a function from the current instruction pointer until it returns.
+ *
+ * If the environment runs out of fuel, the instruction pointer is left at
+ * the instruction that was not run, so the call can be resumed by refueling
+ * the environment and calling run again with the same stack.
+ *
+ * # Arguments
+ *
+ * * function    - The function to interpret.
+ * * stack       - The VM runtime stack.
+ * * environment - The current runtime environment state of the VM.
+ * * constants   - The constant pool of the function's module.
+ * * heap        - The heap on which the function allocates objects.
+ *
+ * Returns the value the function returned, if any.
+ */
+pub fn run(function: &[Opcode],
+           stack: &mut ~[StackValue],
+           environment: &mut Environment,
+           constants: &ConstantPool,
+           heap: &mut Heap) -> Result<Option<StackValue>, VMError> {
 
     while (environment.ip as uint) < function.len() {
-        environment.ip = interpret_opcode(&function[environment.ip], stack, environment);
+        match environment.consume_fuel() {
+            Ok(()) => { }
+            Err(error) => return Err(error)
+        }
+        match interpret_opcode(&function[environment.ip], stack, environment, constants, heap) {
+            Ok(ip) => {
+                if ip <= environment.ip {
+                    environment.back_edges += 1;
+                }
+                environment.ip = ip;
+            }
+            Err(error) => return Err(error)
+        }
     }
+
+    Ok(environment.return_value)
 }
 
 /**
- * Interprets a single opcode.
+ * Observes the interpreter as it runs a function.
+ */
+pub trait Monitor {
+    /**
+     * Called before each opcode is interpreted.
+     *
+     * # Arguments
+     *
+     * * opcode      - The opcode about to be interpreted.
+     * * stack       - The VM runtime stack.
+     * * environment - The current runtime environment state of the VM.
+     */
+    fn before_opcode(&mut self, _opcode: &Opcode, _stack: &[StackValue], _environment: &Environment) { }
+
+    /**
+     * Called after each opcode is interpreted successfully.
+     *
+     * # Arguments
+     *
+     * * opcode      - The opcode that was interpreted.
+     * * stack       - The VM runtime stack.
+     * * environment - The runtime environment, whose ip is still that of the opcode.
+     * * next_ip     - The next value of the instruction pointer.
+     */
+    fn after_opcode(&mut self, _opcode: &Opcode, _stack: &[StackValue], _environment: &Environment, _next_ip: u32) { }
+}
+
+/**
+ * Interprets a function from the current instruction pointer until it
+ * returns, reporting each opcode to a Monitor.
+ *
+ * # Arguments
+ *
+ * * function    - The function to interpret.
+ * * stack       - The VM runtime stack.
+ * * environment - The current runtime environment state of the VM.
+ * * constants   - The constant pool of the function's module.
+ * * heap        - The heap on which the function allocates objects.
+ * * monitor     - The Monitor to report to.
+ *
+ * Returns the value the function returned, if any.
+ */
+pub fn run_monitored(function: &[Opcode],
+                     stack: &mut ~[StackValue],
+                     environment: &mut Environment,
+                     constants: &ConstantPool,
+                     heap: &mut Heap,
+                     monitor: &mut Monitor) -> Result<Option<StackValue>, VMError> {
+
+    while (environment.ip as uint) < function.len() {
+        match environment.consume_fuel() {
+            Ok(()) => { }
+            Err(error) => return Err(error)
+        }
+        let opcode = &function[environment.ip];
+        monitor.before_opcode(opcode, *stack, environment);
+        match interpret_opcode(opcode, stack, environment, constants, heap) {
+            Ok(ip) => {
+                monitor.after_opcode(opcode, *stack, environment, ip);
+                if ip <= environment.ip {
+                    environment.back_edges += 1;
+                }
+                environment.ip = ip;
+            }
+            Err(error) => return Err(error)
+        }
+    }
+
+    Ok(environment.return_value)
+}
+
+/**
+ * Interprets a single opcode. If it raises an error that an installed
+ * handler can catch, the evaluation stack is unwound, the exception value
+ * is pushed and the handler is removed.
  *
  * # Arguments
  *
  * * opcode      - The opcode to interpret.
  * * stack       - The VM runtime stack.
  * * environment - The current runtime environment state of the VM.
+ * * constants   - The constant pool of the function's module.
+ * * heap        - The VM heap.
  *
  * Returns the next value of the instruction pointer.
  */
-fn interpret_opcode(opcode: &Opcode, stack: &mut ~[f32], environment: &mut Environment) -> u32 {
+pub fn interpret_opcode(opcode: &Opcode,
+                        stack: &mut ~[StackValue],
+                        environment: &mut Environment,
+                        constants: &ConstantPool,
+                        heap: &mut Heap) -> Result<u32, VMError> {
+    match execute_opcode(opcode, stack, environment, constants, heap) {
+        Err(error) => {
+            match error.exception_value() {
+                Some(value) if !environment.handlers.is_empty() => {
+                    stack.truncate(environment.bp as uint);
+                    stack.push(Int(value));
+                    Ok(environment.handlers.pop())
+                }
+                _ => Err(error)
+            }
+        }
+        result => result
+    }
+}
+
+/**
+ * Interprets a single opcode, without catching errors.
+ */
+fn execute_opcode(opcode: &Opcode,
+                  stack: &mut ~[StackValue],
+                  environment: &mut Environment,
+                  constants: &ConstantPool,
+                  heap: &mut Heap) -> Result<u32, VMError> {
     match *opcode {
         Constf32(operand) => {
-            stack.push(operand);
+            stack.push(Float(operand));
         }
         Consti32(operand) => {
-            stack.push(operand as f32);
+            stack.push(Int(operand));
+        }
+        ConstStr(index) => {
+            let value = constants.get_string(index).to_owned();
+            match new_string(heap, *stack, value) {
+                Ok(handle) => stack.push(Reference(handle)),
+                Err(error) => return Err(error)
+            }
+        }
+        Concat => {
+            let (s1, s2) = match pop_string_pair(stack, heap) {
+                Ok(pair) => pair,
+                Err(error) => return Err(error)
+            };
+            let mut joined = s1;
+            joined.push_str(s2);
+            match new_string(heap, *stack, joined) {
+                Ok(handle) => stack.push(Reference(handle)),
+                Err(error) => return Err(error)
+            }
+        }
+        StrLen => {
+            match heap.get_string(stack.pop().to_ref()) {
+                Ok(value) => stack.push(Int(value.char_len() as i32)),
+                Err(error) => return Err(error)
+            }
+        }
+        StrCmp => {
+            let (s1, s2) = match pop_string_pair(stack, heap) {
+                Ok(pair) => pair,
+                Err(error) => return Err(error)
+            };
+            let ordering = if s1 < s2 { -1 } else if s1 > s2 { 1 } else { 0 };
+            stack.push(Int(ordering));
+        }
+        IntToStr => {
+            let value = stack.pop().to_i32();
+            match new_string(heap, *stack, value.to_str()) {
+                Ok(handle) => stack.push(Reference(handle)),
+                Err(error) => return Err(error)
+            }
+        }
+        FloatToStr => {
+            let value = stack.pop().to_f32();
+            match new_string(heap, *stack, value.to_str()) {
+                Ok(handle) => stack.push(Reference(handle)),
+                Err(error) => return Err(error)
+            }
         }
         Add => {
-            do binary_opcode(stack) |v1, v2| { v1 + v2 };
+            arithmetic_opcode(stack, |v1, v2| v1 + v2, |v1, v2| v1 + v2);
         }
         Subtract => {
-            do binary_opcode(stack) |v1, v2| { v1 - v2 };
+            arithmetic_opcode(stack, |v1, v2| v1 - v2, |v1, v2| v1 - v2);
         }
         Multiply => {
-            do binary_opcode(stack) |v1, v2| { v1 * v2 };
+            arithmetic_opcode(stack, |v1, v2| v1 * v2, |v1, v2| v1 * v2);
         }
         Divide => {
-            do binary_opcode(stack) |v1, v2| { v1 / v2 };
+            if stack[stack.len() - 1].is_float() || stack[stack.len() - 2].is_float() {
+                arithmetic_opcode(stack, |_, _| 0, |v1, v2| v1 / v2);
+            } else {
+                return do checked_integer_opcode(stack, environment) |v1, v2| {
+                    if v2 == 0 || (v1 == i32::min_value && v2 == -1) { None } else { Some(v1 / v2) }
+                };
+            }
         }
         And => {
-            do binary_opcode(stack) |v1, v2| { ((v1 as uint) & (v2 as uint)) as f32 };
+            do integer_opcode(stack) |v1, v2| { v1 & v2 };
         }
         Or => {
-            do binary_opcode(stack) |v1, v2| { ((v1 as uint) | (v2 as uint)) as f32 };
+            do integer_opcode(stack) |v1, v2| { v1 | v2 };
         }
         Xor => {
-            do binary_opcode(stack) |v1, v2| { ((v1 as uint) ^ (v2 as uint)) as f32 };
+            do integer_opcode(stack) |v1, v2| { v1 ^ v2 };
         }
         Eq => {
-            do binary_opcode(stack) |v1, v2| { ((v1 as uint) == (v2 as uint)) as f32 };
+            comparison_opcode(stack, |v1, v2| v1 == v2, |v1, v2| v1 == v2);
         }
         Neq => {
-            do binary_opcode(stack) |v1, v2| { ((v1 as uint) != (v2 as uint)) as f32 };
+            comparison_opcode(stack, |v1, v2| v1 != v2, |v1, v2| v1 != v2);
         }
         Leq => {
-            do binary_opcode(stack) |v1, v2| { ((v1 as uint) <= (v2 as uint)) as f32 };
+            comparison_opcode(stack, |v1, v2| v1 <= v2, |v1, v2| v1 <= v2);
         }
         Geq => {
-            do binary_opcode(stack) |v1, v2| { ((v1 as uint) >= (v2 as uint)) as f32 };
+            comparison_opcode(stack, |v1, v2| v1 >= v2, |v1, v2| v1 >= v2);
         }
         Lt => {
-            do binary_opcode(stack) |v1, v2| { ((v1 as uint) < (v2 as uint)) as f32 };
+            comparison_opcode(stack, |v1, v2| v1 < v2, |v1, v2| v1 < v2);
         }
         Gt => {
-            do binary_opcode(stack) |v1, v2| { ((v1 as uint) > (v2 as uint)) as f32 };
+            comparison_opcode(stack, |v1, v2| v1 > v2, |v1, v2| v1 > v2);
+        }
+        Rem => {
+            return do checked_integer_opcode(stack, environment) |v1, v2| {
+                if v2 == 0 || (v1 == i32::min_value && v2 == -1) { None } else { Some(v1 % v2) }
+            };
+        }
+        Shl => {
+            do integer_opcode(stack) |v1, v2| { v1 << (v2 & 31) };
+        }
+        Shr => {
+            do integer_opcode(stack) |v1, v2| { v1 >> (v2 & 31) };
+        }
+        Ushr => {
+            do integer_opcode(stack) |v1, v2| { ((v1 as u32) >> ((v2 & 31) as u32)) as i32 };
+        }
+        Ltu => {
+            do integer_opcode(stack) |v1, v2| { ((v1 as u32) < (v2 as u32)) as i32 };
+        }
+        Gtu => {
+            do integer_opcode(stack) |v1, v2| { ((v1 as u32) > (v2 as u32)) as i32 };
+        }
+        Lequ => {
+            do integer_opcode(stack) |v1, v2| { ((v1 as u32) <= (v2 as u32)) as i32 };
+        }
+        Gequ => {
+            do integer_opcode(stack) |v1, v2| { ((v1 as u32) >= (v2 as u32)) as i32 };
+        }
+        AddOvf => {
+            return do checked_integer_opcode(stack, environment) |v1, v2| { v1.checked_add(&v2) };
+        }
+        SubOvf => {
+            return do checked_integer_opcode(stack, environment) |v1, v2| { v1.checked_sub(&v2) };
+        }
+        MulOvf => {
+            return do checked_integer_opcode(stack, environment) |v1, v2| { v1.checked_mul(&v2) };
+        }
+        Min => {
+            arithmetic_opcode(stack, |v1, v2| if v1 <= v2 { v1 } else { v2 }, |v1, v2| {
+                if v1.is_NaN() || v2.is_NaN() { v1 + v2 } else if v1 <= v2 { v1 } else { v2 }
+            });
+        }
+        Max => {
+            arithmetic_opcode(stack, |v1, v2| if v1 >= v2 { v1 } else { v2 }, |v1, v2| {
+                if v1.is_NaN() || v2.is_NaN() { v1 + v2 } else if v1 >= v2 { v1 } else { v2 }
+            });
+        }
+        Pow => {
+            let v1 = stack.pop().to_f32();
+            let v2 = stack.pop().to_f32();
+            stack.push(Float(v2.pow(&v1)));
         }
         Negate => {
-            do unary_opcode(stack) |value| { -(value as uint) as f32 };
+            match stack.pop() {
+                Int(value) => stack.push(Int(0 - value)),
+                value => stack.push(Float(-value.to_f32()))
+            }
         }
         Not => {
-            do unary_opcode(stack) |value| { !(value as uint) as f32 };
+            let value = stack.pop().to_i32();
+            stack.push(Int(!value));
+        }
+        Abs => {
+            match stack.pop() {
+                Int(value) => stack.push(Int(if value < 0 { 0 - value } else { value })),
+                value => stack.push(Float(value.to_f32().abs()))
+            }
+        }
+        Sqrt => {
+            do unary_opcode(stack) |value| { value.sqrt() };
+        }
+        Floor => {
+            do unary_opcode(stack) |value| { value.floor() };
+        }
+        Ceil => {
+            do unary_opcode(stack) |value| { value.ceil() };
+        }
+        Round => {
+            do unary_opcode(stack) |value| { value.round() };
+        }
+        Trunc => {
+            do unary_opcode(stack) |value| { value.trunc() };
+        }
+        Sin => {
+            do unary_opcode(stack) |value| { value.sin() };
+        }
+        Cos => {
+            do unary_opcode(stack) |value| { value.cos() };
+        }
+        Tan => {
+            do unary_opcode(stack) |value| { value.tan() };
+        }
+        Exp => {
+            do unary_opcode(stack) |value| { value.exp() };
+        }
+        Log => {
+            do unary_opcode(stack) |value| { value.ln() };
         }
         Ret => {
-            println(fmt!("Returned: %?", stack.pop()));
-            return environment.end_ip;
+            environment.return_value = Some(stack.pop());
+            return Ok(environment.end_ip);
+        }
+        Disp => {
+            // Strings are displayed by their contents.
+            let value = stack.pop();
+            let text = match value {
+                Reference(handle) => match heap.get_string(handle) {
+                    Ok(contents) => contents,
+                    Err(_) => value.to_str()
+                },
+                _ => value.to_str()
+            };
+            println(text);
         }
-        Disp => println(fmt!("%?", stack.pop())),
         Store(addr) => {
             stack[environment.bp - addr - 1] = stack.pop();
         }
@@ -112,35 +626,262 @@ fn interpret_opcode(opcode: &Opcode, stack: &mut ~[f32], environment: &mut Envir
         Loadi32(addr) => {
             stack.push(stack[environment.bp - addr - 1]);
         }
+        Loadref(addr) => {
+            stack.push(stack[environment.bp - addr - 1]);
+        }
+        NewArray(element_type) => {
+            let length = stack.pop().to_i32();
+            collect_if_needed(heap, *stack, Heap::array_size(length));
+            let handle = match heap.new_array(element_type, length) {
+                Ok(handle) => handle,
+                Err(error) => return Err(error)
+            };
+            stack.push(Reference(handle));
+        }
+        ArrayLen => {
+            let handle = stack.pop().to_ref();
+            match heap.array_length(handle) {
+                Ok(length) => stack.push(Int(length as i32)),
+                Err(error) => return Err(error)
+            }
+        }
+        ArrayLoad(element_type) => {
+            let index = stack.pop().to_i32();
+            let handle = stack.pop().to_ref();
+            match check_element_type(heap, handle, element_type) {
+                Err(error) => return Err(error),
+                _ => { }
+            }
+            match heap.array_load(handle, index) {
+                Ok(word) => stack.push(StackValue::from_word(word, element_type)),
+                Err(error) => return Err(error)
+            }
+        }
+        ArrayStore(element_type) => {
+            let value = stack.pop();
+            let index = stack.pop().to_i32();
+            let handle = stack.pop().to_ref();
+            match check_element_type(heap, handle, element_type) {
+                Err(error) => return Err(error),
+                _ => { }
+            }
+            match heap.array_store(handle, index, value.to_word(element_type)) {
+                Err(error) => return Err(error),
+                _ => { }
+            }
+        }
+        Alloc(field_count) => {
+            collect_if_needed(heap, *stack, Heap::object_size(field_count));
+            match heap.new_object(field_count) {
+                Ok(handle) => stack.push(Reference(handle)),
+                Err(error) => return Err(error)
+            }
+        }
+        GetField(index, field_type) => {
+            let handle = stack.pop().to_ref();
+            match heap.get_field(handle, index, field_type) {
+                Ok(word) => stack.push(StackValue::from_word(word, field_type)),
+                Err(error) => return Err(error)
+            }
+        }
+        SetField(index, field_type) => {
+            let value = stack.pop();
+            let handle = stack.pop().to_ref();
+            match heap.set_field(handle, index, value.to_word(field_type), field_type) {
+                Err(error) => return Err(error),
+                _ => { }
+            }
+        }
+        Throw => {
+            let value = stack.pop().to_i32();
+            return Err(Uncaught(value));
+        }
+        TryBegin(handler) => {
+            environment.handlers.push(handler);
+        }
+        TryEnd => {
+            environment.handlers.pop();
+        }
         Jmp(n) => {
-            return n;
+            return Ok(n);
         }
         Iftrue(n) => {
-            let value = stack.pop();
-            if value as bool {
-                return n;
-            } 
+            if stack.pop().is_true() {
+                return Ok(n);
+            }
         }
         Nop => { }
     }
     
-    environment.ip + 1
+    Ok(environment.ip + 1)
+}
+
+/**
+ * Runs a collection before an allocation if the heap asks for one. The
+ * roots are every reference on the stack, which includes the locals.
+ *
+ * # Arguments
+ *
+ * * heap  - The VM heap.
+ * * stack - The VM runtime stack.
+ * * size  - The size in bytes of the object about to be allocated.
+ */
+fn collect_if_needed(heap: &mut Heap, stack: &[StackValue], size: uint) {
+    if !heap.wants_collection(size) {
+        return;
+    }
+
+    let mut roots = ~[];
+    for value in stack.iter() {
+        match *value {
+            Reference(handle) => roots.push(handle),
+            _ => { }
+        }
+    }
+    heap.collect(roots);
 }
 
 /**
- * Helper function for a binary opcode that pops two values from the stack and pushes the result.
+ * Allocates a string, collecting first if the heap asks for it.
+ *
+ * # Arguments
+ *
+ * * heap  - The VM heap.
+ * * stack - The VM runtime stack, whose references are the roots.
+ * * value - The contents of the string.
+ */
+fn new_string(heap: &mut Heap, stack: &[StackValue], value: ~str) -> Result<HeapRef, VMError> {
+    collect_if_needed(heap, stack, Heap::string_size(value));
+    heap.new_string(value)
+}
+
+/**
+ * Pops the operands of a binary string opcode.
+ *
+ * # Arguments
+ *
+ * * stack - The VM runtime stack.
+ * * heap  - The VM heap.
+ *
+ * Returns the contents of the two strings, in the order they were pushed.
+ */
+fn pop_string_pair(stack: &mut ~[StackValue], heap: &Heap) -> Result<(~str, ~str), VMError> {
+    let h2 = stack.pop().to_ref();
+    let h1 = stack.pop().to_ref();
+    match (heap.get_string(h1), heap.get_string(h2)) {
+        (Ok(s1), Ok(s2)) => Ok((s1, s2)),
+        (Err(error), _) | (_, Err(error)) => Err(error)
+    }
+}
+
+/**
+ * Checks that a typed array opcode matches the element type of an array.
+ *
+ * # Arguments
+ *
+ * * heap         - The VM heap.
+ * * handle       - The array.
+ * * element_type - The element type the opcode expects.
+ */
+fn check_element_type(heap: &Heap, handle: HeapRef, element_type: VariableType) -> Result<(), VMError> {
+    match heap.element_type(handle) {
+        Ok(actual) if actual != element_type => Err(ElementTypeMismatch),
+        Ok(_) => Ok(()),
+        Err(error) => Err(error)
+    }
+}
+
+/**
+ * Helper function for an arithmetic opcode that pops two values from the
+ * stack and pushes the result, computed as floats if either is a float.
  *
  * # Arguments
  *
  * * stack          - The VM runtime stack.
- * * f              - A function that takes two values from the stack and returns a result value.
+ * * int_op         - A function that computes the result of two integers.
+ * * float_op       - A function that computes the result of two floats.
+ */
+fn arithmetic_opcode(stack: &mut ~[StackValue],
+                     int_op: &fn(v1: i32, v2: i32) -> i32,
+                     float_op: &fn(v1: f32, v2: f32) -> f32) {
+
+    let v1 = stack.pop();
+    let v2 = stack.pop();
+    match (v2, v1) {
+        (Int(v2), Int(v1)) => stack.push(Int(int_op(v2, v1))),
+        (v2, v1) => stack.push(Float(float_op(v2.to_f32(), v1.to_f32())))
+    }
+}
+
+/**
+ * Helper function for a comparison opcode that pops two values from the
+ * stack and pushes 1 if the comparison holds or 0 if not, comparing as
+ * floats if either is a float.
+ *
+ * # Arguments
+ *
+ * * stack          - The VM runtime stack.
+ * * int_op         - A function that compares two integers.
+ * * float_op       - A function that compares two floats.
  */
-fn binary_opcode(stack: &mut ~[f32],
-                 f: &fn(v1: f32, v2: f32) -> f32) {
+fn comparison_opcode(stack: &mut ~[StackValue],
+                     int_op: &fn(v1: i32, v2: i32) -> bool,
+                     float_op: &fn(v1: f32, v2: f32) -> bool) {
 
     let v1 = stack.pop();
     let v2 = stack.pop();
-    stack.push(f(v2, v1));
+    let result = match (v2, v1) {
+        (Int(v2), Int(v1)) => int_op(v2, v1),
+        (v2, v1) => float_op(v2.to_f32(), v1.to_f32())
+    };
+    stack.push(Int(result as i32));
+}
+
+/**
+ * Helper function for an integer-only opcode that pops two values from the
+ * stack and pushes the result.
+ *
+ * # Arguments
+ *
+ * * stack          - The VM runtime stack.
+ * * f              - A function that takes two values from the stack and returns a result value.
+ */
+fn integer_opcode(stack: &mut ~[StackValue],
+                  f: &fn(v1: i32, v2: i32) -> i32) {
+
+    let v1 = stack.pop().to_i32();
+    let v2 = stack.pop().to_i32();
+    stack.push(Int(f(v2, v1)));
+}
+
+/**
+ * Helper function for a checked integer opcode that pops two values from
+ * the stack and pushes the result, or raises DivisionByZero or Overflow.
+ *
+ * # Arguments
+ *
+ * * stack          - The VM runtime stack.
+ * * environment    - The current runtime environment state of the VM.
+ * * f              - A function that takes two values from the stack and
+ *                    returns the result, or None if it overflows.
+ *
+ * Returns the next value of the instruction pointer.
+ */
+fn checked_integer_opcode(stack: &mut ~[StackValue],
+                          environment: &Environment,
+                          f: &fn(v1: i32, v2: i32) -> Option<i32>) -> Result<u32, VMError> {
+
+    let v1 = stack.pop().to_i32();
+    let v2 = stack.pop().to_i32();
+    match f(v2, v1) {
+        Some(result) => {
+            stack.push(Int(result));
+            Ok(environment.ip + 1)
+        }
+        // Divide and Rem also refuse a zero divisor.
+        None if v1 == 0 => Err(DivisionByZero),
+        None => Err(Overflow(environment.ip))
+    }
 }
 
 /**
@@ -151,9 +892,9 @@ fn binary_opcode(stack: &mut ~[f32],
  * * stack          - The VM runtime stack.
  * * f              - A function that takes a value from the stack and returns a result value.
  */
-fn unary_opcode(stack: &mut ~[f32],
+fn unary_opcode(stack: &mut ~[StackValue],
                  f: &fn(value: f32) -> f32) {
 
-    let value = stack.pop();
-    stack.push(f(value));
+    let value = stack.pop().to_f32();
+    stack.push(Float(f(value)));
 }
@@ -1,226 +1,240 @@
 use opcode::*;
 use libjit::*;
+use backend::*;
+use heap::*;
+use constant_pool::*;
+use frontend::*;
 use analysis::*;
-use std::vec;
-use basic_block::*;
-use variable_type::*;
+use std::ptr;
 
 /**
  * JIT compiles a function.
+ *
+ * The CompiledFunction borrows the constant pool and heap as well as the
+ * context, since the compiled code refers to them.
  * 
  * # Arguments
  *
  * * function - The function to compile.
+ * * options - How to compile the function.
+ * * constants - The constant pool of the function's module.
  * * context - The JIT context within which to compile the function.
+ * * heap - The heap on which the compiled code allocates objects.
+ *
+ * Returns the compiled function, or why the function was rejected.
  */
-pub fn compile(function: &[Opcode], context: &Context) -> ~Function {
-    context.build_start();
-
-    // TODO: at the moment, functions take no arguments and return a single float.
-    let return_type = Types::get_float32();
-    let params: &[&Type] = &[];
-    let signature = Type::create_signature(CDECL, return_type, params);
-
-    let jit_function = context.create_function(signature);
-
-    // Convert stream of opcodes to basic block representation.
-    let basic_blocks = get_basic_blocks(function);
-
-    // Pre-create Values for each local variable.
-    let mut locals = reserve_locals(function, basic_blocks, jit_function);
-
-    for basic_block in basic_blocks.iter() {
-        compile_basic_block(*basic_block, jit_function, &mut locals);
-    }
-
-    jit_function.compile();
-    context.build_end();
-
-    jit_function
+pub fn compile<'a>(function: &[Opcode], options: &CompileOptions, constants: &'a ConstantPool, context: &'a Context,
+                   heap: &'a mut Heap) -> Result<~CompiledFunction<'a>, VerifyError> {
+    compile_with_entry(function, None, None, options, constants, context, heap)
 }
 
 /**
- * JIT compiles a single basic block.
- * 
+ * JIT compiles a function that consumes a unit of fuel on every backward
+ * branch, and fails with OutOfFuel once there is none left.
+ *
+ * Compiled code cannot be resumed after running out of fuel; the counter
+ * can be topped up between calls with FueledFunction::refuel.
+ *
  * # Arguments
  *
- * * basic_block - The basic block to compile.
- * * function    - The function that is being compiled.
- * * context     - The JIT context within which to compile the function.
+ * * function - The function to compile.
+ * * options - How to compile the function.
+ * * constants - The constant pool of the function's module.
+ * * context - The JIT context within which to compile the function.
+ * * heap - The heap on which the compiled code allocates objects.
+ * * fuel - The units of fuel the function starts with.
+ *
+ * Returns the compiled function, or why the function was rejected.
  */
-fn compile_basic_block(basic_block: @mut BasicBlock, 
-                       function: &Function, 
-                       locals: &mut ~[~Value]) {
-
-    // The evaluation stack must be empty on entering a basic block.
-    let mut stack = ~[];
+pub fn compile_with_fuel<'a>(function: &[Opcode], options: &CompileOptions, constants: &'a ConstantPool,
+                             context: &'a Context, heap: &'a mut Heap, fuel: u32) -> Result<~FueledFunction<'a>, VerifyError> {
+    // The counter is boxed so that it stays where the compiled code looks
+    // for it when the FueledFunction moves.
+    let mut counter = ~fuel;
+    match compile_with_entry(function, None, Some(ptr::to_mut_unsafe_ptr(&mut *counter)), options,
+                             constants, context, heap) {
+        Ok(compiled) => Ok(~FueledFunction { function: compiled, fuel: counter }),
+        Err(error) => Err(error)
+    }
+}
 
-    // Create a Label for this basic block.
-    function.insn_set_label(basic_block.label);
+/**
+ * A compiled function together with the fuel counter it consumes. It
+ * borrows the constant pool and heap the code was compiled against, so
+ * neither can go away while it can still be called.
+ */
+pub struct FueledFunction<'self> {
+    priv function: ~CompiledFunction<'self>,
+    priv fuel: ~u32
+}
 
-    for opcode in basic_block.opcodes.iter() {
-        compile_opcode(opcode, function, &mut stack, locals);
+impl<'self> FueledFunction<'self> {
+    /**
+     * Returns the function to call.
+     */
+    pub fn callable<'a>(&'a self) -> &'a CompiledFunction<'self> {
+        &*self.function
     }
 
-    // If the basic block ends in a conditional branch (Iftrue),
-    // emit a conditional branch JIT instruction here.
-    match basic_block.conditional_block {
-        Some(b) => {
-            let value = stack.pop();
-            function.insn_branch_if(value, b.label);
-        }
-    _   => { }
+    /**
+     * Returns the units of fuel left.
+     */
+    pub fn fuel(&self) -> u32 {
+        *self.fuel
     }
 
-    // If the basic block has a successor, emit a JIT branch
-    // instruction. LibJIT (hopefully) optimises these out
-    // if they are unnecessary.
-    match basic_block.next_block {
-        Some(b) => {
-            function.insn_branch(b.label);
-        }
-    _   => { /* TODO must end in a Ret? */ }
+    /**
+     * Adds fuel for the next calls.
+     *
+     * # Arguments
+     *
+     * * fuel - The units of fuel to add.
+     */
+    pub fn refuel(&mut self, fuel: u32) {
+        *self.fuel += fuel;
     }
 }
 
 /**
- * JIT compiles a single opcode.
- * 
+ * JIT compiles an on-stack replacement entry point into a function.
+ *
+ * The compiled function takes a single argument: a pointer to the values
+ * of the function's locals, one 32-bit word per local, encoded as they are
+ * in heap arrays. It starts running at the given loop header, which must
+ * begin a basic block, with the evaluation stack empty. Like compile's,
+ * the CompiledFunction borrows the constant pool and heap.
+ *
  * # Arguments
  *
- * * opcode   - The Opcode to compile.
- * * function - The JIT function object.
- * * stack    - The VM stack.
- * * locals   - The list of the function's local variable Values.
+ * * function - The function to compile.
+ * * entry - The index of the loop header instruction to enter at.
+ * * options - How to compile the function.
+ * * constants - The constant pool of the function's module.
+ * * context - The JIT context within which to compile the function.
+ * * heap - The heap on which the compiled code allocates objects.
+ *
+ * Returns the compiled entry point, or why the function was rejected.
  */
-fn compile_opcode(opcode: &Opcode,
-                  function: &Function, 
-                  stack: &mut ~[~Value], 
-                  locals: &mut ~[~Value]) {
-
-    match *opcode {
-        Constf32(operand) => {
-            stack.push(function.constant_float32(operand)); 
-        }
-        Consti32(operand) => {
-            stack.push(function.constant_int32(operand));
-        }
-        Add => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_add(v1, v2) };
-        }
-        Subtract => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_sub(v1, v2) };
-        }
-        Multiply => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_mul(v1, v2) };
-        }
-        Divide => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_div(v1, v2) };
-        }
-        And => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_and(v1, v2) };
-        }
-        Or => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_or(v1, v2) };
-        }
-        Xor => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_xor(v1, v2) };
-        }
-        Eq => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_eq(v1, v2) };
-        }
-        Neq => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_neq(v1, v2) };
-        }
-        Leq => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_leq(v1, v2) };
-        }
-        Geq => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_geq(v1, v2) };
-        }
-        Lt => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_lt(v1, v2) };
-        }
-        Gt => { 
-            do binary_opcode(stack) |v1, v2| { function.insn_gt(v1, v2) };
-        }
-        Negate => { 
-            do unary_opcode(stack) |value| { function.insn_neg(value) };
-        }
-        Not => { 
-            do unary_opcode(stack) |value| { function.insn_not(value) };
-        }
-        Ret => { 
-            let v = stack.pop();
-            function.insn_return(v);
-        },
-        Disp => {} // TODO
-        Store(addr) => {
-            let v = stack.pop();
-            function.insn_store(locals[addr], v);
-        }
-        Loadf32(addr) => {
-            let v = locals[addr].clone();
-            let new_value = function.insn_dup(v);
-            stack.push(new_value);
-        }
-        Loadi32(addr) => {
-            let v = locals[addr].clone();
-            let new_value = function.insn_dup(v);
-            stack.push(new_value);
-        }
-        _ => { }
-    }
+pub fn compile_osr<'a>(function: &[Opcode], entry: u32, options: &CompileOptions, constants: &'a ConstantPool,
+                       context: &'a Context, heap: &'a mut Heap) -> Result<~CompiledFunction<'a>, VerifyError> {
+    compile_with_entry(function, Some(entry), None, options, constants, context, heap)
 }
 
 /**
- * Pre-creates some JIT Values for use as the local variables in a function.
+ * JIT compiles a function, with either its normal entry
+ * or an on-stack replacement entry.
+ *
+ * # Arguments
  *
- * * function     - The function to pre-create JIT Values for.
- * * basic_blocks - The basic block representation of the function.
- * * jit_function - The JIT function object to create values for.
+ * * function - The function to compile.
+ * * entry - The index of the loop header for an on-stack replacement
+ *           entry, or None for the normal entry.
+ * * fuel - The fuel counter to consume on backward branches, if any.
+ * * options - How to compile the function.
+ * * constants - The constant pool of the function's module.
+ * * context - The JIT context within which to compile the function.
+ * * heap - The heap on which the compiled code allocates objects.
  *
- * Returns the list of pre-created local variable Values.
+ * Returns the compiled function, or why the function was rejected.
  */
-fn reserve_locals(function: &[Opcode], basic_blocks: &[@mut BasicBlock], jit_function: &Function) -> ~[~Value] {
-    // TODO: count from basic blocks instead.
-    let local_count = local_count(function);
-
-    let types = infer_local_types(basic_blocks, local_count);
+fn compile_with_entry<'a>(function: &[Opcode], entry: Option<u32>, fuel: Option<*mut u32>, options: &CompileOptions,
+                          constants: &'a ConstantPool, context: &'a Context,
+                          heap: &'a mut Heap) -> Result<~CompiledFunction<'a>, VerifyError> {
+    // The function is verified before libjit is asked to build it, since
+    // libjit compiles whatever body the build leaves.
+    let (params, return_type) = match signature(function, entry) {
+        Ok(signature) => signature,
+        Err(error) => return Err(error)
+    };
+    context.build_start();
 
-    let locals: ~[~Value] = do vec::from_fn(local_count as uint) |index| {
-        jit_function.create_value( if types[index] == Float32 { Types::get_float32() } else { Types::get_int() })
+    let mut emitted = Ok(());
+    let compiled = do context.build_function(Type::create_scalar_signature(params, return_type)) |jit_function| {
+        let mut backend = LibJitBackend::new(jit_function);
+        configure(&mut backend, options);
+        emitted = emit_function(function, entry, fuel, options, constants, heap, &mut backend as &mut Backend);
     };
-    return locals;
+
+    context.build_end();
+    match emitted {
+        Ok(()) => Ok(compiled),
+        Err(error) => Err(error)
+    }
 }
 
 /**
- * Helper function for a binary opcode.
+ * Creates a function whose body is only built and compiled when it is
+ * first called, so that a module's functions can be compiled as they are
+ * needed rather than all up front. Calls and closures can be made before
+ * any code is generated.
  *
- * Pops 2 Values from the stack and pushes the resulting Value.
+ * The LazyFunction borrows the constant pool and heap as well as the
+ * context, since building the body on the first call reads them.
  *
  * # Arguments
  *
- * * stack - The VM stack.
- * * f     - A function that takes 2 Values from the stack and returns a result Value.
+ * * function - The function to compile.
+ * * options - How to compile the function.
+ * * constants - The constant pool of the function's module.
+ * * context - The JIT context within which to compile the function.
+ * * heap - The heap on which the compiled code allocates objects.
+ *
+ * Returns the lazy function, or why the function was rejected. Functions
+ * are verified up front, so that building the body cannot fail later.
  */
-fn binary_opcode(stack: &mut ~[~Value], f: &fn(v1: &Value, v2: &Value) -> ~Value) {
-    let v1 = stack.pop();
-    let v2 = stack.pop();
-    stack.push(f(v2, v1));
+pub fn compile_lazily<'a>(function: &[Opcode], options: &CompileOptions, constants: &'a ConstantPool,
+                          context: &'a Context, heap: &'a mut Heap) -> Result<~LazyFunction<'a>, VerifyError> {
+    let (params, return_type) = match signature(function, None) {
+        Ok(signature) => signature,
+        Err(error) => return Err(error)
+    };
+    let body = @LazyBody {
+        opcodes: prepare(function, None, options),
+        options: options.clone(),
+        constants: ptr::to_unsafe_ptr(constants),
+        heap: ptr::to_mut_unsafe_ptr(heap)
+    };
+    Ok(context.create_lazy_function(Type::create_scalar_signature(params, return_type), body as @OnDemandBuilder))
 }
 
 /**
- * Helper function for a binary opcode.
- *
- * Pops 2 Values from the stack and pushes the resulting Value.
+ * What a lazily compiled function needs to build its body. A managed box
+ * cannot hold borrowed pointers, so the constant pool and heap are kept as
+ * raw pointers; compile_lazily ties the LazyFunction to their borrows.
+ */
+struct LazyBody {
+    opcodes: ~[Opcode],
+    options: CompileOptions,
+    constants: *ConstantPool,
+    heap: *mut Heap
+}
+
+impl OnDemandBuilder for LazyBody {
+    fn build<'f>(&self, function: &'f Function<'f>) -> bool {
+        // libjit compiles the function once its body is built.
+        let mut backend = LibJitBackend::new(function);
+        configure(&mut backend, &self.options);
+        unsafe {
+            build_function(self.opcodes, None, None, &self.options, &mut backend as &mut Backend,
+                           &*self.constants, &mut *self.heap).is_ok()
+        }
+    }
+}
+
+/**
+ * Applies the options that libjit handles itself to a libjit backend.
  *
  * # Arguments
  *
- * * stack - The VM stack.
- * * f     - A function that takes 2 Values from the stack and returns a result Value.
+ * * backend - The libjit backend.
+ * * options - How to compile the function.
  */
-fn unary_opcode(stack: &mut ~[~Value], f: &fn(value: &Value) -> ~Value) {
-    let value = stack.pop();
-    stack.push(f(value));
+fn configure(backend: &mut LibJitBackend, options: &CompileOptions) {
+    match options.optimization_level {
+        Some(level) => backend.set_optimization_level(level),
+        None => { }
+    }
+    if options.keep_ir {
+        backend.set_recompilable();
+    }
 }
@@ -0,0 +1,213 @@
+use heap::*;
+use constant_pool::*;
+use variable_type::*;
+use vm_error::*;
+use interpret::*;
+use backend::*;
+use std::libc::*;
+
+/// The exception code thrown for ElementTypeMismatch, which
+/// has no libjit builtin equivalent.
+pub static ELEMENT_TYPE_MISMATCH: c_int = -100;
+
+/// The exception code thrown when compiled code runs out of fuel.
+pub static OUT_OF_FUEL: c_int = -101;
+
+/// The exception code thrown for an Overflow at offset 0. Overflows at
+/// later offsets count up from it, so that the exception itself carries
+/// the offset. It starts past every libjit result code, the largest of
+/// which is JIT_RESULT_OK, so that no overflow reads as success.
+pub static OVERFLOW_BASE: c_int = 2;
+
+/**
+ * Returns the exception code JIT code throws for a VM error.
+ *
+ * # Arguments
+ *
+ * * error - The VM error.
+ */
+pub fn error_code(error: VMError) -> c_int {
+    match error {
+        IndexOutOfBounds => JIT_RESULT_OUT_OF_BOUNDS,
+        NullReference => JIT_RESULT_NULL_REFERENCE,
+        OutOfMemory => JIT_RESULT_OUT_OF_MEMORY,
+        ElementTypeMismatch => ELEMENT_TYPE_MISMATCH,
+        OutOfFuel => OUT_OF_FUEL,
+        DivisionByZero => JIT_RESULT_DIVISION_BY_ZERO,
+        Overflow(offset) => OVERFLOW_BASE + offset as c_int,
+        Uncaught(_) => fail!("Thrown values are not builtin exceptions")
+    }
+}
+
+/**
+ * Converts an exception that escaped a compiled function to a VM error.
+ *
+ * # Arguments
+ *
+ * * exception - The exception returned by Function::apply or Function::execute.
+ */
+pub fn to_vm_error(exception: JitException) -> VMError {
+    match exception {
+        Builtin(code) if code == JIT_RESULT_OUT_OF_BOUNDS => IndexOutOfBounds,
+        Builtin(code) if code == JIT_RESULT_NULL_REFERENCE => NullReference,
+        Builtin(code) if code == JIT_RESULT_OUT_OF_MEMORY => OutOfMemory,
+        Builtin(code) if code == ELEMENT_TYPE_MISMATCH => ElementTypeMismatch,
+        Builtin(code) if code == OUT_OF_FUEL => OutOfFuel,
+        Builtin(code) if code == JIT_RESULT_DIVISION_BY_ZERO => DivisionByZero,
+        Builtin(code) if code >= OVERFLOW_BASE => Overflow((code - OVERFLOW_BASE) as u32),
+        Builtin(code) => fail!(fmt!("Unexpected JIT exception %d", code as int)),
+        // Throw shifts its value left by one so that it cannot be
+        // mistaken for an encoded builtin exception.
+        Thrown(object) => Uncaught(((object as int) >> 1) as i32)
+    }
+}
+
+/**
+ * Returns a helper's result to compiled code: a value, zero-extended to a
+ * pointer-sized integer, or for an error the exception to throw, which is
+ * negative since helpers only raise errors with negative codes. Compiled
+ * code checks the sign once the helper returns, so helpers never unwind.
+ */
+fn returned(result: Result<c_int, VMError>) -> int {
+    match result {
+        Ok(value) => value as u32 as int,
+        Err(error) => {
+            let code = error_code(error);
+            if code >= 0 {
+                fail!(fmt!("%s cannot be raised by a helper", error.to_str()));
+            }
+            ((code as int) << 1) | 1
+        }
+    }
+}
+
+/**
+ * Returns a handle to compiled code, or the exception for an error.
+ */
+fn handle_or_error(result: Result<HeapRef, VMError>) -> int {
+    match result {
+        Ok(handle) => returned(Ok(handle as c_int)),
+        Err(error) => returned(Err(error))
+    }
+}
+
+/**
+ * Returns the contents of the operands of a binary string opcode.
+ */
+fn string_pair(heap: &Heap, s1: c_int, s2: c_int) -> Result<(~str, ~str), VMError> {
+    match (heap.get_string(s1 as HeapRef), heap.get_string(s2 as HeapRef)) {
+        (Ok(s1), Ok(s2)) => Ok((s1, s2)),
+        (Err(error), _) | (_, Err(error)) => Err(error)
+    }
+}
+
+/**
+ * Collects if the heap asks for it before an allocation. The calling
+ * function has already spilled its references into its root frame, and
+ * the helper has already read any operands.
+ */
+fn collect_if_needed(heap: &mut Heap, size: uint) {
+    if heap.wants_collection(size) {
+        heap.collect([]);
+    }
+}
+
+pub extern "C" fn register_root_frame_helper(heap: *mut Heap, size: c_int) -> *mut u32 {
+    let heap = unsafe { &mut *heap };
+    heap.register_root_frame(size as uint)
+}
+
+pub extern "C" fn unregister_root_frame_helper(heap: *mut Heap, frame: *mut u32) {
+    let heap = unsafe { &mut *heap };
+    heap.unregister_root_frame(frame);
+}
+
+pub extern "C" fn new_array_helper(heap: *mut Heap, element_type: c_int, length: c_int) -> int {
+    let heap = unsafe { &mut *heap };
+    collect_if_needed(heap, Heap::array_size(length as i32));
+    handle_or_error(heap.new_array(VariableType::from_code(element_type as i32), length as i32))
+}
+
+pub extern "C" fn new_object_helper(heap: *mut Heap, field_count: c_int) -> int {
+    let heap = unsafe { &mut *heap };
+    collect_if_needed(heap, Heap::object_size(field_count as u32));
+    handle_or_error(heap.new_object(field_count as u32))
+}
+
+pub extern "C" fn const_str_helper(heap: *mut Heap, constants: *ConstantPool, index: c_int) -> int {
+    let (heap, constants) = unsafe { (&mut *heap, &*constants) };
+    let value = constants.get_string(index as u32).to_owned();
+    collect_if_needed(heap, Heap::string_size(value));
+    handle_or_error(heap.new_string(value))
+}
+
+pub extern "C" fn concat_helper(heap: *mut Heap, s1: c_int, s2: c_int) -> int {
+    let heap = unsafe { &mut *heap };
+    let result = match string_pair(heap, s1, s2) {
+        Ok((s1, s2)) => {
+            let mut joined = s1;
+            joined.push_str(s2);
+            collect_if_needed(heap, Heap::string_size(joined));
+            heap.new_string(joined)
+        }
+        Err(error) => Err(error)
+    };
+    handle_or_error(result)
+}
+
+pub extern "C" fn strlen_helper(heap: *mut Heap, s: c_int) -> int {
+    let heap = unsafe { &*heap };
+    let result = match heap.get_string(s as HeapRef) {
+        Ok(value) => Ok(value.char_len() as c_int),
+        Err(error) => Err(error)
+    };
+    returned(result)
+}
+
+pub extern "C" fn strcmp_helper(heap: *mut Heap, s1: c_int, s2: c_int) -> int {
+    let heap = unsafe { &*heap };
+    let result = match string_pair(heap, s1, s2) {
+        Ok((s1, s2)) => Ok(if s1 < s2 { -1 } else if s1 > s2 { 1 } else { 0 }),
+        Err(error) => Err(error)
+    };
+    returned(result)
+}
+
+pub extern "C" fn int_to_str_helper(heap: *mut Heap, value: c_int) -> int {
+    let heap = unsafe { &mut *heap };
+    let value = (value as i32).to_str();
+    collect_if_needed(heap, Heap::string_size(value));
+    handle_or_error(heap.new_string(value))
+}
+
+pub extern "C" fn float_to_str_helper(heap: *mut Heap, value: c_float) -> int {
+    let heap = unsafe { &mut *heap };
+    let value = (value as f32).to_str();
+    collect_if_needed(heap, Heap::string_size(value));
+    handle_or_error(heap.new_string(value))
+}
+
+/**
+ * Displays a float the way the interpreter's Disp does.
+ */
+pub extern "C" fn disp_number_helper(value: c_float) {
+    println(Float(value as f32).to_str());
+}
+
+/**
+ * Displays an integer the way the interpreter's Disp does.
+ */
+pub extern "C" fn disp_int_helper(value: c_int) {
+    println(Int(value as i32).to_str());
+}
+
+/**
+ * Displays a reference the way the interpreter's Disp does.
+ */
+pub extern "C" fn disp_ref_helper(heap: *mut Heap, handle: c_int) {
+    let heap = unsafe { &*heap };
+    match heap.get_string(handle as HeapRef) {
+        Ok(value) => println(value),
+        Err(_) => println(Reference(handle as HeapRef).to_str())
+    }
+}
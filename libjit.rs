@@ -3,11 +3,118 @@ use std::vec;
 use std::ptr;
 use std::io::*;
 use std::cast::*;
+use backend::*;
 
 pub enum ABI {
     CDECL = 0
 }
 
+/// libjit result codes (JIT_RESULT_*) for on-demand compilation. The
+/// builtin exception codes are in backend.rs.
+pub static JIT_RESULT_OK: c_int = 1;
+pub static JIT_RESULT_COMPILE_ERROR: c_int = -3;
+
+/// libjit type kinds (JIT_TYPE_*).
+pub static JIT_TYPE_VOID: c_int = 0;
+pub static JIT_TYPE_INT: c_int = 5;
+pub static JIT_TYPE_UINT: c_int = 6;
+pub static JIT_TYPE_NINT: c_int = 7;
+pub static JIT_TYPE_LONG: c_int = 9;
+pub static JIT_TYPE_FLOAT32: c_int = 11;
+pub static JIT_TYPE_FLOAT64: c_int = 12;
+pub static JIT_TYPE_PTR: c_int = 17;
+
+/**
+ * Why a type-checked call to a compiled function did not return a value.
+ */
+pub enum CallError {
+    /// The function takes the first number of arguments, but was given the second.
+    ArityMismatch(uint, uint),
+    /// The argument at the given index does not have the parameter's type.
+    ArgumentTypeMismatch(uint),
+    /// The function does not return the requested type.
+    ReturnTypeMismatch,
+    /// The function raised an exception.
+    Raised(JitException)
+}
+
+/**
+ * A Rust type that can be passed to compiled code.
+ */
+pub trait NativeArgument {
+    /// Returns the libjit type kind (JIT_TYPE_*) the value is passed as.
+    fn kind(&self) -> c_int;
+
+    /// Returns the address of the value, which is how libjit takes arguments.
+    fn address(&self) -> *c_void {
+        ptr::to_unsafe_ptr(self) as *c_void
+    }
+}
+
+/**
+ * A Rust type that can be passed to or returned from compiled code.
+ */
+pub trait NativeType: NativeArgument {
+    /// Returns a value to be overwritten by a call's result.
+    fn zero() -> Self;
+}
+
+impl NativeArgument for i32 {
+    fn kind(&self) -> c_int { JIT_TYPE_INT }
+}
+
+impl NativeType for i32 {
+    fn zero() -> i32 { 0 }
+}
+
+impl NativeArgument for u32 {
+    fn kind(&self) -> c_int { JIT_TYPE_UINT }
+}
+
+impl NativeType for u32 {
+    fn zero() -> u32 { 0 }
+}
+
+impl NativeArgument for i64 {
+    fn kind(&self) -> c_int { JIT_TYPE_LONG }
+}
+
+impl NativeType for i64 {
+    fn zero() -> i64 { 0 }
+}
+
+impl NativeArgument for f32 {
+    fn kind(&self) -> c_int { JIT_TYPE_FLOAT32 }
+}
+
+impl NativeType for f32 {
+    fn zero() -> f32 { 0f32 }
+}
+
+impl NativeArgument for f64 {
+    fn kind(&self) -> c_int { JIT_TYPE_FLOAT64 }
+}
+
+impl NativeType for f64 {
+    fn zero() -> f64 { 0f64 }
+}
+
+impl NativeArgument for *c_void {
+    fn kind(&self) -> c_int { JIT_TYPE_PTR }
+}
+
+impl NativeType for *c_void {
+    fn zero() -> *c_void { ptr::null() }
+}
+
+impl NativeArgument for () {
+    fn kind(&self) -> c_int { JIT_TYPE_VOID }
+}
+
+impl NativeType for () {
+    fn zero() -> () { () }
+}
+
 #[link_args = "-ljit"]
 extern {
     fn jit_context_create() -> *c_void;
@@ -17,18 +124,89 @@ extern {
     fn jit_function_create(context: *c_void, signature: *c_void) -> *c_void;
     fn jit_function_compile(function: *c_void);
     fn jit_type_create_signature(abi: c_int, return_type: *c_void, params: **c_void, num_params: c_uint, incref: c_int) -> *c_void;
+    fn jit_type_num_params(signature: *c_void) -> c_uint;
+    fn jit_type_get_param(signature: *c_void, param: c_uint) -> *c_void;
+    fn jit_type_get_return(signature: *c_void) -> *c_void;
+    fn jit_type_get_kind(value_type: *c_void) -> c_int;
+    fn jit_type_create_struct(fields: **c_void, num_fields: c_uint, incref: c_int) -> *c_void;
+    fn jit_type_create_pointer(target_type: *c_void, incref: c_int) -> *c_void;
+    fn jit_type_get_size(value_type: *c_void) -> size_t;
+    fn jit_type_get_offset(value_type: *c_void, field_index: c_uint) -> size_t;
+    fn jit_function_set_optimization_level(function: *c_void, level: c_uint);
+    fn jit_function_get_optimization_level(function: *c_void) -> c_uint;
+    fn jit_function_get_max_optimization_level() -> c_uint;
+    fn jit_function_set_recompilable(function: *c_void);
+    fn jit_function_set_on_demand_compiler(function: *c_void, on_demand: extern "C" fn(function: *c_void) -> c_int);
+    fn jit_function_set_meta(function: *c_void, meta_type: c_int, data: *c_void, free_data: *c_void, build_only: c_int) -> c_int;
+    fn jit_function_get_meta(function: *c_void, meta_type: c_int) -> *c_void;
+    fn jit_function_get_signature(function: *c_void) -> *c_void;
+    fn jit_function_is_compiled(function: *c_void) -> c_int;
+    fn jit_insn_default_return(function: *c_void) -> c_int;
+    fn jit_insn_call(function: *c_void, name: *c_char, callee: *c_void, signature: *c_void, args: **c_void, num_args: c_uint, flags: c_int) -> *c_void;
+    fn jit_insn_call_indirect(function: *c_void, value: *c_void, signature: *c_void, args: **c_void, num_args: c_uint, flags: c_int) -> *c_void;
+    fn jit_insn_address_of(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_value_set_addressable(value: *c_void);
+    fn jit_insn_alloca(function: *c_void, size: *c_void) -> *c_void;
+    fn jit_insn_memcpy(function: *c_void, dest: *c_void, src: *c_void, size: *c_void) -> c_int;
+    fn jit_insn_memset(function: *c_void, dest: *c_void, value: *c_void, size: *c_void) -> c_int;
+    fn jit_insn_jump_table(function: *c_void, value: *c_void, labels: *mut *c_void, num_labels: c_uint) -> c_int;
+    fn jit_insn_ushr(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_to_bool(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_to_not_bool(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_atan2(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_log10(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_rint(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_value_create_float64_constant(function: *c_void, value_type: *c_void, value: c_double) -> *c_void;
+    fn jit_value_create_long_constant(function: *c_void, value_type: *c_void, value: i64) -> *c_void;
     fn jit_value_get_param(function: *c_void, param: c_uint) -> *c_void;
     fn jit_insn_return(function: *c_void, value: *c_void);
-    fn jit_function_apply(function: *c_void, args: **c_void, return_area: *mut c_void);
+    fn jit_function_apply(function: *c_void, args: **c_void, return_area: *mut c_void) -> c_int;
+    fn jit_exception_set_handler(handler: extern "C" fn(exception_type: c_int) -> *c_void) -> *c_void;
+    fn jit_exception_get_last() -> *c_void;
+    fn jit_exception_clear_last();
+    fn jit_exception_builtin(exception_type: c_int);
+    fn jit_insn_call_native(function: *c_void, name: *c_char, native_function: *c_void, signature: *c_void, args: **c_void, num_args: c_uint, flags: c_int) -> *c_void;
+    fn jit_insn_load_relative(function: *c_void, value: *c_void, offset: intptr_t, value_type: *c_void) -> *c_void;
+    fn jit_insn_store_relative(function: *c_void, dest: *c_void, offset: intptr_t, value: *c_void) -> c_int;
+    fn jit_insn_add_relative(function: *c_void, value: *c_void, offset: intptr_t) -> *c_void;
+    fn jit_insn_load_elem(function: *c_void, base_addr: *c_void, index: *c_void, elem_type: *c_void) -> *c_void;
+    fn jit_insn_store_elem(function: *c_void, base_addr: *c_void, index: *c_void, value: *c_void) -> c_int;
+    fn jit_insn_check_null(function: *c_void, value: *c_void) -> c_int;
+    fn jit_insn_throw(function: *c_void, value: *c_void) -> c_int;
+    fn jit_insn_thrown_exception(function: *c_void) -> *c_void;
+    fn jit_insn_start_catcher(function: *c_void) -> c_int;
+    fn jit_insn_rethrow_unhandled(function: *c_void) -> c_int;
     fn jit_insn_add(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
     fn jit_insn_mul(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
     fn jit_insn_sub(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
     fn jit_insn_div(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_rem(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_add_ovf(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_sub_ovf(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_mul_ovf(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_shl(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_shr(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_sshr(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_convert(function: *c_void, value: *c_void, value_type: *c_void, overflow_check: c_int) -> *c_void;
     fn jit_insn_and(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
     fn jit_insn_or(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
     fn jit_insn_xor(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
     fn jit_insn_not(function: *c_void, value: *c_void) -> *c_void;
     fn jit_insn_neg(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_min(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_max(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_pow(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void;
+    fn jit_insn_abs(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_sqrt(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_floor(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_ceil(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_round(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_trunc(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_sin(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_cos(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_tan(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_exp(function: *c_void, value: *c_void) -> *c_void;
+    fn jit_insn_log(function: *c_void, value: *c_void) -> *c_void;
     fn jit_insn_load(function: *c_void, value: *c_void) -> *c_void;
     fn jit_value_create(function: *c_void, value_type: *c_void) -> *c_void;
     fn jit_insn_label(function: *c_void, label: *mut c_void);
@@ -44,13 +222,83 @@ extern {
     fn jit_insn_store(function: *c_void, dest: *c_void, src: *c_void);
     fn jit_dump_function (stream: *FILE, funcion: *c_void, name: *c_char);
     fn jit_value_create_float32_constant(function: *c_void, value_type: *c_void, value: c_float) -> *c_void;
-    fn jit_value_create_nint_constant(function: *c_void, value_type: *c_void, value: c_int) -> *c_void;
+    fn jit_value_create_nint_constant(function: *c_void, value_type: *c_void, value: intptr_t) -> *c_void;
     fn jit_function_to_closure(function: *c_void) -> *c_void;
 
     static jit_type_void: *c_void;
     static jit_type_int: *c_void;
+    static jit_type_uint: *c_void;
     static jit_type_float32: *c_void;
     static jit_type_float64: *c_void;
+    static jit_type_void_ptr: *c_void;
+    static jit_type_nint: *c_void;
+    static jit_type_nuint: *c_void;
+    static jit_type_sbyte: *c_void;
+    static jit_type_ubyte: *c_void;
+    static jit_type_short: *c_void;
+    static jit_type_ushort: *c_void;
+    static jit_type_long: *c_void;
+    static jit_type_ulong: *c_void;
+}
+
+/**
+ * Wraps a libjit builtin exception code in a thrown object. Builtin codes
+ * are encoded as odd pointers so they cannot be mistaken for real objects.
+ */
+extern "C" fn builtin_exception_handler(exception_type: c_int) -> *c_void {
+    (((exception_type as int) << 1) | 1) as *c_void
+}
+
+/**
+ * Throws a builtin exception from a native helper called by JIT code.
+ * This does not return, so the caller must not hold any values that
+ * need to be dropped.
+ *
+ * # Arguments
+ *
+ * * exception_type - The exception code, passed to the exception handler.
+ */
+pub fn throw_builtin(exception_type: c_int) {
+    unsafe {
+        jit_exception_builtin(exception_type);
+    }
+}
+
+/// The metadata key under which a lazy function keeps its OnDemandState.
+static ON_DEMAND_META: c_int = 1;
+
+/**
+ * Builds the body of a lazy function, called by libjit the first time the
+ * function runs. libjit holds the context's build lock, and compiles the
+ * function once its body is built.
+ */
+extern "C" fn build_on_demand(function: *c_void) -> c_int {
+    unsafe {
+        let state: &OnDemandState = transmute(jit_function_get_meta(function, ON_DEMAND_META));
+        let wrapper = Function { _function: function, _signature: jit_function_get_signature(function), _brand: ptr::mut_null() };
+        if state.builder.build(&wrapper) {
+            JIT_RESULT_OK
+        } else {
+            JIT_RESULT_COMPILE_ERROR
+        }
+    }
+}
+
+/**
+ * Takes the exception that was last thrown on this thread.
+ */
+fn take_last_exception() -> JitException {
+    unsafe {
+        let object = jit_exception_get_last();
+        jit_exception_clear_last();
+
+        let bits = object as int;
+        if bits & 1 == 1 {
+            Builtin((bits >> 1) as c_int)
+        } else {
+            Thrown(object)
+        }
+    }
 }
 
 pub struct Context {
@@ -61,6 +309,7 @@ impl Context {
     pub fn new() -> ~Context {
         unsafe {
             let context = jit_context_create();
+            jit_exception_set_handler(builtin_exception_handler);
             ~Context { _context: context }
         }
     }
@@ -77,10 +326,43 @@ impl Context {
         }
     }
 
-    pub fn create_function(&self, signature: &Type) -> ~Function {
+    /**
+     * Creates a function, lets a closure emit its body, and compiles it
+     * once the closure returns, when none of the function's Values remain.
+     *
+     * # Arguments
+     *
+     * * signature - The function's signature.
+     * * build     - Emits the function's body.
+     */
+    pub fn build_function<'a>(&'a self, signature: &Type, build: &fn<'f>(function: &'f Function<'f>)) -> ~CompiledFunction<'a> {
+        unsafe {
+            let function = Function {
+                _function: jit_function_create(self._context, signature._type),
+                _signature: signature._type,
+                _brand: ptr::mut_null()
+            };
+            build(&function);
+            jit_function_compile(function._function);
+            ~CompiledFunction { _context: self, _function: function._function, _signature: function._signature }
+        }
+    }
+
+    /**
+     * Creates a function whose body is built by a builder when the function
+     * is first called, and compiled straight after.
+     */
+    pub fn create_lazy_function<'a>(&'a self, signature: &Type, builder: @OnDemandBuilder) -> ~LazyFunction<'a> {
         unsafe {
             let function = jit_function_create(self._context, signature._type);
-            ~Function { _context: self, _function: function }
+            let state = ~OnDemandState { builder: builder };
+            jit_function_set_meta(function, ON_DEMAND_META, ptr::to_unsafe_ptr(&*state) as *c_void, ptr::null(), 0);
+            jit_function_set_on_demand_compiler(function, build_on_demand);
+
+            ~LazyFunction {
+                function: CompiledFunction { _context: self, _function: function, _signature: signature._type },
+                state: state
+            }
         }
     }
 }
@@ -112,125 +394,490 @@ impl Type {
             ~Type { _type: signature }
         }
     }
+
+    /**
+     * Creates a CDECL signature from the machine types of a Backend.
+     */
+    pub fn create_scalar_signature(params: &[ScalarType], return_type: ScalarType) -> ~Type {
+        let param_types = params.map(|&param| Types::get_scalar(param));
+        let param_refs: ~[&Type] = param_types.iter().map(|t| &**t).collect();
+        Type::create_signature(CDECL, Types::get_scalar(return_type), param_refs)
+    }
+
+    pub fn create_struct(fields: &[&Type]) -> ~Type {
+        unsafe {
+            let fs: ~[*c_void] = fields.iter().map(|field| field._type).collect();
+            let fields = if fs.len() > 0 { vec::raw::to_ptr(fs) } else { 0 as **c_void };
+
+            let struct_type = jit_type_create_struct(fields, fs.len() as c_uint, 1);
+            ~Type { _type: struct_type }
+        }
+    }
+
+    pub fn create_pointer(target_type: &Type) -> ~Type {
+        unsafe {
+            let pointer_type = jit_type_create_pointer(target_type._type, 1);
+            ~Type { _type: pointer_type }
+        }
+    }
+
+    /// Returns the type kind (JIT_TYPE_*).
+    pub fn kind(&self) -> c_int {
+        unsafe {
+            jit_type_get_kind(self._type)
+        }
+    }
+
+    /// Returns the size of the type in bytes.
+    pub fn size(&self) -> uint {
+        unsafe {
+            jit_type_get_size(self._type) as uint
+        }
+    }
+
+    /// Returns the offset in bytes of a field of a struct type.
+    pub fn offset(&self, field: uint) -> uint {
+        unsafe {
+            jit_type_get_offset(self._type, field as c_uint) as uint
+        }
+    }
 }
 
-pub struct Function {
-    priv _context: *Context,
-    priv _function: *c_void
+/**
+ * A function under construction. It is only reachable while its body is
+ * built, by Context::build_function or a lazy function's OnDemandBuilder,
+ * and its lifetime is a brand that differs for every function built. The
+ * Values built for it carry the brand, so the compiler rejects using them
+ * with any other function, or after the function is compiled.
+ */
+pub struct Function<'self> {
+    priv _function: *c_void,
+    priv _signature: *c_void,
+
+    /// Makes the brand invariant, so that it cannot be shortened to
+    /// match another function's.
+    priv _brand: *mut &'self ()
 }
 
-impl Function {
-    fn insn_binop(&self, v1: &Value, v2: &Value, f: extern "C" unsafe fn(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void) -> ~Value {
+impl<'self> Function<'self> {
+    /**
+     * Returns the libjit value behind a Value. Its brand guarantees that it
+     * belongs to this function, which libjit does not check.
+     */
+    fn raw(&self, value: &Value<'self>) -> *c_void {
+        value._value
+    }
+
+    fn wrap(&self, value: *c_void) -> ~Value<'self> {
+        ~Value { _value: value, _brand: ptr::mut_null() }
+    }
+
+    fn insn_binop(&self, v1: &Value<'self>, v2: &Value<'self>, f: extern "C" unsafe fn(function: *c_void, v1: *c_void, v2: *c_void) -> *c_void) -> ~Value<'self> {
         unsafe {
-            let value = f(self._function, v1._value, v2._value);
-            ~Value { _value: value }
+            let value = f(self._function, self.raw(v1), self.raw(v2));
+            self.wrap(value)
         }
     }
 
-    fn insn_unop(&self, value: &Value, f: extern "C" unsafe fn(function: *c_void, value: *c_void) -> *c_void) -> ~Value {
+    fn insn_unop(&self, value: &Value<'self>, f: extern "C" unsafe fn(function: *c_void, value: *c_void) -> *c_void) -> ~Value<'self> {
         unsafe {
-            let value = f(self._function, value._value);
-            ~Value { _value: value }
+            let value = f(self._function, self.raw(value));
+            self.wrap(value)
         }
     }
 
     pub fn dump(&self, name: &str) {
+        dump_function(self._function, name);
+    }
+
+    /**
+     * Sets how hard libjit optimizes the function when it is compiled,
+     * from 0 up to max_optimization_level.
+     */
+    pub fn set_optimization_level(&self, level: uint) {
         unsafe {
-            name.as_c_str(|c_str| {
-                jit_dump_function(rustrt::rust_get_stdout(), self._function, c_str);
-            });
+            jit_function_set_optimization_level(self._function, level as c_uint);
+        }
+    }
+
+    pub fn optimization_level(&self) -> uint {
+        unsafe {
+            jit_function_get_optimization_level(self._function) as uint
         }
     }
 
-    pub fn compile(&self) {
+    pub fn max_optimization_level() -> uint {
         unsafe {
-            jit_function_compile(self._function);
+            jit_function_get_max_optimization_level() as uint
         }
     }
 
-    pub fn get_param(&self, param: uint) -> Value {
+    /**
+     * Keeps the function's IR after it is compiled, so that dump shows the IR.
+     */
+    pub fn set_recompilable(&self) {
+        unsafe {
+            jit_function_set_recompilable(self._function);
+        }
+    }
+
+    pub fn get_param(&self, param: uint) -> Value<'self> {
         unsafe {
             let value = jit_value_get_param(self._function, param as c_uint);
-            Value { _value: value }
+            Value { _value: value, _brand: ptr::mut_null() }
         }
     }
 
-    pub fn insn_return(&self, retval: &Value) {
+    pub fn insn_return(&self, retval: &Value<'self>) {
         unsafe {
-            jit_insn_return(self._function, retval._value);
+            jit_insn_return(self._function, self.raw(retval));
         }
     }
 
-    pub fn insn_mul(&self, v1: &Value, v2: &Value) -> ~Value {
+    /**
+     * Returns from the function with the default value of its return type.
+     */
+    pub fn insn_default_return(&self) {
+        unsafe {
+            jit_insn_default_return(self._function);
+        }
+    }
+
+    pub fn insn_mul(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_mul)
     }
 
-    pub fn insn_add(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_add(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_add)
     }
 
-    pub fn insn_sub(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_sub(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_sub)
     }
 
-    pub fn insn_div(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_div(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_div)
     }
 
-    pub fn insn_leq(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_rem(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_rem)
+    }
+
+    pub fn insn_add_ovf(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_add_ovf)
+    }
+
+    pub fn insn_sub_ovf(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_sub_ovf)
+    }
+
+    pub fn insn_mul_ovf(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_mul_ovf)
+    }
+
+    pub fn insn_shl(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_shl)
+    }
+
+    pub fn insn_shr(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_shr)
+    }
+
+    pub fn insn_sshr(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_sshr)
+    }
+
+    pub fn insn_ushr(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_ushr)
+    }
+
+    pub fn insn_convert(&self, value: &Value<'self>, value_type: &Type, overflow_check: bool) -> ~Value<'self> {
+        unsafe {
+            let converted = jit_insn_convert(self._function, self.raw(value), value_type._type, overflow_check as c_int);
+            self.wrap(converted)
+        }
+    }
+
+    pub fn insn_leq(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_le)
     }
 
-    pub fn insn_geq(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_geq(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_ge)
     }
 
-    pub fn insn_lt(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_lt(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_lt)
     }
 
-    pub fn insn_gt(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_gt(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_gt)
     }
 
-    pub fn insn_eq(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_eq(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_eq)
     }
 
-    pub fn insn_neq(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_neq(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_ne)
     }
 
-    pub fn insn_and(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_to_bool(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_to_bool)
+    }
+
+    pub fn insn_to_not_bool(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_to_not_bool)
+    }
+
+    pub fn insn_and(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_and)
     }
 
-    pub fn insn_or(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_or(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_or)
     }
 
-    pub fn insn_xor(&self, v1: &Value, v2: &Value) -> ~Value {
+    pub fn insn_xor(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
         self.insn_binop(v1, v2, jit_insn_xor)
     }
 
-    pub fn insn_not(&self, value: &Value) -> ~Value {
+    pub fn insn_not(&self, value: &Value<'self>) -> ~Value<'self> {
         self.insn_unop(value, jit_insn_not)
     }
 
-    pub fn insn_neg(&self, value: &Value) -> ~Value {
+    pub fn insn_neg(&self, value: &Value<'self>) -> ~Value<'self> {
         self.insn_unop(value, jit_insn_neg)
     }
 
-    pub fn insn_dup(&self, value: &Value) -> ~Value {
+    pub fn insn_min(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_min)
+    }
+
+    pub fn insn_max(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_max)
+    }
+
+    pub fn insn_pow(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_pow)
+    }
+
+    pub fn insn_abs(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_abs)
+    }
+
+    pub fn insn_sqrt(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_sqrt)
+    }
+
+    pub fn insn_floor(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_floor)
+    }
+
+    pub fn insn_ceil(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_ceil)
+    }
+
+    pub fn insn_round(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_round)
+    }
+
+    pub fn insn_trunc(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_trunc)
+    }
+
+    pub fn insn_sin(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_sin)
+    }
+
+    pub fn insn_cos(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_cos)
+    }
+
+    pub fn insn_tan(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_tan)
+    }
+
+    pub fn insn_atan2(&self, v1: &Value<'self>, v2: &Value<'self>) -> ~Value<'self> {
+        self.insn_binop(v1, v2, jit_insn_atan2)
+    }
+
+    pub fn insn_log10(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_log10)
+    }
+
+    pub fn insn_rint(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_rint)
+    }
+
+    pub fn insn_exp(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_exp)
+    }
+
+    pub fn insn_log(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_log)
+    }
+
+    pub fn insn_dup(&self, value: &Value<'self>) -> ~Value<'self> {
+        unsafe {
+            let dup_value = jit_insn_load(self._function, self.raw(value));
+            self.wrap(dup_value)
+        }
+    }
+
+    pub fn insn_store(&self, dest: &Value<'self>, src: &Value<'self>) {
+        unsafe {
+            jit_insn_store(self._function, self.raw(dest), self.raw(src));
+        }
+    }
+
+    pub fn insn_call_native(&self, name: &str, native_function: *c_void, signature: &Type, args: &[&Value<'self>]) -> ~Value<'self> {
+        unsafe {
+            let pargs: ~[*c_void] = args.iter().map(|arg| self.raw(*arg)).collect();
+            let pargs_ptr = if pargs.len() > 0 { vec::raw::to_ptr(pargs) } else { 0 as **c_void };
+            let mut value = 0 as *c_void;
+            name.as_c_str(|c_str| {
+                value = jit_insn_call_native(self._function, c_str, native_function, signature._type,
+                                             pargs_ptr, pargs.len() as c_uint, 0);
+            });
+            self.wrap(value)
+        }
+    }
+
+    /**
+     * Calls another compiled function in the same Context.
+     */
+    pub fn insn_call(&self, name: &str, callee: &CompiledFunction, args: &[&Value<'self>]) -> ~Value<'self> {
+        unsafe {
+            let pargs: ~[*c_void] = args.iter().map(|arg| self.raw(*arg)).collect();
+            let pargs_ptr = if pargs.len() > 0 { vec::raw::to_ptr(pargs) } else { 0 as **c_void };
+            let mut value = 0 as *c_void;
+            name.as_c_str(|c_str| {
+                value = jit_insn_call(self._function, c_str, callee._function, callee._signature,
+                                      pargs_ptr, pargs.len() as c_uint, 0);
+            });
+            self.wrap(value)
+        }
+    }
+
+    /**
+     * Calls the function whose address is held in a Value.
+     */
+    pub fn insn_call_indirect(&self, callee: &Value<'self>, signature: &Type, args: &[&Value<'self>]) -> ~Value<'self> {
+        unsafe {
+            let pargs: ~[*c_void] = args.iter().map(|arg| self.raw(*arg)).collect();
+            let pargs_ptr = if pargs.len() > 0 { vec::raw::to_ptr(pargs) } else { 0 as **c_void };
+            let value = jit_insn_call_indirect(self._function, self.raw(callee), signature._type,
+                                               pargs_ptr, pargs.len() as c_uint, 0);
+            self.wrap(value)
+        }
+    }
+
+    /**
+     * Returns the address of a Value, which must have been made addressable.
+     */
+    pub fn insn_address_of(&self, value: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(value, jit_insn_address_of)
+    }
+
+    /**
+     * Marks a Value as needing a memory location, so that insn_address_of can be used on it.
+     */
+    pub fn set_addressable(&self, value: &Value<'self>) {
+        unsafe {
+            jit_value_set_addressable(self.raw(value));
+        }
+    }
+
+    /**
+     * Allocates memory in the function's stack frame.
+     */
+    pub fn insn_alloca(&self, size: &Value<'self>) -> ~Value<'self> {
+        self.insn_unop(size, jit_insn_alloca)
+    }
+
+    pub fn insn_memcpy(&self, dest: &Value<'self>, src: &Value<'self>, size: &Value<'self>) {
+        unsafe {
+            jit_insn_memcpy(self._function, self.raw(dest), self.raw(src), self.raw(size));
+        }
+    }
+
+    pub fn insn_memset(&self, dest: &Value<'self>, value: &Value<'self>, size: &Value<'self>) {
+        unsafe {
+            jit_insn_memset(self._function, self.raw(dest), self.raw(value), self.raw(size));
+        }
+    }
+
+    pub fn insn_load_relative(&self, value: &Value<'self>, offset: int, value_type: &Type) -> ~Value<'self> {
+        unsafe {
+            let loaded = jit_insn_load_relative(self._function, self.raw(value), offset as intptr_t, value_type._type);
+            self.wrap(loaded)
+        }
+    }
+
+    pub fn insn_store_relative(&self, dest: &Value<'self>, offset: int, value: &Value<'self>) {
+        unsafe {
+            jit_insn_store_relative(self._function, self.raw(dest), offset as intptr_t, self.raw(value));
+        }
+    }
+
+    pub fn insn_add_relative(&self, value: &Value<'self>, offset: int) -> ~Value<'self> {
+        unsafe {
+            let address = jit_insn_add_relative(self._function, self.raw(value), offset as intptr_t);
+            self.wrap(address)
+        }
+    }
+
+    pub fn insn_load_elem(&self, base_addr: &Value<'self>, index: &Value<'self>, elem_type: &Type) -> ~Value<'self> {
         unsafe {
-            let dup_value = jit_insn_load(self._function, value._value);
-            ~Value { _value: dup_value }
+            let value = jit_insn_load_elem(self._function, self.raw(base_addr), self.raw(index), elem_type._type);
+            self.wrap(value)
         }
     }
 
-    pub fn insn_store(&self, dest: &Value, src: &Value) {
+    pub fn insn_store_elem(&self, base_addr: &Value<'self>, index: &Value<'self>, value: &Value<'self>) {
         unsafe {
-            jit_insn_store(self._function, dest._value, src._value);
+            jit_insn_store_elem(self._function, self.raw(base_addr), self.raw(index), self.raw(value));
+        }
+    }
+
+    pub fn insn_check_null(&self, value: &Value<'self>) {
+        unsafe {
+            jit_insn_check_null(self._function, self.raw(value));
+        }
+    }
+
+    pub fn insn_throw_builtin(&self, exception_type: c_int) {
+        unsafe {
+            let code = self.constant_int32(exception_type as i32);
+            let params: &[&Type] = &[&*Types::get_int()];
+            let signature = Type::create_signature(CDECL, Types::get_void(), params);
+            self.insn_call_native("jit_exception_builtin", transmute(jit_exception_builtin), signature, &[&*code]);
+        }
+    }
+
+    pub fn insn_throw(&self, value: &Value<'self>) {
+        unsafe {
+            jit_insn_throw(self._function, self.raw(value));
+        }
+    }
+
+    pub fn insn_thrown_exception(&self) -> ~Value<'self> {
+        unsafe {
+            let value = jit_insn_thrown_exception(self._function);
+            self.wrap(value)
+        }
+    }
+
+    pub fn insn_start_catcher(&self) {
+        unsafe {
+            jit_insn_start_catcher(self._function);
+        }
+    }
+
+    pub fn insn_rethrow_unhandled(&self) {
+        unsafe {
+            jit_insn_rethrow_unhandled(self._function);
         }
     }
 
@@ -257,65 +904,609 @@ impl Function {
         }
     }
 
-    pub fn insn_branch_if(&self, value: &Value, label: &mut Label) {
+    /**
+     * Branches to the label at the index held in a Value, or falls
+     * through if the index is out of range.
+     */
+    pub fn insn_jump_table(&self, value: &Value<'self>, labels: &mut [~Label]) {
+        unsafe {
+            let mut raw_labels: ~[*c_void] = labels.iter().map(|label| label._label).collect();
+            jit_insn_jump_table(self._function, self.raw(value), vec::raw::to_mut_ptr(raw_labels),
+                                raw_labels.len() as c_uint);
+            for (label, &raw_label) in labels.mut_iter().zip(raw_labels.iter()) {
+                label._label = raw_label;
+            }
+        }
+    }
+
+    pub fn insn_branch_if(&self, value: &Value<'self>, label: &mut Label) {
         unsafe {
             let ptr_label = ptr::to_mut_unsafe_ptr(&mut label._label);
-            jit_insn_branch_if(self._function, value._value, ptr_label as *mut c_void);
+            jit_insn_branch_if(self._function, self.raw(value), ptr_label as *mut c_void);
         }
     }
 
-    pub fn insn_branch_if_not(&self, value: &Value, label: &mut Label) {
+    pub fn insn_branch_if_not(&self, value: &Value<'self>, label: &mut Label) {
         unsafe {
             let ptr_label = ptr::to_mut_unsafe_ptr(&mut label._label);
-            jit_insn_branch_if_not(self._function, value._value, ptr_label as *mut c_void);
+            jit_insn_branch_if_not(self._function, self.raw(value), ptr_label as *mut c_void);
         }
     }
 
-    pub fn apply<T>(&self, args: &[*c_void], retval: &mut T) {
+    pub fn constant_float32(&self, constant: f32) -> ~Value<'self> {
         unsafe {
-            let pargs = vec::raw::to_ptr(args);
-            jit_function_apply(self._function, pargs as **c_void, ptr::to_mut_unsafe_ptr(retval) as *mut c_void);
+            let value = jit_value_create_float32_constant(self._function, jit_type_float32, constant as c_float);
+            self.wrap(value)
         }
     }
 
-    pub fn execute(&self, args: &[*c_void]) {
+    pub fn constant_int32(&self, constant: i32) -> ~Value<'self> {
         unsafe {
-            let pargs = vec::raw::to_ptr(args);
-            jit_function_apply(self._function, pargs as **c_void, ptr::mut_null());
+            let value = jit_value_create_nint_constant(self._function, jit_type_int, constant as intptr_t);
+            self.wrap(value)
         }
     }
 
-    pub fn closure<T>(&self) -> T {
+    pub fn constant_pointer(&self, constant: *c_void) -> ~Value<'self> {
         unsafe {
-            transmute(jit_function_to_closure(self._function))
+            let value = jit_value_create_nint_constant(self._function, jit_type_void_ptr, constant as intptr_t);
+            self.wrap(value)
         }
     }
 
-    pub fn constant_float32(&self, constant: f32) -> ~Value {
+    pub fn constant_float64(&self, constant: f64) -> ~Value<'self> {
         unsafe {
-            let value = jit_value_create_float32_constant(self._function, jit_type_float32, constant as c_float);
-            ~Value { _value: value }
+            let value = jit_value_create_float64_constant(self._function, jit_type_float64, constant as c_double);
+            self.wrap(value)
         }
     }
 
-    pub fn constant_int32(&self, constant: i32) -> ~Value {
+    pub fn constant_long(&self, constant: i64) -> ~Value<'self> {
         unsafe {
-            let value = jit_value_create_nint_constant(self._function, jit_type_int, constant as c_int);
-            ~Value { _value: value }
+            let value = jit_value_create_long_constant(self._function, jit_type_long, constant);
+            self.wrap(value)
         }
     }
 
-    pub fn create_value(&self, value_type: &Type) -> ~Value {
+    pub fn create_value(&self, value_type: &Type) -> ~Value<'self> {
         unsafe {
             let value = jit_value_create(self._function, value_type._type);
-            ~Value { _value: value }
+            self.wrap(value)
         }
     }
 }
 
-#[deriving(Clone)]
-pub struct Value {
-    priv _value: *c_void
+/**
+ * A function that can be run. Functions from build_function are compiled
+ * before they get here, and lazy ones are compiled when they are first called.
+ *
+ * `call`, `call_word` and the `closure` variants check the caller's types
+ * against the signature the function was created with.
+ */
+pub struct CompiledFunction<'self> {
+    priv _context: &'self Context,
+    priv _function: *c_void,
+    priv _signature: *c_void
+}
+
+impl<'self> CompiledFunction<'self> {
+    pub fn dump(&self, name: &str) {
+        dump_function(self._function, name);
+    }
+
+    /**
+     * Returns the type kinds (JIT_TYPE_*) of the function's parameters.
+     */
+    pub fn param_kinds(&self) -> ~[c_int] {
+        unsafe {
+            let count = jit_type_num_params(self._signature);
+            do vec::from_fn(count as uint) |param| {
+                jit_type_get_kind(jit_type_get_param(self._signature, param as c_uint))
+            }
+        }
+    }
+
+    /**
+     * Returns the type kind (JIT_TYPE_*) of the function's return value.
+     */
+    pub fn return_kind(&self) -> c_int {
+        unsafe {
+            jit_type_get_kind(jit_type_get_return(self._signature))
+        }
+    }
+
+    /**
+     * Checks that the function has the given parameter types.
+     */
+    fn check_params(&self, param_kinds: &[c_int]) -> Result<(), CallError> {
+        let expected = self.param_kinds();
+        if expected.len() != param_kinds.len() {
+            return Err(ArityMismatch(expected.len(), param_kinds.len()));
+        }
+        for (index, (&actual, &kind)) in param_kinds.iter().zip(expected.iter()).enumerate() {
+            if actual != kind {
+                return Err(ArgumentTypeMismatch(index));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Checks that the function has the given parameter and return types.
+     */
+    fn check_signature(&self, param_kinds: &[c_int], return_kind: c_int) -> Result<(), CallError> {
+        match self.check_params(param_kinds) {
+            Ok(()) => { }
+            Err(error) => return Err(error)
+        }
+        if return_kind != self.return_kind() {
+            return Err(ReturnTypeMismatch);
+        }
+        Ok(())
+    }
+
+    /**
+     * Calls the function, checking the arguments and the requested
+     * return type against its signature.
+     *
+     * # Arguments
+     *
+     * * args - The arguments.
+     *
+     * Returns the function's result.
+     */
+    pub fn call<R: NativeType>(&self, args: &[&NativeArgument]) -> Result<R, CallError> {
+        let mut retval: R = NativeType::zero();
+        let param_kinds = args.map(|arg| arg.kind());
+        match self.check_signature(param_kinds, retval.kind()) {
+            Ok(()) => { }
+            Err(error) => return Err(error)
+        }
+
+        let pargs = args.map(|arg| arg.address());
+        match unsafe { self.apply(pargs, &mut retval) } {
+            Ok(()) => Ok(retval),
+            Err(exception) => Err(Raised(exception))
+        }
+    }
+
+    /**
+     * Calls a function that returns a 32-bit integer or float, checking
+     * the arguments against its signature, for callers that only know
+     * which of the two it returns once it has been compiled.
+     *
+     * # Arguments
+     *
+     * * args - The arguments.
+     *
+     * Returns the bits of the function's result.
+     */
+    pub fn call_word(&self, args: &[&NativeArgument]) -> Result<u32, CallError> {
+        match self.check_params(args.map(|arg| arg.kind())) {
+            Ok(()) => { }
+            Err(error) => return Err(error)
+        }
+        let return_kind = self.return_kind();
+        if return_kind != JIT_TYPE_INT && return_kind != JIT_TYPE_UINT && return_kind != JIT_TYPE_FLOAT32 {
+            return Err(ReturnTypeMismatch);
+        }
+
+        let mut retval = 0u32;
+        let pargs = args.map(|arg| arg.address());
+        match unsafe { self.apply(pargs, &mut retval) } {
+            Ok(()) => Ok(retval),
+            Err(exception) => Err(Raised(exception))
+        }
+    }
+
+    /**
+     * Returns the function as a closure that takes no arguments, if that
+     * matches its signature. The closure borrows the function, so it
+     * cannot be called once the code is gone.
+     */
+    pub fn closure0<'a, R: NativeType>(&'a self) -> Result<Closure0<'a, R>, CallError> {
+        let retval: R = NativeType::zero();
+        match self.check_signature([], retval.kind()) {
+            Ok(()) => Ok(Closure0 { function: unsafe { self.unchecked_closure() }, _owner: &self._function }),
+            Err(error) => Err(error)
+        }
+    }
+
+    /**
+     * Returns the function as a closure that takes one argument, if that
+     * matches its signature. The closure borrows the function, so it
+     * cannot be called once the code is gone.
+     */
+    pub fn closure1<'a, A: NativeType, R: NativeType>(&'a self) -> Result<Closure1<'a, A, R>, CallError> {
+        let arg: A = NativeType::zero();
+        let retval: R = NativeType::zero();
+        match self.check_signature([arg.kind()], retval.kind()) {
+            Ok(()) => Ok(Closure1 { function: unsafe { self.unchecked_closure() }, _owner: &self._function }),
+            Err(error) => Err(error)
+        }
+    }
+
+    /**
+     * Calls the function without checking anything: args must point at
+     * values of the parameters' types, and retval must be the return type.
+     */
+    unsafe fn apply<T>(&self, args: &[*c_void], retval: &mut T) -> Result<(), JitException> {
+        let pargs = vec::raw::to_ptr(args);
+        if jit_function_apply(self._function, pargs as **c_void, ptr::to_mut_unsafe_ptr(retval) as *mut c_void) != 0 {
+            Ok(())
+        } else {
+            Err(take_last_exception())
+        }
+    }
+
+    /**
+     * Calls the function without checking anything, discarding its result:
+     * args must point at values of the parameters' types.
+     */
+    unsafe fn execute(&self, args: &[*c_void]) -> Result<(), JitException> {
+        let pargs = vec::raw::to_ptr(args);
+        if jit_function_apply(self._function, pargs as **c_void, ptr::mut_null()) != 0 {
+            Ok(())
+        } else {
+            Err(take_last_exception())
+        }
+    }
+
+    /**
+     * Returns the address through which native code can call the function,
+     * such as with Function::insn_call_indirect.
+     */
+    pub fn address(&self) -> *c_void {
+        unsafe {
+            jit_function_to_closure(self._function)
+        }
+    }
+
+    /**
+     * Returns the function as a Rust function of any type. closure0 and
+     * closure1 check the type against the signature first.
+     */
+    unsafe fn unchecked_closure<T>(&self) -> T {
+        transmute(self.address())
+    }
+}
+
+/**
+ * A compiled function that takes no arguments, callable for as long as
+ * the CompiledFunction it came from is borrowed.
+ */
+pub struct Closure0<'self, R> {
+    priv function: extern "C" fn() -> R,
+    priv _owner: &'self *c_void
+}
+
+impl<'self, R> Closure0<'self, R> {
+    /**
+     * Calls the function.
+     */
+    pub fn call(&self) -> R {
+        (self.function)()
+    }
+}
+
+/**
+ * A compiled function that takes one argument, callable for as long as
+ * the CompiledFunction it came from is borrowed.
+ */
+pub struct Closure1<'self, A, R> {
+    priv function: extern "C" fn(A) -> R,
+    priv _owner: &'self *c_void
+}
+
+impl<'self, A, R> Closure1<'self, A, R> {
+    /**
+     * Calls the function.
+     *
+     * # Arguments
+     *
+     * * arg - The argument.
+     */
+    pub fn call(&self, arg: A) -> R {
+        (self.function)(arg)
+    }
+}
+
+/**
+ * Builds the body of a lazy function.
+ */
+pub trait OnDemandBuilder {
+    /// Emits the function's body. Returns whether it could be built.
+    fn build<'f>(&self, function: &'f Function<'f>) -> bool;
+}
+
+/**
+ * What libjit's on-demand compiler callback needs to build a lazy function.
+ */
+struct OnDemandState {
+    builder: @OnDemandBuilder
+}
+
+/**
+ * A function whose body is built and compiled the first time it is called.
+ * Closures taken from it before then compile it when they are first called.
+ */
+pub struct LazyFunction<'self> {
+    priv function: CompiledFunction<'self>,
+    priv state: ~OnDemandState
+}
+
+impl<'self> LazyFunction<'self> {
+    /**
+     * Returns the function to call. Calling it compiles it first if need be.
+     */
+    pub fn callable<'a>(&'a self) -> &'a CompiledFunction<'self> {
+        &self.function
+    }
+
+    /**
+     * Returns whether the function has been compiled yet.
+     */
+    pub fn is_compiled(&self) -> bool {
+        unsafe {
+            jit_function_is_compiled(self.function._function) != 0
+        }
+    }
+}
+
+/**
+ * The Backend that emits libjit IR into the body of a function it borrows.
+ * Whoever created the function compiles it once the backend is gone:
+ * Context::build_function, or libjit itself for a lazy function.
+ */
+pub struct LibJitBackend<'self> {
+    priv function: &'self Function<'self>,
+
+    /// The libjit values and labels behind each ValueId and LabelId.
+    priv values: ~[~Value<'self>],
+    priv labels: ~[~Label]
+}
+
+impl<'self> LibJitBackend<'self> {
+    /**
+     * Creates a backend that builds the body of a function.
+     */
+    pub fn new(function: &'self Function<'self>) -> LibJitBackend<'self> {
+        LibJitBackend {
+            function: function,
+            values: ~[],
+            labels: ~[]
+        }
+    }
+
+    /**
+     * Sets how hard libjit optimizes the function, from 0 up to
+     * Function::max_optimization_level().
+     */
+    pub fn set_optimization_level(&mut self, level: uint) {
+        self.function.set_optimization_level(level);
+    }
+
+    /**
+     * Keeps the function's IR after it is compiled, so that dump shows the IR.
+     */
+    pub fn set_recompilable(&mut self) {
+        self.function.set_recompilable();
+    }
+
+    fn value<'a>(&'a self, value: ValueId) -> &'a Value<'self> {
+        &*self.values[*value]
+    }
+
+    fn push(&mut self, value: ~Value<'self>) -> ValueId {
+        self.values.push(value);
+        ValueId(self.values.len() - 1)
+    }
+}
+
+impl<'self> Backend for LibJitBackend<'self> {
+    fn create_function(&mut self, _params: &[ScalarType], _return_type: ScalarType) {
+        // The function was created with the same signature before the backend.
+    }
+
+    fn finalize(&mut self) {
+        // The function's creator compiles it.
+    }
+
+    fn param(&mut self, index: uint) -> ValueId {
+        let param = ~self.function.get_param(index);
+        self.push(param)
+    }
+
+    fn constant_int32(&mut self, constant: i32) -> ValueId {
+        let value = self.function.constant_int32(constant);
+        self.push(value)
+    }
+
+    fn constant_float32(&mut self, constant: f32) -> ValueId {
+        let value = self.function.constant_float32(constant);
+        self.push(value)
+    }
+
+    fn constant_pointer(&mut self, constant: *c_void) -> ValueId {
+        let value = self.function.constant_pointer(constant);
+        self.push(value)
+    }
+
+    fn create_local(&mut self, value_type: ScalarType) -> ValueId {
+        let value = self.function.create_value(Types::get_scalar(value_type));
+        self.push(value)
+    }
+
+    fn store(&mut self, local: ValueId, value: ValueId) {
+        self.function.insn_store(self.value(local), self.value(value));
+    }
+
+    fn copy(&mut self, value: ValueId) -> ValueId {
+        let copy = self.function.insn_dup(self.value(value));
+        self.push(copy)
+    }
+
+    fn binary(&mut self, op: BinaryOp, v1: ValueId, v2: ValueId) -> ValueId {
+        // The operands borrow the backend until the result is built.
+        let result = {
+            let v1 = self.value(v1);
+            let v2 = self.value(v2);
+            match op {
+                AddOp => self.function.insn_add(v1, v2),
+                SubOp => self.function.insn_sub(v1, v2),
+                MulOp => self.function.insn_mul(v1, v2),
+                DivOp => self.function.insn_div(v1, v2),
+                RemOp => self.function.insn_rem(v1, v2),
+                AddOvfOp => self.function.insn_add_ovf(v1, v2),
+                SubOvfOp => self.function.insn_sub_ovf(v1, v2),
+                MulOvfOp => self.function.insn_mul_ovf(v1, v2),
+                AndOp => self.function.insn_and(v1, v2),
+                OrOp => self.function.insn_or(v1, v2),
+                XorOp => self.function.insn_xor(v1, v2),
+                ShlOp => self.function.insn_shl(v1, v2),
+                ShrOp => self.function.insn_shr(v1, v2),
+                SshrOp => self.function.insn_sshr(v1, v2),
+                MinOp => self.function.insn_min(v1, v2),
+                MaxOp => self.function.insn_max(v1, v2),
+                PowOp => self.function.insn_pow(v1, v2),
+                EqOp => self.function.insn_eq(v1, v2),
+                NeqOp => self.function.insn_neq(v1, v2),
+                LtOp => self.function.insn_lt(v1, v2),
+                GtOp => self.function.insn_gt(v1, v2),
+                LeqOp => self.function.insn_leq(v1, v2),
+                GeqOp => self.function.insn_geq(v1, v2)
+            }
+        };
+        self.push(result)
+    }
+
+    fn unary(&mut self, op: UnaryOp, value: ValueId) -> ValueId {
+        let result = {
+            let value = self.value(value);
+            match op {
+                NegOp => self.function.insn_neg(value),
+                NotOp => self.function.insn_not(value),
+                AbsOp => self.function.insn_abs(value),
+                SqrtOp => self.function.insn_sqrt(value),
+                FloorOp => self.function.insn_floor(value),
+                CeilOp => self.function.insn_ceil(value),
+                RoundOp => self.function.insn_round(value),
+                TruncOp => self.function.insn_trunc(value),
+                SinOp => self.function.insn_sin(value),
+                CosOp => self.function.insn_cos(value),
+                TanOp => self.function.insn_tan(value),
+                ExpOp => self.function.insn_exp(value),
+                LogOp => self.function.insn_log(value)
+            }
+        };
+        self.push(result)
+    }
+
+    fn convert(&mut self, value: ValueId, value_type: ScalarType) -> ValueId {
+        let converted = self.function.insn_convert(self.value(value), Types::get_scalar(value_type), false);
+        self.push(converted)
+    }
+
+    fn load_relative(&mut self, address: ValueId, offset: int, value_type: ScalarType) -> ValueId {
+        let loaded = self.function.insn_load_relative(self.value(address), offset, Types::get_scalar(value_type));
+        self.push(loaded)
+    }
+
+    fn store_relative(&mut self, address: ValueId, offset: int, value: ValueId) {
+        self.function.insn_store_relative(self.value(address), offset, self.value(value));
+    }
+
+    fn add_relative(&mut self, address: ValueId, offset: int) -> ValueId {
+        let result = self.function.insn_add_relative(self.value(address), offset);
+        self.push(result)
+    }
+
+    fn load_elem(&mut self, address: ValueId, index: ValueId, value_type: ScalarType) -> ValueId {
+        let loaded = self.function.insn_load_elem(self.value(address), self.value(index),
+                                                  Types::get_scalar(value_type));
+        self.push(loaded)
+    }
+
+    fn store_elem(&mut self, address: ValueId, index: ValueId, value: ValueId) {
+        self.function.insn_store_elem(self.value(address), self.value(index), self.value(value));
+    }
+
+    fn check_null(&mut self, value: ValueId) {
+        self.function.insn_check_null(self.value(value));
+    }
+
+    fn call_native(&mut self, name: &str, address: *c_void, return_type: ScalarType, param_types: &[ScalarType],
+                   args: &[ValueId]) -> ValueId {
+        let signature = Type::create_scalar_signature(param_types, return_type);
+        let result = {
+            let values = args.map(|&arg| self.value(arg));
+            self.function.insn_call_native(name, address, signature, values)
+        };
+        self.push(result)
+    }
+
+    fn new_label(&mut self) -> LabelId {
+        self.labels.push(Label::new());
+        LabelId(self.labels.len() - 1)
+    }
+
+    fn set_label(&mut self, label: LabelId) {
+        self.function.insn_set_label(&mut *self.labels[*label]);
+    }
+
+    fn branch(&mut self, label: LabelId) {
+        self.function.insn_branch(&mut *self.labels[*label]);
+    }
+
+    fn branch_if(&mut self, condition: ValueId, label: LabelId) {
+        self.function.insn_branch_if(&*self.values[*condition], &mut *self.labels[*label]);
+    }
+
+    fn branch_if_not(&mut self, condition: ValueId, label: LabelId) {
+        self.function.insn_branch_if_not(&*self.values[*condition], &mut *self.labels[*label]);
+    }
+
+    fn ret(&mut self, value: ValueId) {
+        self.function.insn_return(self.value(value));
+    }
+
+    fn throw(&mut self, value: ValueId) {
+        self.function.insn_throw(self.value(value));
+    }
+
+    fn throw_builtin(&mut self, exception_type: c_int) {
+        self.function.insn_throw_builtin(exception_type);
+    }
+
+    fn start_catcher(&mut self) {
+        self.function.insn_start_catcher();
+    }
+
+    fn thrown_exception(&mut self) -> ValueId {
+        let value = self.function.insn_thrown_exception();
+        self.push(value)
+    }
+
+    fn rethrow_unhandled(&mut self) {
+        self.function.insn_rethrow_unhandled();
+    }
+}
+
+fn dump_function(function: *c_void, name: &str) {
+    unsafe {
+        name.as_c_str(|c_str| {
+            jit_dump_function(rustrt::rust_get_stdout(), function, c_str);
+        });
+    }
+}
+
+/**
+ * A value in a function under construction. It carries the function's
+ * brand, so it can only be used with that function.
+ */
+pub struct Value<'self> {
+    priv _value: *c_void,
+    priv _brand: *mut &'self ()
 }
 
 pub struct Label {
@@ -342,7 +1533,65 @@ impl Types {
         ~Type { _type: jit_type_int }   
     }
 
+    pub fn get_uint() -> ~Type {
+        ~Type { _type: jit_type_uint }   
+    }
+
+    pub fn get_void_ptr() -> ~Type {
+        ~Type { _type: jit_type_void_ptr }   
+    }
+
+    pub fn get_nint() -> ~Type {
+        ~Type { _type: jit_type_nint }   
+    }
+
     pub fn get_float32() -> ~Type {
         ~Type { _type: jit_type_float32 }   
     }
+
+    pub fn get_float64() -> ~Type {
+        ~Type { _type: jit_type_float64 }
+    }
+
+    pub fn get_nuint() -> ~Type {
+        ~Type { _type: jit_type_nuint }
+    }
+
+    pub fn get_sbyte() -> ~Type {
+        ~Type { _type: jit_type_sbyte }
+    }
+
+    pub fn get_ubyte() -> ~Type {
+        ~Type { _type: jit_type_ubyte }
+    }
+
+    pub fn get_short() -> ~Type {
+        ~Type { _type: jit_type_short }
+    }
+
+    pub fn get_ushort() -> ~Type {
+        ~Type { _type: jit_type_ushort }
+    }
+
+    pub fn get_long() -> ~Type {
+        ~Type { _type: jit_type_long }
+    }
+
+    pub fn get_ulong() -> ~Type {
+        ~Type { _type: jit_type_ulong }
+    }
+
+    /**
+     * Returns the libjit type for one of a Backend's machine types.
+     */
+    pub fn get_scalar(scalar_type: ScalarType) -> ~Type {
+        match scalar_type {
+            IntType => Types::get_int(),
+            UIntType => Types::get_uint(),
+            NIntType => Types::get_nint(),
+            FloatType => Types::get_float32(),
+            PointerType => Types::get_void_ptr(),
+            VoidType => Types::get_void()
+        }
+    }
 }
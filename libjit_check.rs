@@ -0,0 +1,453 @@
+use libjit::*;
+use backend::*;
+use std::cast::transmute;
+use std::libc::*;
+use std::ptr;
+use std::os;
+
+mod backend;
+mod libjit;
+
+/**
+ * Builds, compiles and runs tiny functions, counting those that
+ * do not return what they should.
+ */
+struct Checker<'self> {
+    context: &'self Context,
+    checked: @mut uint,
+    failures: @mut uint
+}
+
+impl<'self> Checker<'self> {
+    /**
+     * Builds and compiles a function.
+     *
+     * # Arguments
+     *
+     * * params      - The parameter types.
+     * * return_type - The return type.
+     * * build       - Emits the function's body.
+     */
+    fn build(&self, params: &[&Type], return_type: &Type,
+             build: &fn<'f>(function: &'f Function<'f>)) -> ~CompiledFunction<'self> {
+        self.context.build_start();
+        let signature = Type::create_signature(CDECL, return_type, params);
+        let compiled = self.context.build_function(signature, build);
+        self.context.build_end();
+        compiled
+    }
+
+    /**
+     * Builds a function, calls it, and checks its result.
+     *
+     * # Arguments
+     *
+     * * name        - The name of the check.
+     * * params      - The parameter types.
+     * * return_type - The return type.
+     * * args        - The arguments to call the function with.
+     * * expected    - The result the function must return.
+     * * build       - Emits the function's body.
+     */
+    fn check<R: NativeType + Eq>(&self, name: &str, params: &[&Type], return_type: &Type, args: &[&NativeArgument],
+                                 expected: R, build: &fn<'f>(function: &'f Function<'f>)) {
+        let compiled = self.build(params, return_type, build);
+        match compiled.call::<R>(args) {
+            Ok(ref result) if *result == expected => self.pass(),
+            result => self.fail(name, fmt!("expected %?, got %?", expected, result))
+        }
+    }
+
+    /**
+     * Builds a function, calls it, and checks that it raises a builtin exception.
+     *
+     * # Arguments
+     *
+     * * name     - The name of the check.
+     * * expected - The JIT_RESULT_* code the function must raise.
+     * * build    - Emits the function's body, which takes and returns an int.
+     */
+    fn check_raises(&self, name: &str, expected: c_int, build: &fn<'f>(function: &'f Function<'f>)) {
+        let int = Types::get_int();
+        let compiled = self.build([&*int], int, build);
+        let arg = 0i32;
+        match compiled.call::<i32>([&arg as &NativeArgument]) {
+            Err(Raised(Builtin(code))) if code == expected => self.pass(),
+            result => self.fail(name, fmt!("expected exception %d, got %?", expected as int, result))
+        }
+    }
+
+    /**
+     * Checks a condition on the host side.
+     */
+    fn check_that(&self, name: &str, condition: bool) {
+        if condition {
+            self.pass();
+        } else {
+            self.fail(name, ~"condition does not hold");
+        }
+    }
+
+    fn pass(&self) {
+        *self.checked += 1;
+    }
+
+    fn fail(&self, name: &str, message: ~str) {
+        *self.checked += 1;
+        *self.failures += 1;
+        println(fmt!("FAIL %s: %s", name, message));
+    }
+}
+
+/**
+ * Checks a function of two ints that returns an int.
+ */
+fn int_binary(checker: &Checker, name: &str, x: i32, y: i32, expected: i32, emit: &fn<'f>(&'f Function<'f>, &Value<'f>, &Value<'f>)) {
+    let int = Types::get_int();
+    do checker.check(name, [&*int, &*int], int, [&x as &NativeArgument, &y as &NativeArgument], expected) |f| {
+        let a = f.get_param(0);
+        let b = f.get_param(1);
+        emit(f, &a, &b);
+    }
+}
+
+/**
+ * Checks a function of an int that returns an int.
+ */
+fn int_unary(checker: &Checker, name: &str, x: i32, expected: i32, emit: &fn<'f>(&'f Function<'f>, &Value<'f>)) {
+    let int = Types::get_int();
+    do checker.check(name, [&*int], int, [&x as &NativeArgument], expected) |f| {
+        let a = f.get_param(0);
+        emit(f, &a);
+    }
+}
+
+/**
+ * Checks a function of two floats that returns a float.
+ */
+fn float_binary(checker: &Checker, name: &str, x: f32, y: f32, expected: f32, emit: &fn<'f>(&'f Function<'f>, &Value<'f>, &Value<'f>)) {
+    let float32 = Types::get_float32();
+    do checker.check(name, [&*float32, &*float32], float32, [&x as &NativeArgument, &y as &NativeArgument],
+                     expected) |f| {
+        let a = f.get_param(0);
+        let b = f.get_param(1);
+        emit(f, &a, &b);
+    }
+}
+
+/**
+ * Checks a function of a float that returns a float.
+ */
+fn float_unary(checker: &Checker, name: &str, x: f32, expected: f32, emit: &fn<'f>(&'f Function<'f>, &Value<'f>)) {
+    let float32 = Types::get_float32();
+    do checker.check(name, [&*float32], float32, [&x as &NativeArgument], expected) |f| {
+        let a = f.get_param(0);
+        emit(f, &a);
+    }
+}
+
+/**
+ * A native function for insn_call_native to call.
+ */
+extern "C" fn triple(x: c_int) -> c_int {
+    x * 3
+}
+
+fn check_arithmetic(checker: &Checker) {
+    do int_binary(checker, "add", 2, 3, 5) |f, a, b| { f.insn_return(f.insn_add(a, b)) }
+    do int_binary(checker, "sub", 2, 3, -1) |f, a, b| { f.insn_return(f.insn_sub(a, b)) }
+    do int_binary(checker, "mul", 6, 7, 42) |f, a, b| { f.insn_return(f.insn_mul(a, b)) }
+    do int_binary(checker, "div", -7, 2, -3) |f, a, b| { f.insn_return(f.insn_div(a, b)) }
+    do int_binary(checker, "rem", -7, 2, -1) |f, a, b| { f.insn_return(f.insn_rem(a, b)) }
+    do int_binary(checker, "min", -7, 2, -7) |f, a, b| { f.insn_return(f.insn_min(a, b)) }
+    do int_binary(checker, "max", -7, 2, 2) |f, a, b| { f.insn_return(f.insn_max(a, b)) }
+    do int_unary(checker, "neg", 5, -5) |f, a| { f.insn_return(f.insn_neg(a)) }
+    do int_unary(checker, "abs", -5, 5) |f, a| { f.insn_return(f.insn_abs(a)) }
+
+    do int_binary(checker, "add_ovf", 2, 3, 5) |f, a, b| { f.insn_return(f.insn_add_ovf(a, b)) }
+    do int_binary(checker, "sub_ovf", 2, 3, -1) |f, a, b| { f.insn_return(f.insn_sub_ovf(a, b)) }
+    do int_binary(checker, "mul_ovf", 6, 7, 42) |f, a, b| { f.insn_return(f.insn_mul_ovf(a, b)) }
+    do checker.check_raises("add_ovf overflows", JIT_RESULT_OVERFLOW) |f| {
+        f.insn_return(f.insn_add_ovf(f.constant_int32(0x7fffffff), f.constant_int32(1)));
+    }
+
+    do float_binary(checker, "float add", 0.5, 0.25, 0.75) |f, a, b| { f.insn_return(f.insn_add(a, b)) }
+    do float_binary(checker, "float div", 1.0, 4.0, 0.25) |f, a, b| { f.insn_return(f.insn_div(a, b)) }
+    do float_binary(checker, "pow", 2.0, 10.0, 1024.0) |f, a, b| { f.insn_return(f.insn_pow(a, b)) }
+    do float_binary(checker, "atan2", 0.0, 1.0, 0.0) |f, a, b| { f.insn_return(f.insn_atan2(a, b)) }
+    do float_unary(checker, "float neg", 2.5, -2.5) |f, a| { f.insn_return(f.insn_neg(a)) }
+    do float_unary(checker, "float abs", -2.5, 2.5) |f, a| { f.insn_return(f.insn_abs(a)) }
+    do float_unary(checker, "sqrt", 16.0, 4.0) |f, a| { f.insn_return(f.insn_sqrt(a)) }
+    do float_unary(checker, "floor", 2.5, 2.0) |f, a| { f.insn_return(f.insn_floor(a)) }
+    do float_unary(checker, "ceil", 2.5, 3.0) |f, a| { f.insn_return(f.insn_ceil(a)) }
+    do float_unary(checker, "round", 2.5, 3.0) |f, a| { f.insn_return(f.insn_round(a)) }
+    do float_unary(checker, "rint", 2.5, 2.0) |f, a| { f.insn_return(f.insn_rint(a)) }
+    do float_unary(checker, "trunc", -2.5, -2.0) |f, a| { f.insn_return(f.insn_trunc(a)) }
+    do float_unary(checker, "sin", 0.0, 0.0) |f, a| { f.insn_return(f.insn_sin(a)) }
+    do float_unary(checker, "cos", 0.0, 1.0) |f, a| { f.insn_return(f.insn_cos(a)) }
+    do float_unary(checker, "tan", 0.0, 0.0) |f, a| { f.insn_return(f.insn_tan(a)) }
+    do float_unary(checker, "exp", 0.0, 1.0) |f, a| { f.insn_return(f.insn_exp(a)) }
+    do float_unary(checker, "log", 1.0, 0.0) |f, a| { f.insn_return(f.insn_log(a)) }
+    do float_unary(checker, "log10", 100.0, 2.0) |f, a| { f.insn_return(f.insn_log10(a)) }
+}
+
+fn check_bitwise(checker: &Checker) {
+    do int_binary(checker, "and", 12, 10, 8) |f, a, b| { f.insn_return(f.insn_and(a, b)) }
+    do int_binary(checker, "or", 12, 10, 14) |f, a, b| { f.insn_return(f.insn_or(a, b)) }
+    do int_binary(checker, "xor", 12, 10, 6) |f, a, b| { f.insn_return(f.insn_xor(a, b)) }
+    do int_unary(checker, "not", 0, -1) |f, a| { f.insn_return(f.insn_not(a)) }
+    do int_binary(checker, "shl", 1, 4, 16) |f, a, b| { f.insn_return(f.insn_shl(a, b)) }
+    do int_binary(checker, "shr", -8, 1, -4) |f, a, b| { f.insn_return(f.insn_shr(a, b)) }
+    do int_binary(checker, "sshr", -8, 1, -4) |f, a, b| { f.insn_return(f.insn_sshr(a, b)) }
+    do int_binary(checker, "ushr", -1, 28, 15) |f, a, b| { f.insn_return(f.insn_ushr(a, b)) }
+}
+
+fn check_comparisons(checker: &Checker) {
+    do int_binary(checker, "leq", 2, 2, 1) |f, a, b| { f.insn_return(f.insn_leq(a, b)) }
+    do int_binary(checker, "geq", 1, 2, 0) |f, a, b| { f.insn_return(f.insn_geq(a, b)) }
+    do int_binary(checker, "lt", 1, 2, 1) |f, a, b| { f.insn_return(f.insn_lt(a, b)) }
+    do int_binary(checker, "gt", 1, 2, 0) |f, a, b| { f.insn_return(f.insn_gt(a, b)) }
+    do int_binary(checker, "eq", 2, 2, 1) |f, a, b| { f.insn_return(f.insn_eq(a, b)) }
+    do int_binary(checker, "neq", 2, 2, 0) |f, a, b| { f.insn_return(f.insn_neq(a, b)) }
+    do int_unary(checker, "to_bool", 7, 1) |f, a| { f.insn_return(f.insn_to_bool(a)) }
+    do int_unary(checker, "to_not_bool", 7, 0) |f, a| { f.insn_return(f.insn_to_not_bool(a)) }
+}
+
+fn check_values(checker: &Checker) {
+    let int = Types::get_int();
+    let float32 = Types::get_float32();
+    let float64 = Types::get_float64();
+    let long = Types::get_long();
+
+    do checker.check("constant_int32", [], int, [], 42i32) |f| {
+        f.insn_return(f.constant_int32(42));
+    }
+    do checker.check("constant_float32", [], float32, [], 0.5f32) |f| {
+        f.insn_return(f.constant_float32(0.5));
+    }
+    do checker.check("constant_float64", [], float64, [], 2.5f64) |f| {
+        f.insn_return(f.constant_float64(2.5));
+    }
+    do checker.check("constant_long", [], long, [], 1i64 << 40) |f| {
+        f.insn_return(f.constant_long(1i64 << 40));
+    }
+    do checker.check("default_return", [], int, [], 0i32) |f| {
+        f.insn_default_return();
+    }
+    do int_unary(checker, "create_value and store", 7, 7) |f, a| {
+        let local = f.create_value(int);
+        f.insn_store(local, a);
+        f.insn_return(local);
+    }
+    do int_unary(checker, "dup", 7, 14) |f, a| {
+        let copy = f.insn_dup(a);
+        f.insn_return(f.insn_add(a, copy));
+    }
+    do float_unary(checker, "convert to int", 2.75, 2.0) |f, a| {
+        let truncated = f.insn_convert(a, int, false);
+        f.insn_return(f.insn_convert(truncated, float32, false));
+    }
+}
+
+fn check_memory(checker: &Checker) {
+    let int = Types::get_int();
+    let float64 = Types::get_float64();
+
+    do int_unary(checker, "address_of", 7, 7) |f, a| {
+        let local = f.create_value(int);
+        f.set_addressable(local);
+        f.insn_store(local, a);
+        let address = f.insn_address_of(local);
+        f.insn_return(f.insn_load_relative(address, 0, int));
+    }
+    do int_unary(checker, "alloca and relative access", 7, 7) |f, a| {
+        let buffer = f.insn_alloca(f.constant_int32(8));
+        f.insn_store_relative(buffer, 4, a);
+        let field = f.insn_add_relative(buffer, 4);
+        f.insn_return(f.insn_load_relative(field, 0, int));
+    }
+    do int_unary(checker, "elements", 7, 7) |f, a| {
+        let buffer = f.insn_alloca(f.constant_int32(12));
+        let index = f.constant_int32(2);
+        f.insn_store_elem(buffer, index, a);
+        f.insn_return(f.insn_load_elem(buffer, index, int));
+    }
+    do int_unary(checker, "memset and memcpy", 1, 0x01010101) |f, a| {
+        let buffer = f.insn_alloca(f.constant_int32(8));
+        let size = f.constant_int32(4);
+        f.insn_memset(buffer, a, size);
+        f.insn_memcpy(f.insn_add_relative(buffer, 4), buffer, size);
+        f.insn_return(f.insn_load_relative(buffer, 4, int));
+    }
+
+    let pair = Type::create_struct([&*int, &*float64]);
+    checker.check_that("struct size", pair.size() == 16);
+    checker.check_that("struct offset", pair.offset(1) == 8);
+    do checker.check("struct field", [&*float64], float64, [&2.5f64 as &NativeArgument], 2.5f64) |f| {
+        let buffer = f.insn_alloca(f.constant_int32(pair.size() as i32));
+        f.insn_store_relative(buffer, pair.offset(1) as int, &f.get_param(0));
+        f.insn_return(f.insn_load_relative(buffer, pair.offset(1) as int, float64));
+    }
+
+    let int_pointer = Type::create_pointer(int);
+    checker.check_that("pointer kind", int_pointer.kind() == JIT_TYPE_PTR);
+    let target = 7i32;
+    let address = ptr::to_unsafe_ptr(&target) as *c_void;
+    do checker.check("pointer parameter", [&*int_pointer], int, [&address as &NativeArgument], 7i32) |f| {
+        f.insn_return(f.insn_load_relative(&f.get_param(0), 0, int));
+    }
+}
+
+fn check_control_flow(checker: &Checker) {
+    do int_unary(checker, "branch", 7, 7) |f, a| {
+        let mut end = Label::new();
+        f.insn_branch(end);
+        f.insn_return(f.constant_int32(0));
+        f.insn_set_label(end);
+        f.insn_return(a);
+    }
+    do int_unary(checker, "branch_if", 7, 1) |f, a| {
+        let mut taken = Label::new();
+        f.insn_branch_if(a, taken);
+        f.insn_return(f.constant_int32(0));
+        f.insn_set_label(taken);
+        f.insn_return(f.constant_int32(1));
+    }
+    do int_unary(checker, "branch_if_not", 7, 0) |f, a| {
+        let mut taken = Label::new();
+        f.insn_branch_if_not(a, taken);
+        f.insn_return(f.constant_int32(0));
+        f.insn_set_label(taken);
+        f.insn_return(f.constant_int32(1));
+    }
+    do int_unary(checker, "insn_label", 3, 0) |f, a| {
+        // Count a down to zero, looping back to a label placed in passing.
+        let counter = f.create_value(Types::get_int());
+        f.insn_store(counter, a);
+        let mut test = f.insn_label();
+        let mut end = Label::new();
+        f.insn_branch_if_not(counter, end);
+        f.insn_store(counter, f.insn_sub(counter, f.constant_int32(1)));
+        f.insn_branch(test);
+        f.insn_set_label(end);
+        f.insn_return(counter);
+    }
+    for &(index, expected) in [(0i32, 10i32), (2, 12), (3, -1)].iter() {
+        do int_unary(checker, fmt!("jump_table %d", index as int), index, expected) |f, a| {
+            let mut labels = ~[Label::new(), Label::new(), Label::new()];
+            f.insn_jump_table(a, labels);
+            f.insn_return(f.constant_int32(-1));
+            for (offset, label) in labels.mut_iter().enumerate() {
+                f.insn_set_label(*label);
+                f.insn_return(f.constant_int32(10 + offset as i32));
+            }
+        }
+    }
+}
+
+fn check_calls(checker: &Checker) {
+    let int = Types::get_int();
+    let int_params: &[&Type] = &[&*int];
+
+    let double = do checker.build(int_params, int) |f| {
+        let x = f.get_param(0);
+        f.insn_return(f.insn_add(&x, &x));
+    };
+    do int_unary(checker, "call", 21, 42) |f, a| {
+        f.insn_return(f.insn_call("double", double, [a]));
+    }
+
+    let address = double.address();
+    let signature = Type::create_signature(CDECL, int, int_params);
+    do int_unary(checker, "call_indirect", 21, 42) |f, a| {
+        let callee = f.constant_pointer(address);
+        f.insn_return(f.insn_call_indirect(callee, signature, [a]));
+    }
+    do int_unary(checker, "call_native", 5, 15) |f, a| {
+        let native: *c_void = unsafe { transmute(triple) };
+        f.insn_return(f.insn_call_native("triple", native, signature, [a]));
+    }
+
+    match double.call::<i32>([]) {
+        Err(ArityMismatch(1, 0)) => checker.pass(),
+        result => checker.fail("call checks arity", fmt!("%?", result))
+    }
+    let wrong = 1f32;
+    match double.call::<i32>([&wrong as &NativeArgument]) {
+        Err(ArgumentTypeMismatch(0)) => checker.pass(),
+        result => checker.fail("call checks argument types", fmt!("%?", result))
+    }
+    match double.closure1::<i32, f32>() {
+        Err(ReturnTypeMismatch) => checker.pass(),
+        _ => checker.fail("closure checks the return type", ~"closure returned")
+    }
+    match double.closure1::<i32, i32>() {
+        Ok(f) if f.call(4) == 8 => checker.pass(),
+        _ => checker.fail("closure1", ~"wrong result")
+    }
+}
+
+fn check_exceptions(checker: &Checker) {
+    do int_unary(checker, "throw and catch", 0, 8) |f, _| {
+        f.insn_throw(f.constant_pointer(8 as *c_void));
+        f.insn_default_return();
+        f.insn_start_catcher();
+        f.insn_return(f.insn_convert(f.insn_thrown_exception(), Types::get_int(), false));
+    }
+    do checker.check_raises("rethrow_unhandled", JIT_RESULT_ARITHMETIC) |f| {
+        f.insn_throw_builtin(JIT_RESULT_ARITHMETIC);
+        f.insn_default_return();
+        f.insn_start_catcher();
+        f.insn_rethrow_unhandled();
+    }
+    do checker.check_raises("throw_builtin", JIT_RESULT_DIVISION_BY_ZERO) |f| {
+        f.insn_throw_builtin(JIT_RESULT_DIVISION_BY_ZERO);
+        f.insn_default_return();
+    }
+    do checker.check_raises("check_null", JIT_RESULT_NULL_REFERENCE) |f| {
+        f.insn_check_null(f.constant_pointer(ptr::null()));
+        f.insn_default_return();
+    }
+}
+
+fn check_optimization_levels(checker: &Checker) {
+    let int = Types::get_int();
+    let max = Function::max_optimization_level();
+    for level in range(0, max + 1) {
+        do checker.check(fmt!("optimization level %u", level), [], int, [], 42i32) |f| {
+            f.set_optimization_level(level);
+            if f.optimization_level() != level {
+                fail!("optimization level not set");
+            }
+            f.insn_return(f.insn_mul(f.constant_int32(6), f.constant_int32(7)));
+        }
+    }
+}
+
+/**
+ * Builds and runs a tiny function for each binding in libjit.rs.
+ * To run:
+ *
+ * > rust run libjit_check.rs
+ */
+fn main() {
+    let context = Context::new();
+    let checker = Checker { context: context, checked: @mut 0, failures: @mut 0 };
+
+    check_arithmetic(&checker);
+    check_bitwise(&checker);
+    check_comparisons(&checker);
+    check_values(&checker);
+    check_memory(&checker);
+    check_control_flow(&checker);
+    check_calls(&checker);
+    check_exceptions(&checker);
+    check_optimization_levels(&checker);
+
+    if *checker.failures > 0 {
+        println(fmt!("%u of %u checks failed", *checker.failures, *checker.checked));
+        os::set_exit_status(1);
+    } else {
+        println(fmt!("All %u checks passed", *checker.checked));
+    }
+}
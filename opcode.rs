@@ -1,20 +1,29 @@
+use variable_type::*;
+
 /**
  * The VM instruction opcodes.
  */
+#[deriving(Clone)]
 #[deriving(ToStr)]
+#[deriving(IterBytes)]
 pub enum Opcode {
     /// No operation
     Nop,
     
     /// Binary opcodes - pop 2 values from
-    /// the stack and push the result.
+    /// the stack and push the result. See
+    /// StackValue for their numeric semantics.
     Add,
     Subtract,
     Multiply,
     Divide,
+
+    /// Integer-only bitwise opcodes.
     And,
     Or,
     Xor,
+
+    /// Comparisons, which push 1 or 0.
     Eq,
     Neq,
     Leq,
@@ -22,16 +31,77 @@ pub enum Opcode {
     Lt,
     Gt,
 
+    /// Integer-only binary opcodes. Float operands
+    /// are rejected by type inference, as they are
+    /// for And, Or, Xor and Not.
+    Rem,
+    Shl,
+    /// Arithmetic (sign-extending) shift right.
+    Shr,
+    /// Logical (zero-filling) shift right.
+    Ushr,
+    /// Comparisons treating both operands as unsigned.
+    Ltu,
+    Gtu,
+    Lequ,
+    Gequ,
+
+    /// Integer-only arithmetic that raises
+    /// Overflow instead of wrapping around.
+    AddOvf,
+    SubOvf,
+    MulOvf,
+
+    /// Binary math intrinsics. Min and Max keep
+    /// the operand type; Pow always yields a float.
+    Min,
+    Max,
+    Pow,
+
     /// Unary opcodes -- pop a value from
     /// the stack and push the result.
     Negate,
     Not,
 
+    /// Unary math intrinsics. Abs keeps the operand
+    /// type; the rest convert their operand to a
+    /// float and yield a float.
+    Abs,
+    Sqrt,
+    Floor,
+    Ceil,
+    Round,
+    Trunc,
+    Sin,
+    Cos,
+    Tan,
+    Exp,
+    Log,
+
     /// Load constant opcodes - pushes the
     /// specified constant value on the stack.
     Constf32(f32),
     Consti32(i32),
 
+    /// Pushes a new string holding the specified
+    /// entry of the module's constant pool.
+    ConstStr(u32),
+
+    /// String opcodes. Concat pops 2 strings and
+    /// pushes their concatenation; StrLen pops a
+    /// string and pushes its length in characters;
+    /// StrCmp pops 2 strings and pushes -1, 0 or 1
+    /// as the first is less than, equal to or
+    /// greater than the second.
+    Concat,
+    StrLen,
+    StrCmp,
+
+    /// Pops a number and pushes its decimal
+    /// string representation.
+    IntToStr,
+    FloatToStr,
+
     /// Pops a value from the stack and returns it.
     Ret,
 
@@ -46,6 +116,54 @@ pub enum Opcode {
     /// variable location and pushes it on the stack.
     Loadf32(u32),
     Loadi32(u32),
+    Loadref(u32),
+
+    /// Pops a length and pushes a new zero-filled
+    /// array with elements of the specified type.
+    NewArray(VariableType),
+
+    /// Pops an array and pushes its length.
+    ArrayLen,
+
+    /// Pops an index and an array and pushes the
+    /// element at that index. The array must have
+    /// elements of the specified type.
+    ArrayLoad(VariableType),
+
+    /// Pops a value, an index and an array and
+    /// stores the value at that index. The array
+    /// must have elements of the specified type.
+    ArrayStore(VariableType),
+
+    /// Pushes a new boxed object with the
+    /// specified number of fields.
+    Alloc(u32),
+
+    /// Pops an object and pushes the value of
+    /// the specified field, which must be of
+    /// the specified type.
+    GetField(u32, VariableType),
+
+    /// Pops a value and an object and stores
+    /// the value, of the specified type, in the
+    /// specified field.
+    SetField(u32, VariableType),
+
+    /// Pops an integer and throws it as an
+    /// exception. Negative values are those of
+    /// the VM's own errors, so handlers do not
+    /// catch them.
+    Throw,
+
+    /// Installs the handler at the specified
+    /// address for exceptions thrown until the
+    /// matching TryEnd. The handler starts with
+    /// only the exception value on the stack, and
+    /// can only be entered by an exception.
+    TryBegin(u32),
+
+    /// Removes the most recently installed handler.
+    TryEnd,
 
     /// Jumps to the instruction at the 
     /// specified address.
@@ -0,0 +1,103 @@
+use opcode::*;
+use basic_block::*;
+
+/**
+ * Runs the bytecode passes over a function. The passes only replace
+ * opcodes, never insert or remove them, so offsets stay valid.
+ *
+ * # Arguments
+ *
+ * * function - The function to optimize.
+ *
+ * Returns the optimized function.
+ */
+pub fn optimize(function: &[Opcode]) -> ~[Opcode] {
+    let threaded = thread_jumps(function);
+    fold_constants(threaded)
+}
+
+/**
+ * Retargets jumps whose target is an unconditional jump
+ * to that jump's own target.
+ *
+ * # Arguments
+ *
+ * * function - The function.
+ */
+pub fn thread_jumps(function: &[Opcode]) -> ~[Opcode] {
+    do function.map |opcode| {
+        match *opcode {
+            Jmp(target) => Jmp(final_target(function, target)),
+            Iftrue(target) => Iftrue(final_target(function, target)),
+            _ => opcode.clone()
+        }
+    }
+}
+
+/**
+ * Follows a chain of unconditional jumps, stopping if it loops.
+ *
+ * # Arguments
+ *
+ * * function - The function.
+ * * target   - Where the chain starts.
+ */
+fn final_target(function: &[Opcode], target: u32) -> u32 {
+    let mut target = target;
+    for _ in range(0, function.len()) {
+        if target as uint >= function.len() {
+            break;
+        }
+        match function[target] {
+            Jmp(next) => target = next,
+            _ => break
+        }
+    }
+    target
+}
+
+/**
+ * Replaces Add, Subtract and Multiply of two constants of the same type
+ * with their result, padding with Nop. Integer results wrap, as they do
+ * when interpreted. Opcodes are only folded within a basic block.
+ *
+ * # Arguments
+ *
+ * * function - The function.
+ */
+pub fn fold_constants(function: &[Opcode]) -> ~[Opcode] {
+    let starts = get_basic_blocks(function).map(|basic_block| basic_block.start);
+    let mut result = function.to_owned();
+
+    let mut index = 2;
+    while index < result.len() {
+        // Nothing may jump to the second constant or to the opcode itself.
+        if !starts.contains(&((index - 1) as u32)) && !starts.contains(&(index as u32)) {
+            match fold(&result[index - 2], &result[index - 1], &result[index]) {
+                Some(constant) => {
+                    result[index - 2] = Nop;
+                    result[index - 1] = Nop;
+                    result[index] = constant;
+                }
+                None => { }
+            }
+        }
+        index += 1;
+    }
+    result
+}
+
+/**
+ * Returns the constant an arithmetic opcode produces from two constants, if it can be folded.
+ */
+fn fold(v1: &Opcode, v2: &Opcode, opcode: &Opcode) -> Option<Opcode> {
+    match (v1, v2, opcode) {
+        (&Consti32(a), &Consti32(b), &Add) => Some(Consti32(a + b)),
+        (&Consti32(a), &Consti32(b), &Subtract) => Some(Consti32(a - b)),
+        (&Consti32(a), &Consti32(b), &Multiply) => Some(Consti32(a * b)),
+        (&Constf32(a), &Constf32(b), &Add) => Some(Constf32(a + b)),
+        (&Constf32(a), &Constf32(b), &Subtract) => Some(Constf32(a - b)),
+        (&Constf32(a), &Constf32(b), &Multiply) => Some(Constf32(a * b)),
+        _ => None
+    }
+}
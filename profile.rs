@@ -0,0 +1,193 @@
+use opcode::*;
+use interpret::*;
+use basic_block::*;
+use heap::*;
+use constant_pool::*;
+use vm_error::*;
+use extra::sort::quick_sort;
+use std::vec;
+
+/**
+ * Execution counts gathered while interpreting a function.
+ */
+pub struct Profile {
+    /// Executions of each instruction, by offset.
+    instruction_counts: ~[uint],
+
+    /// Executions of each Iftrue that jumped, by offset.
+    taken: ~[uint],
+
+    /// Executions of each Iftrue that fell through, by offset.
+    not_taken: ~[uint],
+
+    /// Whether the condition of the Iftrue being run is true. Where the
+    /// Iftrue goes next cannot tell, when it jumps to the next instruction.
+    priv condition: bool,
+
+    /// The offsets at which the function's basic blocks start.
+    priv block_starts: ~[u32],
+
+    /// The names of the function's opcodes, by offset.
+    priv opcode_names: ~[~str]
+}
+
+impl Profile {
+    /**
+     * Creates an empty Profile for a function.
+     *
+     * # Arguments
+     *
+     * * function - The function to be profiled.
+     */
+    pub fn new(function: &[Opcode]) -> Profile {
+        let block_starts = get_basic_blocks(function).map(|block| block.start);
+        Profile {
+            instruction_counts: vec::from_elem(function.len(), 0u),
+            taken: vec::from_elem(function.len(), 0u),
+            not_taken: vec::from_elem(function.len(), 0u),
+            condition: false,
+            block_starts: block_starts,
+            opcode_names: function.map(|opcode| opcode.to_str())
+        }
+    }
+
+    /**
+     * Returns the execution count of each basic block, keyed by the
+     * offset at which it starts, hottest first.
+     */
+    pub fn block_counts(&self) -> ~[(u32, uint)] {
+        let mut counts = do self.block_starts.map |&start| {
+            let count = if (start as uint) < self.instruction_counts.len() {
+                self.instruction_counts[start]
+            } else {
+                0
+            };
+            (start, count)
+        };
+        quick_sort(counts, |&(s1, c1), &(s2, c2)| c1 > c2 || (c1 == c2 && s1 <= s2));
+        counts
+    }
+
+    /**
+     * Returns the execution count of each instruction, keyed by
+     * offset, hottest first.
+     */
+    pub fn instruction_counts_by_hotness(&self) -> ~[(u32, uint)] {
+        let mut counts = ~[];
+        for (offset, &count) in self.instruction_counts.iter().enumerate() {
+            counts.push((offset as u32, count));
+        }
+        quick_sort(counts, |&(o1, c1), &(o2, c2)| c1 > c2 || (c1 == c2 && o1 <= o2));
+        counts
+    }
+
+    /**
+     * Returns the taken and not-taken counts of each Iftrue that
+     * executed, keyed by offset, most executed first.
+     */
+    pub fn branch_counts(&self) -> ~[(u32, uint, uint)] {
+        let mut counts = ~[];
+        for offset in range(0, self.taken.len()) {
+            if self.taken[offset] + self.not_taken[offset] > 0 {
+                counts.push((offset as u32, self.taken[offset], self.not_taken[offset]));
+            }
+        }
+        quick_sort(counts, |&(o1, t1, n1), &(o2, t2, n2)| {
+            t1 + n1 > t2 + n2 || (t1 + n1 == t2 + n2 && o1 <= o2)
+        });
+        counts
+    }
+
+    /**
+     * Formats the profile as a human-readable report.
+     */
+    pub fn to_text(&self) -> ~str {
+        let mut report = ~"Instructions:\n";
+        for &(offset, count) in self.instruction_counts_by_hotness().iter() {
+            report.push_str(fmt!("%10u  %4u  %s\n", count, offset as uint, self.opcode_names[offset]));
+        }
+
+        report.push_str("Basic blocks:\n");
+        for &(start, count) in self.block_counts().iter() {
+            report.push_str(fmt!("%10u  %4u\n", count, start as uint));
+        }
+
+        report.push_str("Branches (taken, not taken):\n");
+        for &(offset, taken, not_taken) in self.branch_counts().iter() {
+            report.push_str(fmt!("%10u  %10u  %4u  %s\n", taken, not_taken, offset as uint, self.opcode_names[offset]));
+        }
+        report
+    }
+
+    /**
+     * Formats the profile as JSON.
+     */
+    pub fn to_json(&self) -> ~str {
+        let instructions = do self.instruction_counts_by_hotness().map |&(offset, count)| {
+            fmt!("{\"offset\":%u,\"opcode\":%s,\"count\":%u}",
+                 offset as uint, json_string(self.opcode_names[offset]), count)
+        };
+        let blocks = do self.block_counts().map |&(start, count)| {
+            fmt!("{\"start\":%u,\"count\":%u}", start as uint, count)
+        };
+        let branches = do self.branch_counts().map |&(offset, taken, not_taken)| {
+            fmt!("{\"offset\":%u,\"taken\":%u,\"not_taken\":%u}", offset as uint, taken, not_taken)
+        };
+
+        fmt!("{\"instructions\":[%s],\"blocks\":[%s],\"branches\":[%s]}",
+             instructions.connect(","), blocks.connect(","), branches.connect(","))
+    }
+}
+
+impl Monitor for Profile {
+    fn before_opcode(&mut self, opcode: &Opcode, stack: &[StackValue], environment: &Environment) {
+        self.instruction_counts[environment.ip] += 1;
+        match *opcode {
+            Iftrue(_) => self.condition = stack[stack.len() - 1].is_true(),
+            _ => { }
+        }
+    }
+
+    fn after_opcode(&mut self, opcode: &Opcode, _stack: &[StackValue], environment: &Environment, _next_ip: u32) {
+        match *opcode {
+            Iftrue(_) if self.condition => self.taken[environment.ip] += 1,
+            Iftrue(_) => self.not_taken[environment.ip] += 1,
+            _ => { }
+        }
+    }
+}
+
+/**
+ * Interprets a function while profiling it.
+ *
+ * # Arguments
+ *
+ * * function  - The function to interpret.
+ * * constants - The constant pool of the function's module.
+ * * heap      - The heap on which the function allocates objects.
+ * * profile   - The Profile to add this run's counts to.
+ *
+ * Returns the value the function returned, if any.
+ */
+pub fn interpret_profiled(function: &[Opcode], constants: &ConstantPool, heap: &mut Heap,
+                          profile: &mut Profile) -> Result<Option<StackValue>, VMError> {
+    let stack = &mut ~[];
+    let environment = &mut Environment::new(function, stack);
+    run_monitored(function, stack, environment, constants, heap, profile as &mut Monitor)
+}
+
+/**
+ * Quotes a string for inclusion in JSON.
+ */
+fn json_string(value: &str) -> ~str {
+    let mut quoted = ~"\"";
+    for c in value.iter() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            _ => quoted.push_char(c)
+        }
+    }
+    quoted.push_char('"');
+    quoted
+}
@@ -0,0 +1,391 @@
+use opcode::*;
+use interpret::*;
+use jit::*;
+use frontend::*;
+use jit_helpers::*;
+use libjit::*;
+use heap::*;
+use constant_pool::*;
+use analysis::*;
+use basic_block::*;
+use variable_type::*;
+use vm_error::*;
+use std::libc::*;
+use std::cast::transmute;
+use std::hashmap::HashMap;
+use std::vec;
+
+/**
+ * When the tiered runtime compiles a function.
+ */
+#[deriving(Clone)]
+pub struct TierPolicy {
+    /// Compile after this many interpreted calls.
+    invocation_threshold: uint,
+
+    /// Compile once the interpreted calls have taken this
+    /// many backward jumps in total.
+    back_edge_threshold: uint,
+
+    /// Switch an interpreted call to compiled code, in the middle of
+    /// the call, once it has jumped back to one loop header this many times.
+    osr_threshold: uint,
+
+    /// How to compile functions and their on-stack replacement entries.
+    compile_options: CompileOptions
+}
+
+impl TierPolicy {
+    /**
+     * Returns the policy used by TieredRuntime::new.
+     */
+    pub fn default() -> TierPolicy {
+        TierPolicy {
+            invocation_threshold: 10,
+            back_edge_threshold: 1000,
+            osr_threshold: 500,
+            compile_options: CompileOptions::default()
+        }
+    }
+}
+
+/**
+ * The execution tiers.
+ */
+#[deriving(Clone)]
+#[deriving(Eq)]
+#[deriving(ToStr)]
+pub enum Tier {
+    Interpreted,
+    Compiled
+}
+
+/**
+ * Execution statistics for a function run by the tiered runtime.
+ */
+#[deriving(Clone)]
+pub struct TierStats {
+    /// The tier the next call will run in.
+    tier: Tier,
+    /// Calls run by the interpreter.
+    interpreted_calls: uint,
+    /// Calls run by compiled code.
+    compiled_calls: uint,
+    /// Backward jumps taken by interpreted calls.
+    back_edges: uint,
+    /// The number of the call that was the first to run compiled, if any.
+    switched_at_call: Option<uint>,
+    /// Interpreted calls that switched to compiled code at a loop header.
+    osr_entries: uint
+}
+
+/**
+ * A function managed by the tiered runtime.
+ */
+struct TieredFunction<'self> {
+    opcodes: ~[Opcode],
+    local_types: ~[VariableType],
+    return_type: VariableType,
+    /// Whether calls return a value; see analysis::returns_value.
+    returns: Option<bool>,
+    compiled: Option<~CompiledFunction<'self>>,
+    /// On-stack replacement entry points, by loop header.
+    osr_entries: HashMap<u32, ~CompiledFunction<'self>>,
+    stats: TierStats
+}
+
+/**
+ * Runs functions in the interpreter until they are hot, then
+ * transparently switches them to JIT compiled code.
+ */
+pub struct TieredRuntime<'self> {
+    priv functions: ~[TieredFunction<'self>],
+    priv policy: TierPolicy,
+    priv constants: ~ConstantPool,
+    priv heap: ~Heap,
+    priv context: &'self Context
+}
+
+impl<'self> TieredRuntime<'self> {
+    /**
+     * Creates a new TieredRuntime with the default policy.
+     *
+     * # Arguments
+     *
+     * * context   - The JIT context within which to compile functions.
+     * * constants - The constant pool shared by the runtime's functions.
+     */
+    pub fn new(context: &'self Context, constants: ~ConstantPool) -> TieredRuntime<'self> {
+        TieredRuntime::with_policy(context, constants, TierPolicy::default())
+    }
+
+    /**
+     * Creates a new TieredRuntime.
+     *
+     * # Arguments
+     *
+     * * context   - The JIT context within which to compile functions.
+     * * constants - The constant pool shared by the runtime's functions.
+     * * policy    - When to compile functions.
+     */
+    pub fn with_policy(context: &'self Context, constants: ~ConstantPool, policy: TierPolicy) -> TieredRuntime<'self> {
+        TieredRuntime {
+            functions: ~[],
+            policy: policy,
+            constants: constants,
+            heap: Heap::new(),
+            context: context
+        }
+    }
+
+    /**
+     * Adds a function to the runtime.
+     *
+     * # Arguments
+     *
+     * * opcodes - The function.
+     *
+     * Returns the index with which to call the function, or why the
+     * function was rejected. Functions are verified here, so that they
+     * can always be compiled once they are hot.
+     */
+    pub fn add_function(&mut self, opcodes: ~[Opcode]) -> Result<uint, VerifyError> {
+        match verify(opcodes) {
+            Ok(()) => { }
+            Err(error) => return Err(error)
+        }
+        let basic_blocks = get_basic_blocks(opcodes);
+        let local_types = match infer_local_types(basic_blocks, local_count(opcodes)) {
+            Ok(local_types) => local_types,
+            Err(error) => return Err(error)
+        };
+        let return_type = infer_return_type(basic_blocks, local_types);
+        let returns = returns_value(basic_blocks);
+
+        self.functions.push(TieredFunction {
+            opcodes: opcodes,
+            local_types: local_types,
+            return_type: return_type,
+            returns: returns,
+            compiled: None,
+            osr_entries: HashMap::new(),
+            stats: TierStats {
+                tier: Interpreted,
+                interpreted_calls: 0,
+                compiled_calls: 0,
+                back_edges: 0,
+                switched_at_call: None,
+                osr_entries: 0
+            }
+        });
+        Ok(self.functions.len() - 1)
+    }
+
+    /**
+     * Calls a function in whichever tier it has reached.
+     *
+     * # Arguments
+     *
+     * * index - The index returned by add_function.
+     *
+     * Returns the value the function returned, if any.
+     */
+    pub fn call(&mut self, index: uint) -> Result<Option<StackValue>, VMError> {
+        if self.functions[index].compiled.is_none() && self.is_hot(index) {
+            self.compile(index);
+        }
+
+        let (constants, heap) = self.compiled_borrows();
+        let function = &mut self.functions[index];
+        match function.compiled {
+            Some(ref compiled) => {
+                function.stats.compiled_calls += 1;
+                match compiled.call_word([]) {
+                    Ok(retval) => Ok(from_compiled(retval, function.returns, function.return_type)),
+                    Err(Raised(exception)) => Err(to_vm_error(exception)),
+                    Err(error) => fail!(fmt!("Compiled code has an unexpected signature: %?", error))
+                }
+            }
+            None => {
+                function.stats.interpreted_calls += 1;
+                interpret_with_osr(function, &self.policy, constants, self.context, heap)
+            }
+        }
+    }
+
+    /**
+     * Returns the execution statistics of a function.
+     *
+     * # Arguments
+     *
+     * * index - The index returned by add_function.
+     */
+    pub fn stats(&self, index: uint) -> TierStats {
+        self.functions[index].stats.clone()
+    }
+
+    /**
+     * Returns the heap the runtime's functions allocate on.
+     */
+    pub fn heap<'a>(&'a self) -> &'a Heap {
+        &*self.heap
+    }
+
+    /**
+     * Returns whether a function has crossed a threshold of the policy.
+     * Functions that only sometimes return a value are never hot, since
+     * only the interpreter can tell which kind of return a call took.
+     */
+    fn is_hot(&self, index: uint) -> bool {
+        let function = &self.functions[index];
+        let stats = &function.stats;
+        function.returns.is_some() &&
+            stats.interpreted_calls >= self.policy.invocation_threshold ||
+            stats.back_edges >= self.policy.back_edge_threshold
+    }
+
+    /**
+     * Returns the constant pool and heap for compiled code to keep borrows
+     * of. The runtime owns both boxes and never replaces them, and its
+     * compiled functions are dropped with it and only called through it,
+     * so the borrows can neither outlive the boxes nor overlap the
+     * interpreter's use of them.
+     */
+    fn compiled_borrows(&mut self) -> (&'self ConstantPool, &'self mut Heap) {
+        unsafe { (transmute(&*self.constants), transmute(&mut *self.heap)) }
+    }
+
+    /**
+     * Compiles a function, so that later calls run the compiled code.
+     */
+    fn compile(&mut self, index: uint) {
+        let (constants, heap) = self.compiled_borrows();
+        let function = &mut self.functions[index];
+        let compiled = match compile(function.opcodes, &self.policy.compile_options, constants, self.context, heap) {
+            Ok(compiled) => compiled,
+            Err(error) => fail!(fmt!("add_function verified the function, but compiling it failed: %s", error.to_str()))
+        };
+        function.compiled = Some(compiled);
+        function.stats.tier = Compiled;
+        function.stats.switched_at_call = Some(function.stats.interpreted_calls + 1);
+    }
+}
+
+/**
+ * Interprets a call to a function, switching to compiled code at a loop
+ * header once the call has jumped back to it often enough.
+ *
+ * # Arguments
+ *
+ * * function  - The function.
+ * * policy    - The policy giving the on-stack replacement threshold.
+ * * constants - The constant pool shared by the runtime's functions.
+ * * context   - The JIT context within which to compile entry points.
+ * * heap      - The VM heap.
+ *
+ * Returns the value the function returned, if any.
+ */
+fn interpret_with_osr<'a>(function: &mut TieredFunction<'a>, policy: &TierPolicy, constants: &'a ConstantPool,
+                          context: &'a Context, heap: &'a mut Heap) -> Result<Option<StackValue>, VMError> {
+    let stack = &mut ~[];
+    let environment = &mut Environment::new(function.opcodes, stack);
+    let mut header_counts: HashMap<u32, uint> = HashMap::new();
+
+    while (environment.ip as uint) < function.opcodes.len() {
+        let ip = match interpret_opcode(&function.opcodes[environment.ip], stack, environment, constants, heap) {
+            Ok(ip) => ip,
+            Err(error) => {
+                function.stats.back_edges += environment.back_edges;
+                return Err(error);
+            }
+        };
+
+        if ip <= environment.ip {
+            environment.back_edges += 1;
+
+            let count = header_counts.find_or_insert(ip, 0);
+            *count += 1;
+
+            // Loop headers start basic blocks, so the evaluation stack
+            // holds nothing but the locals here.
+            if *count >= policy.osr_threshold && stack.len() == environment.bp as uint && function.returns.is_some() {
+                function.stats.back_edges += environment.back_edges;
+                function.stats.osr_entries += 1;
+                return enter_compiled(function, ip, *stack, &policy.compile_options, constants, context, heap);
+            }
+        }
+        environment.ip = ip;
+    }
+
+    function.stats.back_edges += environment.back_edges;
+    Ok(environment.return_value)
+}
+
+/**
+ * Continues an interpreted call in compiled code from a loop header.
+ *
+ * # Arguments
+ *
+ * * function  - The function.
+ * * header    - The index of the loop header instruction.
+ * * stack     - The VM runtime stack, holding only the call's locals.
+ * * options   - How to compile the entry point.
+ * * constants - The constant pool shared by the runtime's functions.
+ * * context   - The JIT context within which to compile the entry point.
+ * * heap      - The VM heap.
+ *
+ * Returns the value the function returned, if any.
+ */
+fn enter_compiled<'a>(function: &mut TieredFunction<'a>, header: u32, stack: &[StackValue], options: &CompileOptions,
+                      constants: &'a ConstantPool, context: &'a Context, heap: &'a mut Heap) -> Result<Option<StackValue>, VMError> {
+    if !function.osr_entries.contains_key(&header) {
+        let entry = match compile_osr(function.opcodes, header, options, constants, context, heap) {
+            Ok(entry) => entry,
+            Err(error) => fail!(fmt!("add_function verified the function, but compiling it failed: %s", error.to_str()))
+        };
+        function.osr_entries.insert(header, entry);
+    }
+
+    // Local N lives at stack[bp - N - 1], and bp is the top of the stack.
+    let local_count = function.local_types.len();
+    let words = do vec::from_fn(local_count) |local| {
+        // Locals of unknown type are compiled as integers.
+        let local_type = match function.local_types[local] {
+            Unknown => Int32,
+            t => t
+        };
+        match stack[stack.len() - local - 1] {
+            // Locals start out as Int(0), which stands for null in a
+            // reference local that has not been stored to yet.
+            Int(0) if local_type.is_reference() => 0,
+            ref value => value.to_word(local_type)
+        }
+    };
+
+    let words_ptr = vec::raw::to_ptr(words) as *c_void;
+    match function.osr_entries.get(&header).call_word([&words_ptr as &NativeArgument]) {
+        Ok(retval) => Ok(from_compiled(retval, function.returns, function.return_type)),
+        Err(Raised(exception)) => Err(to_vm_error(exception)),
+        Err(error) => fail!(fmt!("Compiled code has an unexpected signature: %?", error))
+    }
+}
+
+/**
+ * Converts the value returned by compiled code to what the interpreter
+ * would have returned. Compiled functions return at their inferred type,
+ * so the bits are a float for float functions and an integer or handle
+ * otherwise; functions without a Ret return nothing.
+ *
+ * # Arguments
+ *
+ * * retval      - The bits of the returned value.
+ * * returns     - Whether the function returns a value.
+ * * return_type - The inferred return type of the function.
+ */
+fn from_compiled(retval: u32, returns: Option<bool>, return_type: VariableType) -> Option<StackValue> {
+    match (returns, return_type) {
+        (Some(false), _) => None,
+        (_, Unknown) => Some(Int(retval as i32)),
+        (_, t) => Some(StackValue::from_word(retval, t))
+    }
+}
@@ -0,0 +1,81 @@
+use opcode::*;
+use interpret::*;
+use heap::*;
+use constant_pool::*;
+use vm_error::*;
+use std::io::Writer;
+
+/**
+ * Logs every opcode the interpreter runs, with the evaluation stack
+ * before and after it, one line per opcode:
+ *
+ *     4  Loadf32(0u32)  [] -> [10f32]
+ */
+pub struct Tracer {
+    /// Where to write the trace.
+    priv writer: @Writer,
+
+    /// The evaluation stack before the opcode being traced.
+    priv before: ~str
+}
+
+impl Tracer {
+    /**
+     * Creates a Tracer.
+     *
+     * # Arguments
+     *
+     * * writer - Where to write the trace.
+     */
+    pub fn new(writer: @Writer) -> Tracer {
+        Tracer {
+            writer: writer,
+            before: ~""
+        }
+    }
+}
+
+impl Monitor for Tracer {
+    fn before_opcode(&mut self, _opcode: &Opcode, stack: &[StackValue], environment: &Environment) {
+        self.before = format_stack(stack, environment);
+    }
+
+    fn after_opcode(&mut self, opcode: &Opcode, stack: &[StackValue], environment: &Environment, _next_ip: u32) {
+        self.writer.write_line(fmt!("%4u  %s  %s -> %s", environment.ip as uint, opcode.to_str(),
+                                    self.before, format_stack(stack, environment)));
+    }
+}
+
+/**
+ * Interprets a function, tracing every opcode it runs.
+ *
+ * # Arguments
+ *
+ * * function  - The function to interpret.
+ * * constants - The constant pool of the function's module.
+ * * heap      - The heap on which the function allocates objects.
+ * * writer    - Where to write the trace.
+ *
+ * Returns the value the function returned, if any.
+ */
+pub fn interpret_traced(function: &[Opcode], constants: &ConstantPool, heap: &mut Heap,
+                        writer: @Writer) -> Result<Option<StackValue>, VMError> {
+    let stack = &mut ~[];
+    let environment = &mut Environment::new(function, stack);
+    let mut tracer = Tracer::new(writer);
+    let result = run_monitored(function, stack, environment, constants, heap, &mut tracer as &mut Monitor);
+
+    match result {
+        Err(ref error) => writer.write_line(fmt!("%4u  error %s", environment.ip as uint, error.to_str())),
+        _ => { }
+    }
+    result
+}
+
+/**
+ * Formats the evaluation stack, bottom first, leaving out the locals.
+ */
+fn format_stack(stack: &[StackValue], environment: &Environment) -> ~str {
+    let values = stack.slice_from(environment.bp as uint).map(|value| value.to_str());
+    fmt!("[%s]", values.connect(", "))
+}
@@ -0,0 +1,94 @@
+use opcode::*;
+use interpret::*;
+use heap::*;
+use constant_pool::*;
+use trace::*;
+use std::io;
+use std::os;
+
+mod variable_type;
+mod vm_error;
+mod heap;
+mod constant_pool;
+mod backend;
+mod opcode;
+mod interpret;
+mod basic_block;
+mod analysis;
+mod trace;
+
+/// The trace that interpret_traced must write for factorial(10).
+static EXPECTED: &'static str = "factorial.trace";
+
+fn main() {
+    // The same function as factorial.rs.
+    let factorial = ~[
+        Constf32(10f32),
+        Store(0),
+        Constf32(1f32),
+        Store(1),
+        Loadf32(0),
+        Constf32(1f32),
+        Leq,
+        Iftrue(17),
+        Loadf32(0),
+        Loadf32(1),
+        Multiply,
+        Store(1),
+        Loadf32(0),
+        Constf32(1f32),
+        Subtract,
+        Store(0),
+        Jmp(4),
+        Loadf32(1),
+        Ret
+    ];
+
+    let constants = ConstantPool::new();
+    let mut heap = Heap::new();
+    let mut result = Ok(None);
+    let actual = do io::with_str_writer |writer| {
+        result = interpret_traced(factorial, constants, heap, writer);
+    };
+
+    // Writes the trace as the new expected one, after a deliberate change.
+    if os::args().contains(&~"--bless") {
+        match io::file_writer(&Path(EXPECTED), [io::Create, io::Truncate]) {
+            Ok(writer) => writer.write_str(actual),
+            Err(error) => fail!(error)
+        }
+        return;
+    }
+
+    let expected = match io::read_whole_file_str(&Path(EXPECTED)) {
+        Ok(expected) => expected,
+        Err(error) => fail!(error)
+    };
+
+    let mut failures = 0;
+    if result != Ok(Some(Float(3628800f32))) {
+        failures += 1;
+        println(fmt!("FAIL result: expected 3628800f32, got %?", result));
+    }
+
+    let actual_lines: ~[&str] = actual.line_iter().collect();
+    let expected_lines: ~[&str] = expected.line_iter().collect();
+    for (index, (a, e)) in actual_lines.iter().zip(expected_lines.iter()).enumerate() {
+        if a != e {
+            failures += 1;
+            println(fmt!("FAIL line %u: expected \"%s\", got \"%s\"", index + 1, *e, *a));
+            break;
+        }
+    }
+    if actual_lines.len() != expected_lines.len() {
+        failures += 1;
+        println(fmt!("FAIL length: expected %u lines, got %u", expected_lines.len(), actual_lines.len()));
+    }
+
+    if failures > 0 {
+        println(fmt!("%u failures", failures as uint));
+        os::set_exit_status(1);
+    } else {
+        println("The trace matches");
+    }
+}
@@ -1,11 +1,51 @@
-
 /**
  * VM primitive types.
  */
 #[deriving(Clone)]
 #[deriving(Eq)]
+#[deriving(ToStr)]
+#[deriving(IterBytes)]
 pub enum VariableType {
     pub Unknown,
     pub Float32,
-    pub Int32
-}
\ No newline at end of file
+    pub Int32,
+    /// A reference to an array on the VM heap.
+    pub Array,
+    /// A reference to a boxed object on the VM heap.
+    pub Object,
+    /// A reference to an immutable string on the VM heap.
+    pub String
+}
+
+impl VariableType {
+    /**
+     * Returns whether values of this type are heap references.
+     */
+    pub fn is_reference(&self) -> bool {
+        match *self {
+            Array | Object | String => true,
+            _ => false
+        }
+    }
+
+    /**
+     * Encodes the type as an integer, for passing to native helpers.
+     */
+    pub fn to_code(&self) -> i32 {
+        *self as i32
+    }
+
+    /**
+     * Decodes a type previously encoded with to_code.
+     */
+    pub fn from_code(code: i32) -> VariableType {
+        match code {
+            1 => Float32,
+            2 => Int32,
+            3 => Array,
+            4 => Object,
+            5 => String,
+            _ => Unknown
+        }
+    }
+}
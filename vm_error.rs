@@ -0,0 +1,60 @@
+/**
+ * Errors raised by the VM at runtime.
+ */
+#[deriving(Clone)]
+#[deriving(Eq)]
+#[deriving(ToStr)]
+pub enum VMError {
+    /// An array was indexed outside its bounds, or
+    /// created with a negative length.
+    IndexOutOfBounds,
+
+    /// A null or dead heap reference was dereferenced.
+    NullReference,
+
+    /// The heap has no room for another object.
+    OutOfMemory,
+
+    /// A typed array opcode did not match the
+    /// element type of the array it was applied to.
+    ElementTypeMismatch,
+
+    /// The instruction budget given to the function ran out.
+    /// Handlers cannot catch this.
+    OutOfFuel,
+
+    /// A Throw was not caught by any handler. Negative
+    /// values are never caught.
+    Uncaught(i32),
+
+    /// An integer Divide or Rem had a zero divisor.
+    DivisionByZero,
+
+    /// A checked arithmetic opcode overflowed. Holds
+    /// the offset of the opcode within its function.
+    Overflow(u32)
+}
+
+impl VMError {
+    /**
+     * Returns the exception value a handler receives for the error,
+     * or None if handlers cannot catch it. Errors raised by the VM
+     * itself have negative values, from the codes of the matching libjit
+     * builtin exceptions so that compiled code and the interpreter agree,
+     * and only non-negative thrown values can be caught, so that a handler
+     * can always tell a Throw from an error.
+     */
+    pub fn exception_value(&self) -> Option<i32> {
+        match *self {
+            IndexOutOfBounds => Some(-8),
+            NullReference => Some(-5),
+            OutOfMemory => Some(-4),
+            ElementTypeMismatch => Some(-100),
+            OutOfFuel => None,
+            Uncaught(value) if value >= 0 => Some(value),
+            Uncaught(_) => None,
+            DivisionByZero => Some(-2),
+            Overflow(_) => Some(-1)
+        }
+    }
+}
@@ -0,0 +1,1050 @@
+use opcode::*;
+use analysis::*;
+use basic_block::*;
+use variable_type::*;
+use std::cast::transmute;
+use std::vec;
+
+/// The instruction encodings of the subset of WebAssembly the VM uses.
+pub static UNREACHABLE: u8 = 0x00;
+pub static NOP: u8 = 0x01;
+pub static BLOCK: u8 = 0x02;
+pub static LOOP: u8 = 0x03;
+pub static IF: u8 = 0x04;
+pub static ELSE: u8 = 0x05;
+pub static END: u8 = 0x0B;
+pub static BR: u8 = 0x0C;
+pub static BR_IF: u8 = 0x0D;
+pub static RETURN: u8 = 0x0F;
+pub static CALL: u8 = 0x10;
+pub static DROP: u8 = 0x1A;
+pub static SELECT: u8 = 0x1B;
+pub static LOCAL_GET: u8 = 0x20;
+pub static LOCAL_SET: u8 = 0x21;
+pub static LOCAL_TEE: u8 = 0x22;
+pub static I32_CONST: u8 = 0x41;
+pub static F32_CONST: u8 = 0x43;
+pub static I32_EQZ: u8 = 0x45;
+pub static I32_EQ: u8 = 0x46;
+pub static I32_NE: u8 = 0x47;
+pub static I32_LT_S: u8 = 0x48;
+pub static I32_LT_U: u8 = 0x49;
+pub static I32_GT_S: u8 = 0x4A;
+pub static I32_GT_U: u8 = 0x4B;
+pub static I32_LE_S: u8 = 0x4C;
+pub static I32_LE_U: u8 = 0x4D;
+pub static I32_GE_S: u8 = 0x4E;
+pub static I32_GE_U: u8 = 0x4F;
+pub static I64_NE: u8 = 0x52;
+pub static F32_EQ: u8 = 0x5B;
+pub static F32_NE: u8 = 0x5C;
+pub static F32_LT: u8 = 0x5D;
+pub static F32_GT: u8 = 0x5E;
+pub static F32_LE: u8 = 0x5F;
+pub static F32_GE: u8 = 0x60;
+pub static I32_ADD: u8 = 0x6A;
+pub static I32_SUB: u8 = 0x6B;
+pub static I32_MUL: u8 = 0x6C;
+pub static I32_DIV_S: u8 = 0x6D;
+pub static I32_REM_S: u8 = 0x6F;
+pub static I32_AND: u8 = 0x71;
+pub static I32_OR: u8 = 0x72;
+pub static I32_XOR: u8 = 0x73;
+pub static I32_SHL: u8 = 0x74;
+pub static I32_SHR_S: u8 = 0x75;
+pub static I32_SHR_U: u8 = 0x76;
+pub static I64_ADD: u8 = 0x7C;
+pub static I64_SUB: u8 = 0x7D;
+pub static I64_MUL: u8 = 0x7E;
+pub static F32_ABS: u8 = 0x8B;
+pub static F32_NEG: u8 = 0x8C;
+pub static F32_CEIL: u8 = 0x8D;
+pub static F32_FLOOR: u8 = 0x8E;
+pub static F32_TRUNC: u8 = 0x8F;
+pub static F32_SQRT: u8 = 0x91;
+pub static F32_ADD: u8 = 0x92;
+pub static F32_SUB: u8 = 0x93;
+pub static F32_MUL: u8 = 0x94;
+pub static F32_DIV: u8 = 0x95;
+pub static F32_MIN: u8 = 0x96;
+pub static F32_MAX: u8 = 0x97;
+pub static F32_COPYSIGN: u8 = 0x98;
+pub static I32_WRAP_I64: u8 = 0xA7;
+pub static I32_TRUNC_F32_S: u8 = 0xA8;
+pub static I64_EXTEND_I32_S: u8 = 0xAC;
+pub static F32_CONVERT_I32_S: u8 = 0xB2;
+
+/// The encodings of value types, function types and the empty block type.
+pub static I32: u8 = 0x7F;
+pub static I64: u8 = 0x7E;
+pub static F32: u8 = 0x7D;
+pub static FUNCTION_TYPE: u8 = 0x60;
+pub static EMPTY_BLOCK: u8 = 0x40;
+
+/// The module header: the magic number and version 1.
+pub static HEADER: [u8, ..8] = [0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00];
+
+/// The section ids.
+pub static TYPE_SECTION: u8 = 1;
+pub static IMPORT_SECTION: u8 = 2;
+pub static FUNCTION_SECTION: u8 = 3;
+pub static EXPORT_SECTION: u8 = 7;
+pub static CODE_SECTION: u8 = 10;
+
+/**
+ * Returns the text format name of an instruction or value type.
+ */
+pub fn mnemonic(encoding: u8) -> &'static str {
+    match find_mnemonic(encoding) {
+        Some(name) => name,
+        None => fail!(fmt!("No mnemonic for 0x%02X", encoding as uint))
+    }
+}
+
+/**
+ * Returns the text format name of an instruction or value type in the
+ * subset, or None for other encodings.
+ */
+pub fn find_mnemonic(encoding: u8) -> Option<&'static str> {
+    let name = match encoding {
+        0x00 => "unreachable", 0x01 => "nop", 0x02 => "block", 0x03 => "loop", 0x04 => "if",
+        0x05 => "else", 0x0B => "end", 0x0C => "br", 0x0D => "br_if", 0x0F => "return",
+        0x10 => "call", 0x1A => "drop", 0x1B => "select", 0x20 => "local.get", 0x21 => "local.set",
+        0x22 => "local.tee", 0x41 => "i32.const", 0x43 => "f32.const", 0x45 => "i32.eqz",
+        0x46 => "i32.eq", 0x47 => "i32.ne", 0x48 => "i32.lt_s", 0x49 => "i32.lt_u",
+        0x4A => "i32.gt_s", 0x4B => "i32.gt_u", 0x4C => "i32.le_s", 0x4D => "i32.le_u",
+        0x4E => "i32.ge_s", 0x4F => "i32.ge_u", 0x52 => "i64.ne", 0x5B => "f32.eq", 0x5C => "f32.ne",
+        0x5D => "f32.lt", 0x5E => "f32.gt", 0x5F => "f32.le", 0x60 => "f32.ge", 0x6A => "i32.add",
+        0x6B => "i32.sub", 0x6C => "i32.mul", 0x6D => "i32.div_s", 0x6F => "i32.rem_s",
+        0x71 => "i32.and", 0x72 => "i32.or", 0x73 => "i32.xor", 0x74 => "i32.shl",
+        0x75 => "i32.shr_s", 0x76 => "i32.shr_u", 0x7C => "i64.add", 0x7D => "i64.sub",
+        0x7E => "i64.mul", 0x8B => "f32.abs", 0x8C => "f32.neg", 0x8D => "f32.ceil",
+        0x8E => "f32.floor", 0x8F => "f32.trunc", 0x91 => "f32.sqrt", 0x92 => "f32.add",
+        0x93 => "f32.sub", 0x94 => "f32.mul", 0x95 => "f32.div", 0x96 => "f32.min",
+        0x97 => "f32.max", 0x98 => "f32.copysign", 0xA7 => "i32.wrap_i64",
+        0xA8 => "i32.trunc_f32_s", 0xAC => "i64.extend_i32_s", 0xB2 => "f32.convert_i32_s",
+        0x7F => "i32", 0x7E => "i64", 0x7D => "f32",
+        _ => return None
+    };
+    Some(name)
+}
+
+/**
+ * Appends an unsigned LEB128 integer.
+ */
+pub fn write_unsigned(bytes: &mut ~[u8], value: u32) {
+    let mut value = value;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value = value >> 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/**
+ * Appends a signed LEB128 integer.
+ */
+pub fn write_signed(bytes: &mut ~[u8], value: i32) {
+    let mut value = value;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value = value >> 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn write_name(bytes: &mut ~[u8], name: &str) {
+    write_unsigned(bytes, name.len() as u32);
+    bytes.push_all(name.as_bytes());
+}
+
+fn write_section(module: &mut ~[u8], id: u8, contents: &[u8]) {
+    module.push(id);
+    write_unsigned(module, contents.len() as u32);
+    module.push_all(contents);
+}
+
+/**
+ * A WebAssembly module exporting one function, in both formats.
+ */
+pub struct WasmModule {
+    /// The binary format, for a .wasm file.
+    binary: ~[u8],
+
+    /// The text format, for a .wat file.
+    text: ~str
+}
+
+/**
+ * Why a function cannot be exported to WebAssembly.
+ */
+#[deriving(Clone)]
+#[deriving(ToStr)]
+pub enum WasmExportError {
+    /// The opcode at the given offset needs the VM heap or exception
+    /// handling, which exported functions do not have.
+    ExportUnsupportedOpcode(u32, Opcode),
+
+    /// The loop entered at the given offset has more than one entry, so
+    /// it has no structured equivalent.
+    IrreducibleControlFlow(u32),
+
+    /// The function does not verify.
+    ExportInvalidBytecode(VerifyError)
+}
+
+/**
+ * Exports a function as a WebAssembly module, for running VM programs in
+ * browsers.
+ *
+ * The module exports the function under the given name. It takes no
+ * parameters, and returns an i32 or f32 from the inferred return type, or
+ * nothing if that is unknown. Locals become wasm locals of their inferred
+ * types. The control flow graph is turned back into nested block, loop
+ * and if instructions with Ramsey's algorithm from "Beyond Relooper", so
+ * any function whose loops have a single entry can be exported.
+ *
+ * Integer division by zero, Divide overflow and checked arithmetic
+ * overflow trap, rather than raising VM errors; Rem of the smallest
+ * integer by -1 gives 0. Disp imports console.log, and Pow, Sin, Cos, Tan,
+ * Exp and Log import the functions of JavaScript's Math, which compute in
+ * double precision, so a browser instantiates the module with
+ *
+ *     WebAssembly.instantiate(bytes, { console: console, Math: Math })
+ *
+ * # Arguments
+ *
+ * * function - The function to export.
+ * * name     - The name to export it under.
+ */
+pub fn export(function: &[Opcode], name: &str) -> Result<WasmModule, WasmExportError> {
+    match verify(function) {
+        Ok(()) => { }
+        Err(error) => return Err(ExportInvalidBytecode(error))
+    }
+    let basic_blocks = get_basic_blocks(function);
+    let local_types = match infer_local_types(basic_blocks, local_count(function)) {
+        Ok(local_types) => local_types,
+        Err(error) => return Err(ExportInvalidBytecode(error))
+    };
+    let return_type = infer_return_type(basic_blocks, local_types);
+
+    let mut exporter = Exporter::new(basic_blocks, local_types, return_type);
+    match exporter.check() {
+        Some(error) => return Err(error),
+        None => { }
+    }
+
+    let mut context = ~[];
+    exporter.do_tree(0, &mut context);
+    if return_type != Unknown {
+        // Every path has returned, but validation needs the result type at the end.
+        exporter.code.push(Simple(UNREACHABLE));
+    }
+
+    Ok(WasmModule { binary: exporter.binary(name), text: exporter.text(name) })
+}
+
+/**
+ * An instruction with its immediates.
+ */
+enum Instruction {
+    /// An instruction without immediates.
+    Simple(u8),
+
+    /// A block, loop or if, which take no values and produce none.
+    Structured(u8),
+
+    /// A br or br_if, and the depth of its target.
+    Branch(u8, u32),
+
+    /// A local.get, local.set or local.tee, and the local.
+    Local(u8, u32),
+
+    /// A call to the import with the given index.
+    Call(u32),
+
+    I32Const(i32),
+    F32Const(f32)
+}
+
+/**
+ * A function the module imports from the host.
+ */
+struct Import {
+    module: &'static str,
+    field: &'static str,
+    params: ~[u8],
+    result: Option<u8>
+}
+
+/**
+ * An enclosing structured instruction, which a branch can target.
+ */
+#[deriving(Eq)]
+enum ContextEntry {
+    IfThenElse,
+
+    /// A loop, which a br to continues the loop with the given block.
+    LoopHeadedBy(uint),
+
+    /// A block, which a br to continues after with the given block.
+    BlockFollowedBy(uint)
+}
+
+struct Exporter {
+    basic_blocks: ~[@mut BasicBlock],
+
+    /// The conditional and next successor of each block, by index.
+    successors: ~[(Option<uint>, Option<uint>)],
+
+    /// The position of each block in reverse postorder, or None if it is
+    /// unreachable.
+    order: ~[Option<uint>],
+
+    /// The reachable blocks, in reverse postorder.
+    reverse_postorder: ~[uint],
+
+    /// The immediate dominator of each reachable block. The entry block
+    /// is its own.
+    dominators: ~[Option<uint>],
+
+    /// Whether each block is entered by a forward edge from more than
+    /// one place, so is placed after a block that branches to it.
+    merge: ~[bool],
+
+    /// Whether each block is the target of a back edge, so is placed at
+    /// the start of a loop.
+    loop_header: ~[bool],
+
+    local_types: ~[VariableType],
+    return_type: VariableType,
+    imports: ~[Import],
+    code: ~[Instruction]
+}
+
+impl Exporter {
+    fn new(basic_blocks: ~[@mut BasicBlock], local_types: ~[VariableType],
+           return_type: VariableType) -> Exporter {
+        let count = basic_blocks.len();
+        let successors = do basic_blocks.map |b| {
+            (index_of(basic_blocks, b.conditional_block), index_of(basic_blocks, b.next_block))
+        };
+
+        let mut visited = vec::from_elem(count, false);
+        let mut postorder = ~[];
+        depth_first(0, successors, &mut visited, &mut postorder);
+        postorder.reverse();
+        let reverse_postorder = postorder;
+
+        let mut order = vec::from_elem(count, None);
+        for (position, &node) in reverse_postorder.iter().enumerate() {
+            order[node] = Some(position);
+        }
+
+        let mut predecessors = vec::from_elem(count, ~[]);
+        for &node in reverse_postorder.iter() {
+            for &next in successor_list(successors[node]).iter() {
+                predecessors[next].push(node);
+            }
+        }
+
+        // Cooper, Harvey and Kennedy's "A Simple, Fast Dominance Algorithm".
+        let mut dominators = vec::from_elem(count, None);
+        dominators[0] = Some(0u);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node in reverse_postorder.iter().skip(1) {
+                let mut dominator = None;
+                for &predecessor in predecessors[node].iter() {
+                    if dominators[predecessor].is_some() {
+                        dominator = match dominator {
+                            None => Some(predecessor),
+                            Some(other) => Some(intersect(predecessor, other, dominators, order))
+                        };
+                    }
+                }
+                if dominator != dominators[node] {
+                    dominators[node] = dominator;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut merge = vec::from_elem(count, false);
+        let mut loop_header = vec::from_elem(count, false);
+        for node in range(0, count) {
+            let forward = predecessors[node].iter().count(|&p| order[p].unwrap() < order[node].unwrap());
+            merge[node] = forward > 1;
+            loop_header[node] = forward < predecessors[node].len();
+        }
+
+        Exporter {
+            basic_blocks: basic_blocks,
+            successors: successors,
+            order: order,
+            reverse_postorder: reverse_postorder,
+            dominators: dominators,
+            merge: merge,
+            loop_header: loop_header,
+            local_types: local_types,
+            return_type: return_type,
+            imports: ~[],
+            code: ~[]
+        }
+    }
+
+    /**
+     * Returns the first reason the reachable blocks cannot be exported.
+     */
+    fn check(&self) -> Option<WasmExportError> {
+        for &node in self.reverse_postorder.iter() {
+            let basic_block = self.basic_blocks[node];
+            for (index, opcode) in basic_block.opcodes.iter().enumerate() {
+                if !is_supported(opcode) {
+                    return Some(ExportUnsupportedOpcode(basic_block.start + index as u32, opcode.clone()));
+                }
+            }
+            for &next in successor_list(self.successors[node]).iter() {
+                if self.is_back_edge(node, next) && !self.dominates(next, node) {
+                    return Some(IrreducibleControlFlow(self.basic_blocks[next].start));
+                }
+            }
+        }
+        None
+    }
+
+    fn is_back_edge(&self, source: uint, target: uint) -> bool {
+        self.order[target].unwrap() <= self.order[source].unwrap()
+    }
+
+    fn dominates(&self, dominator: uint, node: uint) -> bool {
+        let mut node = node;
+        loop {
+            if node == dominator {
+                return true;
+            }
+            if node == 0 {
+                return false;
+            }
+            node = self.dominators[node].unwrap();
+        }
+    }
+
+    /**
+     * Emits a block and the blocks it dominates.
+     */
+    fn do_tree(&mut self, node: uint, context: &mut ~[ContextEntry]) {
+        let mut merge_children = ~[];
+        for &child in self.reverse_postorder.iter() {
+            if child != node && self.dominators[child] == Some(node) && self.merge[child] {
+                merge_children.push(child);
+            }
+        }
+
+        if self.loop_header[node] {
+            self.code.push(Structured(LOOP));
+            context.push(LoopHeadedBy(node));
+            self.node_within(node, merge_children, context);
+            context.pop();
+            self.code.push(Simple(END));
+        } else {
+            self.node_within(node, merge_children, context);
+        }
+    }
+
+    /**
+     * Emits a block inside a wasm block for each of the merge blocks it
+     * dominates, each followed by the merge block. The latest merge
+     * block's wasm block is outermost.
+     */
+    fn node_within(&mut self, node: uint, merge_children: &[uint], context: &mut ~[ContextEntry]) {
+        if merge_children.is_empty() {
+            self.emit_basic_block(node, context);
+            return;
+        }
+
+        let last = merge_children.len() - 1;
+        let following = merge_children[last];
+        self.code.push(Structured(BLOCK));
+        context.push(BlockFollowedBy(following));
+        self.node_within(node, merge_children.slice(0, last), context);
+        context.pop();
+        self.code.push(Simple(END));
+        self.do_tree(following, context);
+    }
+
+    /**
+     * Emits a transfer of control to a block, as a br for a loop or merge
+     * block, or the block itself otherwise.
+     */
+    fn do_branch(&mut self, source: uint, target: uint, context: &mut ~[ContextEntry]) {
+        let depth = self.branch_depth(source, target, *context);
+        match depth {
+            Some(depth) => self.code.push(Branch(BR, depth)),
+            None => self.do_tree(target, context)
+        }
+    }
+
+    fn branch_depth(&self, source: uint, target: uint, context: &[ContextEntry]) -> Option<u32> {
+        let entry = if self.is_back_edge(source, target) {
+            LoopHeadedBy(target)
+        } else if self.merge[target] {
+            BlockFollowedBy(target)
+        } else {
+            return None;
+        };
+        for (depth, enclosing) in context.rev_iter().enumerate() {
+            if *enclosing == entry {
+                return Some(depth as u32);
+            }
+        }
+        fail!(fmt!("No enclosing %? for the branch to %u", entry, self.basic_blocks[target].start as uint))
+    }
+
+    /**
+     * Emits a basic block's opcodes and the transfer of control at its end.
+     */
+    fn emit_basic_block(&mut self, node: uint, context: &mut ~[ContextEntry]) {
+        let basic_block = self.basic_blocks[node];
+        let types = stack_types(&*basic_block, self.local_types);
+        let mut top = Unknown;
+        for (opcode, stack) in basic_block.opcodes.iter().zip(types.iter()) {
+            top = self.emit_opcode(opcode, *stack);
+        }
+
+        match self.successors[node] {
+            (Some(conditional), Some(next)) => {
+                if top == Float32 {
+                    self.code.push(F32Const(0f32));
+                    self.code.push(Simple(F32_NE));
+                }
+                let conditional_depth = self.branch_depth(node, conditional, *context);
+                let next_depth = self.branch_depth(node, next, *context);
+                match (conditional_depth, next_depth) {
+                    (Some(depth), _) => {
+                        self.code.push(Branch(BR_IF, depth));
+                        self.do_branch(node, next, context);
+                    }
+                    (None, Some(depth)) => {
+                        self.code.push(Simple(I32_EQZ));
+                        self.code.push(Branch(BR_IF, depth));
+                        self.do_tree(conditional, context);
+                    }
+                    (None, None) => {
+                        self.code.push(Structured(IF));
+                        context.push(IfThenElse);
+                        self.do_tree(conditional, context);
+                        self.code.push(Simple(ELSE));
+                        self.do_tree(next, context);
+                        context.pop();
+                        self.code.push(Simple(END));
+                    }
+                }
+            }
+            (None, Some(next)) => self.do_branch(node, next, context),
+            _ => {
+                match basic_block.opcodes.last_opt() {
+                    Some(&Ret) => { }
+                    // Falling off the end returns nothing, which a function with a result cannot.
+                    _ => self.code.push(Simple(if self.return_type == Unknown { RETURN } else { UNREACHABLE }))
+                }
+            }
+        }
+    }
+
+    /**
+     * Emits an opcode, given the types on the stack before it.
+     *
+     * Returns the type of the value it pushes, or Unknown.
+     */
+    fn emit_opcode(&mut self, opcode: &Opcode, stack: &[VariableType]) -> VariableType {
+        let top = if stack.is_empty() { Unknown } else { stack[stack.len() - 1] };
+        match *opcode {
+            Constf32(value) => { self.code.push(F32Const(value)); Float32 }
+            Consti32(value) => { self.code.push(I32Const(value)); Int32 }
+            Add => self.arithmetic(stack, I32_ADD, F32_ADD),
+            Subtract => self.arithmetic(stack, I32_SUB, F32_SUB),
+            Multiply => self.arithmetic(stack, I32_MUL, F32_MUL),
+            Divide => self.arithmetic(stack, I32_DIV_S, F32_DIV),
+            Eq => self.comparison(stack, I32_EQ, F32_EQ),
+            Neq => self.comparison(stack, I32_NE, F32_NE),
+            Leq => self.comparison(stack, I32_LE_S, F32_LE),
+            Geq => self.comparison(stack, I32_GE_S, F32_GE),
+            Lt => self.comparison(stack, I32_LT_S, F32_LT),
+            Gt => self.comparison(stack, I32_GT_S, F32_GT),
+            Rem => self.simple(I32_REM_S, Int32),
+            And => self.simple(I32_AND, Int32),
+            Or => self.simple(I32_OR, Int32),
+            Xor => self.simple(I32_XOR, Int32),
+            Shl => self.simple(I32_SHL, Int32),
+            Shr => self.simple(I32_SHR_S, Int32),
+            Ushr => self.simple(I32_SHR_U, Int32),
+            Ltu => self.simple(I32_LT_U, Int32),
+            Gtu => self.simple(I32_GT_U, Int32),
+            Lequ => self.simple(I32_LE_U, Int32),
+            Gequ => self.simple(I32_GE_U, Int32),
+            AddOvf => self.checked(I64_ADD),
+            SubOvf => self.checked(I64_SUB),
+            MulOvf => self.checked(I64_MUL),
+            Min => if self.operands(stack) { self.simple(F32_MIN, Float32) } else { self.select(I32_LE_S) },
+            Max => if self.operands(stack) { self.simple(F32_MAX, Float32) } else { self.select(I32_GE_S) },
+            Pow => {
+                self.operands(stack);
+                self.call("Math", "pow", ~[F32, F32], Some(F32));
+                Float32
+            }
+            Negate => {
+                if top == Float32 {
+                    self.simple(F32_NEG, Float32)
+                } else {
+                    self.code.push(I32Const(-1));
+                    self.simple(I32_MUL, Int32)
+                }
+            }
+            Not => {
+                self.code.push(I32Const(-1));
+                self.simple(I32_XOR, Int32)
+            }
+            Abs => if top == Float32 { self.simple(F32_ABS, Float32) } else { self.integer_abs() },
+            Sqrt => self.math(top, F32_SQRT),
+            Floor => self.math(top, F32_FLOOR),
+            Ceil => self.math(top, F32_CEIL),
+            Trunc => self.math(top, F32_TRUNC),
+            Round => self.round(top),
+            Sin => self.imported_math(top, "sin"),
+            Cos => self.imported_math(top, "cos"),
+            Tan => self.imported_math(top, "tan"),
+            Exp => self.imported_math(top, "exp"),
+            Log => self.imported_math(top, "log"),
+            Ret => {
+                let return_type = self.return_type;
+                if return_type != Unknown {
+                    self.convert(top, return_type);
+                }
+                self.simple(RETURN, Unknown)
+            }
+            Disp => {
+                self.call("console", "log", ~[value_type(top)], None);
+                Unknown
+            }
+            Store(addr) => {
+                let local_type = self.local_types[addr];
+                self.convert(top, local_type);
+                self.code.push(Local(LOCAL_SET, addr));
+                Unknown
+            }
+            Loadf32(addr) | Loadi32(addr) => {
+                self.code.push(Local(LOCAL_GET, addr));
+                if self.local_types[addr] == Float32 { Float32 } else { Int32 }
+            }
+            _ => Unknown
+        }
+    }
+
+    fn simple(&mut self, encoding: u8, result: VariableType) -> VariableType {
+        self.code.push(Simple(encoding));
+        result
+    }
+
+    /**
+     * Converts the operands of a binary opcode to floats if either is.
+     * The one below the top is converted through a scratch local.
+     *
+     * Returns whether the operands are floats.
+     */
+    fn operands(&mut self, stack: &[VariableType]) -> bool {
+        let top = stack[stack.len() - 1];
+        let below = stack[stack.len() - 2];
+        if top != Float32 && below != Float32 {
+            return false;
+        }
+        if below != Float32 {
+            let scratch = self.scratch(2);
+            self.code.push(Local(LOCAL_SET, scratch));
+            self.code.push(Simple(F32_CONVERT_I32_S));
+            self.code.push(Local(LOCAL_GET, scratch));
+        }
+        if top != Float32 {
+            self.code.push(Simple(F32_CONVERT_I32_S));
+        }
+        true
+    }
+
+    fn arithmetic(&mut self, stack: &[VariableType], integer: u8, float: u8) -> VariableType {
+        if self.operands(stack) { self.simple(float, Float32) } else { self.simple(integer, Int32) }
+    }
+
+    fn comparison(&mut self, stack: &[VariableType], integer: u8, float: u8) -> VariableType {
+        let encoding = if self.operands(stack) { float } else { integer };
+        self.simple(encoding, Int32)
+    }
+
+    /**
+     * Emits AddOvf, SubOvf or MulOvf, computing in 64 bits and trapping
+     * if the result does not fit in 32.
+     */
+    fn checked(&mut self, encoding: u8) -> VariableType {
+        let wide = self.scratch(4);
+        self.code.push_all_move(~[
+            Simple(I64_EXTEND_I32_S), Local(LOCAL_SET, wide), Simple(I64_EXTEND_I32_S),
+            Local(LOCAL_GET, wide), Simple(encoding), Local(LOCAL_TEE, wide),
+            Local(LOCAL_GET, wide), Simple(I32_WRAP_I64), Simple(I64_EXTEND_I32_S), Simple(I64_NE),
+            Structured(IF), Simple(UNREACHABLE), Simple(END),
+            Local(LOCAL_GET, wide), Simple(I32_WRAP_I64)
+        ]);
+        Int32
+    }
+
+    /**
+     * Emits integer Min or Max, selecting the operand below the top if
+     * the comparison holds.
+     */
+    fn select(&mut self, comparison: u8) -> VariableType {
+        let (v1, v2) = (self.scratch(0), self.scratch(1));
+        self.code.push_all_move(~[
+            Local(LOCAL_SET, v2), Local(LOCAL_SET, v1), Local(LOCAL_GET, v1), Local(LOCAL_GET, v2),
+            Local(LOCAL_GET, v1), Local(LOCAL_GET, v2), Simple(comparison), Simple(SELECT)
+        ]);
+        Int32
+    }
+
+    fn integer_abs(&mut self) -> VariableType {
+        let v = self.scratch(0);
+        self.code.push_all_move(~[
+            Local(LOCAL_SET, v), I32Const(0), Local(LOCAL_GET, v), Simple(I32_SUB), Local(LOCAL_GET, v),
+            Local(LOCAL_GET, v), I32Const(0), Simple(I32_LT_S), Simple(SELECT)
+        ]);
+        Int32
+    }
+
+    fn math(&mut self, top: VariableType, encoding: u8) -> VariableType {
+        self.convert(top, Float32);
+        self.simple(encoding, Float32)
+    }
+
+    /**
+     * Emits Round, which rounds halfway cases away from zero where
+     * f32.nearest rounds them to even: the truncation, moved one away
+     * from zero if that is at least a half away.
+     */
+    fn round(&mut self, top: VariableType) -> VariableType {
+        self.convert(top, Float32);
+        let (value, truncated) = (self.scratch(2), self.scratch(3));
+        self.code.push_all_move(~[
+            Local(LOCAL_SET, value), Local(LOCAL_GET, value), Simple(F32_TRUNC), Local(LOCAL_SET, truncated),
+            Local(LOCAL_GET, truncated), F32Const(1f32), Local(LOCAL_GET, value), Simple(F32_COPYSIGN),
+            Simple(F32_ADD), Local(LOCAL_GET, truncated), Local(LOCAL_GET, value),
+            Local(LOCAL_GET, truncated), Simple(F32_SUB), Simple(F32_ABS), F32Const(0.5f32), Simple(F32_GE),
+            Simple(SELECT)
+        ]);
+        Float32
+    }
+
+    fn imported_math(&mut self, top: VariableType, field: &'static str) -> VariableType {
+        self.convert(top, Float32);
+        self.call("Math", field, ~[F32], Some(F32));
+        Float32
+    }
+
+    /**
+     * Converts the value on top of the stack between an integer and a float.
+     */
+    fn convert(&mut self, from: VariableType, to: VariableType) {
+        if to == Float32 && from != Float32 {
+            self.code.push(Simple(F32_CONVERT_I32_S));
+        } else if to != Float32 && from == Float32 {
+            self.code.push(Simple(I32_TRUNC_F32_S));
+        }
+    }
+
+    /**
+     * Emits a call to an imported function, importing it if this is the
+     * first call.
+     */
+    fn call(&mut self, module: &'static str, field: &'static str, params: ~[u8], result: Option<u8>) {
+        let existing = self.imports.iter().position(|import| {
+            import.module == module && import.field == field && import.params == params
+        });
+        let index = match existing {
+            Some(index) => index,
+            None => {
+                self.imports.push(Import { module: module, field: field, params: params, result: result });
+                self.imports.len() - 1
+            }
+        };
+        self.code.push(Call(index as u32));
+    }
+
+    /**
+     * Returns the index of a scratch local: two i32s, two f32s and an i64.
+     */
+    fn scratch(&self, index: u32) -> u32 {
+        self.local_types.len() as u32 + index
+    }
+
+    /**
+     * Returns the types of the function's locals, then its scratch locals.
+     */
+    fn local_declarations(&self) -> ~[u8] {
+        let mut types = self.local_types.map(|&t| value_type(t));
+        types.push_all([I32, I32, F32, F32, I64]);
+        types
+    }
+
+    fn binary(&self, name: &str) -> ~[u8] {
+        let mut module = ~[];
+        module.push_all(HEADER);
+
+        let mut types = ~[];
+        write_unsigned(&mut types, self.imports.len() as u32 + 1);
+        for import in self.imports.iter() {
+            write_function_type(&mut types, import.params, import.result);
+        }
+        write_function_type(&mut types, [], self.result());
+        write_section(&mut module, TYPE_SECTION, types);
+
+        if !self.imports.is_empty() {
+            let mut imports = ~[];
+            write_unsigned(&mut imports, self.imports.len() as u32);
+            for (index, import) in self.imports.iter().enumerate() {
+                write_name(&mut imports, import.module);
+                write_name(&mut imports, import.field);
+                imports.push(0x00);
+                write_unsigned(&mut imports, index as u32);
+            }
+            write_section(&mut module, IMPORT_SECTION, imports);
+        }
+
+        let mut functions = ~[];
+        write_unsigned(&mut functions, 1);
+        write_unsigned(&mut functions, self.imports.len() as u32);
+        write_section(&mut module, FUNCTION_SECTION, functions);
+
+        let mut exports = ~[];
+        write_unsigned(&mut exports, 1);
+        write_name(&mut exports, name);
+        exports.push(0x00);
+        write_unsigned(&mut exports, self.imports.len() as u32);
+        write_section(&mut module, EXPORT_SECTION, exports);
+
+        let mut body = ~[];
+        let locals = self.local_declarations();
+        let mut groups = ~[];
+        for &local in locals.iter() {
+            if !groups.is_empty() && groups[groups.len() - 1].second() == local {
+                let (count, _) = groups.pop();
+                groups.push((count + 1, local));
+            } else {
+                groups.push((1u32, local));
+            }
+        }
+        write_unsigned(&mut body, groups.len() as u32);
+        for &(count, local) in groups.iter() {
+            write_unsigned(&mut body, count);
+            body.push(local);
+        }
+        for instruction in self.code.iter() {
+            instruction.encode(&mut body);
+        }
+        body.push(END);
+
+        let mut code = ~[];
+        write_unsigned(&mut code, 1);
+        write_unsigned(&mut code, body.len() as u32);
+        code.push_all(body);
+        write_section(&mut module, CODE_SECTION, code);
+
+        module
+    }
+
+    fn text(&self, name: &str) -> ~str {
+        let mut text = ~"(module\n";
+        for import in self.imports.iter() {
+            text.push_str(fmt!("  (import \"%s\" \"%s\" (func%s))\n", import.module, import.field,
+                               signature_text(import.params, import.result)));
+        }
+        text.push_str(fmt!("  (func (export \"%s\")%s\n", escape(name), signature_text([], self.result())));
+
+        let locals = self.local_declarations().map(|&t| mnemonic(t).to_owned());
+        text.push_str(fmt!("    (local %s)\n", locals.connect(" ")));
+
+        let mut depth = 2u;
+        for instruction in self.code.iter() {
+            match *instruction {
+                Simple(encoding) if encoding == END || encoding == ELSE => depth -= 1,
+                _ => { }
+            }
+            text.push_str(fmt!("%s%s\n", "  ".repeat(depth), instruction.to_text()));
+            match *instruction {
+                Structured(_) => depth += 1,
+                Simple(encoding) if encoding == ELSE => depth += 1,
+                _ => { }
+            }
+        }
+
+        text.push_str("  )\n)\n");
+        text
+    }
+
+    fn result(&self) -> Option<u8> {
+        if self.return_type == Unknown { None } else { Some(value_type(self.return_type)) }
+    }
+}
+
+impl Instruction {
+    fn encode(&self, bytes: &mut ~[u8]) {
+        match *self {
+            Simple(encoding) => bytes.push(encoding),
+            Structured(encoding) => bytes.push_all([encoding, EMPTY_BLOCK]),
+            Branch(encoding, depth) | Local(encoding, depth) => {
+                bytes.push(encoding);
+                write_unsigned(bytes, depth);
+            }
+            Call(index) => {
+                bytes.push(CALL);
+                write_unsigned(bytes, index);
+            }
+            I32Const(value) => {
+                bytes.push(I32_CONST);
+                write_signed(bytes, value);
+            }
+            F32Const(value) => {
+                let bits: u32 = unsafe { transmute(value) };
+                bytes.push(F32_CONST);
+                bytes.push_all([bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+            }
+        }
+    }
+
+    fn to_text(&self) -> ~str {
+        match *self {
+            Simple(encoding) | Structured(encoding) => mnemonic(encoding).to_owned(),
+            Branch(encoding, index) | Local(encoding, index) => fmt!("%s %u", mnemonic(encoding), index as uint),
+            Call(index) => fmt!("call %u", index as uint),
+            I32Const(value) => fmt!("i32.const %d", value as int),
+            F32Const(value) => fmt!("f32.const %s", float_text(value))
+        }
+    }
+}
+
+fn index_of(basic_blocks: &[@mut BasicBlock], block: Option<@mut BasicBlock>) -> Option<uint> {
+    do block.map |b| { basic_blocks.iter().position(|other| other.start == b.start).unwrap() }
+}
+
+fn depth_first(node: uint, successors: &[(Option<uint>, Option<uint>)], visited: &mut ~[bool],
+               postorder: &mut ~[uint]) {
+    visited[node] = true;
+    for &next in successor_list(successors[node]).iter() {
+        if !visited[next] {
+            depth_first(next, successors, visited, postorder);
+        }
+    }
+    postorder.push(node);
+}
+
+fn successor_list(successors: (Option<uint>, Option<uint>)) -> ~[uint] {
+    let (conditional, next) = successors;
+    conditional.iter().chain(next.iter()).map(|&n| n).collect()
+}
+
+/**
+ * Returns the nearest common dominator of two blocks.
+ */
+fn intersect(a: uint, b: uint, dominators: &[Option<uint>], order: &[Option<uint>]) -> uint {
+    let (mut a, mut b) = (a, b);
+    while a != b {
+        while order[a].unwrap() > order[b].unwrap() {
+            a = dominators[a].unwrap();
+        }
+        while order[b].unwrap() > order[a].unwrap() {
+            b = dominators[b].unwrap();
+        }
+    }
+    a
+}
+
+fn is_supported(opcode: &Opcode) -> bool {
+    match *opcode {
+        ConstStr(_) | Concat | StrLen | StrCmp | IntToStr | FloatToStr | Loadref(_) | NewArray(_) | ArrayLen |
+        ArrayLoad(_) | ArrayStore(_) | Alloc(_) | GetField(_, _) | SetField(_, _) | Throw | TryBegin(_) |
+        TryEnd => false,
+        _ => true
+    }
+}
+
+/**
+ * Returns the wasm type of values of a VM type. Locals of unknown type
+ * are never stored to, and are given i32.
+ */
+fn value_type(variable_type: VariableType) -> u8 {
+    if variable_type == Float32 { F32 } else { I32 }
+}
+
+fn write_function_type(bytes: &mut ~[u8], params: &[u8], result: Option<u8>) {
+    bytes.push(FUNCTION_TYPE);
+    write_unsigned(bytes, params.len() as u32);
+    bytes.push_all(params);
+    match result {
+        Some(t) => bytes.push_all([1, t]),
+        None => bytes.push(0)
+    }
+}
+
+fn signature_text(params: &[u8], result: Option<u8>) -> ~str {
+    let mut text = ~"";
+    if !params.is_empty() {
+        let names = params.map(|&t| mnemonic(t).to_owned());
+        text.push_str(fmt!(" (param %s)", names.connect(" ")));
+    }
+    match result {
+        Some(t) => text.push_str(fmt!(" (result %s)", mnemonic(t))),
+        None => { }
+    }
+    text
+}
+
+fn escape(name: &str) -> ~str {
+    name.replace("\\", "\\\\").replace("\"", "\\\"")
+}
+
+/**
+ * Returns the text format of a float constant: a decimal literal for
+ * whole numbers, and the exact hexadecimal form otherwise.
+ */
+fn float_text(value: f32) -> ~str {
+    let bits: u32 = unsafe { transmute(value) };
+    if !value.is_NaN() {
+        let whole = value as i32;
+        let whole_bits: u32 = unsafe { transmute(whole as f32) };
+        if whole_bits == bits {
+            return fmt!("%d", whole as int);
+        }
+    }
+
+    let sign = if bits >> 31 != 0 { "-" } else { "" };
+    let exponent = ((bits >> 23) & 0xFF) as int;
+    let mantissa = (bits & 0x7FFFFF) as uint;
+    if exponent == 0xFF {
+        if mantissa == 0 {
+            fmt!("%sinf", sign)
+        } else if mantissa == 0x400000 {
+            fmt!("%snan", sign)
+        } else {
+            fmt!("%snan:0x%x", sign, mantissa)
+        }
+    } else if exponent == 0 {
+        fmt!("%s0x0.%06xp-126", sign, mantissa << 1)
+    } else {
+        fmt!("%s0x1.%06xp%d", sign, mantissa << 1, exponent - 127)
+    }
+}
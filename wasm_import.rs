@@ -0,0 +1,726 @@
+use opcode::*;
+use interpret::*;
+use wasm::*;
+use std::cast::transmute;
+
+/// The most locals, parameters included, an imported function may declare.
+/// Local declarations take a count each, so a few bytes can ask for billions.
+static MAX_LOCALS: uint = 50000;
+
+/**
+ * Why a WebAssembly function cannot be imported.
+ */
+#[deriving(Clone)]
+#[deriving(ToStr)]
+pub enum WasmImportError {
+    /// The bytes are not a well-formed module, for the given reason.
+    MalformedModule(~str),
+
+    /// The module does not export a function of the given name that it
+    /// defines itself.
+    MissingExport(~str),
+
+    /// The instruction at the given offset in the module is outside the
+    /// subset the importer supports.
+    UnsupportedInstruction(uint, ~str),
+
+    /// The function's signature or locals use a value type other than
+    /// i32 and f32.
+    UnsupportedType(u8),
+
+    /// The operands of the instruction at the given offset have the
+    /// wrong types, or there are too few of them.
+    TypeMismatch(uint),
+
+    /// The arguments do not match the function's parameters in number
+    /// or type.
+    ArgumentMismatch
+}
+
+/**
+ * Imports a function from a WebAssembly module as a VM function, so
+ * existing numeric kernels written for wasm can run on the VM.
+ *
+ * The importer takes a subset of the binary format: i32 and f32
+ * arithmetic and comparisons, locals, drop, and the block, loop, if, br,
+ * br_if and return control flow, whose blocks take and produce no values.
+ * Structured control flow is lowered into Jmp and Iftrue, and locals into
+ * Store, Loadi32 and Loadf32 with the same indices. The parameters are
+ * the first locals, so the function starts by storing the arguments, and
+ * zeroing the f32 locals, as the VM's locals start as integer zeros.
+ * unreachable throws -1, which no handler catches, as a wasm trap cannot
+ * be caught either; it escapes as Uncaught(-1).
+ *
+ * Traps become VM errors: integer division by zero raises DivisionByZero,
+ * and the smallest integer divided by -1 raises Overflow, as does i32.rem_s
+ * for it, where wasm gives 0.
+ *
+ * # Arguments
+ *
+ * * module    - The module, in the binary format.
+ * * name      - The name the module exports the function under.
+ * * arguments - The values of the function's parameters.
+ */
+pub fn import(module: &[u8], name: &str, arguments: &[StackValue]) -> Result<~[Opcode], WasmImportError> {
+    let mut reader = Reader { bytes: module, position: 0, error: None };
+    if module.len() < HEADER.len() || module.iter().zip(HEADER.iter()).any(|(a, b)| a != b) {
+        return Err(MalformedModule(~"Not a WebAssembly module"));
+    }
+    reader.position = HEADER.len();
+
+    let mut types: ~[(~[u8], ~[u8])] = ~[];
+    let mut imported_functions = 0u;
+    let mut function_types: ~[u32] = ~[];
+    let mut exported: Option<uint> = None;
+    let mut body: Option<(uint, uint)> = None;
+
+    while reader.position < module.len() && reader.error.is_none() {
+        let id = reader.byte();
+        let size = reader.length(module.len());
+        let end = reader.position + size;
+
+        if id == TYPE_SECTION {
+            for _ in range(0, reader.length(end)) {
+                if reader.error.is_some() {
+                    break;
+                }
+                if reader.byte() != FUNCTION_TYPE {
+                    reader.fail(~"Expected a function type");
+                }
+                let params = reader.value_types();
+                let results = reader.value_types();
+                types.push((params, results));
+            }
+        } else if id == IMPORT_SECTION {
+            for _ in range(0, reader.length(end)) {
+                if reader.error.is_some() {
+                    break;
+                }
+                reader.bytes_vector();
+                reader.bytes_vector();
+                match reader.byte() {
+                    0 => {
+                        reader.unsigned();
+                        imported_functions += 1;
+                    }
+                    1 => {
+                        reader.byte();
+                        reader.limits();
+                    }
+                    2 => reader.limits(),
+                    3 => {
+                        reader.byte();
+                        reader.byte();
+                    }
+                    _ => reader.fail(~"Unknown import kind")
+                }
+            }
+        } else if id == FUNCTION_SECTION {
+            for _ in range(0, reader.length(end)) {
+                if reader.error.is_some() {
+                    break;
+                }
+                function_types.push(reader.unsigned());
+            }
+        } else if id == EXPORT_SECTION {
+            for _ in range(0, reader.length(end)) {
+                if reader.error.is_some() {
+                    break;
+                }
+                let export_name = reader.bytes_vector();
+                let kind = reader.byte();
+                let index = reader.unsigned() as uint;
+                if kind == 0 && export_name.as_slice() == name.as_bytes() {
+                    exported = Some(index);
+                }
+            }
+        } else if id == CODE_SECTION {
+            for index in range(0, reader.length(end)) {
+                if reader.error.is_some() {
+                    break;
+                }
+                let size = reader.length(end);
+                if exported == Some(imported_functions + index) {
+                    body = Some((reader.position, reader.position + size));
+                }
+                reader.position += size;
+            }
+        }
+
+        // Other sections do not affect the functions the importer takes.
+        reader.position = end;
+    }
+    match reader.error.take() {
+        Some(error) => return Err(error),
+        None => { }
+    }
+
+    let (function, body_start, body_end) = match (exported, body) {
+        (Some(index), Some((start, end))) if index >= imported_functions => (index - imported_functions, start, end),
+        _ => return Err(MissingExport(name.to_owned()))
+    };
+    if function >= function_types.len() || function_types[function] as uint >= types.len() {
+        return Err(MalformedModule(~"Function without a type"));
+    }
+    let (ref params, ref results) = types[function_types[function]];
+    for &value_type in params.iter().chain(results.iter()) {
+        if value_type != I32 && value_type != F32 {
+            return Err(UnsupportedType(value_type));
+        }
+    }
+    if results.len() > 1 {
+        return Err(MalformedModule(~"Function with more than one result"));
+    }
+    if arguments.len() != params.len() {
+        return Err(ArgumentMismatch);
+    }
+
+    let mut lowering = Lowering {
+        opcodes: ~[],
+        locals: params.clone(),
+        result: if results.is_empty() { None } else { Some(results[0]) },
+        stack: ~[],
+        frames: ~[],
+        returns: ~[]
+    };
+    for (index, (argument, &param)) in arguments.iter().zip(params.iter()).enumerate() {
+        match *argument {
+            Int(value) if param == I32 => lowering.opcodes.push(Consti32(value)),
+            Float(value) if param == F32 => lowering.opcodes.push(Constf32(value)),
+            _ => return Err(ArgumentMismatch)
+        }
+        lowering.opcodes.push(Store(index as u32));
+    }
+
+    reader.position = body_start;
+    for _ in range(0, reader.length(body_end)) {
+        let count = reader.unsigned() as uint;
+        let value_type = reader.byte();
+        if reader.error.is_some() {
+            break;
+        }
+        if count > MAX_LOCALS - lowering.locals.len() {
+            return Err(MalformedModule(fmt!("More than %u locals", MAX_LOCALS)));
+        }
+        for _ in range(0, count) {
+            if value_type == F32 {
+                let index = lowering.locals.len() as u32;
+                lowering.opcodes.push_all([Constf32(0f32), Store(index)]);
+            }
+            lowering.locals.push(value_type);
+        }
+    }
+    match reader.error.take() {
+        Some(error) => return Err(error),
+        None => { }
+    }
+
+    match lowering.lower(&mut reader, body_end) {
+        Ok(()) => Ok(lowering.opcodes),
+        Err(error) => Err(error)
+    }
+}
+
+/**
+ * Reads the binary format. The first error is kept, and reads after it,
+ * or past the end, return zeros, so callers check once per construct.
+ */
+struct Reader<'self> {
+    bytes: &'self [u8],
+    position: uint,
+    error: Option<WasmImportError>
+}
+
+impl<'self> Reader<'self> {
+    /**
+     * Reads a count of items or a size in bytes, which cannot exceed the
+     * bytes left before the given end, as every item takes at least one.
+     * Returns 0 for a larger count, or once there has been an error, so
+     * that a few bytes cannot make the importer loop for ever.
+     */
+    fn length(&mut self, end: uint) -> uint {
+        let length = self.unsigned() as uint;
+        if self.error.is_some() {
+            return 0;
+        }
+        if self.position > end || length > end - self.position {
+            self.fail(~"Length past the end of its section");
+            return 0;
+        }
+        length
+    }
+
+    fn fail(&mut self, reason: ~str) {
+        if self.error.is_none() {
+            self.error = Some(MalformedModule(fmt!("%s at offset %u", reason, self.position)));
+        }
+    }
+
+    fn byte(&mut self) -> u8 {
+        if self.position >= self.bytes.len() {
+            self.fail(~"Unexpected end of module");
+            return 0;
+        }
+        self.position += 1;
+        self.bytes[self.position - 1]
+    }
+
+    fn unsigned(&mut self) -> u32 {
+        let mut value = 0u32;
+        let mut shift = 0u32;
+        loop {
+            let byte = self.byte();
+            if shift >= 32 {
+                self.fail(~"Integer too long");
+                return 0;
+            }
+            value = value | ((byte & 0x7F) as u32 << shift);
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+
+    fn signed(&mut self) -> i32 {
+        let mut value = 0i32;
+        let mut shift = 0i32;
+        loop {
+            let byte = self.byte();
+            if shift >= 32 {
+                self.fail(~"Integer too long");
+                return 0;
+            }
+            value = value | ((byte & 0x7F) as i32 << shift);
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 32 && byte & 0x40 != 0 {
+                    value = value | (-1i32 << shift);
+                }
+                return value;
+            }
+        }
+    }
+
+    fn float(&mut self) -> f32 {
+        let mut bits = 0u32;
+        for shift in range(0u32, 4) {
+            bits = bits | (self.byte() as u32 << (shift * 8));
+        }
+        unsafe { transmute(bits) }
+    }
+
+    /**
+     * Reads a vector of bytes, such as a name.
+     */
+    fn bytes_vector(&mut self) -> ~[u8] {
+        let mut bytes = ~[];
+        for _ in range(0, self.length(self.bytes.len())) {
+            bytes.push(self.byte());
+        }
+        bytes
+    }
+
+    fn value_types(&mut self) -> ~[u8] {
+        let mut value_types = ~[];
+        for _ in range(0, self.length(self.bytes.len())) {
+            value_types.push(self.byte());
+        }
+        value_types
+    }
+
+    fn limits(&mut self) {
+        let flags = self.byte();
+        self.unsigned();
+        if flags & 1 != 0 {
+            self.unsigned();
+        }
+    }
+}
+
+/**
+ * A block, loop or if being lowered, or the function body.
+ */
+struct Frame {
+    /// BLOCK, LOOP or IF.
+    kind: u8,
+
+    /// The height of the operand stack on entering the frame.
+    height: uint,
+
+    /// The offset of the first opcode, which a branch to a loop targets.
+    start: u32,
+
+    /// The Jmp and Iftrue opcodes to patch with the frame's end.
+    branches: ~[uint],
+
+    /// For an if without an else yet, the Iftrue to patch with the else.
+    else_branch: Option<uint>,
+
+    /// Whether the frame was entered from unreachable code.
+    dead: bool,
+
+    /// Whether the rest of the frame, up to an else or the end, is
+    /// unreachable, after a branch.
+    unreachable: bool
+}
+
+struct Lowering {
+    opcodes: ~[Opcode],
+
+    /// The type of each wasm local, parameters first.
+    locals: ~[u8],
+
+    result: Option<u8>,
+
+    /// The types on the operand stack, bottom first.
+    stack: ~[u8],
+
+    frames: ~[Frame],
+
+    /// The Jmp opcodes of returns from a function without a result, to
+    /// patch with the end of the function.
+    returns: ~[uint]
+}
+
+impl Lowering {
+    /**
+     * Lowers the instructions of a function body.
+     */
+    fn lower(&mut self, reader: &mut Reader, end: uint) -> Result<(), WasmImportError> {
+        self.frames.push(Frame { kind: BLOCK, height: 0, start: 0, branches: ~[], else_branch: None,
+                                 dead: false, unreachable: false });
+
+        while !self.frames.is_empty() {
+            if reader.position >= end {
+                return Err(MalformedModule(~"Function body without an end"));
+            }
+            let offset = reader.position;
+            let encoding = reader.byte();
+            let lowered = self.lower_instruction(reader, offset, encoding);
+            match reader.error.take() {
+                Some(error) => return Err(error),
+                None => { }
+            }
+            match lowered {
+                Ok(()) => { }
+                Err(error) => return Err(error)
+            }
+        }
+
+        let function_end = self.opcodes.len() as u32;
+        for &position in self.returns.clone().iter() {
+            self.patch(position, function_end);
+        }
+        Ok(())
+    }
+
+    /**
+     * Sets the target of the Jmp or Iftrue at a position.
+     */
+    fn patch(&mut self, position: uint, target: u32) {
+        self.opcodes[position] = match self.opcodes[position] {
+            Jmp(_) => Jmp(target),
+            _ => Iftrue(target)
+        };
+    }
+
+    fn lower_instruction(&mut self, reader: &mut Reader, offset: uint, encoding: u8) -> Result<(), WasmImportError> {
+        if encoding == BLOCK || encoding == LOOP || encoding == IF {
+            if reader.byte() != EMPTY_BLOCK {
+                return Err(UnsupportedInstruction(offset, fmt!("%s with parameters or results",
+                                                               mnemonic(encoding))));
+            }
+            let dead = self.is_unreachable();
+            if !dead && encoding == IF {
+                if !self.pop(I32) {
+                    return Err(TypeMismatch(offset));
+                }
+                self.opcodes.push_all([Consti32(0), Eq, Iftrue(0)]);
+            }
+            if !dead && self.stack.len() > self.frames[self.frames.len() - 1].height {
+                // The VM's basic blocks start with an empty evaluation stack.
+                return Err(UnsupportedInstruction(offset, fmt!("%s with values on the stack", mnemonic(encoding))));
+            }
+            let else_branch = if !dead && encoding == IF { Some(self.opcodes.len() - 1) } else { None };
+            self.frames.push(Frame { kind: encoding, height: self.stack.len(), start: self.opcodes.len() as u32,
+                                     branches: ~[], else_branch: else_branch, dead: dead, unreachable: false });
+        } else if encoding == ELSE {
+            let mut frame = self.frames.pop();
+            if frame.kind != IF || (frame.else_branch.is_none() && !frame.dead) {
+                return Err(MalformedModule(fmt!("else without if at offset %u", offset)));
+            }
+            if !frame.dead {
+                if !frame.unreachable && self.stack.len() != frame.height {
+                    return Err(TypeMismatch(offset));
+                }
+                if !frame.unreachable {
+                    frame.branches.push(self.opcodes.len());
+                    self.opcodes.push(Jmp(0));
+                }
+                let else_start = self.opcodes.len() as u32;
+                self.patch(frame.else_branch.take_unwrap(), else_start);
+            }
+            self.stack.truncate(frame.height);
+            frame.unreachable = false;
+            self.frames.push(frame);
+        } else if encoding == END {
+            let frame = self.frames.pop();
+            if !frame.dead && !frame.unreachable {
+                if self.frames.is_empty() {
+                    match self.result {
+                        Some(result) => {
+                            if !self.pop(result) {
+                                return Err(TypeMismatch(offset));
+                            }
+                            self.opcodes.push(Ret);
+                        }
+                        None => { }
+                    }
+                }
+                if self.stack.len() != frame.height {
+                    return Err(TypeMismatch(offset));
+                }
+            }
+            self.stack.truncate(frame.height);
+
+            let end = self.opcodes.len() as u32;
+            for &position in frame.branches.iter().chain(frame.else_branch.iter()) {
+                self.patch(position, end);
+            }
+        } else if encoding == BR || encoding == BR_IF {
+            let depth = reader.unsigned() as uint;
+            if depth >= self.frames.len() {
+                return Err(MalformedModule(fmt!("Branch depth out of range at offset %u", offset)));
+            }
+            if self.is_unreachable() {
+                return Ok(());
+            }
+            return self.lower_branch(offset, encoding, self.frames.len() - 1 - depth);
+        } else if encoding == RETURN {
+            if !self.is_unreachable() {
+                return self.lower_return(offset);
+            }
+        } else if encoding == UNREACHABLE {
+            if !self.is_unreachable() {
+                self.opcodes.push_all([Consti32(-1), Throw]);
+                self.set_unreachable();
+            }
+        } else if encoding == NOP {
+        } else if encoding == DROP {
+            if !self.is_unreachable() {
+                if self.stack.is_empty() {
+                    return Err(TypeMismatch(offset));
+                }
+                let value_type = self.stack.pop();
+                let scratch = self.scratch(value_type);
+                self.opcodes.push(Store(scratch));
+            }
+        } else if encoding == LOCAL_GET || encoding == LOCAL_SET || encoding == LOCAL_TEE {
+            let index = reader.unsigned();
+            if index as uint >= self.locals.len() {
+                return Err(MalformedModule(fmt!("Local out of range at offset %u", offset)));
+            }
+            let local_type = self.locals[index];
+            if local_type != I32 && local_type != F32 {
+                return Err(UnsupportedType(local_type));
+            }
+            if self.is_unreachable() {
+                return Ok(());
+            }
+            if encoding != LOCAL_GET {
+                if !self.pop(local_type) {
+                    return Err(TypeMismatch(offset));
+                }
+                self.opcodes.push(Store(index));
+            }
+            if encoding != LOCAL_SET {
+                self.opcodes.push(if local_type == F32 { Loadf32(index) } else { Loadi32(index) });
+                self.stack.push(local_type);
+            }
+        } else if encoding == I32_CONST {
+            let value = reader.signed();
+            if !self.is_unreachable() {
+                self.opcodes.push(Consti32(value));
+                self.stack.push(I32);
+            }
+        } else if encoding == F32_CONST {
+            let value = reader.float();
+            if !self.is_unreachable() {
+                self.opcodes.push(Constf32(value));
+                self.stack.push(F32);
+            }
+        } else {
+            let (operands, result, opcodes) = match numeric(encoding) {
+                Some(lowered) => lowered,
+                None => {
+                    let name = match find_mnemonic(encoding) {
+                        Some(name) => name.to_owned(),
+                        None => fmt!("0x%02X", encoding as uint)
+                    };
+                    return Err(UnsupportedInstruction(offset, name));
+                }
+            };
+            if !self.is_unreachable() {
+                for &operand in operands.rev_iter() {
+                    if !self.pop(operand) {
+                        return Err(TypeMismatch(offset));
+                    }
+                }
+                self.opcodes.push_all(opcodes);
+                self.stack.push(result);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Lowers a br or br_if to the frame with the given index.
+     */
+    fn lower_branch(&mut self, offset: uint, encoding: u8, target: uint) -> Result<(), WasmImportError> {
+        if encoding == BR_IF && !self.pop(I32) {
+            return Err(TypeMismatch(offset));
+        }
+        if target == 0 {
+            // A branch out of the function body returns.
+            if encoding == BR {
+                return self.lower_return(offset);
+            }
+            if self.result.is_some() {
+                return Err(UnsupportedInstruction(offset, ~"br_if out of a function with a result"));
+            }
+            let position = self.opcodes.len();
+            self.returns.push(position);
+            self.opcodes.push(Iftrue(0));
+            return Ok(());
+        }
+
+        let height = self.frames[target].height;
+        if encoding == BR_IF && self.stack.len() > height {
+            // Only the branch would discard them.
+            return Err(UnsupportedInstruction(offset, ~"br_if with values on the stack"));
+        }
+        while self.stack.len() > height {
+            let value_type = self.stack.pop();
+            let scratch = self.scratch(value_type);
+            self.opcodes.push(Store(scratch));
+        }
+
+        // A branch to a loop continues it, and is known; one to a block
+        // or if leaves it, and is patched at its end.
+        let position = self.opcodes.len();
+        let destination = if self.frames[target].kind == LOOP {
+            self.frames[target].start
+        } else {
+            self.frames[target].branches.push(position);
+            0
+        };
+        self.opcodes.push(if encoding == BR { Jmp(destination) } else { Iftrue(destination) });
+        if encoding == BR {
+            self.set_unreachable();
+        }
+        Ok(())
+    }
+
+    fn lower_return(&mut self, offset: uint) -> Result<(), WasmImportError> {
+        match self.result {
+            Some(result) => {
+                if !self.pop(result) {
+                    return Err(TypeMismatch(offset));
+                }
+                self.opcodes.push(Ret);
+            }
+            None => {
+                let position = self.opcodes.len();
+                self.returns.push(position);
+                self.opcodes.push(Jmp(0));
+            }
+        }
+        self.set_unreachable();
+        Ok(())
+    }
+
+    /**
+     * Pops an operand, returning whether there was one of the given type.
+     */
+    fn pop(&mut self, value_type: u8) -> bool {
+        match self.stack.pop_opt() {
+            Some(t) => t == value_type,
+            None => false
+        }
+    }
+
+    fn is_unreachable(&self) -> bool {
+        let frame = &self.frames[self.frames.len() - 1];
+        frame.dead || frame.unreachable
+    }
+
+    fn set_unreachable(&mut self) {
+        let index = self.frames.len() - 1;
+        self.frames[index].unreachable = true;
+        let height = self.frames[index].height;
+        self.stack.truncate(height);
+    }
+
+    /**
+     * Returns the local that dropped values of a type are stored to,
+     * after the function's own.
+     */
+    fn scratch(&self, value_type: u8) -> u32 {
+        self.locals.len() as u32 + if value_type == F32 { 1 } else { 0 }
+    }
+}
+
+/**
+ * Returns the operand types, result type and opcodes of an arithmetic,
+ * comparison or conversion instruction, or None if it is outside the subset.
+ */
+fn numeric(encoding: u8) -> Option<(~[u8], u8, ~[Opcode])> {
+    let binary = |operand: u8, result: u8, opcode: Opcode| Some((~[operand, operand], result, ~[opcode]));
+    let unary = |operand: u8, result: u8, opcodes: ~[Opcode]| Some((~[operand], result, opcodes));
+    match encoding {
+        0x45 => unary(I32, I32, ~[Consti32(0), Eq]),
+        0x46 => binary(I32, I32, Eq),
+        0x47 => binary(I32, I32, Neq),
+        0x48 => binary(I32, I32, Lt),
+        0x49 => binary(I32, I32, Ltu),
+        0x4A => binary(I32, I32, Gt),
+        0x4B => binary(I32, I32, Gtu),
+        0x4C => binary(I32, I32, Leq),
+        0x4D => binary(I32, I32, Lequ),
+        0x4E => binary(I32, I32, Geq),
+        0x4F => binary(I32, I32, Gequ),
+        0x5B => binary(F32, I32, Eq),
+        0x5C => binary(F32, I32, Neq),
+        0x5D => binary(F32, I32, Lt),
+        0x5E => binary(F32, I32, Gt),
+        0x5F => binary(F32, I32, Leq),
+        0x60 => binary(F32, I32, Geq),
+        0x6A => binary(I32, I32, Add),
+        0x6B => binary(I32, I32, Subtract),
+        0x6C => binary(I32, I32, Multiply),
+        0x6D => binary(I32, I32, Divide),
+        0x6F => binary(I32, I32, Rem),
+        0x71 => binary(I32, I32, And),
+        0x72 => binary(I32, I32, Or),
+        0x73 => binary(I32, I32, Xor),
+        0x74 => binary(I32, I32, Shl),
+        0x75 => binary(I32, I32, Shr),
+        0x76 => binary(I32, I32, Ushr),
+        0x8B => unary(F32, F32, ~[Abs]),
+        0x8C => unary(F32, F32, ~[Negate]),
+        0x8D => unary(F32, F32, ~[Ceil]),
+        0x8E => unary(F32, F32, ~[Floor]),
+        0x8F => unary(F32, F32, ~[Trunc]),
+        0x91 => unary(F32, F32, ~[Sqrt]),
+        0x92 => binary(F32, F32, Add),
+        0x93 => binary(F32, F32, Subtract),
+        0x94 => binary(F32, F32, Multiply),
+        0x95 => binary(F32, F32, Divide),
+        0x96 => binary(F32, F32, Min),
+        0x97 => binary(F32, F32, Max),
+        // Adding a float zero converts an integer exactly as wasm does.
+        0xB2 => unary(I32, F32, ~[Constf32(0f32), Add]),
+        _ => None
+    }
+}
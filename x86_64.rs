@@ -0,0 +1,1144 @@
+use backend::*;
+use std::cast::transmute;
+use std::libc::{c_int, c_void, size_t, off_t};
+use std::ptr;
+use std::util;
+use std::vec;
+
+extern {
+    fn mmap(addr: *c_void, length: size_t, prot: c_int, flags: c_int, fd: c_int, offset: off_t) -> *c_void;
+    fn mprotect(addr: *c_void, length: size_t, prot: c_int) -> c_int;
+    fn munmap(addr: *c_void, length: size_t) -> c_int;
+}
+
+static PROT_READ: c_int = 1;
+static PROT_WRITE: c_int = 2;
+static PROT_EXEC: c_int = 4;
+static MAP_PRIVATE: c_int = 2;
+static MAP_ANONYMOUS: c_int = 0x20;
+
+static RAX: uint = 0;
+static RCX: uint = 1;
+static RDX: uint = 2;
+static RSP: uint = 4;
+static RBP: uint = 5;
+static RSI: uint = 6;
+static RDI: uint = 7;
+static R8: uint = 8;
+static R9: uint = 9;
+static R10: uint = 10;
+static R11: uint = 11;
+static XMM15: uint = 15;
+
+/// The registers that carry integer and pointer arguments, in order.
+static INTEGER_ARGUMENTS: [uint, ..6] = [RDI, RSI, RDX, RCX, R8, R9];
+
+/// The number of xmm registers that carry float arguments.
+static FLOAT_ARGUMENTS: uint = 8;
+
+/// Condition codes, as they are added to the jcc, setcc and cmovcc opcodes.
+static CC_NO: u8 = 0x1;
+static CC_B: u8 = 0x2;
+static CC_AE: u8 = 0x3;
+static CC_E: u8 = 0x4;
+static CC_NE: u8 = 0x5;
+static CC_BE: u8 = 0x6;
+static CC_A: u8 = 0x7;
+static CC_S: u8 = 0x8;
+static CC_P: u8 = 0xA;
+static CC_NP: u8 = 0xB;
+static CC_L: u8 = 0xC;
+static CC_GE: u8 = 0xD;
+static CC_LE: u8 = 0xE;
+static CC_G: u8 = 0xF;
+
+/**
+ * The exception a native function leaves its caller. Compiled code cannot
+ * unwind through Rust frames, so NativeFunction::call passes each call one
+ * of these, after the function's own arguments, and an exception nothing
+ * in the function catches is stored there before the function returns.
+ */
+struct PendingException {
+    /// Non-zero if an exception escaped.
+    raised: uint,
+
+    /// The exception, encoded as thrown_exception returns it.
+    value: uint
+}
+
+impl PendingException {
+    /**
+     * Returns the exception that escaped, if any.
+     */
+    fn exception(&self) -> Option<JitException> {
+        if self.raised == 0 {
+            return None;
+        }
+
+        let bits = self.value as int;
+        if bits & 1 == 1 {
+            Some(Builtin((bits >> 1) as c_int))
+        } else {
+            Some(Thrown(bits as *c_void))
+        }
+    }
+}
+
+/**
+ * Where a value lives while the function runs.
+ */
+enum NativeOperand {
+    /// A stack slot, numbered from the frame pointer down.
+    SlotOperand(uint),
+
+    /// A constant, extended to 64 bits as its type is. Floats keep their bits in the low half.
+    ImmediateOperand(u64)
+}
+
+struct NativeValue {
+    operand: NativeOperand,
+    value_type: ScalarType
+}
+
+/**
+ * The r/m operand of an instruction.
+ */
+enum RegisterOrMemory {
+    /// A register.
+    RegisterRm(uint),
+
+    /// A base register plus a displacement.
+    MemoryRm(uint, i32),
+
+    /// A base register plus an index register times a scale of 4 or 8.
+    ElementRm(uint, uint, uint)
+}
+
+/**
+ * A Backend that generates x86-64 machine code itself, for the System V
+ * calling convention, without calling into libjit.
+ *
+ * The code is simple rather than fast: every value has a slot in the stack
+ * frame, and each instruction loads its operands into fixed registers, so
+ * nothing is kept in registers between instructions.
+ */
+pub struct X86Backend {
+    priv code: ~[u8],
+    priv values: ~[NativeValue],
+    priv slots: uint,
+
+    /// The position of each label, once it is placed, and the rel32 branches to patch.
+    priv labels: ~[Option<uint>],
+    priv fixups: ~[(uint, LabelId)],
+
+    priv params: ~[ValueId],
+    priv param_types: ~[ScalarType],
+    priv return_type: ScalarType,
+
+    /// Where exceptions go: the catcher, if the function has one, and the
+    /// stub that returns them to the caller.
+    priv catcher: LabelId,
+    priv has_catcher: bool,
+    priv unwind: LabelId,
+
+    /// The slots holding the exception being thrown, and the address of
+    /// the caller's PendingException.
+    priv exception_slot: uint,
+    priv pending_slot: uint,
+
+    /// The exception being caught, once the catcher has read it.
+    priv caught: Option<ValueId>,
+
+    priv function: Option<~NativeFunction>
+}
+
+impl X86Backend {
+    pub fn new() -> X86Backend {
+        X86Backend {
+            code: ~[],
+            values: ~[],
+            slots: 0,
+            labels: ~[],
+            fixups: ~[],
+            params: ~[],
+            param_types: ~[],
+            return_type: VoidType,
+            catcher: LabelId(0),
+            has_catcher: false,
+            unwind: LabelId(0),
+            exception_slot: 0,
+            pending_slot: 0,
+            caught: None,
+            function: None
+        }
+    }
+
+    /**
+     * Returns the compiled function, once the backend is finalized.
+     */
+    pub fn compiled(self) -> ~NativeFunction {
+        let mut backend = self;
+        match backend.function.take() {
+            Some(function) => function,
+            None => fail!("The function has not been compiled")
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.push_all(bytes);
+    }
+
+    fn emit_u32(&mut self, value: u32) {
+        let mut shift = 0u32;
+        while shift < 32 {
+            self.code.push((value >> shift) as u8);
+            shift += 8;
+        }
+    }
+
+    fn emit_u64(&mut self, value: u64) {
+        let mut shift = 0u64;
+        while shift < 64 {
+            self.code.push((value >> shift) as u8);
+            shift += 8;
+        }
+    }
+
+    /**
+     * Emits a REX prefix, if the instruction needs one.
+     */
+    fn rex(&mut self, wide: bool, reg: uint, index: uint, base: uint) {
+        let rex = (if wide { 8 } else { 0 }) | ((reg >> 3) << 2) | ((index >> 3) << 1) | (base >> 3);
+        if rex != 0 {
+            self.code.push(0x40 | rex as u8);
+        }
+    }
+
+    /**
+     * Emits an instruction with a ModRM byte.
+     *
+     * # Arguments
+     *
+     * * prefix - The mandatory prefix of an SSE instruction, which goes before REX.
+     * * wide   - Whether the instruction operates on 64 bits (REX.W).
+     * * opcode - The opcode bytes.
+     * * reg    - The register, or opcode extension, in the ModRM reg field.
+     * * rm     - The r/m operand.
+     */
+    fn instruction(&mut self, prefix: Option<u8>, wide: bool, opcode: &[u8], reg: uint, rm: RegisterOrMemory) {
+        match prefix {
+            Some(prefix) => self.code.push(prefix),
+            None => { }
+        }
+        match rm {
+            RegisterRm(base) | MemoryRm(base, _) => self.rex(wide, reg, 0, base),
+            ElementRm(base, index, _) => self.rex(wide, reg, index, base)
+        }
+        self.emit(opcode);
+
+        let reg = ((reg & 7) << 3) as u8;
+        match rm {
+            RegisterRm(base) => self.code.push(0xC0 | reg | (base & 7) as u8),
+            MemoryRm(base, displacement) => {
+                self.code.push(0x80 | reg | (base & 7) as u8);
+                if base & 7 == RSP {
+                    self.code.push(0x24);
+                }
+                self.emit_u32(displacement as u32);
+            }
+            ElementRm(base, index, scale) => {
+                let scale_bits = if scale == 8 { 3 } else { 2 };
+                self.code.push(0x84 | reg);
+                self.code.push((scale_bits << 6) as u8 | ((index & 7) << 3) as u8 | (base & 7) as u8);
+                self.emit_u32(0);
+            }
+        }
+    }
+
+    /**
+     * Emits a move of a constant into a general purpose register.
+     */
+    fn mov_imm(&mut self, reg: uint, bits: u64, wide: bool) {
+        self.rex(wide, 0, 0, reg);
+        self.code.push(0xB8 + (reg & 7) as u8);
+        if wide {
+            self.emit_u64(bits);
+        } else {
+            self.emit_u32(bits as u32);
+        }
+    }
+
+    fn setcc(&mut self, cc: u8, reg: uint) {
+        self.emit([0x0F, 0x90 + cc, 0xC0 | reg as u8]);
+    }
+
+    fn jump(&mut self, label: LabelId) {
+        self.code.push(0xE9);
+        let position = self.code.len();
+        self.fixups.push((position, label));
+        self.emit_u32(0);
+    }
+
+    fn jump_if(&mut self, cc: u8, label: LabelId) {
+        self.emit([0x0F, 0x80 + cc]);
+        let position = self.code.len();
+        self.fixups.push((position, label));
+        self.emit_u32(0);
+    }
+
+    fn call_address(&mut self, address: *c_void) {
+        self.mov_imm(RAX, address as uint as u64, true);
+        self.emit([0xFF, 0xD0]);
+    }
+
+    fn epilogue(&mut self) {
+        // mov rsp, rbp; pop rbp; ret
+        self.emit([0x48, 0x89, 0xEC, 0x5D, 0xC3]);
+    }
+
+    fn push_value(&mut self, operand: NativeOperand, value_type: ScalarType) -> ValueId {
+        self.values.push(NativeValue { operand: operand, value_type: value_type });
+        ValueId(self.values.len() - 1)
+    }
+
+    fn new_slot(&mut self) -> uint {
+        self.slots += 1;
+        self.slots - 1
+    }
+
+    /**
+     * Loads a value into a general purpose register, converted to an
+     * integer or pointer type.
+     */
+    fn load_gpr(&mut self, reg: uint, value: NativeValue, value_type: ScalarType) {
+        let wide = is_wide(value_type);
+        match (value.value_type, value.operand) {
+            (FloatType, _) => {
+                // Unsigned targets convert through 64 bits, so that values
+                // above i32's range survive.
+                self.load_xmm(XMM15, value);
+                self.instruction(Some(0xF3), wide || value_type == UIntType, [0x0F, 0x2C], reg, RegisterRm(XMM15));
+            }
+            (_, ImmediateOperand(bits)) => self.mov_imm(reg, bits, wide),
+            (IntType, SlotOperand(slot)) if wide => self.instruction(None, true, [0x63], reg, slot_rm(slot)),
+            (source_type, SlotOperand(slot)) => {
+                self.instruction(None, wide && is_wide(source_type), [0x8B], reg, slot_rm(slot));
+            }
+        }
+    }
+
+    /**
+     * Loads a value into an xmm register, converted to a float.
+     */
+    fn load_xmm(&mut self, reg: uint, value: NativeValue) {
+        match (value.value_type, value.operand) {
+            (FloatType, SlotOperand(slot)) => self.instruction(Some(0xF3), false, [0x0F, 0x10], reg, slot_rm(slot)),
+            (FloatType, ImmediateOperand(bits)) => {
+                self.mov_imm(R11, bits, false);
+                self.instruction(Some(0x66), false, [0x0F, 0x6E], reg, RegisterRm(R11));
+            }
+            (IntType, _) => {
+                self.load_gpr(R11, value, IntType);
+                self.instruction(Some(0xF3), false, [0x0F, 0x2A], reg, RegisterRm(R11));
+            }
+            _ => {
+                self.load_gpr(R11, value, NIntType);
+                self.instruction(Some(0xF3), true, [0x0F, 0x2A], reg, RegisterRm(R11));
+            }
+        }
+    }
+
+    /**
+     * Loads the bits of a value into a general purpose register, without
+     * converting it, so that floats can be moved around as integers.
+     */
+    fn load_raw(&mut self, reg: uint, value: NativeValue) {
+        match (value.value_type, value.operand) {
+            (FloatType, SlotOperand(slot)) => self.instruction(None, false, [0x8B], reg, slot_rm(slot)),
+            (FloatType, ImmediateOperand(bits)) => self.mov_imm(reg, bits, false),
+            (value_type, _) => self.load_gpr(reg, value, value_type)
+        }
+    }
+
+    /**
+     * Stores a register into a new slot, as a value of the given type.
+     * Floats are stored from the xmm register with the same number.
+     */
+    fn result(&mut self, reg: uint, value_type: ScalarType) -> ValueId {
+        let slot = self.new_slot();
+        if value_type == FloatType {
+            self.instruction(Some(0xF3), false, [0x0F, 0x11], reg, slot_rm(slot));
+        } else {
+            self.instruction(None, is_wide(value_type), [0x89], reg, slot_rm(slot));
+        }
+        self.push_value(SlotOperand(slot), value_type)
+    }
+
+    /**
+     * Stores the bits in a general purpose register into a new slot, as a
+     * value of the given type.
+     */
+    fn raw_result(&mut self, reg: uint, value_type: ScalarType) -> ValueId {
+        let slot = self.new_slot();
+        self.instruction(None, is_wide(value_type), [0x89], reg, slot_rm(slot));
+        self.push_value(SlotOperand(slot), value_type)
+    }
+
+    /**
+     * Emits a compare of a condition with zero, returning whether it
+     * compared floats, in which case the parity flag marks NaN.
+     */
+    fn test_condition(&mut self, condition: ValueId) -> bool {
+        let value = self.values[*condition];
+        if value.value_type == FloatType {
+            self.load_xmm(0, value);
+            self.instruction(None, false, [0x0F, 0x57], 1, RegisterRm(1));
+            self.instruction(None, false, [0x0F, 0x2E], 0, RegisterRm(1));
+            true
+        } else {
+            self.load_gpr(RAX, value, value.value_type);
+            self.instruction(None, is_wide(value.value_type), [0x85], RAX, RegisterRm(RAX));
+            false
+        }
+    }
+
+    /**
+     * Emits a builtin exception unless a condition code holds.
+     */
+    fn check(&mut self, ok: u8, exception_type: c_int) {
+        let passed = self.new_label();
+        self.jump_if(ok, passed);
+        self.throw_builtin(exception_type);
+        self.set_label(passed);
+    }
+
+    /**
+     * Emits a throw of the exception in rax to the catcher.
+     */
+    fn throw_rax(&mut self) {
+        let exception_slot = self.exception_slot;
+        self.instruction(None, true, [0x89], RAX, slot_rm(exception_slot));
+        let catcher = self.catcher;
+        self.jump(catcher);
+    }
+
+    fn float_binary(&mut self, op: BinaryOp, v1: NativeValue, v2: NativeValue) -> ValueId {
+        self.load_xmm(0, v1);
+        self.load_xmm(1, v2);
+
+        let opcode = match op {
+            AddOp | AddOvfOp => 0x58,
+            SubOp | SubOvfOp => 0x5C,
+            MulOp | MulOvfOp => 0x59,
+            DivOp => 0x5E,
+            MinOp => 0x5D,
+            MaxOp => 0x5F,
+            RemOp | PowOp => {
+                let helper = if op == RemOp { float_rem_helper } else { pow_helper };
+                self.call_address(unsafe { transmute(helper) });
+                return self.result(0, FloatType);
+            }
+            EqOp | NeqOp | LtOp | GtOp | LeqOp | GeqOp => {
+                self.float_comparison(op);
+                return self.result(RAX, IntType);
+            }
+            _ => fail!(fmt!("%s does not apply to floats", op.to_str()))
+        };
+        self.instruction(Some(0xF3), false, [0x0F, opcode], 0, RegisterRm(1));
+        self.result(0, FloatType)
+    }
+
+    /**
+     * Emits an IEEE comparison of xmm0 with xmm1 into eax. Only Neq is
+     * true when either operand is NaN.
+     */
+    fn float_comparison(&mut self, op: BinaryOp) {
+        // ucomiss sets CF for less than, so Lt and Leq swap the operands
+        // and test for greater than, which NaN fails.
+        let (first, second) = match op {
+            LtOp | LeqOp => (1, 0),
+            _ => (0, 1)
+        };
+        self.instruction(None, false, [0x0F, 0x2E], first, RegisterRm(second));
+        match op {
+            EqOp => {
+                self.setcc(CC_E, RAX);
+                self.setcc(CC_NP, RCX);
+                self.emit([0x20, 0xC8]);
+            }
+            NeqOp => {
+                self.setcc(CC_NE, RAX);
+                self.setcc(CC_P, RCX);
+                self.emit([0x08, 0xC8]);
+            }
+            GtOp | LtOp => self.setcc(CC_A, RAX),
+            _ => self.setcc(CC_AE, RAX)
+        }
+        self.emit([0x0F, 0xB6, 0xC0]);
+    }
+
+    fn integer_binary(&mut self, op: BinaryOp, operand_type: ScalarType, v1: NativeValue,
+                      v2: NativeValue) -> ValueId {
+        let wide = is_wide(operand_type);
+        let unsigned = operand_type == UIntType;
+        self.load_gpr(RAX, v1, operand_type);
+        match op {
+            ShlOp | ShrOp | SshrOp => self.load_gpr(RCX, v2, IntType),
+            _ => self.load_gpr(RCX, v2, operand_type)
+        }
+
+        match op {
+            AddOp => self.instruction(None, wide, [0x01], RCX, RegisterRm(RAX)),
+            SubOp => self.instruction(None, wide, [0x29], RCX, RegisterRm(RAX)),
+            AndOp => self.instruction(None, wide, [0x21], RCX, RegisterRm(RAX)),
+            OrOp => self.instruction(None, wide, [0x09], RCX, RegisterRm(RAX)),
+            XorOp => self.instruction(None, wide, [0x31], RCX, RegisterRm(RAX)),
+            MulOp => self.instruction(None, wide, [0x0F, 0xAF], RAX, RegisterRm(RCX)),
+            AddOvfOp | SubOvfOp => {
+                let opcode = if op == AddOvfOp { 0x01 } else { 0x29 };
+                self.instruction(None, wide, [opcode], RCX, RegisterRm(RAX));
+                self.check(if unsigned { CC_AE } else { CC_NO }, JIT_RESULT_OVERFLOW);
+            }
+            MulOvfOp => {
+                if unsigned {
+                    self.instruction(None, wide, [0xF7], 4, RegisterRm(RCX));
+                } else {
+                    self.instruction(None, wide, [0x0F, 0xAF], RAX, RegisterRm(RCX));
+                }
+                self.check(CC_NO, JIT_RESULT_OVERFLOW);
+            }
+            DivOp | RemOp => {
+                self.division(operand_type);
+                if op == RemOp {
+                    return self.result(RDX, operand_type);
+                }
+            }
+            ShlOp => self.instruction(None, wide, [0xD3], 4, RegisterRm(RAX)),
+            ShrOp => self.instruction(None, wide, [0xD3], if unsigned { 5 } else { 7 }, RegisterRm(RAX)),
+            SshrOp => self.instruction(None, wide, [0xD3], 7, RegisterRm(RAX)),
+            MinOp | MaxOp => {
+                // Take the second operand if the first is greater (or less).
+                let cc = match (op, unsigned) {
+                    (MinOp, true) => CC_A,
+                    (MinOp, false) => CC_G,
+                    (_, true) => CC_B,
+                    (_, false) => CC_L
+                };
+                self.instruction(None, wide, [0x39], RCX, RegisterRm(RAX));
+                self.instruction(None, wide, [0x0F, 0x40 + cc], RAX, RegisterRm(RCX));
+            }
+            EqOp | NeqOp | LtOp | GtOp | LeqOp | GeqOp => {
+                let cc = match (op, unsigned) {
+                    (EqOp, _) => CC_E,
+                    (NeqOp, _) => CC_NE,
+                    (LtOp, true) => CC_B,
+                    (LtOp, false) => CC_L,
+                    (GtOp, true) => CC_A,
+                    (GtOp, false) => CC_G,
+                    (LeqOp, true) => CC_BE,
+                    (LeqOp, false) => CC_LE,
+                    (_, true) => CC_AE,
+                    (_, false) => CC_GE
+                };
+                self.instruction(None, wide, [0x39], RCX, RegisterRm(RAX));
+                self.setcc(cc, RAX);
+                self.emit([0x0F, 0xB6, 0xC0]);
+                return self.result(RAX, IntType);
+            }
+            PowOp => fail!("Pow applies to floats")
+        }
+        self.result(RAX, operand_type)
+    }
+
+    /**
+     * Emits a division of rax by rcx, leaving the quotient in rax and the
+     * remainder in rdx. A zero divisor throws JIT_RESULT_DIVISION_BY_ZERO
+     * and the smallest integer divided by -1 throws JIT_RESULT_ARITHMETIC,
+     * as libjit's do, rather than trapping.
+     */
+    fn division(&mut self, operand_type: ScalarType) {
+        let wide = is_wide(operand_type);
+        self.instruction(None, wide, [0x85], RCX, RegisterRm(RCX));
+        self.check(CC_NE, JIT_RESULT_DIVISION_BY_ZERO);
+
+        if operand_type == UIntType {
+            self.instruction(None, false, [0x31], RDX, RegisterRm(RDX));
+            self.instruction(None, false, [0xF7], 6, RegisterRm(RCX));
+            return;
+        }
+
+        let in_range = self.new_label();
+        self.instruction(None, wide, [0x83], 7, RegisterRm(RCX));
+        self.code.push(0xFF);
+        self.jump_if(CC_NE, in_range);
+        if wide {
+            self.mov_imm(RDX, 1u64 << 63, true);
+            self.instruction(None, true, [0x39], RDX, RegisterRm(RAX));
+        } else {
+            self.instruction(None, false, [0x81], 7, RegisterRm(RAX));
+            self.emit_u32(0x80000000);
+        }
+        self.jump_if(CC_NE, in_range);
+        self.throw_builtin(JIT_RESULT_ARITHMETIC);
+        self.set_label(in_range);
+
+        // cqo or cdq, then idiv.
+        if wide {
+            self.emit([0x48, 0x99]);
+        } else {
+            self.code.push(0x99);
+        }
+        self.instruction(None, wide, [0xF7], 7, RegisterRm(RCX));
+    }
+
+    /**
+     * Builds the prologue: sets up the frame, zeroes its slots, so that
+     * locals read before they are stored hold zero, and stores the
+     * parameters and the address of the caller's PendingException in theirs.
+     */
+    fn prologue(&mut self) {
+        // push rbp; mov rbp, rsp
+        self.emit([0x55, 0x48, 0x89, 0xE5]);
+
+        let frame = (self.slots * 8 + 15) & !15;
+        if frame > 0 {
+            self.instruction(None, true, [0x81], 5, RegisterRm(RSP));
+            self.emit_u32(frame as u32);
+
+            // The loop only uses registers that do not carry arguments.
+            self.instruction(None, true, [0x8D], R10, MemoryRm(RBP, -(frame as i32)));
+            self.mov_imm(R11, (frame / 8) as u64, false);
+            self.instruction(None, false, [0x31], RAX, RegisterRm(RAX));
+            let top = self.code.len();
+            self.instruction(None, true, [0x89], RAX, MemoryRm(R10, 0));
+            self.instruction(None, true, [0x83], 0, RegisterRm(R10));
+            self.code.push(8);
+            self.instruction(None, false, [0xFF], 1, RegisterRm(R11));
+            let distance = top as int - (self.code.len() + 2) as int;
+            self.emit([0x75, distance as i8 as u8]);
+        }
+
+        let mut integers = 0;
+        let mut floats = 0;
+        let params = self.params.clone();
+        let param_types = self.param_types.clone();
+        for (&param, &param_type) in params.iter().zip(param_types.iter()) {
+            let slot = match self.values[*param].operand {
+                SlotOperand(slot) => slot,
+                ImmediateOperand(_) => fail!("Parameters live in slots")
+            };
+            if param_type == FloatType {
+                self.instruction(Some(0xF3), false, [0x0F, 0x11], floats, slot_rm(slot));
+                floats += 1;
+            } else {
+                self.instruction(None, is_wide(param_type), [0x89], INTEGER_ARGUMENTS[integers], slot_rm(slot));
+                integers += 1;
+            }
+        }
+        let pending_slot = self.pending_slot;
+        self.instruction(None, true, [0x89], INTEGER_ARGUMENTS[integers], slot_rm(pending_slot));
+    }
+}
+
+impl Backend for X86Backend {
+    fn create_function(&mut self, params: &[ScalarType], return_type: ScalarType) {
+        // The caller's PendingException is passed after the parameters.
+        let integers = params.iter().count(|&t| t != FloatType);
+        if integers + 1 > INTEGER_ARGUMENTS.len() || params.len() - integers > FLOAT_ARGUMENTS {
+            fail!("Parameters passed on the stack are not supported");
+        }
+
+        *self = X86Backend::new();
+        self.param_types = params.to_owned();
+        self.return_type = return_type;
+        for &param_type in params.iter() {
+            let slot = self.new_slot();
+            let param = self.push_value(SlotOperand(slot), param_type);
+            self.params.push(param);
+        }
+        self.exception_slot = self.new_slot();
+        self.pending_slot = self.new_slot();
+        self.catcher = self.new_label();
+        self.unwind = self.new_label();
+    }
+
+    fn finalize(&mut self) {
+        if self.function.is_some() {
+            return;
+        }
+
+        // Exceptions nothing catches arrive in rax, and are stored in the
+        // caller's PendingException before returning.
+        let unwind = self.unwind;
+        self.set_label(unwind);
+        if !self.has_catcher {
+            self.labels[*self.catcher] = self.labels[*unwind];
+        }
+        let pending_slot = self.pending_slot;
+        self.instruction(None, true, [0x8B], RDX, slot_rm(pending_slot));
+        self.instruction(None, true, [0x89], RAX, MemoryRm(RDX, 8));
+        self.instruction(None, true, [0xC7], 0, MemoryRm(RDX, 0));
+        self.emit_u32(1);
+        self.emit([0x31, 0xC0, 0x0F, 0x57, 0xC0]);
+        self.epilogue();
+
+        let fixups = self.fixups.clone();
+        for &(position, label) in fixups.iter() {
+            let target = match self.labels[*label] {
+                Some(target) => target,
+                None => fail!("A branch targets a label that was never placed")
+            };
+            let distance = (target as int - (position + 4) as int) as u32;
+            for i in range(0u, 4) {
+                self.code[position + i] = (distance >> (8 * i as u32)) as u8;
+            }
+        }
+
+        // The branches are relative, so the prologue can go in front.
+        let body = util::replace(&mut self.code, ~[]);
+        self.prologue();
+        self.code.push_all(body);
+
+        let size = self.code.len();
+        unsafe {
+            let memory = mmap(ptr::null(), size as size_t, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if memory as int == -1 {
+                fail!("Could not map memory for compiled code");
+            }
+            ptr::copy_memory(memory as *mut u8, vec::raw::to_ptr(self.code), size);
+            if mprotect(memory, size as size_t, PROT_READ | PROT_EXEC) != 0 {
+                munmap(memory, size as size_t);
+                fail!("Could not make compiled code executable");
+            }
+            self.function = Some(~NativeFunction {
+                code: memory,
+                size: size,
+                params: self.param_types.clone(),
+                return_type: self.return_type
+            });
+        }
+    }
+
+    fn param(&mut self, index: uint) -> ValueId {
+        self.params[index]
+    }
+
+    fn constant_int32(&mut self, constant: i32) -> ValueId {
+        self.push_value(ImmediateOperand(constant as i64 as u64), IntType)
+    }
+
+    fn constant_float32(&mut self, constant: f32) -> ValueId {
+        let bits: u32 = unsafe { transmute(constant) };
+        self.push_value(ImmediateOperand(bits as u64), FloatType)
+    }
+
+    fn constant_pointer(&mut self, constant: *c_void) -> ValueId {
+        self.push_value(ImmediateOperand(constant as uint as u64), PointerType)
+    }
+
+    fn create_local(&mut self, value_type: ScalarType) -> ValueId {
+        let slot = self.new_slot();
+        self.push_value(SlotOperand(slot), value_type)
+    }
+
+    fn store(&mut self, local: ValueId, value: ValueId) {
+        let local = self.values[*local];
+        let value = self.values[*value];
+        let slot = match local.operand {
+            SlotOperand(slot) => slot,
+            ImmediateOperand(_) => fail!("Constants cannot be stored to")
+        };
+        if local.value_type == FloatType {
+            self.load_xmm(0, value);
+            self.instruction(Some(0xF3), false, [0x0F, 0x11], 0, slot_rm(slot));
+        } else {
+            self.load_gpr(RAX, value, local.value_type);
+            self.instruction(None, is_wide(local.value_type), [0x89], RAX, slot_rm(slot));
+        }
+    }
+
+    fn copy(&mut self, value: ValueId) -> ValueId {
+        let value = self.values[*value];
+        match value.operand {
+            ImmediateOperand(_) => self.push_value(value.operand, value.value_type),
+            SlotOperand(slot) => {
+                let copy = self.new_slot();
+                self.instruction(None, true, [0x8B], RAX, slot_rm(slot));
+                self.instruction(None, true, [0x89], RAX, slot_rm(copy));
+                self.push_value(SlotOperand(copy), value.value_type)
+            }
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp, v1: ValueId, v2: ValueId) -> ValueId {
+        let v1 = self.values[*v1];
+        let v2 = self.values[*v2];
+        let operand_type = match op {
+            ShlOp | ShrOp | SshrOp => integer_type(v1.value_type),
+            AndOp | OrOp | XorOp => common_type(integer_type(v1.value_type), integer_type(v2.value_type)),
+            PowOp => FloatType,
+            _ => common_type(v1.value_type, v2.value_type)
+        };
+        if operand_type == FloatType {
+            self.float_binary(op, v1, v2)
+        } else {
+            self.integer_binary(op, operand_type, v1, v2)
+        }
+    }
+
+    fn unary(&mut self, op: UnaryOp, value: ValueId) -> ValueId {
+        let value = self.values[*value];
+        let value_type = value.value_type;
+        match op {
+            NegOp | AbsOp if value_type == FloatType => {
+                // Flip or clear the sign bit.
+                self.load_raw(RAX, value);
+                self.code.push(if op == NegOp { 0x35 } else { 0x25 });
+                self.emit_u32(if op == NegOp { 0x80000000 } else { 0x7FFFFFFF });
+                self.raw_result(RAX, FloatType)
+            }
+            NegOp | NotOp => {
+                let value_type = integer_type(value_type);
+                self.load_gpr(RAX, value, value_type);
+                self.instruction(None, is_wide(value_type), [0xF7], if op == NegOp { 3 } else { 2 }, RegisterRm(RAX));
+                self.result(RAX, value_type)
+            }
+            AbsOp => {
+                self.load_gpr(RAX, value, value_type);
+                if value_type == UIntType {
+                    return self.result(RAX, value_type);
+                }
+                // Negate a copy, and keep the original if that made it negative.
+                let wide = is_wide(value_type);
+                self.instruction(None, wide, [0x89], RAX, RegisterRm(RCX));
+                self.instruction(None, wide, [0xF7], 3, RegisterRm(RCX));
+                self.instruction(None, wide, [0x0F, 0x40 + CC_S], RCX, RegisterRm(RAX));
+                self.result(RCX, value_type)
+            }
+            SqrtOp => {
+                self.load_xmm(0, value);
+                self.instruction(Some(0xF3), false, [0x0F, 0x51], 0, RegisterRm(0));
+                self.result(0, FloatType)
+            }
+            _ => {
+                self.load_xmm(0, value);
+                self.call_address(math_helper(op));
+                self.result(0, FloatType)
+            }
+        }
+    }
+
+    fn convert(&mut self, value: ValueId, value_type: ScalarType) -> ValueId {
+        let value = self.values[*value];
+        match value_type {
+            FloatType => {
+                self.load_xmm(0, value);
+                self.result(0, FloatType)
+            }
+            VoidType => fail!("Values cannot be converted to void"),
+            _ => {
+                self.load_gpr(RAX, value, value_type);
+                self.result(RAX, value_type)
+            }
+        }
+    }
+
+    fn load_relative(&mut self, address: ValueId, offset: int, value_type: ScalarType) -> ValueId {
+        let address = self.values[*address];
+        self.load_gpr(RAX, address, PointerType);
+        self.instruction(None, is_wide(value_type), [0x8B], RAX, MemoryRm(RAX, offset as i32));
+        self.raw_result(RAX, value_type)
+    }
+
+    fn store_relative(&mut self, address: ValueId, offset: int, value: ValueId) {
+        let address = self.values[*address];
+        let value = self.values[*value];
+        self.load_gpr(RAX, address, PointerType);
+        self.load_raw(RCX, value);
+        self.instruction(None, is_wide(value.value_type), [0x89], RCX, MemoryRm(RAX, offset as i32));
+    }
+
+    fn add_relative(&mut self, address: ValueId, offset: int) -> ValueId {
+        let address = self.values[*address];
+        self.load_gpr(RAX, address, PointerType);
+        self.instruction(None, true, [0x81], 0, RegisterRm(RAX));
+        self.emit_u32(offset as u32);
+        self.raw_result(RAX, PointerType)
+    }
+
+    fn load_elem(&mut self, address: ValueId, index: ValueId, value_type: ScalarType) -> ValueId {
+        let address = self.values[*address];
+        let index = self.values[*index];
+        self.load_gpr(RAX, address, PointerType);
+        self.load_gpr(RCX, index, NIntType);
+        self.instruction(None, is_wide(value_type), [0x8B], RAX, ElementRm(RAX, RCX, size_of(value_type)));
+        self.raw_result(RAX, value_type)
+    }
+
+    fn store_elem(&mut self, address: ValueId, index: ValueId, value: ValueId) {
+        let address = self.values[*address];
+        let index = self.values[*index];
+        let value = self.values[*value];
+        self.load_gpr(RAX, address, PointerType);
+        self.load_gpr(RCX, index, NIntType);
+        self.load_raw(RDX, value);
+        self.instruction(None, is_wide(value.value_type), [0x89], RDX,
+                         ElementRm(RAX, RCX, size_of(value.value_type)));
+    }
+
+    fn check_null(&mut self, value: ValueId) {
+        let value = self.values[*value];
+        self.load_gpr(RAX, value, PointerType);
+        self.instruction(None, true, [0x85], RAX, RegisterRm(RAX));
+        self.check(CC_NE, JIT_RESULT_NULL_REFERENCE);
+    }
+
+    fn call_native(&mut self, _name: &str, address: *c_void, return_type: ScalarType, param_types: &[ScalarType],
+                   args: &[ValueId]) -> ValueId {
+        // Loading an argument only uses r11 and xmm15 as scratch, which
+        // carry no arguments.
+        let mut integers = 0;
+        let mut floats = 0;
+        for (&arg, &param_type) in args.iter().zip(param_types.iter()) {
+            let value = self.values[*arg];
+            if param_type == FloatType {
+                if floats == FLOAT_ARGUMENTS {
+                    fail!("Arguments passed on the stack are not supported");
+                }
+                self.load_xmm(floats, value);
+                floats += 1;
+            } else {
+                if integers == INTEGER_ARGUMENTS.len() {
+                    fail!("Arguments passed on the stack are not supported");
+                }
+                self.load_gpr(INTEGER_ARGUMENTS[integers], value, param_type);
+                integers += 1;
+            }
+        }
+        self.call_address(address);
+
+        match return_type {
+            VoidType => self.push_value(ImmediateOperand(0), IntType),
+            FloatType => self.result(0, FloatType),
+            _ => self.result(RAX, return_type)
+        }
+    }
+
+    fn new_label(&mut self) -> LabelId {
+        self.labels.push(None);
+        LabelId(self.labels.len() - 1)
+    }
+
+    fn set_label(&mut self, label: LabelId) {
+        if self.labels[*label].is_some() {
+            fail!("A label can only be placed once");
+        }
+        self.labels[*label] = Some(self.code.len());
+    }
+
+    fn branch(&mut self, label: LabelId) {
+        self.jump(label);
+    }
+
+    fn branch_if(&mut self, condition: ValueId, label: LabelId) {
+        // NaN is not zero, so it branches.
+        if self.test_condition(condition) {
+            self.jump_if(CC_P, label);
+        }
+        self.jump_if(CC_NE, label);
+    }
+
+    fn branch_if_not(&mut self, condition: ValueId, label: LabelId) {
+        if self.test_condition(condition) {
+            let nan = self.new_label();
+            self.jump_if(CC_P, nan);
+            self.jump_if(CC_E, label);
+            self.set_label(nan);
+        } else {
+            self.jump_if(CC_E, label);
+        }
+    }
+
+    fn ret(&mut self, value: ValueId) {
+        let value = self.values[*value];
+        match self.return_type {
+            VoidType => { }
+            FloatType => self.load_xmm(0, value),
+            return_type => self.load_gpr(RAX, value, return_type)
+        }
+        self.epilogue();
+    }
+
+    fn throw(&mut self, value: ValueId) {
+        let value = self.values[*value];
+        self.load_gpr(RAX, value, NIntType);
+        self.throw_rax();
+    }
+
+    fn throw_builtin(&mut self, exception_type: c_int) {
+        self.mov_imm(RAX, (((exception_type as int) << 1) | 1) as u64, true);
+        self.throw_rax();
+    }
+
+    fn start_catcher(&mut self) {
+        let catcher = self.catcher;
+        self.set_label(catcher);
+        self.has_catcher = true;
+    }
+
+    fn thrown_exception(&mut self) -> ValueId {
+        let exception_slot = self.exception_slot;
+        self.instruction(None, true, [0x8B], RAX, slot_rm(exception_slot));
+        let exception = self.raw_result(RAX, PointerType);
+        self.caught = Some(exception);
+        exception
+    }
+
+    fn rethrow_unhandled(&mut self) {
+        let exception = match self.caught {
+            Some(exception) => self.values[*exception],
+            None => fail!("rethrow_unhandled must follow thrown_exception")
+        };
+        self.load_gpr(RAX, exception, PointerType);
+        let unwind = self.unwind;
+        self.jump(unwind);
+    }
+}
+
+/**
+ * A function compiled by X86Backend, in memory mapped for it.
+ */
+pub struct NativeFunction {
+    priv code: *c_void,
+    priv size: uint,
+    priv params: ~[ScalarType],
+    priv return_type: ScalarType
+}
+
+impl NativeFunction {
+    /**
+     * Calls a function that takes up to five integer or pointer arguments
+     * and returns a float or a 32-bit integer, as the frontend compiles them.
+     *
+     * # Arguments
+     *
+     * * args - The arguments, which must match the function's parameters.
+     *
+     * Returns the bits of the returned value.
+     */
+    pub fn call(&self, args: &[*c_void]) -> Result<u32, JitException> {
+        if (self.return_type != FloatType && self.return_type != IntType) || args.len() != self.params.len()
+                || self.params.iter().any(|&t| t == FloatType) {
+            fail!(fmt!("Cannot call a function of %? returning %s with %u arguments",
+                       self.params, self.return_type.to_str(), args.len()));
+        }
+
+        let mut pending = PendingException { raised: 0, value: 0 };
+        let mut words = [0u, ..6];
+        for (word, &arg) in words.mut_iter().zip(args.iter()) {
+            *word = arg as uint;
+        }
+        words[args.len()] = ptr::to_mut_unsafe_ptr(&mut pending) as uint;
+        unsafe {
+            let retval = if self.return_type == FloatType {
+                let function: extern "C" fn(uint, uint, uint, uint, uint, uint) -> f32 = transmute(self.code);
+                transmute(function(words[0], words[1], words[2], words[3], words[4], words[5]))
+            } else {
+                let function: extern "C" fn(uint, uint, uint, uint, uint, uint) -> i32 = transmute(self.code);
+                function(words[0], words[1], words[2], words[3], words[4], words[5]) as u32
+            };
+            match pending.exception() {
+                Some(exception) => Err(exception),
+                None => Ok(retval)
+            }
+        }
+    }
+
+    /**
+     * Returns the size of the function's machine code, in bytes.
+     */
+    pub fn size(&self) -> uint {
+        self.size
+    }
+}
+
+impl Drop for NativeFunction {
+    fn drop(&self) {
+        unsafe {
+            munmap(self.code, self.size as size_t);
+        }
+    }
+}
+
+fn slot_rm(slot: uint) -> RegisterOrMemory {
+    MemoryRm(RBP, -8 * (slot as i32 + 1))
+}
+
+/**
+ * Returns whether values of a type take 64 bits.
+ */
+fn is_wide(value_type: ScalarType) -> bool {
+    value_type == NIntType || value_type == PointerType
+}
+
+fn size_of(value_type: ScalarType) -> uint {
+    if is_wide(value_type) { 8 } else { 4 }
+}
+
+/**
+ * Returns the type bitwise operations treat a value as, which is IntType
+ * for floats.
+ */
+fn integer_type(value_type: ScalarType) -> ScalarType {
+    if value_type == FloatType { IntType } else { value_type }
+}
+
+/**
+ * Returns the type a binary operation computes in, as libjit promotes:
+ * floats win, then 64-bit integers, and the operation is only unsigned
+ * if both operands are.
+ */
+fn common_type(t1: ScalarType, t2: ScalarType) -> ScalarType {
+    if t1 == FloatType || t2 == FloatType {
+        FloatType
+    } else if is_wide(t1) || is_wide(t2) {
+        NIntType
+    } else if t1 == UIntType && t2 == UIntType {
+        UIntType
+    } else {
+        IntType
+    }
+}
+
+// The float operations without an SSE instruction, computed as the interpreter does.
+extern "C" fn float_rem_helper(x: f32, y: f32) -> f32 { x % y }
+extern "C" fn pow_helper(x: f32, y: f32) -> f32 { x.pow(&y) }
+extern "C" fn floor_helper(x: f32) -> f32 { x.floor() }
+extern "C" fn ceil_helper(x: f32) -> f32 { x.ceil() }
+extern "C" fn round_helper(x: f32) -> f32 { x.round() }
+extern "C" fn trunc_helper(x: f32) -> f32 { x.trunc() }
+extern "C" fn sin_helper(x: f32) -> f32 { x.sin() }
+extern "C" fn cos_helper(x: f32) -> f32 { x.cos() }
+extern "C" fn tan_helper(x: f32) -> f32 { x.tan() }
+extern "C" fn exp_helper(x: f32) -> f32 { x.exp() }
+extern "C" fn log_helper(x: f32) -> f32 { x.ln() }
+
+/**
+ * Returns the address of the helper that computes a math intrinsic.
+ */
+fn math_helper(op: UnaryOp) -> *c_void {
+    unsafe {
+        match op {
+            FloorOp => transmute(floor_helper),
+            CeilOp => transmute(ceil_helper),
+            RoundOp => transmute(round_helper),
+            TruncOp => transmute(trunc_helper),
+            SinOp => transmute(sin_helper),
+            CosOp => transmute(cos_helper),
+            TanOp => transmute(tan_helper),
+            ExpOp => transmute(exp_helper),
+            LogOp => transmute(log_helper),
+            _ => fail!(fmt!("%s has no helper", op.to_str()))
+        }
+    }
+}